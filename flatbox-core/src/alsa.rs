@@ -0,0 +1,62 @@
+use crate::{bwrap::BwrapBuilder, environment::Environment};
+use std::{fmt::Write, path::Path};
+
+const SOUND_DEVICES_DIR: &str = "/dev/snd";
+const HOST_ASOUND_CONF: &str = "/etc/asound.conf";
+const HOST_ASOUND_CONF_MOUNT: &str = "/run/host/etc/asound.conf";
+const HOST_ALSA_DATA_DIR: &str = "/usr/share/alsa";
+const HOST_ALSA_DATA_MOUNT: &str = "/run/host/share/alsa";
+
+/// Lets ALSA clients use the sound hardware directly when the host has no sound server running.
+/// The runtime's own ALSA configuration routes everything through PulseAudio, so it's replaced by one
+/// that includes the host's configuration and falls back to software mixing on the default card.
+pub fn setup_alsa(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    private_dev: bool,
+) -> anyhow::Result<()> {
+    if !Path::new(SOUND_DEVICES_DIR).is_dir() {
+        return Ok(());
+    }
+    if private_dev {
+        bwrap.dev_bind(SOUND_DEVICES_DIR, SOUND_DEVICES_DIR);
+    }
+
+    let host_ucm_dir = Path::new(HOST_ALSA_DATA_DIR).join("ucm2");
+    if host_ucm_dir.is_dir() {
+        bwrap.ro_bind(HOST_ALSA_DATA_DIR, HOST_ALSA_DATA_MOUNT);
+        environment.set(
+            "ALSA_CONFIG_UCM2",
+            Path::new(HOST_ALSA_DATA_MOUNT).join("ucm2"),
+        );
+    }
+
+    let include_host_conf = Path::new(HOST_ASOUND_CONF).is_file();
+    if include_host_conf {
+        bwrap.ro_bind(HOST_ASOUND_CONF, HOST_ASOUND_CONF_MOUNT);
+    }
+    bwrap.ro_bind_data(
+        "/etc/asound.conf",
+        asound_conf(include_host_conf).as_bytes(),
+    )?;
+
+    Ok(())
+}
+
+fn asound_conf(include_host_conf: bool) -> String {
+    let mut conf = String::new();
+    if include_host_conf {
+        writeln!(conf, "<{HOST_ASOUND_CONF_MOUNT}>").unwrap();
+    }
+    conf.push_str(
+        "pcm.!default {\n\
+         \ttype plug\n\
+         \tslave.pcm \"dmix\"\n\
+         }\n\
+         ctl.!default {\n\
+         \ttype hw\n\
+         \tcard 0\n\
+         }\n",
+    );
+    conf
+}
@@ -0,0 +1,50 @@
+use std::process::Command;
+
+/// Flatpak's name for the host architecture. `uname` is preferred over the architecture flatbox was built for,
+/// which differs when it runs emulated or as a 32-bit binary.
+pub fn host_arch() -> String {
+    Command::new("uname")
+        .arg("-m")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|machine| flatpak_arch(machine.trim_ascii()).to_owned())
+        .unwrap_or_else(|| flatpak_arch(std::env::consts::ARCH).to_owned())
+}
+
+/// Maps a kernel or Rust architecture name to the one used in flatpak refs
+pub fn flatpak_arch(machine: &str) -> &str {
+    match machine {
+        "i386" | "i486" | "i586" | "i686" | "x86" => "i386",
+        "arm64" => "aarch64",
+        machine if machine.starts_with("arm") => "arm",
+        machine => machine,
+    }
+}
+
+/// Replaces the architecture of a `name/arch/branch` ref
+pub fn ref_with_arch(reference: &str, arch: &str) -> String {
+    let mut parts: Vec<&str> = reference.split('/').collect();
+    if let Some(ref_arch) = parts.get_mut(1) {
+        *ref_arch = arch;
+    }
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arch::{flatpak_arch, ref_with_arch};
+
+    #[test]
+    fn arch_names() {
+        assert_eq!("x86_64", flatpak_arch("x86_64"));
+        assert_eq!("i386", flatpak_arch("i686"));
+        assert_eq!("aarch64", flatpak_arch("arm64"));
+        assert_eq!("arm", flatpak_arch("armv7l"));
+        assert_eq!(
+            "org.freedesktop.Platform/i386/24.08",
+            ref_with_arch("org.freedesktop.Platform/x86_64/24.08", "i386")
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{bwrap::BwrapBuilder, environment::Environment};
+use anyhow::Context;
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Where the build dir's `files` are mounted, and what builds install to
+const PREFIX: &str = "/app";
+
+/// A host path mounted read-write at a path of the sandbox, written as `DEST=SRC` like `flatpak build --bind-mount`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BindMount {
+    pub dest: PathBuf,
+    pub source: PathBuf,
+}
+
+impl FromStr for BindMount {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once('=') {
+            Some((dest, source)) if dest.starts_with('/') && !source.is_empty() => Ok(Self {
+                dest: dest.into(),
+                source: source.into(),
+            }),
+            _ => Err(format!(
+                "expected DEST=SRC with an absolute DEST, found '{value}'"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BindMount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.dest.display(), self.source.display())
+    }
+}
+
+/// A build dir created by `flatpak build-init`, holding the `metadata` of the app being built and its `files`
+/// and `var`, with the options of `flatpak build`
+#[derive(Debug, Clone, Default)]
+pub struct BuildDir {
+    pub path: PathBuf,
+    pub bind_mounts: Vec<BindMount>,
+    /// Mount the files read-only, e.g. to run the result of a build
+    pub readonly: bool,
+}
+
+/// Mounts the build dir instead of an installed app: its `files` at /app, writable unless it's read-only,
+/// and its `var` at /var, and sets the variables builds expect
+pub fn setup_build_dir(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    build_dir: &BuildDir,
+    app_id: &str,
+    arch: &str,
+) -> anyhow::Result<()> {
+    let path = fs::canonicalize(&build_dir.path)
+        .with_context(|| format!("Could not find {}", build_dir.path.display()))?;
+    let files = path.join("files");
+    if build_dir.readonly {
+        bwrap.ro_bind(&files, PREFIX);
+    } else {
+        bwrap.bind(&files, PREFIX);
+    }
+    let var = path.join("var");
+    if var.is_dir() {
+        bwrap.bind(&var, "/var");
+    }
+
+    for mount in &build_dir.bind_mounts {
+        let source = fs::canonicalize(&mount.source)
+            .with_context(|| format!("Could not find {} to mount", mount.source.display()))?;
+        bwrap.bind(&source, &mount.dest);
+    }
+
+    environment.set("FLATPAK_ID", app_id);
+    environment.set("FLATPAK_DEST", PREFIX);
+    environment.set("FLATPAK_ARCH", arch);
+    Ok(())
+}
+
+/// Whether a dir looks like one created by `flatpak build-init`
+pub fn is_build_dir(path: &Path) -> bool {
+    path.join("metadata").is_file() && path.join("files").is_dir()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::build::BindMount;
+    use std::path::PathBuf;
+
+    #[test]
+    fn parses_bind_mounts() {
+        assert_eq!(
+            "/run/build/foo=/home/me/.cache/build/foo=1".parse(),
+            Ok(BindMount {
+                dest: PathBuf::from("/run/build/foo"),
+                source: PathBuf::from("/home/me/.cache/build/foo=1"),
+            })
+        );
+        assert!("run/build=/tmp".parse::<BindMount>().is_err());
+        assert!("/run/build=".parse::<BindMount>().is_err());
+    }
+}
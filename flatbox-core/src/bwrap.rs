@@ -0,0 +1,452 @@
+use crate::{
+    cgroup::ResourceLimits,
+    container,
+    dbus_proxy::DbusProxy,
+    extension_image::ImageMount,
+    gc, native,
+    options::Backend,
+    plan::{BindMode, MountOp, SandboxPlan},
+    priority::Priority,
+    rlimit::Rlimit,
+};
+use anyhow::Context;
+use std::{
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tempdir::TempDir;
+
+/// Collects the sandbox plan, which is turned into a bwrap command line or set up by another backend
+pub struct BwrapBuilder {
+    plan: SandboxPlan,
+    next_perms: Option<u32>,
+    apparmor_unconfined: bool,
+    data: BwrapData,
+}
+
+impl Default for BwrapBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BwrapBuilder {
+    pub fn new() -> Self {
+        Self {
+            plan: SandboxPlan::default(),
+            next_perms: None,
+            apparmor_unconfined: false,
+            data: BwrapData::default(),
+        }
+    }
+
+    /// Starts from a plan composed before, e.g. by `flatbox prepare`
+    pub fn from_plan(plan: SandboxPlan, apparmor_unconfined: bool) -> Self {
+        Self {
+            plan,
+            apparmor_unconfined,
+            ..Self::new()
+        }
+    }
+
+    fn mount(&mut self, op: MountOp) -> &mut Self {
+        crate::trace!(target: "mounts", "{op:?}");
+        self.plan.mounts.push(op);
+        self
+    }
+
+    fn bind_mode(
+        &mut self,
+        source: impl AsRef<OsStr>,
+        dest: impl AsRef<OsStr>,
+        mode: BindMode,
+    ) -> &mut Self {
+        self.mount(MountOp::Bind {
+            source: PathBuf::from(source.as_ref()),
+            dest: PathBuf::from(dest.as_ref()),
+            mode,
+        })
+    }
+
+    pub fn tmpfs(&mut self, path: impl AsRef<OsStr>) -> &mut Self {
+        let perms = self.next_perms.take();
+        self.mount(MountOp::Tmpfs {
+            dest: PathBuf::from(path.as_ref()),
+            perms,
+        })
+    }
+
+    pub fn bind(&mut self, source: impl AsRef<OsStr>, dest: impl AsRef<OsStr>) -> &mut Self {
+        self.bind_mode(source, dest, BindMode::ReadWrite)
+    }
+
+    pub fn dir(&mut self, path: impl AsRef<OsStr>) -> &mut Self {
+        let perms = self.next_perms.take();
+        self.mount(MountOp::Dir {
+            dest: PathBuf::from(path.as_ref()),
+            perms,
+        })
+    }
+
+    /// Sets the permissions of the next created file or directory
+    pub fn perms(&mut self, mode: u32) -> &mut Self {
+        self.next_perms = Some(mode);
+        self
+    }
+
+    pub fn dev(&mut self, path: impl AsRef<OsStr>) -> &mut Self {
+        self.mount(MountOp::Dev {
+            dest: PathBuf::from(path.as_ref()),
+        })
+    }
+
+    pub fn proc(&mut self, path: impl AsRef<OsStr>) -> &mut Self {
+        self.mount(MountOp::Proc {
+            dest: PathBuf::from(path.as_ref()),
+        })
+    }
+
+    pub fn ro_bind(&mut self, source: impl AsRef<OsStr>, dest: impl AsRef<OsStr>) -> &mut Self {
+        self.bind_mode(source, dest, BindMode::ReadOnly)
+    }
+
+    pub fn symlink(&mut self, source: impl AsRef<OsStr>, dest: impl AsRef<OsStr>) -> &mut Self {
+        self.mount(MountOp::Symlink {
+            target: PathBuf::from(source.as_ref()),
+            dest: PathBuf::from(dest.as_ref()),
+        })
+    }
+
+    /// Mounts an overlay of `source` with a temporary writable upper layer
+    pub fn tmp_overlay(&mut self, source: impl AsRef<OsStr>, dest: impl AsRef<OsStr>) -> &mut Self {
+        self.mount(MountOp::TmpOverlay {
+            source: PathBuf::from(source.as_ref()),
+            dest: PathBuf::from(dest.as_ref()),
+        })
+    }
+
+    /// Mounts an overlay of `source` with the writable upper layer `upper`, using `work` as overlayfs' work dir
+    pub fn overlay(
+        &mut self,
+        source: impl AsRef<OsStr>,
+        upper: impl AsRef<OsStr>,
+        work: impl AsRef<OsStr>,
+        dest: impl AsRef<OsStr>,
+    ) -> &mut Self {
+        self.mount(MountOp::Overlay {
+            source: PathBuf::from(source.as_ref()),
+            upper: PathBuf::from(upper.as_ref()),
+            work: PathBuf::from(work.as_ref()),
+            dest: PathBuf::from(dest.as_ref()),
+        })
+    }
+
+    pub fn set_env(&mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> &mut Self {
+        self.plan
+            .env
+            .push((key.as_ref().to_owned(), Some(value.as_ref().to_owned())));
+        self
+    }
+
+    pub fn unset_env(&mut self, key: impl AsRef<OsStr>) -> &mut Self {
+        self.plan.env.push((key.as_ref().to_owned(), None));
+        self
+    }
+
+    pub fn unshare_net(&mut self) -> &mut Self {
+        self.plan.unshare_net = true;
+        self
+    }
+
+    pub fn devel(&mut self) -> &mut Self {
+        self.plan.devel = true;
+        self
+    }
+
+    pub fn unshare_ipc(&mut self) -> &mut Self {
+        self.plan.unshare_ipc = true;
+        self
+    }
+
+    pub fn limits(&mut self, limits: ResourceLimits) -> &mut Self {
+        self.plan.limits = limits;
+        self
+    }
+
+    pub fn rlimits(&mut self, rlimits: Vec<Rlimit>) -> &mut Self {
+        self.plan.rlimits = rlimits;
+        self
+    }
+
+    pub fn priority(&mut self, priority: Priority) -> &mut Self {
+        self.plan.priority = priority;
+        self
+    }
+
+    pub fn new_session(&mut self) -> &mut Self {
+        self.plan.new_session = true;
+        self
+    }
+
+    pub fn chdir(&mut self, path: impl AsRef<OsStr>) -> &mut Self {
+        self.plan.chdir = Some(PathBuf::from(path.as_ref()));
+        self
+    }
+
+    pub fn dev_bind(&mut self, source: impl AsRef<OsStr>, dest: impl AsRef<OsStr>) -> &mut Self {
+        self.bind_mode(source, dest, BindMode::Device)
+    }
+
+    /*pub fn ro_bind_data(
+        &mut self,
+        path: impl AsRef<OsStr>,
+        contents: &[u8],
+    ) -> anyhow::Result<&mut Self> {
+        let memfd = MemfdOptions::new()
+            .allow_sealing(true)
+            .close_on_exec(false)
+            .create("memfd-data")
+            .context("Could not create memfd")?;
+
+        eprintln!(
+            "creating file with contents {:?}",
+            std::str::from_utf8(contents)
+        );
+
+        memfd
+            .as_file()
+            .write_all(contents)
+            .context("Could not write to memfd")?;
+        memfd
+            .as_file()
+            .seek(SeekFrom::Start(0))
+            .context("Could not seek memfd")?;
+
+        memfd.add_seals(&[
+            FileSeal::SealShrink,
+            FileSeal::SealGrow,
+            FileSeal::SealWrite,
+            FileSeal::SealSeal,
+        ])?;
+
+        let raw_fd = memfd.as_raw_fd();
+        self.data.mem_fds.push(memfd);
+
+        Ok(self.arg("--ro-bind-data").arg(raw_fd.to_string()).arg(path))
+    }*/
+
+    pub fn ro_bind_data(
+        &mut self,
+        path: impl AsRef<OsStr>,
+        contents: &[u8],
+    ) -> anyhow::Result<&mut Self> {
+        let tempfile_path = self.tempfile(contents)?;
+        Ok(self.ro_bind(tempfile_path, path))
+    }
+
+    /// Path in the setup directory on the host, which is removed after the sandbox exits
+    pub fn data_path(&self, name: &str) -> PathBuf {
+        self.data.tempdir.path().join(name)
+    }
+
+    /// Directory holding the generated files bound into the sandbox
+    pub fn setup_dir(&self) -> &Path {
+        self.data.tempdir.path()
+    }
+
+    /// Keeps a D-Bus proxy used by the sandbox running until the sandbox exits
+    pub fn add_dbus_proxy(&mut self, proxy: DbusProxy) -> &mut Self {
+        self.data.dbus_proxies.push(proxy);
+        self
+    }
+
+    /// Keeps an image mounted in the setup dir until the sandbox exits
+    pub fn add_image_mount(&mut self, mount: ImageMount) -> &mut Self {
+        self.data.image_mounts.push(mount);
+        self
+    }
+
+    fn tempfile(&mut self, contents: &[u8]) -> anyhow::Result<PathBuf> {
+        let tempfile_path = self
+            .data
+            .tempdir
+            .path()
+            .join(format!("tempfile-{}", self.data.files.len()));
+        let mut file = File::create(&tempfile_path).context("Could not create file")?;
+
+        file.write_all(contents)
+            .context("Could not write to file")?;
+        file.seek(SeekFrom::Start(0))
+            .context("Could not seek file")?;
+
+        self.data.files.push(file);
+
+        Ok(tempfile_path)
+    }
+
+    /// Runs bwrap through `aa-exec -p unconfined`
+    pub fn wrap_apparmor_unconfined(mut self) -> Self {
+        self.apparmor_unconfined = true;
+        self
+    }
+
+    pub fn plan(&self) -> &SandboxPlan {
+        &self.plan
+    }
+
+    pub fn is_apparmor_unconfined(&self) -> bool {
+        self.apparmor_unconfined
+    }
+
+    /// Creates the command running `argv` in the sandbox with the given backend
+    pub fn finish(
+        self,
+        backend: Backend,
+        argv: &[impl AsRef<OsStr>],
+    ) -> anyhow::Result<(Command, BwrapData)> {
+        if matches!(backend, Backend::Bwrap | Backend::Native) {
+            for rlimit in &self.plan.rlimits {
+                rlimit.apply()?;
+            }
+        }
+        let mut command = match backend {
+            Backend::Bwrap => {
+                let mut command = if self.apparmor_unconfined {
+                    let mut command = Command::new("aa-exec");
+                    command.args(["-p", "unconfined", "bwrap"]);
+                    command
+                } else {
+                    Command::new("bwrap")
+                };
+                command.args(bwrap_args(&self.plan)).args(argv);
+                command
+            }
+            Backend::Native | Backend::Podman | Backend::Nspawn => {
+                if self.apparmor_unconfined {
+                    crate::warn!("--apparmor-unconfined only has an effect with the bwrap backend");
+                }
+                match backend {
+                    Backend::Podman => {
+                        container::podman_command(&self.plan, self.setup_dir(), argv)?
+                    }
+                    Backend::Nspawn => {
+                        container::nspawn_command(&self.plan, self.setup_dir(), argv)?
+                    }
+                    _ => native::command(&self.plan, argv)?,
+                }
+            }
+        };
+        // The native setup applies it itself, before entering the user namespace,
+        // and podman sets the OOM score adjustment of the container
+        let mut priority = self.plan.priority;
+        if backend == Backend::Podman {
+            priority.oom_score_adj = None;
+        }
+        if backend != Backend::Native && !priority.is_empty() {
+            // SAFETY: only makes syscalls in the forked child
+            unsafe {
+                command.pre_exec(move || priority.apply());
+            }
+        }
+        Ok((command, self.data))
+    }
+}
+
+fn bwrap_args(plan: &SandboxPlan) -> Vec<OsString> {
+    let mut args = Vec::new();
+    let mut push = |new_args: &[&OsStr]| args.extend(new_args.iter().map(|arg| arg.to_os_string()));
+
+    for op in &plan.mounts {
+        if let MountOp::Tmpfs {
+            perms: Some(perms), ..
+        }
+        | MountOp::Dir {
+            perms: Some(perms), ..
+        } = op
+        {
+            push(&["--perms".as_ref(), format!("{perms:04o}").as_ref()]);
+        }
+        match op {
+            MountOp::Bind { source, dest, mode } => {
+                let flag = match mode {
+                    BindMode::ReadWrite => "--bind",
+                    BindMode::ReadOnly => "--ro-bind",
+                    BindMode::Device => "--dev-bind",
+                };
+                push(&[flag.as_ref(), source.as_ref(), dest.as_ref()]);
+            }
+            MountOp::Tmpfs { dest, .. } => push(&["--tmpfs".as_ref(), dest.as_ref()]),
+            MountOp::Dir { dest, .. } => push(&["--dir".as_ref(), dest.as_ref()]),
+            MountOp::Dev { dest } => push(&["--dev".as_ref(), dest.as_ref()]),
+            MountOp::Proc { dest } => push(&["--proc".as_ref(), dest.as_ref()]),
+            MountOp::Symlink { target, dest } => {
+                push(&["--symlink".as_ref(), target.as_ref(), dest.as_ref()]);
+            }
+            MountOp::TmpOverlay { source, dest } => push(&[
+                "--overlay-src".as_ref(),
+                source.as_ref(),
+                "--tmp-overlay".as_ref(),
+                dest.as_ref(),
+            ]),
+            MountOp::Overlay {
+                source,
+                upper,
+                work,
+                dest,
+            } => push(&[
+                "--overlay-src".as_ref(),
+                source.as_ref(),
+                "--overlay".as_ref(),
+                upper.as_ref(),
+                work.as_ref(),
+                dest.as_ref(),
+            ]),
+        }
+    }
+    for (key, value) in &plan.env {
+        match value {
+            Some(value) => push(&["--setenv".as_ref(), key, value]),
+            None => push(&["--unsetenv".as_ref(), key]),
+        }
+    }
+    if plan.unshare_net {
+        push(&["--unshare-net".as_ref()]);
+    }
+    if plan.unshare_ipc {
+        push(&["--unshare-ipc".as_ref()]);
+    }
+    if plan.new_session {
+        push(&["--new-session".as_ref()]);
+    }
+    if let Some(chdir) = &plan.chdir {
+        push(&["--chdir".as_ref(), chdir.as_ref()]);
+    }
+    args
+}
+
+#[derive(Debug)]
+pub struct BwrapData {
+    // mem_fds: Vec<Memfd>,
+    // Proxies are declared first so they stop before their sockets in the tempdir are removed
+    dbus_proxies: Vec<DbusProxy>,
+    // Likewise, images are unmounted before their mountpoints in the tempdir are removed
+    image_mounts: Vec<ImageMount>,
+    tempdir: TempDir,
+    files: Vec<File>,
+}
+
+impl Default for BwrapData {
+    fn default() -> Self {
+        Self {
+            // mem_fds: Default::default(),
+            dbus_proxies: Default::default(),
+            image_mounts: Default::default(),
+            tempdir: TempDir::new(&gc::temp_prefix("setup")).expect("Could not create tempdir"),
+            files: Default::default(),
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use crate::{error::FlatboxError, probe, system};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, SystemTime},
+};
+
+/// Cache of the directory listings, in the cache dir
+const LISTINGS_CACHE_FILE: &str = "dir-listings.toml";
+/// Listings of directories modified more recently aren't cached, as another change within the granularity of
+/// the modification time wouldn't be noticed
+const MIN_CACHED_AGE: Duration = Duration::from_secs(2);
+
+/// Entry names of directories, valid as long as the directory's modification time is the same.
+/// Adding or removing an entry changes it, so installing or removing a ref invalidates its listing.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ListingsCache {
+    #[serde(default)]
+    listings: Vec<Listing>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct Listing {
+    dir: PathBuf,
+    modified: SystemTime,
+    entries: Vec<String>,
+}
+
+/// flatbox's cache dir, `$XDG_CACHE_HOME/flatbox` (or `~/.cache/flatbox`), or the one in the state dir in
+/// system mode
+pub(crate) fn cache_dir() -> Option<PathBuf> {
+    if system::is_system_mode() {
+        return Some(system::cache_dir());
+    }
+    let cache_home = env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(cache_home.join("flatbox"))
+}
+
+impl ListingsCache {
+    fn path() -> Option<PathBuf> {
+        Some(cache_dir()?.join(LISTINGS_CACHE_FILE))
+    }
+
+    /// Reads the cache, which is empty when it doesn't exist or can't be read
+    fn load(path: &Path) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|cache| toml::from_str(&cache).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let dir = path.parent().context("Invalid cache path")?;
+        fs::create_dir_all(dir).with_context(|| format!("Could not create {}", dir.display()))?;
+        // Renamed into place, so concurrent launches never read a partial cache
+        let tmp_path = path.with_extension(format!("{}.tmp", process::id()));
+        fs::write(&tmp_path, toml::to_string(self)?)
+            .with_context(|| format!("Could not write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, path).with_context(|| format!("Could not write {}", path.display()))
+    }
+
+    fn lookup(&self, dir: &Path, modified: SystemTime) -> Option<&[String]> {
+        self.listings
+            .iter()
+            .find(|listing| listing.dir == dir && listing.modified == modified)
+            .map(|listing| listing.entries.as_slice())
+    }
+
+    fn insert(&mut self, dir: &Path, modified: SystemTime, entries: Vec<String>) {
+        self.listings.retain(|listing| listing.dir != dir);
+        self.listings.push(Listing {
+            dir: dir.to_owned(),
+            modified,
+            entries,
+        });
+    }
+}
+
+/// The entry names of each of the directories, empty for the ones which don't exist. The listings are cached
+/// until the directories are modified.
+pub fn dir_listings(dirs: &[PathBuf]) -> anyhow::Result<Vec<Vec<String>>> {
+    let modified = probe::parallel_map(dirs, |dir| {
+        fs::metadata(dir)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    });
+    let cache_path = ListingsCache::path();
+    let mut cache = cache_path
+        .as_deref()
+        .map(ListingsCache::load)
+        .unwrap_or_default();
+
+    let stale_dirs: Vec<PathBuf> = dirs
+        .iter()
+        .zip(&modified)
+        .filter(|(dir, modified)| {
+            modified.is_none_or(|modified| cache.lookup(dir, modified).is_none())
+        })
+        .map(|(dir, _)| dir.clone())
+        .collect();
+    let mut read_listings = stale_dirs
+        .iter()
+        .zip(probe::read_dirs(&stale_dirs))
+        .map(|(dir, entries)| {
+            let entries = match entries {
+                Some(entries) => entries
+                    .context("Could not read entry")?
+                    .into_iter()
+                    .map(|entry| {
+                        entry
+                            .file_name
+                            .into_string()
+                            .map_err(|name| FlatboxError::NonUtf8Path { path: name.into() }.into())
+                    })
+                    .collect::<anyhow::Result<Vec<String>>>()
+                    .with_context(|| format!("Could not list {}", dir.display()))?,
+                None => Vec::new(),
+            };
+            Ok((dir, entries))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?
+        .into_iter();
+
+    let now = SystemTime::now();
+    let mut cache_changed = false;
+    let mut listings = Vec::new();
+    for (dir, modified) in dirs.iter().zip(modified) {
+        if let Some(entries) = modified.and_then(|modified| cache.lookup(dir, modified)) {
+            listings.push(entries.to_vec());
+            continue;
+        }
+        let (_, entries) = read_listings.next().expect("Listing of a stale dir");
+        if let Some(modified) = modified
+            && now
+                .duration_since(modified)
+                .is_ok_and(|age| age >= MIN_CACHED_AGE)
+        {
+            cache.insert(dir, modified, entries.clone());
+            cache_changed = true;
+        }
+        listings.push(entries);
+    }
+
+    if cache_changed
+        && let Some(cache_path) = cache_path
+        && let Err(err) = cache.save(&cache_path)
+    {
+        crate::debug!("Could not cache the listings: {err:#}");
+    }
+    Ok(listings)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::ListingsCache;
+    use std::{
+        path::Path,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    #[test]
+    fn listings_are_invalidated_by_modification() {
+        let dir = Path::new("/var/lib/flatpak/runtime");
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut cache = ListingsCache::default();
+        cache.insert(dir, modified, vec!["org.freedesktop.Platform".to_owned()]);
+        assert_eq!(
+            cache.lookup(dir, modified),
+            Some(&["org.freedesktop.Platform".to_owned()][..])
+        );
+        assert_eq!(cache.lookup(dir, modified + Duration::from_nanos(1)), None);
+
+        let mut cache: ListingsCache = toml::from_str(&toml::to_string(&cache).unwrap()).unwrap();
+        cache.lookup(dir, modified).unwrap();
+        cache.insert(dir, modified + Duration::from_secs(1), Vec::new());
+        assert_eq!(cache.lookup(dir, modified), None);
+        assert_eq!(cache.listings.len(), 1);
+    }
+}
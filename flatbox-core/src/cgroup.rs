@@ -0,0 +1,292 @@
+use crate::error::FlatboxError;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Period of `cpu.max` in microseconds, the kernel's and systemd's default
+pub const CPU_PERIOD_US: u64 = 100_000;
+const SIZE_SUFFIXES: [(char, u32); 4] = [('T', 40), ('G', 30), ('M', 20), ('K', 10)];
+
+/// A size in bytes, written with an optional binary suffix (`512M`, `4G`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MemorySize(pub u64);
+
+impl FromStr for MemorySize {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected a size like 512M or 4G, found '{value}'");
+        let (number, shift) = match value.char_indices().last() {
+            Some((i, suffix)) if suffix.is_ascii_alphabetic() => {
+                let Some((_, shift)) = SIZE_SUFFIXES
+                    .iter()
+                    .find(|(known, _)| *known == suffix.to_ascii_uppercase())
+                else {
+                    return Err(invalid());
+                };
+                (&value[..i], *shift)
+            }
+            _ => (value, 0),
+        };
+        number
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(1 << shift))
+            .map(MemorySize)
+            .ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for MemorySize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match SIZE_SUFFIXES
+            .iter()
+            .find(|(_, shift)| self.0 != 0 && self.0.is_multiple_of(1 << shift))
+        {
+            Some((suffix, shift)) => write!(f, "{}{suffix}", self.0 >> shift),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+impl TryFrom<String> for MemorySize {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<MemorySize> for String {
+    fn from(size: MemorySize) -> Self {
+        size.to_string()
+    }
+}
+
+/// CPU time as a percentage of one CPU (`50%`, `200%`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CpuQuota(pub u32);
+
+impl FromStr for CpuQuota {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_suffix('%').unwrap_or(value).parse() {
+            Ok(percent) if percent > 0 => Ok(CpuQuota(percent)),
+            _ => Err(format!(
+                "expected a percentage of one CPU like 50% or 200%, found '{value}'"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CpuQuota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}%", self.0)
+    }
+}
+
+impl TryFrom<String> for CpuQuota {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<CpuQuota> for String {
+    fn from(quota: CpuQuota) -> Self {
+        quota.to_string()
+    }
+}
+
+impl CpuQuota {
+    /// The quota per `CPU_PERIOD_US`, as used by `cpu.max`
+    pub fn quota_us(self) -> u64 {
+        u64::from(self.0) * CPU_PERIOD_US / 100
+    }
+}
+
+/// Limits of the resources the whole sandbox can use
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ResourceLimits {
+    pub memory_max: Option<MemorySize>,
+    pub cpu_quota: Option<CpuQuota>,
+    pub pids_max: Option<u32>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The properties setting the limits on a systemd unit
+    pub fn systemd_properties(&self) -> Vec<String> {
+        self.memory_max
+            .map(|size| format!("MemoryMax={}", size.0))
+            .into_iter()
+            .chain(self.cpu_quota.map(|quota| format!("CPUQuota={quota}")))
+            .chain(self.pids_max.map(|pids| format!("TasksMax={pids}")))
+            .collect()
+    }
+}
+
+/// A cgroup limiting flatbox and everything it starts. When dropped, flatbox moves back to its original cgroup
+/// and the limited one is removed.
+pub struct LimitedCgroup {
+    path: PathBuf,
+    original: PathBuf,
+}
+
+/// Moves flatbox into a new cgroup next to its own one with the limits applied, so the sandbox and its helpers
+/// are limited as well. This needs the parent cgroup to be writable, as it is in the delegated subtree of a
+/// systemd user session.
+pub fn limit_process(limits: &ResourceLimits) -> anyhow::Result<LimitedCgroup> {
+    let original = self_cgroup()?;
+    let Some(parent) = original
+        .parent()
+        .filter(|parent| parent.starts_with(CGROUP_ROOT))
+    else {
+        return Err(FlatboxError::RootCgroup.into());
+    };
+
+    let controllers = fs::read_to_string(parent.join("cgroup.subtree_control")).unwrap_or_default();
+    let needed = [
+        ("memory", limits.memory_max.is_some()),
+        ("cpu", limits.cpu_quota.is_some()),
+        ("pids", limits.pids_max.is_some()),
+    ];
+    for (controller, _) in needed.iter().filter(|(_, needed)| *needed) {
+        if !controllers
+            .split_whitespace()
+            .any(|enabled| enabled == *controller)
+        {
+            return Err(FlatboxError::CgroupControllerDisabled {
+                controller: (*controller).to_owned(),
+                cgroup: parent.to_owned(),
+            }
+            .into());
+        }
+    }
+
+    let path = parent.join(format!("flatbox-{}", process::id()));
+    fs::create_dir(&path).with_context(|| {
+        format!(
+            "Could not create cgroup {}, use --systemd-scope to apply resource limits",
+            path.display()
+        )
+    })?;
+    let cgroup = LimitedCgroup { path, original };
+
+    if let Some(size) = limits.memory_max {
+        cgroup.write("memory.max", &size.0.to_string())?;
+    }
+    if let Some(quota) = limits.cpu_quota {
+        cgroup.write("cpu.max", &format!("{} {CPU_PERIOD_US}", quota.quota_us()))?;
+    }
+    if let Some(pids) = limits.pids_max {
+        cgroup.write("pids.max", &pids.to_string())?;
+    }
+    cgroup.write("cgroup.procs", &process::id().to_string())?;
+    Ok(cgroup)
+}
+
+/// The cgroup flatbox runs in
+fn self_cgroup() -> anyhow::Result<PathBuf> {
+    let self_cgroup =
+        fs::read_to_string("/proc/self/cgroup").context("Could not read /proc/self/cgroup")?;
+    // On hybrid setups, the v2 hierarchy is mounted elsewhere without any controllers
+    let Some(relative) = self_cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .filter(|_| Path::new(CGROUP_ROOT).join("cgroup.controllers").exists())
+    else {
+        return Err(FlatboxError::CgroupV2Required.into());
+    };
+    Ok(Path::new(CGROUP_ROOT).join(relative.trim_start_matches('/')))
+}
+
+/// The limited cgroups next to the one of flatbox which launches left behind, with the pid of the launch
+pub(crate) fn leftover_cgroups() -> Vec<(PathBuf, u32)> {
+    let Some(parent) = self_cgroup()
+        .ok()
+        .and_then(|cgroup| cgroup.parent().map(Path::to_owned))
+        .filter(|parent| parent.starts_with(CGROUP_ROOT))
+    else {
+        return Vec::new();
+    };
+    let Ok(entries) = fs::read_dir(&parent) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let pid = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("flatbox-")?
+                .parse()
+                .ok()?;
+            Some((entry.path(), pid))
+        })
+        .collect()
+}
+
+impl LimitedCgroup {
+    fn write(&self, name: &str, value: &str) -> anyhow::Result<()> {
+        let path = self.path.join(name);
+        fs::write(&path, value).with_context(|| format!("Could not write {}", path.display()))
+    }
+}
+
+impl Drop for LimitedCgroup {
+    fn drop(&mut self) {
+        // Fails when processes started by flatbox are still running, the cgroup is left behind then
+        let _ = fs::write(
+            self.original.join("cgroup.procs"),
+            process::id().to_string(),
+        );
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cgroup::{CpuQuota, MemorySize, ResourceLimits};
+
+    #[test]
+    fn limit_parsing() {
+        assert_eq!("512M".parse(), Ok(MemorySize(512 << 20)));
+        assert_eq!("4g".parse(), Ok(MemorySize(4 << 30)));
+        assert_eq!("1000".parse(), Ok(MemorySize(1000)));
+        assert!("4X".parse::<MemorySize>().is_err());
+        assert!("G".parse::<MemorySize>().is_err());
+        assert_eq!(MemorySize(1536 << 20).to_string(), "1536M");
+        assert_eq!(MemorySize(1000).to_string(), "1000");
+
+        assert_eq!("150%".parse(), Ok(CpuQuota(150)));
+        assert_eq!("50".parse(), Ok(CpuQuota(50)));
+        assert!("0%".parse::<CpuQuota>().is_err());
+        assert_eq!(CpuQuota(150).quota_us(), 150_000);
+
+        let limits = ResourceLimits {
+            memory_max: Some(MemorySize(1 << 30)),
+            cpu_quota: Some(CpuQuota(200)),
+            pids_max: Some(512),
+        };
+        assert_eq!(
+            limits.systemd_properties(),
+            ["MemoryMax=1073741824", "CPUQuota=200%", "TasksMax=512"]
+        );
+        assert!(ResourceLimits::default().is_empty());
+    }
+}
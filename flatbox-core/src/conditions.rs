@@ -0,0 +1,125 @@
+use std::{env, fs, path::Path, process::Command, sync::OnceLock};
+
+const INTEL_VENDOR_ID: &str = "0x8086";
+
+/// Evaluates an extension's `enable-if` conditions against the given extension implementation.
+/// Multiple conditions can be separated by `;`, in which case all of them have to match.
+pub fn extension_enabled(enable_if: &str, extension_name: &str, impl_name: &str) -> bool {
+    enable_if
+        .split(';')
+        .map(str::trim_ascii)
+        .filter(|condition| !condition.is_empty())
+        .all(|condition| evaluate(condition, extension_name, impl_name))
+}
+
+fn evaluate(condition: &str, extension_name: &str, impl_name: &str) -> bool {
+    match condition {
+        "active-gl-driver" => match impl_name {
+            "default" | "host" => true,
+            _ => {
+                if let Some(nvidia_version) = impl_name.strip_prefix("nvidia-") {
+                    fs::read_to_string("/sys/module/nvidia/version")
+                        .map(|version| version.trim().replace('.', "-"))
+                        .is_ok_and(|allowed_version| allowed_version == nvidia_version)
+                } else {
+                    false
+                }
+            }
+        },
+        "active-gtk-theme" => active_gtk_theme().is_some_and(|theme| theme == impl_name),
+        "have-intel-gpu" => have_intel_gpu(),
+        _ if condition.starts_with("on-xdg-desktop-") => {
+            let desktop = &condition["on-xdg-desktop-".len()..];
+            env::var("XDG_CURRENT_DESKTOP").is_ok_and(|current| desktop_matches(&current, desktop))
+        }
+        _ if condition.starts_with("have-kernel-module-") => {
+            let module = &condition["have-kernel-module-".len()..];
+            Path::new("/sys/module").join(module).exists()
+        }
+        _ => {
+            crate::warn!(
+                "Unsupported enable-if reason '{condition}' on extension '{extension_name}'"
+            );
+            false
+        }
+    }
+}
+
+/// Returns the extension implementation which would be enabled by the given condition on this host
+pub fn preferred_impl(enable_if: &str) -> Option<String> {
+    enable_if
+        .split(';')
+        .map(str::trim_ascii)
+        .find_map(|condition| match condition {
+            "active-gl-driver" => Some(
+                fs::read_to_string("/sys/module/nvidia/version")
+                    .map(|version| format!("nvidia-{}", version.trim().replace('.', "-")))
+                    .unwrap_or_else(|_| "default".to_owned()),
+            ),
+            "active-gtk-theme" => active_gtk_theme().map(str::to_owned),
+            _ => None,
+        })
+}
+
+fn have_intel_gpu() -> bool {
+    fs::read_dir("/sys/class/drm")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .any(|entry| {
+            fs::read_to_string(entry.path().join("device").join("vendor"))
+                .is_ok_and(|vendor| vendor.trim() == INTEL_VENDOR_ID)
+        })
+}
+
+fn desktop_matches(current_desktops: &str, desktop: &str) -> bool {
+    current_desktops
+        .split(':')
+        .any(|current| current.eq_ignore_ascii_case(desktop))
+}
+
+fn active_gtk_theme() -> Option<&'static str> {
+    static THEME: OnceLock<Option<String>> = OnceLock::new();
+
+    THEME
+        .get_or_init(|| {
+            if let Ok(theme) = env::var("GTK_THEME") {
+                // Strip the variant suffix, e.g. `Adwaita:dark`
+                let name = theme.split(':').next().unwrap_or_default();
+                return Some(name.to_owned()).filter(|name| !name.is_empty());
+            }
+
+            [
+                &[
+                    "gsettings",
+                    "get",
+                    "org.gnome.desktop.interface",
+                    "gtk-theme",
+                ][..],
+                &["dconf", "read", "/org/gnome/desktop/interface/gtk-theme"][..],
+            ]
+            .into_iter()
+            .find_map(|cmd| {
+                let output = Command::new(cmd[0]).args(&cmd[1..]).output().ok()?;
+                if !output.status.success() {
+                    return None;
+                }
+                let value = String::from_utf8(output.stdout).ok()?;
+                Some(value.trim().trim_matches('\'').to_owned()).filter(|name| !name.is_empty())
+            })
+        })
+        .as_deref()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conditions::desktop_matches;
+
+    #[test]
+    fn match_xdg_desktop() {
+        assert!(desktop_matches("GNOME", "gnome"));
+        assert!(desktop_matches("ubuntu:GNOME", "GNOME"));
+        assert!(!desktop_matches("KDE", "gnome"));
+        assert!(!desktop_matches("", "gnome"));
+    }
+}
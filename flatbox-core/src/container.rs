@@ -0,0 +1,311 @@
+use crate::{
+    cgroup::CPU_PERIOD_US,
+    plan::{BindMode, MountOp, SandboxPlan},
+    rlimit::Rlimit,
+};
+use anyhow::Context;
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, IsTerminal},
+    os::unix::{ffi::OsStrExt, fs::OpenOptionsExt},
+    path::Path,
+    process::{self, Command},
+};
+
+/// Creates a rootless podman container running `argv` with the mounts and environment of the plan.
+/// Podman provides its own `/dev` and `/proc`.
+pub fn podman_command(
+    plan: &SandboxPlan,
+    setup_dir: &Path,
+    argv: &[impl AsRef<OsStr>],
+) -> anyhow::Result<Command> {
+    let rootfs = setup_dir.join("rootfs");
+    let mounts = plan.create_rootfs(&rootfs)?;
+
+    let mut command = Command::new("podman");
+    command.args(["run", "--rm", "--interactive", "--userns=keep-id"]);
+    command.args(["--security-opt", "label=disable"]);
+    if plan.devel {
+        command.args([
+            "--cap-add=SYS_PTRACE,PERFMON",
+            "--security-opt",
+            "seccomp=unconfined",
+        ]);
+    }
+    if io::stdin().is_terminal() {
+        command.arg("--tty");
+    }
+    command.arg(if plan.unshare_net {
+        "--network=none"
+    } else {
+        "--network=host"
+    });
+    command.arg(if plan.unshare_ipc {
+        "--ipc=private"
+    } else {
+        "--ipc=host"
+    });
+    // Written to a file rather than passed as arguments, so the environment doesn't show up in the process list
+    let env_file = setup_dir.join("environment");
+    write_env_file(&env_file, &plan.environment())?;
+    command.arg("--env-file").arg(&env_file);
+    if let Some(chdir) = &plan.chdir {
+        command.arg("--workdir").arg(chdir);
+    }
+    if let Some(size) = plan.limits.memory_max {
+        command.arg(format!("--memory={}", size.0));
+    }
+    if let Some(quota) = plan.limits.cpu_quota {
+        command.arg(format!("--cpu-period={CPU_PERIOD_US}"));
+        command.arg(format!("--cpu-quota={}", quota.quota_us()));
+    }
+    if let Some(pids) = plan.limits.pids_max {
+        command.arg(format!("--pids-limit={pids}"));
+    }
+    // Otherwise podman resets it for the container
+    if let Some(oom_score_adj) = plan.priority.oom_score_adj {
+        command.arg(format!("--oom-score-adj={oom_score_adj}"));
+    }
+    for rlimit in &plan.rlimits {
+        command.arg(format!(
+            "--ulimit={}={}:{}",
+            rlimit.name(),
+            limit_value(rlimit.soft, "-1"),
+            limit_value(rlimit.hard, "-1")
+        ));
+    }
+
+    for op in mounts {
+        match op {
+            MountOp::Bind { source, dest, mode } => {
+                let mut mount = mount_option("type=bind,source=", source);
+                mount.push(mount_option(",destination=", dest));
+                if *mode == BindMode::ReadOnly {
+                    mount.push(",readonly=true");
+                }
+                command.arg("--mount").arg(mount);
+            }
+            MountOp::Tmpfs { dest, perms } => {
+                let mut mount = mount_option("type=tmpfs,destination=", dest);
+                mount.push(format!(",tmpfs-mode={:o}", perms.unwrap_or(0o755)));
+                command.arg("--mount").arg(mount);
+            }
+            MountOp::TmpOverlay { source, dest } => {
+                let mut volume = source.as_os_str().to_owned();
+                volume.push(":");
+                volume.push(dest);
+                volume.push(":O");
+                command.arg("--volume").arg(volume);
+            }
+            MountOp::Overlay {
+                source,
+                upper,
+                work,
+                dest,
+            } => {
+                let mut volume = source.as_os_str().to_owned();
+                volume.push(":");
+                volume.push(dest);
+                volume.push(":O,upperdir=");
+                volume.push(upper);
+                volume.push(",workdir=");
+                volume.push(work);
+                command.arg("--volume").arg(volume);
+            }
+            MountOp::Dev { .. } | MountOp::Proc { .. } => {}
+            // Created in the rootfs
+            MountOp::Dir { .. } | MountOp::Symlink { .. } => {}
+        }
+    }
+
+    command.arg("--rootfs").arg(&rootfs);
+    command.args(argv);
+    Ok(command)
+}
+
+/// Creates a systemd-nspawn container running `argv` with the mounts and environment of the plan, which can be
+/// managed with machinectl (as `flatbox-<pid>`) while it runs. systemd-nspawn provides its own `/dev` and `/proc`,
+/// and needs root.
+pub fn nspawn_command(
+    plan: &SandboxPlan,
+    setup_dir: &Path,
+    argv: &[impl AsRef<OsStr>],
+) -> anyhow::Result<Command> {
+    let rootfs = setup_dir.join("rootfs");
+    let mounts = plan.create_rootfs(&rootfs)?;
+
+    let mut command = Command::new("systemd-nspawn");
+    command.args(["--quiet", "--as-pid2", "--link-journal=no"]);
+    command.args(["--resolv-conf=off", "--timezone=off"]);
+    command.arg(format!("--machine=flatbox-{}", process::id()));
+    command.arg("--directory").arg(&rootfs);
+    if plan.unshare_net {
+        command.arg("--private-network");
+    }
+    if plan.devel {
+        command.args([
+            "--capability=CAP_SYS_PTRACE,CAP_PERFMON",
+            "--system-call-filter=@debug",
+        ]);
+    }
+    for property in plan.limits.systemd_properties() {
+        command.arg(format!("--property={property}"));
+    }
+    for rlimit in &plan.rlimits {
+        command.arg(format!(
+            "--rlimit={}={}:{}",
+            rlimit.constant_name(),
+            limit_value(rlimit.soft, "infinity"),
+            limit_value(rlimit.hard, "infinity")
+        ));
+    }
+    // nspawn starts the command with a clean environment
+    for (key, value) in plan.environment() {
+        let mut setenv = OsString::from("--setenv=");
+        setenv.push(key);
+        setenv.push("=");
+        setenv.push(value);
+        command.arg(setenv);
+    }
+    if let Some(chdir) = &plan.chdir {
+        let mut option = OsString::from("--chdir=");
+        option.push(chdir);
+        command.arg(option);
+    }
+
+    for op in mounts {
+        match op {
+            MountOp::Bind { source, dest, mode } => {
+                let mut bind = OsString::from(if *mode == BindMode::ReadOnly {
+                    "--bind-ro="
+                } else {
+                    "--bind="
+                });
+                bind.push(escape_nspawn_path(source));
+                bind.push(":");
+                bind.push(escape_nspawn_path(dest));
+                command.arg(bind);
+            }
+            MountOp::Tmpfs { dest, perms } => {
+                let mut tmpfs = OsString::from("--tmpfs=");
+                tmpfs.push(escape_nspawn_path(dest));
+                tmpfs.push(format!(":mode={:04o}", perms.unwrap_or(0o755)));
+                command.arg(tmpfs);
+            }
+            MountOp::TmpOverlay { source, dest } => {
+                // An empty upper dir makes nspawn use a temporary one
+                let mut overlay = OsString::from("--overlay=");
+                overlay.push(escape_nspawn_path(source));
+                overlay.push("::");
+                overlay.push(escape_nspawn_path(dest));
+                command.arg(overlay);
+            }
+            MountOp::Overlay {
+                source,
+                upper,
+                dest,
+                ..
+            } => {
+                // nspawn uses the last layer as the upper one and creates its own work dir next to it
+                let mut overlay = OsString::from("--overlay=");
+                overlay.push(escape_nspawn_path(source));
+                overlay.push(":");
+                overlay.push(escape_nspawn_path(upper));
+                overlay.push(":");
+                overlay.push(escape_nspawn_path(dest));
+                command.arg(overlay);
+            }
+            MountOp::Dev { .. } | MountOp::Proc { .. } => {}
+            // Created in the rootfs
+            MountOp::Dir { .. } | MountOp::Symlink { .. } => {}
+        }
+    }
+
+    command.arg("--");
+    command.args(argv);
+    Ok(command)
+}
+
+fn limit_value(value: u64, unlimited: &str) -> String {
+    if Rlimit::is_unlimited(value) {
+        unlimited.to_owned()
+    } else {
+        value.to_string()
+    }
+}
+
+/// `prefix` followed by `path`, quoted if it contains the separators of podman's `--mount` option
+fn mount_option(prefix: &str, path: &Path) -> OsString {
+    let mut option = OsString::from(prefix);
+    let bytes = path.as_os_str().as_bytes();
+    if bytes.contains(&b',') || bytes.contains(&b'"') {
+        option.push("\"");
+        option.push(OsStr::from_bytes(
+            &bytes.escape_ascii().collect::<Vec<u8>>(),
+        ));
+        option.push("\"");
+    } else {
+        option.push(path);
+    }
+    option
+}
+
+/// Escapes the colons separating the paths of nspawn's mount options
+fn escape_nspawn_path(path: &Path) -> OsString {
+    let mut escaped = Vec::new();
+    for &byte in path.as_os_str().as_bytes() {
+        if matches!(byte, b':' | b'\\') {
+            escaped.push(b'\\');
+        }
+        escaped.push(byte);
+    }
+    OsStr::from_bytes(&escaped).to_owned()
+}
+
+fn write_env_file(path: &Path, environment: &[(OsString, OsString)]) -> anyhow::Result<()> {
+    let mut contents = Vec::new();
+    for (key, value) in environment {
+        if value.as_bytes().contains(&b'\n') {
+            crate::warn!(
+                "Not passing {} to the container, env files can't contain multi-line values",
+                key.to_string_lossy()
+            );
+            continue;
+        }
+        contents.extend_from_slice(key.as_bytes());
+        contents.push(b'=');
+        contents.extend_from_slice(value.as_bytes());
+        contents.push(b'\n');
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| io::Write::write_all(&mut file, &contents))
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::container::{escape_nspawn_path, mount_option};
+    use std::{ffi::OsStr, path::Path};
+
+    #[test]
+    fn mount_path_escaping() {
+        assert_eq!(
+            escape_nspawn_path(Path::new("/mnt/a:b\\c")),
+            OsStr::new("/mnt/a\\:b\\\\c")
+        );
+        assert_eq!(
+            mount_option("source=", Path::new("/mnt/plain")),
+            OsStr::new("source=/mnt/plain")
+        );
+        assert_eq!(
+            mount_option("source=", Path::new("/mnt/a,b")),
+            OsStr::new("source=\"/mnt/a,b\"")
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use crate::{bwrap::BwrapBuilder, error::FlatboxError, rlimit::Rlimit};
+use anyhow::Context;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// How the kernel names the core files, shared by the host and the sandbox
+const CORE_PATTERN: &str = "/proc/sys/kernel/core_pattern";
+
+/// Where the kernel writes the core of a crashed process, according to `kernel.core_pattern`
+#[derive(Debug, PartialEq, Eq)]
+enum CoreLocation {
+    /// Cores are piped to a program on the host, e.g. systemd-coredump
+    Handler(String),
+    /// An absolute pattern, resolved in the mount namespace of the crashed process
+    Dir(PathBuf),
+    /// A relative pattern, resolved against the current dir of the crashed process
+    CurrentDir,
+}
+
+impl CoreLocation {
+    fn from_pattern(pattern: &str) -> Self {
+        let pattern = pattern.trim();
+        if let Some(handler) = pattern.strip_prefix('|') {
+            let handler = handler.split_whitespace().next().unwrap_or_default();
+            Self::Handler(handler.to_owned())
+        } else if pattern.starts_with('/') {
+            Self::Dir(
+                Path::new(pattern)
+                    .parent()
+                    .unwrap_or(Path::new("/"))
+                    .to_owned(),
+            )
+        } else {
+            Self::CurrentDir
+        }
+    }
+}
+
+/// Makes the cores of crashed processes land in the coredump dir on the host: its soft limit is raised and the
+/// dir is mounted where `kernel.core_pattern` puts them. With a relative pattern, the command is started in
+/// the dir. Cores piped to a handler on the host (e.g. systemd-coredump) can't be redirected.
+pub fn setup_coredumps(
+    bwrap: &mut BwrapBuilder,
+    rlimits: &mut Vec<Rlimit>,
+    coredump_dir: &Path,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(coredump_dir)
+        .with_context(|| format!("Could not create {}", coredump_dir.display()))?;
+    let coredump_dir = fs::canonicalize(coredump_dir)?;
+    if !rlimits.iter().any(|rlimit| rlimit.name() == "core") {
+        let core = Rlimit::raised("core")?;
+        if core.hard == 0 {
+            crate::warn!("The hard core limit is 0, so no cores can be written");
+        }
+        rlimits.push(core);
+    }
+
+    let pattern = fs::read_to_string(CORE_PATTERN)
+        .with_context(|| format!("Could not read {CORE_PATTERN}"))?;
+    match CoreLocation::from_pattern(&pattern) {
+        CoreLocation::Handler(handler) => {
+            crate::warn!(
+                "Cores are handled by {handler} on the host and can't be written to {} (with \
+                 systemd-coredump, they're listed by `coredumpctl`)",
+                coredump_dir.display()
+            );
+        }
+        CoreLocation::Dir(dir) => {
+            if dir.to_string_lossy().contains('%') {
+                return Err(FlatboxError::UnredirectableCorePattern {
+                    pattern: pattern.trim().to_owned(),
+                }
+                .into());
+            }
+            bwrap.bind(&coredump_dir, dir);
+        }
+        CoreLocation::CurrentDir => {
+            bwrap.bind(&coredump_dir, &coredump_dir);
+            bwrap.chdir(&coredump_dir);
+        }
+    }
+    Ok(())
+}
+
+/// The cores written to the coredump dir since the sandbox was started
+pub fn find_cores(coredump_dir: &Path, since: SystemTime) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(coredump_dir) else {
+        return Vec::new();
+    };
+    let mut cores: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|entry| {
+            entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified >= since)
+        })
+        .map(|entry| entry.path())
+        .collect();
+    cores.sort();
+    cores
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coredump::CoreLocation;
+    use std::path::PathBuf;
+
+    #[test]
+    fn core_locations() {
+        assert_eq!(
+            CoreLocation::from_pattern("|/usr/lib/systemd/systemd-coredump %P %u %g\n"),
+            CoreLocation::Handler("/usr/lib/systemd/systemd-coredump".to_owned())
+        );
+        assert_eq!(
+            CoreLocation::from_pattern("/var/crash/core.%e.%p\n"),
+            CoreLocation::Dir(PathBuf::from("/var/crash"))
+        );
+        assert_eq!(
+            CoreLocation::from_pattern("core\n"),
+            CoreLocation::CurrentDir
+        );
+    }
+}
@@ -0,0 +1,1024 @@
+use crate::{
+    error::FlatboxError,
+    fd_passing::{recv_with_fds, send_with_fds},
+};
+use anyhow::Context;
+use std::{
+    collections::VecDeque,
+    env,
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, OwnedFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixStream},
+    },
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+pub const BUS_NAME: &str = "org.freedesktop.DBus";
+pub const BUS_PATH: &str = "/org/freedesktop/DBus";
+/// Reply flag of method calls
+pub const NO_REPLY_EXPECTED: u8 = 1;
+/// The most fds read along a message
+const MAX_MESSAGE_FDS: usize = 64;
+/// The longest message of the specification, 128 MiB
+const MAX_MESSAGE_LEN: usize = 128 << 20;
+/// Header fields of messages
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+const FIELD_UNIX_FDS: u8 = 9;
+
+/// A value of the D-Bus type system
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Byte(u8),
+    Bool(bool),
+    I16(i16),
+    U16(u16),
+    U32(u32),
+    I32(i32),
+    I64(i64),
+    U64(u64),
+    Double(f64),
+    Str(String),
+    ObjectPath(String),
+    Signature(String),
+    /// The signature of the elements, and the elements
+    Array(String, Vec<Value>),
+    Struct(Vec<Value>),
+    DictEntry(Box<Value>, Box<Value>),
+    Variant(Box<Value>),
+    /// The index of an fd passed along the message
+    Fd(u32),
+}
+
+impl Value {
+    pub fn signature(&self) -> String {
+        match self {
+            Self::Byte(_) => "y".to_owned(),
+            Self::Bool(_) => "b".to_owned(),
+            Self::I16(_) => "n".to_owned(),
+            Self::U16(_) => "q".to_owned(),
+            Self::U32(_) => "u".to_owned(),
+            Self::I32(_) => "i".to_owned(),
+            Self::I64(_) => "x".to_owned(),
+            Self::U64(_) => "t".to_owned(),
+            Self::Double(_) => "d".to_owned(),
+            Self::Str(_) => "s".to_owned(),
+            Self::ObjectPath(_) => "o".to_owned(),
+            Self::Signature(_) => "g".to_owned(),
+            Self::Array(element, _) => format!("a{element}"),
+            Self::Struct(fields) => {
+                let fields: String = fields.iter().map(Self::signature).collect();
+                format!("({fields})")
+            }
+            Self::DictEntry(key, value) => format!("{{{}{}}}", key.signature(), value.signature()),
+            Self::Variant(_) => "v".to_owned(),
+            Self::Fd(_) => "h".to_owned(),
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            Self::U32(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(value) | Self::ObjectPath(value) | Self::Signature(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Self::Array(_, elements) => Some(elements),
+            _ => None,
+        }
+    }
+
+    /// The bytes of an `ay`
+    pub fn as_bytes(&self) -> Option<Vec<u8>> {
+        self.as_array()?
+            .iter()
+            .map(|element| match element {
+                Self::Byte(byte) => Some(*byte),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The key and value of a dict entry
+    pub fn as_entry(&self) -> Option<(&Value, &Value)> {
+        match self {
+            Self::DictEntry(key, value) => Some((key, value)),
+            _ => None,
+        }
+    }
+}
+
+/// The alignment of a type, by the first character of its signature
+fn alignment(code: u8) -> usize {
+    match code {
+        b'y' | b'g' | b'v' => 1,
+        b'n' | b'q' => 2,
+        b'(' | b'{' | b'x' | b't' | b'd' => 8,
+        _ => 4,
+    }
+}
+
+/// Splits the first complete type off a signature
+fn split_type(signature: &str) -> anyhow::Result<(&str, &str)> {
+    let bytes = signature.as_bytes();
+    let mut end = 0;
+    while bytes.get(end) == Some(&b'a') {
+        end += 1;
+    }
+    match bytes.get(end) {
+        Some(b'(' | b'{') => {
+            let mut depth = 0;
+            for (i, byte) in bytes.iter().enumerate().skip(end) {
+                match byte {
+                    b'(' | b'{' => depth += 1,
+                    b')' | b'}' => depth -= 1,
+                    _ => {}
+                }
+                if depth == 0 {
+                    return Ok(signature.split_at(i + 1));
+                }
+            }
+            Err(FlatboxError::BusProtocol {
+                message: format!("Unbalanced signature {signature}"),
+            }
+            .into())
+        }
+        Some(_) => Ok(signature.split_at(end + 1)),
+        None => Err(FlatboxError::BusProtocol {
+            message: format!("Incomplete signature {signature}"),
+        }
+        .into()),
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn pad(&mut self, alignment: usize) {
+        self.buf
+            .resize(self.buf.len().next_multiple_of(alignment), 0);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.pad(2);
+        self.buf.extend(value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.pad(4);
+        self.buf.extend(value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.pad(8);
+        self.buf.extend(value.to_le_bytes());
+    }
+
+    fn write(&mut self, value: &Value) {
+        match value {
+            Value::Byte(byte) => self.buf.push(*byte),
+            Value::Bool(value) => self.u32(u32::from(*value)),
+            Value::U32(value) | Value::Fd(value) => self.u32(*value),
+            Value::I32(value) => self.u32(*value as u32),
+            Value::I16(value) => self.u16(*value as u16),
+            Value::U16(value) => self.u16(*value),
+            Value::I64(value) => self.u64(*value as u64),
+            Value::U64(value) => self.u64(*value),
+            Value::Double(value) => self.u64(value.to_bits()),
+            Value::Str(value) | Value::ObjectPath(value) => {
+                self.u32(value.len() as u32);
+                self.buf.extend(value.as_bytes());
+                self.buf.push(0);
+            }
+            Value::Signature(value) => {
+                self.buf.push(value.len() as u8);
+                self.buf.extend(value.as_bytes());
+                self.buf.push(0);
+            }
+            Value::Array(element, elements) => {
+                self.u32(0);
+                let len_pos = self.buf.len() - 4;
+                self.pad(alignment(element.as_bytes()[0]));
+                let start = self.buf.len();
+                for element in elements {
+                    self.write(element);
+                }
+                let len = (self.buf.len() - start) as u32;
+                self.buf[len_pos..len_pos + 4].copy_from_slice(&len.to_le_bytes());
+            }
+            Value::Struct(fields) => {
+                self.pad(8);
+                for field in fields {
+                    self.write(field);
+                }
+            }
+            Value::DictEntry(key, value) => {
+                self.pad(8);
+                self.write(key);
+                self.write(value);
+            }
+            Value::Variant(value) => {
+                self.write(&Value::Signature(value.signature()));
+                self.write(value);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&[u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .context("Truncated message")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn align(&mut self, alignment: usize) -> anyhow::Result<()> {
+        let aligned = self.pos.next_multiple_of(alignment);
+        self.take(aligned - self.pos)?;
+        Ok(())
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        self.align(2)?;
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes(bytes.try_into()?))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        self.align(4)?;
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into()?))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        self.align(8)?;
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into()?))
+    }
+
+    fn string(&mut self, len: usize) -> anyhow::Result<String> {
+        let value = String::from_utf8(self.take(len)?.to_vec())?;
+        self.take(1)?;
+        Ok(value)
+    }
+
+    /// Reads the values of a signature
+    fn read_all(&mut self, mut signature: &str) -> anyhow::Result<Vec<Value>> {
+        let mut values = Vec::new();
+        while !signature.is_empty() {
+            let (single, rest) = split_type(signature)?;
+            values.push(self.read(single)?);
+            signature = rest;
+        }
+        Ok(values)
+    }
+
+    /// Reads a value of a single complete type. The signature may come from the message (of a variant), so it's
+    /// checked to be one first.
+    fn read(&mut self, signature: &str) -> anyhow::Result<Value> {
+        let (single, rest) = split_type(signature)?;
+        if !rest.is_empty() {
+            return Err(FlatboxError::BusProtocol {
+                message: format!("{signature} isn't a single complete type"),
+            }
+            .into());
+        }
+        Ok(match single.as_bytes()[0] {
+            b'y' => Value::Byte(self.take(1)?[0]),
+            b'b' => Value::Bool(self.u32()? != 0),
+            b'n' => Value::I16(self.u16()? as i16),
+            b'q' => Value::U16(self.u16()?),
+            b'u' => Value::U32(self.u32()?),
+            b'i' => Value::I32(self.u32()? as i32),
+            b'x' => Value::I64(self.u64()? as i64),
+            b't' => Value::U64(self.u64()?),
+            b'd' => Value::Double(f64::from_bits(self.u64()?)),
+            b'h' => Value::Fd(self.u32()?),
+            b's' => {
+                let len = self.u32()? as usize;
+                Value::Str(self.string(len)?)
+            }
+            b'o' => {
+                let len = self.u32()? as usize;
+                Value::ObjectPath(self.string(len)?)
+            }
+            b'g' => {
+                let len = self.take(1)?[0].into();
+                Value::Signature(self.string(len)?)
+            }
+            b'a' => {
+                let len = self.u32()? as usize;
+                let element = &signature[1..];
+                self.align(alignment(element.as_bytes()[0]))?;
+                let end = self.pos + len;
+                let mut elements = Vec::new();
+                while self.pos < end {
+                    let start = self.pos;
+                    elements.push(self.read(element)?);
+                    // An empty struct would never reach the end
+                    if self.pos == start {
+                        return Err(FlatboxError::BusProtocol {
+                            message: format!("Empty array element {element}"),
+                        }
+                        .into());
+                    }
+                }
+                Value::Array(element.to_owned(), elements)
+            }
+            b'(' => {
+                self.align(8)?;
+                Value::Struct(self.read_all(&signature[1..signature.len() - 1])?)
+            }
+            b'{' => {
+                self.align(8)?;
+                let (key, value) = split_type(&signature[1..signature.len() - 1])?;
+                Value::DictEntry(Box::new(self.read(key)?), Box::new(self.read(value)?))
+            }
+            b'v' => {
+                let len = self.take(1)?[0].into();
+                let signature = self.string(len)?;
+                Value::Variant(Box::new(self.read(&signature)?))
+            }
+            code => {
+                return Err(FlatboxError::BusProtocol {
+                    message: format!("Unsupported type {}", code as char),
+                }
+                .into());
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    MethodCall = 1,
+    MethodReturn = 2,
+    Error = 3,
+    Signal = 4,
+}
+
+/// A D-Bus message, with the fds passed along it
+#[derive(Debug)]
+pub struct Message {
+    pub kind: MessageType,
+    pub flags: u8,
+    pub serial: u32,
+    pub path: Option<String>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    pub error_name: Option<String>,
+    pub reply_serial: Option<u32>,
+    pub destination: Option<String>,
+    pub sender: Option<String>,
+    pub body: Vec<Value>,
+    pub fds: Vec<OwnedFd>,
+}
+
+impl Message {
+    fn new(kind: MessageType, body: Vec<Value>) -> Self {
+        Self {
+            kind,
+            flags: 0,
+            serial: 0,
+            path: None,
+            interface: None,
+            member: None,
+            error_name: None,
+            reply_serial: None,
+            destination: None,
+            sender: None,
+            body,
+            fds: Vec::new(),
+        }
+    }
+
+    pub fn method_call(
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        body: Vec<Value>,
+    ) -> Self {
+        Self {
+            destination: Some(destination.to_owned()),
+            path: Some(path.to_owned()),
+            interface: Some(interface.to_owned()),
+            member: Some(member.to_owned()),
+            ..Self::new(MessageType::MethodCall, body)
+        }
+    }
+
+    /// A signal sent to a single connection, or broadcast without a destination
+    pub fn signal(
+        destination: Option<&str>,
+        path: &str,
+        interface: &str,
+        member: &str,
+        body: Vec<Value>,
+    ) -> Self {
+        Self {
+            destination: destination.map(str::to_owned),
+            path: Some(path.to_owned()),
+            interface: Some(interface.to_owned()),
+            member: Some(member.to_owned()),
+            ..Self::new(MessageType::Signal, body)
+        }
+    }
+
+    /// The reply to this method call
+    pub fn method_return(&self, body: Vec<Value>) -> Self {
+        Self {
+            destination: self.sender.clone(),
+            reply_serial: Some(self.serial),
+            ..Self::new(MessageType::MethodReturn, body)
+        }
+    }
+
+    /// The error reply to this method call
+    pub fn error(&self, name: &str, text: &str) -> Self {
+        Self {
+            destination: self.sender.clone(),
+            reply_serial: Some(self.serial),
+            error_name: Some(name.to_owned()),
+            ..Self::new(MessageType::Error, vec![Value::Str(text.to_owned())])
+        }
+    }
+
+    pub fn is_method(&self, interface: &str, member: &str) -> bool {
+        self.interface.as_deref() == Some(interface) && self.member.as_deref() == Some(member)
+    }
+
+    fn encode(&self, serial: u32) -> Vec<u8> {
+        let mut body = Writer::default();
+        for value in &self.body {
+            body.write(value);
+        }
+        let signature: String = self.body.iter().map(Value::signature).collect();
+
+        let strings = [
+            (FIELD_PATH, &self.path),
+            (FIELD_INTERFACE, &self.interface),
+            (FIELD_MEMBER, &self.member),
+            (FIELD_ERROR_NAME, &self.error_name),
+            (FIELD_DESTINATION, &self.destination),
+            (FIELD_SENDER, &self.sender),
+        ];
+        let mut fields = Vec::new();
+        for (code, value) in strings {
+            if let Some(value) = value {
+                let value = match code {
+                    FIELD_PATH => Value::ObjectPath(value.clone()),
+                    _ => Value::Str(value.clone()),
+                };
+                fields.push((code, value));
+            }
+        }
+        if let Some(reply_serial) = self.reply_serial {
+            fields.push((FIELD_REPLY_SERIAL, Value::U32(reply_serial)));
+        }
+        if !signature.is_empty() {
+            fields.push((FIELD_SIGNATURE, Value::Signature(signature)));
+        }
+        if !self.fds.is_empty() {
+            fields.push((FIELD_UNIX_FDS, Value::U32(self.fds.len() as u32)));
+        }
+        let fields = fields
+            .into_iter()
+            .map(|(code, value)| {
+                Value::Struct(vec![Value::Byte(code), Value::Variant(Box::new(value))])
+            })
+            .collect();
+
+        let mut message = Writer::default();
+        for value in [
+            Value::Byte(b'l'),
+            Value::Byte(self.kind as u8),
+            Value::Byte(self.flags),
+            Value::Byte(1),
+            Value::U32(body.buf.len() as u32),
+            Value::U32(serial),
+            Value::Array("(yv)".to_owned(), fields),
+        ] {
+            message.write(&value);
+        }
+        message.pad(8);
+        message.buf.extend(body.buf);
+        message.buf
+    }
+
+    /// The length of the message at the start of `bytes`, or `None` while its fixed header is incomplete. Only
+    /// fails when the messages can't be told apart anymore.
+    fn framed_len(bytes: &[u8]) -> anyhow::Result<Option<usize>> {
+        if bytes.len() < 16 {
+            return Ok(None);
+        }
+        let read_u32 = |offset: usize| {
+            let field = bytes[offset..offset + 4].try_into().unwrap();
+            match bytes[0] {
+                b'B' => Some(u32::from_be_bytes(field)),
+                b'l' => Some(u32::from_le_bytes(field)),
+                _ => None,
+            }
+        };
+        let (Some(body_len), Some(fields_len)) = (read_u32(4), read_u32(12)) else {
+            return Err(FlatboxError::BusProtocol {
+                message: format!("Unknown endianness {}", bytes[0].escape_ascii()),
+            }
+            .into());
+        };
+        let len = (16 + fields_len as usize).next_multiple_of(8) + body_len as usize;
+        if len > MAX_MESSAGE_LEN {
+            return Err(FlatboxError::BusProtocol {
+                message: format!("Message of {len} bytes is too long"),
+            }
+            .into());
+        }
+        Ok(Some(len))
+    }
+
+    /// Decodes a whole message, taking its fds from `fds`. A malformed message keeps its header when that could
+    /// be decoded, to reply to it.
+    fn decode(bytes: &[u8], fds: &mut VecDeque<OwnedFd>) -> Result<Self, Malformed> {
+        let malformed = |message: String| Malformed {
+            header: None,
+            error: FlatboxError::BusProtocol { message }.into(),
+        };
+        if bytes[0] != b'l' {
+            return Err(malformed(
+                "Only little-endian messages are supported".to_owned(),
+            ));
+        }
+        let read_u32 =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let header_len = (16 + read_u32(12) as usize).next_multiple_of(8);
+        let kind = match bytes[1] {
+            1 => MessageType::MethodCall,
+            2 => MessageType::MethodReturn,
+            3 => MessageType::Error,
+            4 => MessageType::Signal,
+            kind => return Err(malformed(format!("Unknown message type {kind}"))),
+        };
+        let mut message = Self::new(kind, Vec::new());
+        message.flags = bytes[2];
+        message.serial = read_u32(8);
+        let mut header = Reader {
+            buf: &bytes[..header_len],
+            pos: 12,
+        };
+        let mut signature = String::new();
+        let mut fd_count = 0;
+        let fields = header.read("a(yv)").map_err(|error| Malformed {
+            header: None,
+            error,
+        })?;
+        for field in fields.as_array().unwrap_or_default() {
+            let Value::Struct(field) = field else {
+                continue;
+            };
+            let (Some(Value::Byte(code)), Some(Value::Variant(value))) =
+                (field.first(), field.get(1))
+            else {
+                continue;
+            };
+            let string = value.as_str().map(str::to_owned);
+            match *code {
+                FIELD_PATH => message.path = string,
+                FIELD_INTERFACE => message.interface = string,
+                FIELD_MEMBER => message.member = string,
+                FIELD_ERROR_NAME => message.error_name = string,
+                FIELD_REPLY_SERIAL => message.reply_serial = value.as_u32(),
+                FIELD_DESTINATION => message.destination = string,
+                FIELD_SENDER => message.sender = string,
+                FIELD_SIGNATURE => signature = string.unwrap_or_default(),
+                FIELD_UNIX_FDS => fd_count = value.as_u32().unwrap_or_default() as usize,
+                _ => {}
+            }
+        }
+        if fds.len() < fd_count {
+            return Err(Malformed {
+                error: FlatboxError::BusProtocol {
+                    message: format!("Expected {fd_count} fds along the message"),
+                }
+                .into(),
+                header: Some(Box::new(message)),
+            });
+        }
+        message.fds = fds.drain(..fd_count).collect();
+        let body = Reader {
+            buf: &bytes[header_len..],
+            pos: 0,
+        }
+        .read_all(&signature);
+        match body {
+            Ok(body) => {
+                message.body = body;
+                Ok(message)
+            }
+            Err(error) => Err(Malformed {
+                header: Some(Box::new(message)),
+                error,
+            }),
+        }
+    }
+}
+
+/// A message which couldn't be decoded
+struct Malformed {
+    /// The message without its body, when its header could be decoded
+    header: Option<Box<Message>>,
+    error: anyhow::Error,
+}
+
+/// The sending half of a connection, shared with the threads sending signals
+pub struct BusSender {
+    socket: Mutex<UnixStream>,
+    serial: AtomicU32,
+}
+
+impl BusSender {
+    /// Sends a message with its fds, returns its serial
+    pub fn send(&self, message: &Message) -> io::Result<u32> {
+        let serial = self.serial.fetch_add(1, Ordering::Relaxed);
+        let bytes = message.encode(serial);
+        let socket = self.socket.lock().unwrap_or_else(|err| err.into_inner());
+        if message.fds.is_empty() {
+            (&*socket).write_all(&bytes)?;
+        } else {
+            let fds: Vec<RawFd> = message.fds.iter().map(AsRawFd::as_raw_fd).collect();
+            send_with_fds(&socket, &bytes, &fds)?;
+        }
+        Ok(serial)
+    }
+}
+
+/// A connection to a message bus
+pub struct Connection {
+    socket: UnixStream,
+    sender: Arc<BusSender>,
+    buf: Vec<u8>,
+    fds: VecDeque<OwnedFd>,
+    /// Messages received while waiting for a reply
+    pending: VecDeque<Message>,
+    pub unique_name: String,
+}
+
+impl Connection {
+    /// Connects to the session bus of `DBUS_SESSION_BUS_ADDRESS`, or the one in the user's runtime dir
+    pub fn session() -> anyhow::Result<Self> {
+        let address = env::var("DBUS_SESSION_BUS_ADDRESS")
+            .ok()
+            .filter(|address| !address.is_empty())
+            .or_else(|| {
+                let runtime_dir = env::var_os("XDG_RUNTIME_DIR")?;
+                Some(format!(
+                    "unix:path={}",
+                    Path::new(&runtime_dir).join("bus").display()
+                ))
+            })
+            .context("No session bus (DBUS_SESSION_BUS_ADDRESS) found")?;
+        let socket = connect(&address)?;
+        Self::authenticate(&socket)?;
+
+        let mut connection = Self::new(socket)?;
+        let hello = Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "Hello", Vec::new());
+        let reply = connection.call(&hello)?;
+        connection.unique_name = reply
+            .body
+            .first()
+            .and_then(Value::as_str)
+            .context("The bus didn't assign a name")?
+            .to_owned();
+        Ok(connection)
+    }
+
+    fn new(socket: UnixStream) -> io::Result<Self> {
+        Ok(Self {
+            sender: Arc::new(BusSender {
+                socket: Mutex::new(socket.try_clone()?),
+                serial: AtomicU32::new(1),
+            }),
+            socket,
+            buf: Vec::new(),
+            fds: VecDeque::new(),
+            pending: VecDeque::new(),
+            unique_name: String::new(),
+        })
+    }
+
+    /// The EXTERNAL authentication of the user of the process, with support for passing fds
+    fn authenticate(mut socket: &UnixStream) -> anyhow::Result<()> {
+        let uid = unsafe { libc::getuid() }.to_string();
+        let hex_uid: String = uid.bytes().map(|byte| format!("{byte:02x}")).collect();
+        socket.write_all(format!("\0AUTH EXTERNAL {hex_uid}\r\n").as_bytes())?;
+        if !read_line(socket)?.starts_with("OK ") {
+            return Err(FlatboxError::BusConnection {
+                message: "The bus rejected the authentication".to_owned(),
+            }
+            .into());
+        }
+        socket.write_all(b"NEGOTIATE_UNIX_FD\r\n")?;
+        if read_line(socket)? != "AGREE_UNIX_FD" {
+            return Err(FlatboxError::BusConnection {
+                message: "The bus doesn't support passing fds".to_owned(),
+            }
+            .into());
+        }
+        socket.write_all(b"BEGIN\r\n")?;
+        Ok(())
+    }
+
+    pub fn sender(&self) -> Arc<BusSender> {
+        Arc::clone(&self.sender)
+    }
+
+    /// Calls a method and waits for its reply, failing with the error replied
+    pub fn call(&mut self, message: &Message) -> anyhow::Result<Message> {
+        let serial = self.sender.send(message)?;
+        loop {
+            let reply = self.read()?;
+            if reply.reply_serial != Some(serial) {
+                self.pending.push_back(reply);
+                continue;
+            }
+            if reply.kind == MessageType::Error {
+                let text = reply
+                    .body
+                    .first()
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                return Err(FlatboxError::BusError {
+                    name: reply.error_name.unwrap_or_default(),
+                    message: text.to_owned(),
+                }
+                .into());
+            }
+            return Ok(reply);
+        }
+    }
+
+    /// Waits for the next message
+    pub fn receive(&mut self) -> anyhow::Result<Message> {
+        match self.pending.pop_front() {
+            Some(message) => Ok(message),
+            None => self.read(),
+        }
+    }
+
+    /// Reads the next message. Malformed messages are skipped, so a peer sending one can't end the connection:
+    /// method calls get an error reply, and replies fail the call waiting for them.
+    fn read(&mut self) -> anyhow::Result<Message> {
+        loop {
+            if let Some(len) = Message::framed_len(&self.buf)?
+                && self.buf.len() >= len
+            {
+                let decoded = Message::decode(&self.buf[..len], &mut self.fds);
+                self.buf.drain(..len);
+                match decoded {
+                    Ok(message) => return Ok(message),
+                    Err(Malformed {
+                        header: Some(header),
+                        error,
+                    }) if matches!(header.kind, MessageType::MethodReturn | MessageType::Error) => {
+                        return Err(error);
+                    }
+                    Err(Malformed { header, error }) => {
+                        crate::warn!("Skipping a malformed message: {error:#}");
+                        if let Some(call) = header
+                            && call.kind == MessageType::MethodCall
+                            && call.flags & NO_REPLY_EXPECTED == 0
+                        {
+                            self.sender.send(&call.error(
+                                "org.freedesktop.DBus.Error.InvalidArgs",
+                                &format!("{error:#}"),
+                            ))?;
+                        }
+                        continue;
+                    }
+                }
+            }
+            let mut chunk = [0; 4096];
+            let (received, fds) = recv_with_fds(&self.socket, &mut chunk, MAX_MESSAGE_FDS)?;
+            if received == 0 {
+                return Err(FlatboxError::BusConnection {
+                    message: "The bus closed the connection".to_owned(),
+                }
+                .into());
+            }
+            self.buf.extend(&chunk[..received]);
+            self.fds.extend(fds);
+        }
+    }
+}
+
+fn connect(address: &str) -> anyhow::Result<UnixStream> {
+    // The first of the alternative addresses which is a unix socket
+    for address in address.split(';') {
+        let Some(params) = address.strip_prefix("unix:") else {
+            continue;
+        };
+        for param in params.split(',') {
+            let socket = match param.split_once('=') {
+                Some(("path", path)) => UnixStream::connect(path),
+                Some(("abstract", name)) => {
+                    UnixStream::connect_addr(&SocketAddr::from_abstract_name(name)?)
+                }
+                _ => continue,
+            };
+            return socket.with_context(|| format!("Could not connect to the bus at {address}"));
+        }
+    }
+    Err(FlatboxError::BusConnection {
+        message: format!("Unsupported bus address {address}"),
+    }
+    .into())
+}
+
+fn read_line(mut socket: &UnixStream) -> anyhow::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    while !line.ends_with(b"\r\n") {
+        if socket.read(&mut byte)? == 0 {
+            return Err(FlatboxError::BusConnection {
+                message: "The bus closed the connection".to_owned(),
+            }
+            .into());
+        }
+        line.push(byte[0]);
+    }
+    line.truncate(line.len() - 2);
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        dbus::{Connection, Message, MessageType, Reader, Value},
+        error::FlatboxError,
+    };
+    use std::{collections::VecDeque, io::Write, os::unix::net::UnixStream};
+
+    fn decode(bytes: &[u8]) -> Message {
+        let len = Message::framed_len(bytes).unwrap().unwrap();
+        assert_eq!(len, bytes.len());
+        Message::decode(bytes, &mut VecDeque::new())
+            .map_err(|malformed| malformed.error)
+            .unwrap()
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let mut call = Message::method_call(
+            "org.freedesktop.Flatpak",
+            "/org/freedesktop/Flatpak/Development",
+            "org.freedesktop.Flatpak.Development",
+            "HostCommand",
+            vec![
+                Value::Array("y".to_owned(), b"/tmp\0".map(Value::Byte).into()),
+                Value::Array(
+                    "ay".to_owned(),
+                    vec![Value::Array(
+                        "y".to_owned(),
+                        b"ls\0".map(Value::Byte).into(),
+                    )],
+                ),
+                Value::Array(
+                    "{uh}".to_owned(),
+                    vec![Value::DictEntry(
+                        Box::new(Value::U32(1)),
+                        Box::new(Value::Fd(0)),
+                    )],
+                ),
+                Value::Array(
+                    "{sv}".to_owned(),
+                    vec![Value::DictEntry(
+                        Box::new(Value::Str("version".to_owned())),
+                        Box::new(Value::Variant(Box::new(Value::Bool(true)))),
+                    )],
+                ),
+                Value::U32(2),
+            ],
+        );
+        call.sender = Some(":1.42".to_owned());
+        let bytes = call.encode(7);
+        let decoded = decode(&bytes);
+        assert_eq!(decoded.serial, 7);
+        assert_eq!(decoded.sender.as_deref(), Some(":1.42"));
+        assert!(decoded.is_method("org.freedesktop.Flatpak.Development", "HostCommand"));
+        assert_eq!(decoded.body, call.body);
+        assert_eq!(decoded.body[0].as_bytes().as_deref(), Some(&b"/tmp\0"[..]));
+        assert!(Message::framed_len(&bytes[..15]).unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_all_basic_types() {
+        let call = Message::method_call(
+            "org.test",
+            "/",
+            "org.test",
+            "Test",
+            vec![Value::Array(
+                "{sv}".to_owned(),
+                [
+                    Value::I16(-2),
+                    Value::U16(2),
+                    Value::I64(-1 << 40),
+                    Value::U64(1 << 40),
+                    Value::Double(0.5),
+                ]
+                .into_iter()
+                .map(|value| {
+                    Value::DictEntry(
+                        Box::new(Value::Str(value.signature())),
+                        Box::new(Value::Variant(Box::new(value))),
+                    )
+                })
+                .collect(),
+            )],
+        );
+        assert_eq!(decode(&call.encode(1)).body, call.body);
+    }
+
+    #[test]
+    fn rejects_invalid_signatures() {
+        for (signature, buf) in [
+            ("v", &[0, 0][..]),
+            ("v", b"\x01a\0\0\x04\0\0\0\0\0\0\0"),
+            ("v", b"\x01(\0"),
+            ("v", b"\x02{s\0"),
+            ("v", b"\x02uu\0"),
+            ("a()", b"\x08\0\0\0\0\0\0\0"),
+        ] {
+            let err = Reader { buf, pos: 0 }.read(signature).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(FlatboxError::BusProtocol { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn skips_malformed_messages() {
+        let (socket, mut peer) = UnixStream::pair().unwrap();
+        let mut connection = Connection::new(socket).unwrap();
+        // A variant with an array of the incomplete type `(`
+        let mut malformed = Message::method_call(
+            "org.test",
+            "/",
+            "org.test",
+            "Malformed",
+            vec![Value::Variant(Box::new(Value::Array(
+                "(".to_owned(),
+                vec![Value::Struct(vec![Value::Byte(1)])],
+            )))],
+        );
+        malformed.sender = Some(":1.42".to_owned());
+        let valid = Message::signal(None, "/", "org.test", "Valid", vec![Value::I64(-1)]);
+        peer.write_all(&malformed.encode(7)).unwrap();
+        peer.write_all(&valid.encode(8)).unwrap();
+
+        let received = connection.receive().unwrap();
+        assert_eq!(received.member.as_deref(), Some("Valid"));
+        assert_eq!(received.body, [Value::I64(-1)]);
+
+        let reply = Connection::new(peer).unwrap().receive().unwrap();
+        assert_eq!(reply.kind, MessageType::Error);
+        assert_eq!(reply.reply_serial, Some(7));
+        assert_eq!(reply.destination.as_deref(), Some(":1.42"));
+        assert_eq!(
+            reply.error_name.as_deref(),
+            Some("org.freedesktop.DBus.Error.InvalidArgs")
+        );
+    }
+}
@@ -0,0 +1,59 @@
+use crate::error::FlatboxError;
+use anyhow::Context;
+use std::{
+    ffi::OsStr,
+    io::{PipeReader, Read, pipe},
+    path::Path,
+    process::{Child, Command, Stdio},
+};
+
+/// A running `xdg-dbus-proxy` instance filtering a bus for the sandbox.
+/// The proxy exits once this is dropped.
+#[derive(Debug)]
+pub struct DbusProxy {
+    child: Child,
+    /// The proxy stops when this end of its sync pipe is closed
+    sync: PipeReader,
+}
+
+impl DbusProxy {
+    /// Starts a proxy for the bus at `bus_address` listening on `socket_path`, and waits until it's ready.
+    pub fn spawn(
+        bus_address: &str,
+        socket_path: &Path,
+        filter_args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    ) -> anyhow::Result<Self> {
+        let (sync, sync_writer) = pipe().context("Could not create pipe")?;
+
+        let child = Command::new("xdg-dbus-proxy")
+            .arg(bus_address)
+            .arg(socket_path)
+            .args(filter_args)
+            .arg("--fd=1")
+            .stdin(Stdio::null())
+            .stdout(sync_writer)
+            .spawn()
+            .context("Could not start xdg-dbus-proxy")?;
+        let mut proxy = Self { child, sync };
+
+        // A byte is written once the socket is listening
+        let mut ready = [0u8];
+        if proxy.sync.read(&mut ready).unwrap_or_default() == 0 {
+            return Err(FlatboxError::ToolFailed {
+                tool: "xdg-dbus-proxy".to_owned(),
+                action: "start".to_owned(),
+                details: "it exited before becoming ready".to_owned(),
+            }
+            .into());
+        }
+
+        Ok(proxy)
+    }
+}
+
+impl Drop for DbusProxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,222 @@
+use anyhow::Context;
+use std::{fs, path::Path};
+
+/// Written by flatpak next to the `files` and `metadata` of each deployment
+pub const DEPLOY_FILE: &str = "deploy";
+/// Alignment and fixed size of the members of the `(ssasta{sv})` tuple of the deploy file
+const DEPLOY_MEMBERS: [(usize, Option<usize>); 5] =
+    [(1, None), (1, None), (1, None), (8, Some(8)), (8, None)];
+/// Members of the `{sv}` entries of the metadata dict
+const ENTRY_MEMBERS: [(usize, Option<usize>); 2] = [(1, None), (8, None)];
+
+/// What flatpak recorded when it deployed a ref: where it came from, the commit and the installed subpaths
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DeployData {
+    /// The remote the ref was pulled from
+    pub origin: String,
+    pub commit: String,
+    /// The subdirectories which were installed (e.g. `/de` of a locale extension), all of them when empty
+    pub subpaths: Vec<String>,
+    pub installed_size: u64,
+    /// Ids the app had before it was renamed
+    pub previous_ids: Vec<String>,
+    /// Why the ref is end-of-life, if it is
+    pub eol: Option<String>,
+    /// The ref replacing an end-of-life ref
+    pub eol_rebase: Option<String>,
+    /// When the commit was made, in seconds since the epoch
+    pub timestamp: Option<u64>,
+    pub appdata_name: Option<String>,
+    pub appdata_version: Option<String>,
+}
+
+/// The subpaths installed of the `files` of a deployment, relative to it. Empty when all of them are, which
+/// includes deployments without a (readable) deploy file.
+pub fn installed_subpaths(files: &Path) -> Vec<String> {
+    let Some(deployment) = files.parent() else {
+        return Vec::new();
+    };
+    let Ok(data) = fs::read(deployment.join(DEPLOY_FILE)) else {
+        return Vec::new();
+    };
+    let subpaths = parse_deploy_data(&data).map(|deploy_data| deploy_data.subpaths);
+    subpaths
+        .unwrap_or_default()
+        .iter()
+        .map(|subpath| subpath.trim_matches('/').to_owned())
+        .filter(|subpath| !subpath.is_empty())
+        .collect()
+}
+
+/// Whether a path in the files of a deployment (e.g. `lib/debug` of an extension) is in the installed subpaths
+pub fn subpath_installed(subpaths: &[String], path: &str) -> bool {
+    let path = path.trim_matches('/');
+    subpaths.is_empty()
+        || subpaths
+            .iter()
+            .any(|subpath| Path::new(path).starts_with(subpath))
+}
+
+/// Reads the deploy file of a deployment (e.g. the `active` dir of an installed ref)
+pub fn read_deploy_data(deployment: &Path) -> anyhow::Result<DeployData> {
+    let path = deployment.join(DEPLOY_FILE);
+    let data = fs::read(&path).with_context(|| format!("Could not read {}", path.display()))?;
+    parse_deploy_data(&data).with_context(|| format!("{} is malformed", path.display()))
+}
+
+/// Parses a deploy file, which is a serialized GVariant of type `(ssasta{sv})`: the origin, the commit, the
+/// subpaths, the installed size and a dict of the remaining data
+pub fn parse_deploy_data(data: &[u8]) -> Option<DeployData> {
+    let [origin, commit, subpaths, installed_size, metadata] =
+        <[&[u8]; 5]>::try_from(tuple_members(data, &DEPLOY_MEMBERS)?).ok()?;
+    let mut deploy_data = DeployData {
+        origin: string(origin)?,
+        commit: string(commit)?,
+        subpaths: strings(subpaths)?,
+        // Flatpak stores the sizes and timestamps big-endian
+        installed_size: u64::from_be_bytes(installed_size.try_into().ok()?),
+        ..Default::default()
+    };
+
+    for entry in array_elements(metadata, 8)? {
+        let [key, value] = <[&[u8]; 2]>::try_from(tuple_members(entry, &ENTRY_MEMBERS)?).ok()?;
+        let (value_type, value) = variant(value)?;
+        match (string(key)?.as_str(), value_type) {
+            ("previous-ids", b"as") => deploy_data.previous_ids = strings(value)?,
+            ("eol", b"s") => deploy_data.eol = Some(string(value)?),
+            ("eol-rebase", b"s") => deploy_data.eol_rebase = Some(string(value)?),
+            ("timestamp", b"t") => {
+                deploy_data.timestamp = Some(u64::from_be_bytes(value.try_into().ok()?))
+            }
+            ("appdata-name", b"s") => deploy_data.appdata_name = Some(string(value)?),
+            ("appdata-version", b"s") => deploy_data.appdata_version = Some(string(value)?),
+            _ => {}
+        }
+    }
+    Some(deploy_data)
+}
+
+fn align(pos: usize, alignment: usize) -> usize {
+    pos.next_multiple_of(alignment)
+}
+
+/// The size of the framing offsets of a GVariant container, which depends on its size
+fn offset_size(len: usize) -> usize {
+    match len {
+        0 => 0,
+        1..=0xff => 1,
+        0x100..=0xffff => 2,
+        0x1_0000..=0xffff_ffff => 4,
+        _ => 8,
+    }
+}
+
+fn read_offset(data: &[u8], pos: usize, size: usize) -> Option<usize> {
+    let bytes = data.get(pos..pos.checked_add(size)?)?;
+    Some(
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |offset, byte| (offset << 8) | *byte as usize),
+    )
+}
+
+/// Splits a tuple into its members, given their alignment and fixed size. The ends of the variable sized
+/// members but the last are stored at the end of the tuple, in reverse order.
+fn tuple_members<'a>(data: &'a [u8], members: &[(usize, Option<usize>)]) -> Option<Vec<&'a [u8]>> {
+    let size = offset_size(data.len());
+    let frames = members[..members.len() - 1]
+        .iter()
+        .filter(|(_, fixed)| fixed.is_none())
+        .count();
+    let frames_start = data.len().checked_sub(frames * size)?;
+
+    let mut values = Vec::with_capacity(members.len());
+    let mut pos = 0;
+    let mut frame = 0;
+    for (index, (alignment, fixed)) in members.iter().enumerate() {
+        let start = align(pos, *alignment);
+        let end = match fixed {
+            Some(fixed) => start + fixed,
+            None if index == members.len() - 1 => frames_start,
+            None => {
+                frame += 1;
+                read_offset(data, data.len() - frame * size, size)?
+            }
+        };
+        values.push(data.get(start..end)?);
+        pos = end;
+    }
+    Some(values)
+}
+
+/// Splits an array of variable sized elements. The ends of the elements are stored after them, in order.
+fn array_elements(data: &[u8], alignment: usize) -> Option<Vec<&[u8]>> {
+    if data.is_empty() {
+        return Some(Vec::new());
+    }
+    let size = offset_size(data.len());
+    let frames_start = read_offset(data, data.len() - size, size)?;
+    let count = (data.len().checked_sub(frames_start)?) / size;
+
+    let mut elements = Vec::with_capacity(count);
+    let mut pos = 0;
+    for index in 0..count {
+        let end = read_offset(data, frames_start + index * size, size)?;
+        elements.push(data.get(align(pos, alignment)..end)?);
+        pos = end;
+    }
+    Some(elements)
+}
+
+fn string(data: &[u8]) -> Option<String> {
+    let data = data.strip_suffix(b"\0")?;
+    String::from_utf8(data.to_vec()).ok()
+}
+
+fn strings(data: &[u8]) -> Option<Vec<String>> {
+    array_elements(data, 1)?.into_iter().map(string).collect()
+}
+
+/// Splits a variant into its type string and value
+fn variant(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let separator = data.iter().rposition(|byte| *byte == 0)?;
+    Some((&data[separator + 1..], &data[..separator]))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deploy::{DeployData, parse_deploy_data, subpath_installed};
+
+    #[test]
+    fn parses_deploy_data() {
+        // The origin, commit, subpaths, installed size and the metadata dict of a locale extension
+        let data = b"flathub\0\
+                     0123abcd\0\
+                     /de\0/fr\0\x04\x08\
+                     \0\0\0\0\0\0\0\0\0\0\x10\0\0\
+                     eol\0\0\0\0\0use org.example.New\0\0s\x04\0\
+                     timestamp\0\0\0\0\0\0\0\0\0\0\0g\x89\xab\xcd\0t\x0a\x1f;\
+                     \x1b\x11\x08";
+        assert_eq!(
+            parse_deploy_data(data),
+            Some(DeployData {
+                origin: "flathub".to_owned(),
+                commit: "0123abcd".to_owned(),
+                subpaths: vec!["/de".to_owned(), "/fr".to_owned()],
+                installed_size: 0x100000,
+                eol: Some("use org.example.New".to_owned()),
+                timestamp: Some(0x6789abcd),
+                ..Default::default()
+            })
+        );
+        assert!(parse_deploy_data(b"flathub\0").is_none());
+
+        let subpaths = ["de".to_owned(), "lib/debug".to_owned()];
+        assert!(subpath_installed(&subpaths, "/de/share"));
+        assert!(subpath_installed(&subpaths, "lib/debug"));
+        assert!(!subpath_installed(&subpaths, "lib"));
+        assert!(!subpath_installed(&subpaths, "desktop"));
+        assert!(subpath_installed(&[], "lib"));
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{
+    dbus::{Connection, Message, Value},
+    sockets::{self, RuntimeDir},
+};
+use anyhow::Context;
+use std::{
+    fs::File,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+const DOCUMENTS_NAME: &str = "org.freedesktop.portal.Documents";
+const DOCUMENTS_PATH: &str = "/org/freedesktop/portal/documents";
+/// What the app may do with the files it is passed, like in flatpak
+const PERMISSIONS: [&str; 2] = ["read", "write"];
+
+/// The document portal (xdg-document-portal) of the session bus, which exports host files to an app under a
+/// document ID, visible in its view of the portal at `$XDG_RUNTIME_DIR/doc/ID/NAME`
+pub struct DocumentPortal {
+    bus: Connection,
+    app_id: String,
+    /// Where the app's view of the portal is mounted in the sandbox
+    sandbox_dir: PathBuf,
+}
+
+impl DocumentPortal {
+    /// Connects to the portal when it runs and its view is available in the sandbox
+    pub fn for_app(runtime_dir: &RuntimeDir, app_id: &str) -> Option<Self> {
+        if !sockets::app_documents(runtime_dir, app_id).is_dir() {
+            return None;
+        }
+        match Connection::session() {
+            Ok(bus) => Some(Self {
+                bus,
+                app_id: app_id.to_owned(),
+                sandbox_dir: sockets::sandbox_documents(runtime_dir),
+            }),
+            Err(err) => {
+                crate::warn!("Could not connect to the document portal: {err:#}");
+                None
+            }
+        }
+    }
+
+    /// Exports a file to the app, returns its path in the sandbox
+    pub fn export(&mut self, path: &Path) -> anyhow::Result<PathBuf> {
+        let name = path.file_name().context("Only files can be exported")?;
+        let file = File::options()
+            .read(true)
+            .custom_flags(libc::O_PATH)
+            .open(path)
+            .with_context(|| format!("Could not open {}", path.display()))?;
+
+        // Reuses the document of the file when it was exported before, and doesn't keep it after the session
+        let mut add = Message::method_call(
+            DOCUMENTS_NAME,
+            DOCUMENTS_PATH,
+            DOCUMENTS_NAME,
+            "Add",
+            vec![Value::Fd(0), Value::Bool(true), Value::Bool(false)],
+        );
+        add.fds.push(file.into());
+        let reply = self.bus.call(&add)?;
+        let doc_id = reply
+            .body
+            .first()
+            .and_then(Value::as_str)
+            .context("The document portal returned no document")?
+            .to_owned();
+
+        let permissions = PERMISSIONS
+            .iter()
+            .map(|permission| Value::Str((*permission).to_owned()))
+            .collect();
+        let grant = Message::method_call(
+            DOCUMENTS_NAME,
+            DOCUMENTS_PATH,
+            DOCUMENTS_NAME,
+            "GrantPermissions",
+            vec![
+                Value::Str(doc_id.clone()),
+                Value::Str(self.app_id.clone()),
+                Value::Array("s".to_owned(), permissions),
+            ],
+        );
+        self.bus.call(&grant)?;
+        Ok(self.sandbox_dir.join(doc_id).join(name))
+    }
+}
@@ -0,0 +1,231 @@
+use crate::bwrap::BwrapBuilder;
+use anyhow::Context;
+use indexmap::IndexMap;
+use std::{
+    ffi::{OsStr, OsString},
+    os::unix::ffi::OsStrExt,
+};
+
+/// Environment variables of the sandboxed process.
+/// Variables are collected from multiple sources and only passed to bwrap once fully composed,
+/// so later sources can extend values set by earlier ones.
+#[derive(Debug, Default)]
+pub struct Environment {
+    vars: IndexMap<OsString, Option<OsString>>,
+}
+
+impl Environment {
+    pub fn set(&mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) {
+        self.vars
+            .insert(key.as_ref().to_owned(), Some(value.as_ref().to_owned()));
+    }
+
+    pub fn unset(&mut self, key: impl AsRef<OsStr>) {
+        self.vars.insert(key.as_ref().to_owned(), None);
+    }
+
+    /// Sets a variable, or unsets it when the value is empty, as flatpak does for `[Environment]` entries and `--env`
+    pub fn set_or_unset(&mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) {
+        if value.as_ref().is_empty() {
+            self.unset(key);
+        } else {
+            self.set(key, value);
+        }
+    }
+
+    pub fn get(&self, key: impl AsRef<OsStr>) -> Option<&OsStr> {
+        self.vars
+            .get(key.as_ref())
+            .and_then(|value| value.as_deref())
+    }
+
+    /// Adds an entry to the end of a colon-separated list variable, unless it's already present
+    pub fn append_path(&mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        let new_value = match self.get(key).filter(|existing| !existing.is_empty()) {
+            Some(existing) => {
+                if existing
+                    .as_encoded_bytes()
+                    .split(|byte| *byte == b':')
+                    .any(|entry| entry == value.as_encoded_bytes())
+                {
+                    return;
+                }
+
+                let mut new_value = existing.to_owned();
+                new_value.push(":");
+                new_value.push(value);
+                new_value
+            }
+            None => value.to_owned(),
+        };
+        self.vars.insert(key.to_owned(), Some(new_value));
+    }
+
+    /// Adds an entry to the start of a colon-separated list variable, moving it there if it's already present
+    pub fn prepend_path(&mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        let mut new_value = value.to_owned();
+        if let Some(existing) = self.get(key) {
+            for entry in existing.as_encoded_bytes().split(|byte| *byte == b':') {
+                if !entry.is_empty() && entry != value.as_encoded_bytes() {
+                    new_value.push(":");
+                    new_value.push(OsStr::from_bytes(entry));
+                }
+            }
+        }
+        self.vars.insert(key.to_owned(), Some(new_value));
+    }
+
+    /// Copies the host variables matching any of the glob `patterns`, overriding the values set so far
+    pub fn keep_host_vars(&mut self, patterns: &[String]) {
+        if patterns.is_empty() {
+            return;
+        }
+        for (key, value) in std::env::vars_os() {
+            if let Some(name) = key.to_str()
+                && patterns.iter().any(|pattern| glob_match(pattern, name))
+            {
+                self.set(key, value);
+            }
+        }
+    }
+
+    pub fn apply(&self, bwrap: &mut BwrapBuilder) {
+        for (key, value) in &self.vars {
+            match value {
+                Some(value) => crate::trace!("{}={}", key.display(), value.display()),
+                None => crate::trace!("Unsetting {}", key.display()),
+            }
+            match value {
+                Some(value) => bwrap.set_env(key, value),
+                None => bwrap.unset_env(key),
+            };
+        }
+    }
+}
+
+/// Matches `name` against a shell style pattern supporting `*` and `?`
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name position it was tried at, for backtracking
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Parses a dotenv style file: `KEY=VALUE` lines with optional `export` prefixes, quoted values and `#` comments
+pub fn parse_env_file(contents: &str) -> anyhow::Result<Vec<(String, String)>> {
+    contents
+        .lines()
+        .map(str::trim_ascii)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line
+                .split_once('=')
+                .with_context(|| format!("Expected KEY=VALUE, found '{line}' at line {}", i + 1))?;
+            let value = value.trim_ascii();
+            let value = [('"', '"'), ('\'', '\'')]
+                .into_iter()
+                .find_map(|(start, end)| value.strip_prefix(start)?.strip_suffix(end))
+                .unwrap_or(value);
+            Ok((key.trim_ascii().to_owned(), value.to_owned()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::environment::{Environment, glob_match, parse_env_file};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn append_path_vars() {
+        let mut env = Environment::default();
+        env.append_path("PATH", "/usr/bin");
+        env.append_path("PATH", "/opt/bin");
+        env.append_path("PATH", "/usr/bin");
+        assert_eq!(Some(OsStr::new("/usr/bin:/opt/bin")), env.get("PATH"));
+
+        env.unset("PATH");
+        assert_eq!(None, env.get("PATH"));
+        env.append_path("PATH", "/usr/bin");
+        assert_eq!(Some(OsStr::new("/usr/bin")), env.get("PATH"));
+    }
+
+    #[test]
+    fn prepend_path_vars() {
+        let mut env = Environment::default();
+        env.prepend_path("PATH", "/usr/bin");
+        assert_eq!(Some(OsStr::new("/usr/bin")), env.get("PATH"));
+
+        env.set("PATH", "/app/bin:/usr/bin");
+        env.prepend_path("PATH", "/usr/lib/sdk/bin");
+        assert_eq!(
+            Some(OsStr::new("/usr/lib/sdk/bin:/app/bin:/usr/bin")),
+            env.get("PATH")
+        );
+        env.prepend_path("PATH", "/usr/bin");
+        assert_eq!(
+            Some(OsStr::new("/usr/bin:/usr/lib/sdk/bin:/app/bin")),
+            env.get("PATH")
+        );
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("WINE*", "WINEPREFIX"));
+        assert!(glob_match("WINE*", "WINE"));
+        assert!(glob_match("DXVK_*", "DXVK_HUD"));
+        assert!(glob_match("*_SOCK", "SSH_AUTH_SOCK"));
+        assert!(glob_match("LC_?", "LC_X"));
+        assert!(glob_match("A*B*C", "AxxBCyyC"));
+        assert!(!glob_match("WINE*", "XWINE"));
+        assert!(!glob_match("SSH_AUTH_SOCK", "SSH_AUTH_SOCKET"));
+        assert!(!glob_match("LC_?", "LC_"));
+    }
+
+    #[test]
+    fn env_file() {
+        let contents = "
+# Comment
+FOO=bar
+export QUOTED=\"with spaces\"
+SINGLE='a=b'
+EMPTY=
+";
+        assert_eq!(
+            parse_env_file(contents).unwrap(),
+            [
+                ("FOO".to_owned(), "bar".to_owned()),
+                ("QUOTED".to_owned(), "with spaces".to_owned()),
+                ("SINGLE".to_owned(), "a=b".to_owned()),
+                ("EMPTY".to_owned(), String::new()),
+            ]
+        );
+        assert!(parse_env_file("INVALID").is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use crate::{nested::OuterSandbox, sandbox::DEBUGGER};
+use std::{error::Error, fmt, io, path::PathBuf, time::Duration};
+
+/// The failures of flatbox-core: composing and starting a sandbox, installing and checking out refs, unpacking
+/// images, applying resource limits and talking to D-Bus. They're returned inside of `anyhow::Error`, so they
+/// can be found with `downcast_ref` below the context added by the callers. Only I/O errors of reading and
+/// writing files are passed on as they are.
+#[derive(Debug)]
+pub enum FlatboxError {
+    /// The app (given as a full or partial ref) isn't installed in any of the searched installations
+    AppNotFound {
+        app: String,
+        searched_dirs: Vec<PathBuf>,
+    },
+    /// The runtime (given as a full or partial ref) isn't installed in any of the searched installations
+    RuntimeNotFound {
+        runtime: String,
+        searched_dirs: Vec<PathBuf>,
+    },
+    /// A metadata or overrides keyfile is malformed at the 1-based `line`
+    MetadataParse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+    /// A metadata keyfile lacks a key which is needed, e.g. the `runtime` of `[Application]`
+    MissingMetadataKey {
+        path: PathBuf,
+        group: String,
+        key: String,
+    },
+    /// An extension point of the runtime or app couldn't be set up
+    ExtensionSetup { name: String, source: anyhow::Error },
+    /// The program creating the sandbox (bwrap, aa-exec or a container tool) couldn't be started
+    BwrapSpawn { program: String, source: io::Error },
+    /// The command didn't exit within `--timeout`, so the sandbox was terminated
+    TimedOut { timeout: Duration },
+    /// flatbox runs inside of a sandbox which doesn't allow creating another one
+    NestedSandbox { outer: OuterSandbox },
+    /// The options can't be combined, or a required one is missing
+    InvalidOptions { message: String },
+    /// No installation of `installations.d` has the name
+    InstallationNotFound { name: String },
+    /// A deployment isn't inside of a flatpak installation
+    NotInInstallation { path: PathBuf },
+    /// The remote to install from isn't configured, or no remote is when no name is given
+    RemoteNotFound { name: Option<String> },
+    /// None of the remotes has a ref matching the one to install
+    RemoteRefNotFound {
+        reference: String,
+        arch: String,
+        remotes: Vec<String>,
+    },
+    /// A ref or commit has no metadata, so it's neither an app nor a runtime
+    NotFlatpakRef { reference: String },
+    /// The repo of a bundle doesn't contain exactly one commit
+    InvalidBundle { commits: usize },
+    /// An external program (ostree, tar, podman, a FUSE mounter, xdg-dbus-proxy) failed, with its error output
+    /// or exit status
+    ToolFailed {
+        tool: String,
+        action: String,
+        details: String,
+    },
+    /// A tool to wrap the command with isn't in the runtime or on the host
+    ToolNotFound { tool: String },
+    /// Something is used from the host's root, which `--no-host-root` hides
+    HostRootRequired { what: String },
+    /// Debugging needs gdb, which only SDKs contain
+    NoDebugger { runtime: String },
+    /// The host's GL libraries for `--gl=host` weren't found
+    HostGlNotFound,
+    /// Where an extension given with `--with-extension` is mounted isn't declared by any installed runtime
+    ExtensionPointNotFound { name: String },
+    /// An extension image is neither a squashfs nor an erofs image
+    UnsupportedExtensionImage { path: PathBuf },
+    /// An image reference has no or an unsupported transport
+    InvalidImageReference { reference: String },
+    /// The image (with the tag, when given) isn't in the layout or archive
+    ImageNotFound { path: PathBuf, tag: Option<String> },
+    /// The layout or archive has several images and none was chosen, `reference` shows how to choose one
+    AmbiguousImage { path: PathBuf, reference: String },
+    /// An image uses a digest algorithm other than sha256
+    UnsupportedDigest { digest: String },
+    /// A JSON document of an image is malformed at the byte `offset`
+    InvalidJson { offset: usize, message: String },
+    /// Resource limits need a cgroup below the one of flatbox, which can't be created in the root cgroup
+    RootCgroup,
+    /// The controller of a resource limit isn't enabled for the children of flatbox's cgroup
+    CgroupControllerDisabled { controller: String, cgroup: PathBuf },
+    /// Resource limits need the unified cgroup hierarchy
+    CgroupV2Required,
+    /// The kernel's core pattern depends on the crashed process, so cores can't be redirected
+    UnredirectableCorePattern { pattern: String },
+    /// Several candidates match and there's no terminal to choose one on
+    AmbiguousChoice {
+        prompt: String,
+        candidates: Vec<String>,
+    },
+    /// The input ended before a candidate was chosen
+    NoChoice,
+    /// A build dir wasn't created with `flatpak build-init`
+    NotABuildDir { path: PathBuf },
+    /// The template of an ephemeral home isn't a dir
+    HomeTemplateNotDir { path: PathBuf },
+    /// The dir to create a rootfs in already has contents
+    RootfsNotEmpty { path: PathBuf },
+    /// The symlinks of a path point at each other
+    SymlinkLoop { path: PathBuf },
+    /// A path which has to be passed on as a string isn't valid UTF-8
+    NonUtf8Path { path: PathBuf },
+    /// A prepared sandbox was written by another version of flatbox
+    PreparedByOtherVersion { path: PathBuf },
+    /// The `@@`/`@@u` sections of forwarded files are malformed
+    InvalidForwardedArgs { message: String },
+    /// A D-Bus message is malformed or uses what isn't supported
+    BusProtocol { message: String },
+    /// The bus couldn't be connected to, or closed the connection
+    BusConnection { message: String },
+    /// A method call was answered with a D-Bus error
+    BusError { name: String, message: String },
+}
+
+impl fmt::Display for FlatboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlatboxError::AppNotFound { app, searched_dirs } => {
+                write!(f, "App {app} is not installed")?;
+                write_dirs(f, searched_dirs)
+            }
+            FlatboxError::RuntimeNotFound {
+                runtime,
+                searched_dirs,
+            } => {
+                write!(f, "Runtime {runtime} is not installed")?;
+                write_dirs(f, searched_dirs)
+            }
+            FlatboxError::MetadataParse {
+                path,
+                line,
+                message,
+            } => write!(
+                f,
+                "Could not parse {} at line {line}: {message}",
+                path.display()
+            ),
+            FlatboxError::MissingMetadataKey { path, group, key } => write!(
+                f,
+                "{} doesn't declare the {key} of [{group}]",
+                path.display()
+            ),
+            FlatboxError::ExtensionSetup { name, .. } => {
+                write!(f, "Could not set up extension {name}")
+            }
+            FlatboxError::BwrapSpawn { program, source } => {
+                if source.kind() == io::ErrorKind::NotFound {
+                    write!(f, "{program} is not installed")
+                } else {
+                    write!(f, "Could not start {program}")
+                }
+            }
+            FlatboxError::TimedOut { timeout } => write!(
+                f,
+                "The command didn't exit within {}s, the sandbox was terminated",
+                timeout.as_secs()
+            ),
+            FlatboxError::NestedSandbox { outer } => {
+                write!(
+                    f,
+                    "flatbox runs inside of {outer}, which doesn't allow creating sandboxes"
+                )
+            }
+            FlatboxError::InvalidOptions { message } => f.write_str(message),
+            FlatboxError::InstallationNotFound { name } => {
+                write!(f, "No installation named {name} is configured in installations.d")
+            }
+            FlatboxError::NotInInstallation { path } => {
+                write!(f, "{} isn't in an installation", path.display())
+            }
+            FlatboxError::RemoteNotFound { name: Some(name) } => {
+                write!(f, "No remote named {name} is configured")
+            }
+            FlatboxError::RemoteNotFound { name: None } => f.write_str("No remotes are configured"),
+            FlatboxError::RemoteRefNotFound {
+                reference,
+                arch,
+                remotes,
+            } => write!(
+                f,
+                "No ref matching {reference} for {arch} was found in {}",
+                remotes.join(", ")
+            ),
+            FlatboxError::NotFlatpakRef { reference } => {
+                write!(f, "{reference} isn't a flatpak app or runtime, it has no metadata")
+            }
+            FlatboxError::InvalidBundle { commits } => {
+                write!(f, "Expected one commit in the bundle, found {commits}")
+            }
+            FlatboxError::ToolFailed {
+                tool,
+                action,
+                details,
+            } => write!(f, "{tool} could not {action}: {details}"),
+            FlatboxError::ToolNotFound { tool } => {
+                write!(f, "{tool} isn't installed in the runtime or on the host")
+            }
+            FlatboxError::HostRootRequired { what } => {
+                write!(f, "{what} needs the host's root")
+            }
+            FlatboxError::NoDebugger { runtime } => write!(
+                f,
+                "{runtime} has no {DEBUGGER}, debugging needs an SDK (the app's `sdk`, or one given with --runtime)"
+            ),
+            FlatboxError::HostGlNotFound => f.write_str("Could not find the host GL libraries"),
+            FlatboxError::ExtensionPointNotFound { name } => {
+                write!(f, "Could not find where {name} is mounted")
+            }
+            FlatboxError::UnsupportedExtensionImage { path } => {
+                write!(f, "{} isn't a squashfs or erofs image", path.display())
+            }
+            FlatboxError::InvalidImageReference { reference } => write!(
+                f,
+                "{reference} isn't an image reference, use oci:PATH or docker-archive:PATH"
+            ),
+            FlatboxError::ImageNotFound {
+                path,
+                tag: Some(tag),
+            } => write!(f, "No image {tag} in {}", path.display()),
+            FlatboxError::ImageNotFound { path, tag: None } => {
+                write!(f, "{} has no images", path.display())
+            }
+            FlatboxError::AmbiguousImage { path, reference } => write!(
+                f,
+                "{} has several images, choose one with {reference}",
+                path.display()
+            ),
+            FlatboxError::UnsupportedDigest { digest } => {
+                write!(f, "Unsupported digest {digest}")
+            }
+            FlatboxError::InvalidJson { offset, message } => {
+                write!(f, "{message} at offset {offset}")
+            }
+            FlatboxError::RootCgroup => f.write_str(
+                "flatbox runs in the root cgroup, so no cgroup can be created to apply resource limits",
+            ),
+            FlatboxError::CgroupControllerDisabled { controller, cgroup } => write!(
+                f,
+                "The {controller} controller isn't enabled for the cgroup of flatbox ({})",
+                cgroup.display()
+            ),
+            FlatboxError::CgroupV2Required => {
+                f.write_str("Resource limits need the unified cgroup hierarchy (cgroup v2)")
+            }
+            FlatboxError::UnredirectableCorePattern { pattern } => write!(
+                f,
+                "The core pattern {pattern} depends on the crashed process, so cores can't be redirected"
+            ),
+            FlatboxError::AmbiguousChoice { prompt, candidates } => {
+                write!(f, "{prompt}, choose one of:")?;
+                for (i, candidate) in candidates.iter().enumerate() {
+                    write!(f, "\n  {}) {candidate}", i + 1)?;
+                }
+                Ok(())
+            }
+            FlatboxError::NoChoice => f.write_str("No choice made"),
+            FlatboxError::NotABuildDir { path } => write!(
+                f,
+                "{} isn't a build dir, create it with `flatpak build-init`",
+                path.display()
+            ),
+            FlatboxError::HomeTemplateNotDir { path } => {
+                write!(f, "Home template {} is not a directory", path.display())
+            }
+            FlatboxError::RootfsNotEmpty { path } => {
+                write!(f, "{} already exists and isn't empty", path.display())
+            }
+            FlatboxError::SymlinkLoop { path } => {
+                write!(f, "Too many levels of symlinks in {}", path.display())
+            }
+            FlatboxError::NonUtf8Path { path } => {
+                write!(f, "{} is not valid UTF-8", path.display())
+            }
+            FlatboxError::PreparedByOtherVersion { path } => write!(
+                f,
+                "{} was prepared by another version of flatbox, prepare it again",
+                path.display()
+            ),
+            FlatboxError::InvalidForwardedArgs { message }
+            | FlatboxError::BusProtocol { message }
+            | FlatboxError::BusConnection { message } => f.write_str(message),
+            FlatboxError::BusError { name, message } => write!(f, "{name}: {message}"),
+        }
+    }
+}
+
+fn write_dirs(f: &mut fmt::Formatter<'_>, dirs: &[PathBuf]) -> fmt::Result {
+    let dirs: Vec<String> = dirs.iter().map(|dir| dir.display().to_string()).collect();
+    write!(f, " (searched {})", dirs.join(", "))
+}
+
+impl Error for FlatboxError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FlatboxError::ExtensionSetup { source, .. } => Some(source.as_ref()),
+            FlatboxError::BwrapSpawn { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
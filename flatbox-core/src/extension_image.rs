@@ -0,0 +1,282 @@
+use crate::{bwrap::BwrapBuilder, error::FlatboxError, extensions::LD_SO_CONF_DIR};
+use anyhow::Context;
+use std::{
+    ffi::OsStr,
+    fmt,
+    fs::{self, File},
+    io::{self, Read},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+/// The erofs superblock starts after 1024 bytes of padding
+const EROFS_MAGIC_OFFSET: usize = 1024;
+const EROFS_MAGIC: [u8; 4] = 0xE0F5E1E2u32.to_le_bytes();
+/// Present in systemd-sysext images, whose `usr` is merged into the host's
+const EXTENSION_RELEASE_DIR: &str = "usr/lib/extension-release.d";
+/// Library dirs of a mounted image which are added to the ld.so.conf, besides the `lib/<triplet>` ones
+const LIBRARY_DIRS: [&str; 2] = ["lib", "lib64"];
+/// Where the images are mounted on the host, in the setup dir of the sandbox
+const MOUNTS_DIR: &str = "extension-images";
+
+/// An image attached to the sandbox, written as `PATH:MOUNTPOINT`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionImage {
+    pub path: PathBuf,
+    pub mountpoint: PathBuf,
+}
+
+impl FromStr for ExtensionImage {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // The mountpoint comes last, so paths of images can contain colons
+        let Some((path, mountpoint)) = value.rsplit_once(':') else {
+            return Err(format!("expected PATH:MOUNTPOINT, found '{value}'"));
+        };
+        if path.is_empty() {
+            return Err(format!("expected PATH:MOUNTPOINT, found '{value}'"));
+        }
+        if !mountpoint.starts_with('/') || mountpoint == "/" {
+            return Err(format!(
+                "the mountpoint has to be an absolute path below /, found '{mountpoint}'"
+            ));
+        }
+        Ok(Self {
+            path: path.into(),
+            mountpoint: mountpoint.into(),
+        })
+    }
+}
+
+impl fmt::Display for ExtensionImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.path.display(), self.mountpoint.display())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Filesystem {
+    Squashfs,
+    Erofs,
+}
+
+impl Filesystem {
+    /// Detects the filesystem of an image by its magic number
+    fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(SQUASHFS_MAGIC) {
+            Some(Self::Squashfs)
+        } else if header.get(EROFS_MAGIC_OFFSET..EROFS_MAGIC_OFFSET + EROFS_MAGIC.len())
+            == Some(&EROFS_MAGIC)
+        {
+            Some(Self::Erofs)
+        } else {
+            None
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Squashfs => "squashfs",
+            Self::Erofs => "erofs",
+        }
+    }
+
+    /// The FUSE implementation used to mount the image without privileges
+    fn fuse_command(self) -> &'static str {
+        match self {
+            Self::Squashfs => "squashfuse",
+            Self::Erofs => "erofsfuse",
+        }
+    }
+}
+
+/// An image mounted read-only on the host for the lifetime of the sandbox. It's unmounted once this is dropped.
+#[derive(Debug)]
+pub struct ImageMount {
+    path: PathBuf,
+    fuse: bool,
+}
+
+impl ImageMount {
+    /// Loop mounts the image as root, and with its FUSE implementation otherwise
+    fn mount(image: &Path, filesystem: Filesystem, target: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(target)
+            .with_context(|| format!("Could not create {}", target.display()))?;
+        let fuse = unsafe { libc::geteuid() } != 0;
+        let mut command = if fuse {
+            let mut command = Command::new(filesystem.fuse_command());
+            command.arg(image).arg(target);
+            command
+        } else {
+            let mut command = Command::new("mount");
+            command
+                .args(["-t", filesystem.name(), "-o", "loop,ro"])
+                .arg(image)
+                .arg(target);
+            command
+        };
+        let program = command.get_program().to_string_lossy().into_owned();
+        let output = command
+            .output()
+            .with_context(|| format!("Could not run {program}, is it installed?"))?;
+        if !output.status.success() {
+            return Err(FlatboxError::ToolFailed {
+                tool: program,
+                action: format!("mount {}", image.display()),
+                details: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            }
+            .into());
+        }
+        Ok(Self {
+            path: target.to_owned(),
+            fuse,
+        })
+    }
+}
+
+impl Drop for ImageMount {
+    fn drop(&mut self) {
+        if self.fuse {
+            let unmounted = ["fusermount3", "fusermount"].iter().any(|fusermount| {
+                Command::new(fusermount)
+                    .arg("-uz")
+                    .arg(&self.path)
+                    .output()
+                    .is_ok_and(|output| output.status.success())
+            });
+            if !unmounted {
+                crate::warn!("Could not unmount {}", self.path.display());
+            }
+        } else {
+            let path = std::ffi::CString::new(self.path.as_os_str().as_bytes())
+                .expect("Paths can't contain NUL");
+            if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } != 0 {
+                crate::warn!(
+                    "Could not unmount {}: {}",
+                    self.path.display(),
+                    io::Error::last_os_error()
+                );
+            }
+        }
+    }
+}
+
+fn read_header(path: &Path) -> io::Result<Vec<u8>> {
+    let mut header = Vec::new();
+    File::open(path)?
+        .take((EROFS_MAGIC_OFFSET + EROFS_MAGIC.len()) as u64)
+        .read_to_end(&mut header)?;
+    Ok(header)
+}
+
+/// The library dirs in the tree of a mounted image, as paths in the sandbox
+fn library_dirs(tree: &Path, mountpoint: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = LIBRARY_DIRS
+        .iter()
+        .filter(|dir| tree.join(dir).is_dir())
+        .map(PathBuf::from)
+        .collect();
+    if let Ok(entries) = fs::read_dir(tree.join("lib")) {
+        let mut triplets: Vec<PathBuf> = entries
+            .flatten()
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.file_name())
+            .filter(|name| is_triplet(name))
+            .map(|name| Path::new("lib").join(name))
+            .collect();
+        triplets.sort();
+        dirs.extend(triplets);
+    }
+    dirs.into_iter().map(|dir| mountpoint.join(dir)).collect()
+}
+
+/// Multiarch library dirs, e.g. `x86_64-linux-gnu`
+fn is_triplet(name: &OsStr) -> bool {
+    name.to_str().is_some_and(|name| name.contains("-linux-"))
+}
+
+/// Mounts the extension images read-only at their mountpoints and adds their library dirs to the ld.so.conf.
+/// Squashfs and erofs images are mounted on the host first, directories are used as they are. The `usr` of
+/// systemd-sysext images is mounted, as that's what gets merged into the host's.
+pub fn setup_extension_images(
+    bwrap: &mut BwrapBuilder,
+    images: &[ExtensionImage],
+) -> anyhow::Result<()> {
+    for (index, image) in images.iter().enumerate() {
+        let metadata = fs::metadata(&image.path)
+            .with_context(|| format!("Could not find the image {}", image.path.display()))?;
+        let root = if metadata.is_dir() {
+            image.path.clone()
+        } else {
+            let header = read_header(&image.path)
+                .with_context(|| format!("Could not read {}", image.path.display()))?;
+            let Some(filesystem) = Filesystem::detect(&header) else {
+                return Err(FlatboxError::UnsupportedExtensionImage {
+                    path: image.path.clone(),
+                }
+                .into());
+            };
+            let target = bwrap.setup_dir().join(MOUNTS_DIR).join(index.to_string());
+            crate::info!(
+                "Mounting the {} image {} at {}",
+                filesystem.name(),
+                image.path.display(),
+                image.mountpoint.display()
+            );
+            let mount = ImageMount::mount(&image.path, filesystem, &target)?;
+            bwrap.add_image_mount(mount);
+            target
+        };
+        let tree = if root.join(EXTENSION_RELEASE_DIR).is_dir() {
+            root.join("usr")
+        } else {
+            root
+        };
+        bwrap.ro_bind(&tree, &image.mountpoint);
+
+        let ld_contents: String = library_dirs(&tree, &image.mountpoint)
+            .iter()
+            .map(|dir| format!("{}\n", dir.display()))
+            .collect();
+        if !ld_contents.is_empty() {
+            bwrap.ro_bind_data(
+                Path::new(LD_SO_CONF_DIR).join(format!("runtime-image-{index}.conf")),
+                ld_contents.as_bytes(),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::extension_image::{EROFS_MAGIC_OFFSET, ExtensionImage, Filesystem};
+    use std::path::PathBuf;
+
+    #[test]
+    fn parses_images_and_detects_filesystems() {
+        assert_eq!(
+            "/srv/tool:chain.raw:/opt/toolchain".parse(),
+            Ok(ExtensionImage {
+                path: PathBuf::from("/srv/tool:chain.raw"),
+                mountpoint: PathBuf::from("/opt/toolchain"),
+            })
+        );
+        assert!("toolchain.raw".parse::<ExtensionImage>().is_err());
+        assert!("toolchain.raw:opt".parse::<ExtensionImage>().is_err());
+        assert!(":/opt".parse::<ExtensionImage>().is_err());
+
+        assert_eq!(
+            Filesystem::detect(b"hsqs\x04\0\0\0"),
+            Some(Filesystem::Squashfs)
+        );
+        let mut erofs = vec![0; EROFS_MAGIC_OFFSET];
+        erofs.extend([0xe2, 0xe1, 0xf5, 0xe0]);
+        assert_eq!(Filesystem::detect(&erofs), Some(Filesystem::Erofs));
+        assert_eq!(Filesystem::detect(&erofs[..EROFS_MAGIC_OFFSET]), None);
+    }
+}
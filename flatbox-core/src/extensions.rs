@@ -0,0 +1,952 @@
+use crate::{
+    bwrap::BwrapBuilder, conditions, deploy, environment::Environment, error::FlatboxError, index,
+    keyfile::parse_keyfile, locale, probe, video,
+};
+use anyhow::Context;
+use indexmap::IndexMap;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+const EXTENSION_PREFIX: &str = "Extension ";
+pub const LD_SO_CONF_DIR: &str = "/run/flatpak/ld.so.conf.d";
+/// Variables which have to be extended with the mounted paths of extensions placed in the given directory.
+/// Contains the extension directory, variable name and the path inside of each mounted extension, which is
+/// only added when the extension has it.
+const EXTENSION_ENV_PATHS: [(&str, &str, &str); 4] = [
+    ("lib/extensions/gstreamer-1.0", "GST_PLUGIN_SYSTEM_PATH", ""),
+    ("lib/gstreamer-1.0", "GST_PLUGIN_SYSTEM_PATH", ""),
+    // The GStreamer plugins built against the codecs of ffmpeg-full
+    ("lib/ffmpeg", "GST_PLUGIN_SYSTEM_PATH", "gstreamer-1.0"),
+    ("dri/intel-vaapi-driver", "LIBVA_DRIVERS_PATH", ""),
+];
+const SDK_EXTENSION_DIR: &str = "lib/sdk";
+/// SDK extensions are named `<sdk>.Extension.<name>` and mounted at `lib/sdk/<name>`
+const SDK_EXTENSION_INFIX: &str = ".Sdk.Extension.";
+/// The debug info and sources extensions of a ref (`<id>.Debug`, `<id>.Sources`) and where they're mounted
+const DEBUG_EXTENSIONS: [(&str, &str); 2] = [("Debug", "lib/debug"), ("Sources", "sources")];
+const GLVND_EGL_VENDOR_DIR: &str = "glvnd/egl_vendor.d";
+/// Paths inside of mounted SDK extensions which get exported, if present
+const SDK_EXTENSION_ENV_PATHS: [(&str, &str); 2] =
+    [("PATH", "bin"), ("GI_TYPELIB_PATH", "lib/girepository-1.0")];
+/// Primary architecture, its secondary (compat) architecture and the library dir triplet of the secondary one
+const MULTIARCH: [(&str, &str, &str); 2] = [
+    ("x86_64", "i386", "i386-linux-gnu"),
+    ("aarch64", "arm", "arm-linux-gnueabihf"),
+];
+
+pub struct ExtensionContext<'a> {
+    pub arch: &'a str,
+    pub runtime_version: &'a str,
+    pub available_runtimes: &'a [String],
+    pub install_dirs: &'a [PathBuf],
+    /// Skip GL driver extensions, e.g. because the host drivers are passed through instead
+    pub skip_gl_drivers: bool,
+}
+
+impl<'a> ExtensionContext<'a> {
+    pub fn new(
+        runtime: &'a str,
+        available_runtimes: &'a [String],
+        install_dirs: &'a [PathBuf],
+    ) -> anyhow::Result<Self> {
+        let mut runtime_split = runtime.split('/').skip(1);
+        let arch = runtime_split
+            .next()
+            .context("Could not extract architecture from runtime id")?;
+        let runtime_version = runtime_split
+            .next()
+            .context("Could not extract version from runtime id")?;
+
+        Ok(Self {
+            arch,
+            runtime_version,
+            available_runtimes,
+            install_dirs,
+            skip_gl_drivers: false,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ExtensionSource {
+    Runtime,
+    App,
+}
+
+impl ExtensionSource {
+    fn base_path(self) -> &'static Path {
+        match self {
+            ExtensionSource::Runtime => Path::new("/usr"),
+            ExtensionSource::App => Path::new("/app"),
+        }
+    }
+
+    /// Prefix of the generated ld.so.conf files, which determines their priority
+    fn ld_conf_prefix(self) -> &'static str {
+        match self {
+            ExtensionSource::Runtime => "runtime",
+            ExtensionSource::App => "app",
+        }
+    }
+}
+
+pub fn setup_runtime_extensions(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    ctx: &ExtensionContext,
+    runtime_metadata: &IndexMap<&str, IndexMap<&str, &str>>,
+) -> anyhow::Result<()> {
+    setup_extensions(
+        bwrap,
+        environment,
+        ctx,
+        runtime_metadata,
+        ExtensionSource::Runtime,
+    )
+}
+
+pub fn setup_app_extensions(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    ctx: &ExtensionContext,
+    app_metadata: &IndexMap<&str, IndexMap<&str, &str>>,
+) -> anyhow::Result<()> {
+    setup_extensions(bwrap, environment, ctx, app_metadata, ExtensionSource::App)
+}
+
+fn setup_extensions(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    ctx: &ExtensionContext,
+    metadata: &IndexMap<&str, IndexMap<&str, &str>>,
+    source: ExtensionSource,
+) -> anyhow::Result<()> {
+    let mut extensions: Vec<(&str, &IndexMap<&str, &str>)> = metadata
+        .iter()
+        .filter_map(|(group, metadata)| {
+            group
+                .strip_prefix(EXTENSION_PREFIX)
+                .map(|name| (name, metadata))
+        })
+        .filter(|(_, metadata)| !(ctx.skip_gl_drivers && is_gl_driver_extension(metadata)))
+        .collect();
+
+    // Extensions which are mounted inside of another extension's directory (e.g. GL32 inside of Compat.i386)
+    // have to be set up after their parent
+    extensions.sort_by_key(|(_, metadata)| {
+        metadata
+            .get("directory")
+            .map(|directory| Path::new(directory).components().count())
+            .unwrap_or_default()
+    });
+
+    let extension_points = ExtensionPoints {
+        names: extensions.iter().map(|(name, _)| *name).collect(),
+        existing_paths: probe_extension_paths(ctx, &extensions),
+    };
+
+    for (name, metadata) in extensions {
+        setup_extension(
+            bwrap,
+            environment,
+            ctx,
+            metadata,
+            name,
+            &extension_points,
+            source,
+        )
+        .map_err(|source| FlatboxError::ExtensionSetup {
+            name: name.to_owned(),
+            source,
+        })?;
+    }
+
+    Ok(())
+}
+
+/// The extension points being set up, with the paths found to exist of the ones they may be installed at
+struct ExtensionPoints<'a> {
+    names: Vec<&'a str>,
+    existing_paths: HashSet<PathBuf>,
+}
+
+/// GL driver extensions are selected through the active GL driver, e.g. org.freedesktop.Platform.GL
+fn is_gl_driver_extension(metadata: &IndexMap<&str, &str>) -> bool {
+    ["enable-if", "download-if", "autoprune-unless"]
+        .into_iter()
+        .filter_map(|key| metadata.get(key))
+        .any(|conditions| {
+            conditions
+                .split(';')
+                .any(|condition| condition == "active-gl-driver")
+        })
+}
+
+fn setup_extension(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    ctx: &ExtensionContext,
+    extension_metadata: &IndexMap<&str, &str>,
+    name: &str,
+    extension_points: &ExtensionPoints,
+    source: ExtensionSource,
+) -> anyhow::Result<()> {
+    let existing_paths = &extension_points.existing_paths;
+    let directory = extension_metadata
+        .get("directory")
+        .context("Missing directory")?;
+
+    let expected_prefix = format!("{name}.");
+    let extension_base_mount_path = source.base_path().join(directory);
+
+    let allowed_versions = allowed_versions(ctx, extension_metadata);
+    let arches = extension_arches(ctx, directory);
+
+    let subdirectories = extension_metadata.get("subdirectories") == Some(&"true");
+    let subdirectory_suffix = extension_metadata.get("subdirectory-suffix");
+
+    let is_enabled = |impl_name: &str| match (
+        extension_metadata.get("enable-if"),
+        extension_metadata.get("autoprune-unless"),
+    ) {
+        (Some(enable_if), _) => conditions::extension_enabled(enable_if, name, impl_name),
+        // Installed extensions which would get pruned on the next update are not in use
+        (None, Some(autoprune_unless)) => {
+            conditions::extension_enabled(autoprune_unless, name, impl_name)
+        }
+        (None, None) => true,
+    };
+
+    let mut mounted_paths = Vec::new();
+
+    let exact_extension_path =
+        if ctx.available_runtimes.iter().any(|runtime| runtime == name) && is_enabled(name) {
+            find_extension_path(ctx, name, &arches, &allowed_versions, existing_paths)
+        } else {
+            None
+        };
+
+    if subdirectories && exact_extension_path.is_none() {
+        bwrap.tmpfs(&extension_base_mount_path);
+    }
+
+    if let Some(full_extension_path) = &exact_extension_path {
+        crate::debug!(
+            "Mounting {name} from {} at {}",
+            full_extension_path.display(),
+            extension_base_mount_path.display()
+        );
+        let subpaths = deploy::installed_subpaths(full_extension_path);
+        if is_locale_extension(name, extension_metadata) {
+            mount_locale_subset(
+                bwrap,
+                ctx,
+                full_extension_path,
+                &extension_base_mount_path,
+                &subpaths,
+            );
+        } else {
+            bind_installed_files(
+                bwrap,
+                full_extension_path,
+                &extension_base_mount_path,
+                &subpaths,
+            );
+        }
+
+        if let Some(triplet) = secondary_arch_triplet(ctx.arch)
+            && Path::new(directory) == Path::new("lib").join(triplet)
+        {
+            let ld_contents = format!("{}\n", extension_base_mount_path.display());
+            let filename = format!("{}-{name}.conf", source.ld_conf_prefix());
+            bwrap.ro_bind_data(
+                Path::new(LD_SO_CONF_DIR).join(filename),
+                ld_contents.as_bytes(),
+            )?;
+        }
+
+        mounted_paths.push((
+            full_extension_path.clone(),
+            extension_base_mount_path.clone(),
+            None,
+            subpaths,
+        ));
+    }
+
+    for extension in ctx.available_runtimes.iter().filter(|_| subdirectories) {
+        let Some(extension_impl_name) = extension.strip_prefix(&expected_prefix) else {
+            continue;
+        };
+
+        if is_nested_extension_point(extension, name, &extension_points.names) {
+            continue;
+        }
+
+        if !is_enabled(extension_impl_name) {
+            crate::debug!("Skipping {extension}, its conditions aren't met");
+            continue;
+        }
+
+        if let Some(full_extension_path) =
+            find_extension_path(ctx, extension, &arches, &allowed_versions, existing_paths)
+        {
+            let mut extension_mount_path = extension_base_mount_path.join(extension_impl_name);
+            if let Some(suffix) = subdirectory_suffix {
+                extension_mount_path.push(suffix);
+            }
+            crate::debug!(
+                "Mounting {extension} from {} at {}",
+                full_extension_path.display(),
+                extension_mount_path.display()
+            );
+            let subpaths = deploy::installed_subpaths(&full_extension_path);
+            bind_installed_files(
+                bwrap,
+                &full_extension_path,
+                &extension_mount_path,
+                &subpaths,
+            );
+            mounted_paths.push((
+                full_extension_path,
+                extension_mount_path,
+                Some(extension_impl_name),
+                subpaths,
+            ));
+        }
+    }
+
+    // Merged dirs are symlinked into the base dir, which is only possible when it's a tmpfs
+    let merge_dirs: Vec<&str> = match extension_metadata.get("merge-dirs") {
+        Some(merge_dirs) if exact_extension_path.is_none() => merge_dirs.split(';').collect(),
+        _ => Vec::new(),
+    };
+    let merge_dir_paths: Vec<PathBuf> = mounted_paths
+        .iter()
+        .filter(|(_, _, impl_name, _)| impl_name.is_some())
+        .flat_map(|(source_path, _, _, _)| merge_dirs.iter().map(|dir| source_path.join(dir)))
+        .collect();
+    let mut merge_dir_entries: HashMap<PathBuf, _> = merge_dir_paths
+        .iter()
+        .cloned()
+        .zip(probe::read_dirs(&merge_dir_paths))
+        .collect();
+
+    let mut existing_symlinks = HashSet::new();
+    for (source_path, target, impl_name, subpaths) in &mounted_paths {
+        add_extension_env_paths(environment, directory, source_path, target);
+
+        // The proprietary nvidia driver only provides EGL through its GLVND vendor config,
+        // which is merged into the extension dir rather than in the runtime's default search path
+        if impl_name.is_some_and(|name| name.starts_with("nvidia-"))
+            && source_path.join(GLVND_EGL_VENDOR_DIR).is_dir()
+        {
+            environment.append_path(
+                "__EGL_VENDOR_LIBRARY_DIRS",
+                extension_base_mount_path.join(GLVND_EGL_VENDOR_DIR),
+            );
+        }
+
+        video::add_driver_env(environment, &source_path.join("lib"), &target.join("lib"));
+
+        if Path::new(directory) == Path::new(SDK_EXTENSION_DIR) {
+            add_sdk_extension_env(environment, source_path, target);
+        }
+
+        if impl_name.is_some() {
+            let mut processed_paths = HashSet::new();
+            for merge_dir in &merge_dirs {
+                let merge_dir_path = source_path.join(merge_dir);
+                if let Some(Some(entries)) = merge_dir_entries.remove(&merge_dir_path) {
+                    for entry in entries? {
+                        if !entry.is_file {
+                            continue;
+                        }
+
+                        if processed_paths.insert(merge_dir_path.join(&entry.file_name)) {
+                            let symlink_source = target.join(merge_dir).join(&entry.file_name);
+                            let symlink_target = extension_base_mount_path
+                                .join(merge_dir)
+                                .join(&entry.file_name);
+                            if existing_symlinks.insert(symlink_target.clone()) {
+                                bwrap.symlink(symlink_source, symlink_target);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // A library dir outside of the installed subpaths doesn't exist in the sandbox
+        if let Some(add_ld_path) = extension_metadata
+            .get("add-ld-path")
+            .filter(|add_ld_path| deploy::subpath_installed(subpaths, add_ld_path))
+        {
+            let ld_path = target.join(add_ld_path);
+            let mut ld_contents = ld_path
+                .to_str()
+                .context("Invalid ld path formed")?
+                .to_owned();
+            ld_contents.push('\n');
+
+            let filename = match impl_name {
+                Some(impl_name) => format!("{}-{name}.{impl_name}.conf", source.ld_conf_prefix()),
+                None => format!("{}-{name}.conf", source.ld_conf_prefix()),
+            };
+            let ld_config_path = Path::new(LD_SO_CONF_DIR).join(filename);
+
+            bwrap.ro_bind_data(&ld_config_path, ld_contents.as_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+pub struct ExtensionPointStatus<'a> {
+    pub name: &'a str,
+    /// Installed refs which implement the extension point
+    pub installed: Vec<String>,
+    /// Whether the extension point is expected to have an implementation installed on this host
+    pub required: bool,
+    /// Ref which should be installed to implement the extension point
+    pub suggested_ref: Option<String>,
+}
+
+pub fn extension_points_status<'a>(
+    ctx: &ExtensionContext,
+    metadata: &'a IndexMap<&'a str, IndexMap<&'a str, &'a str>>,
+) -> Vec<ExtensionPointStatus<'a>> {
+    let extensions: Vec<(&str, &IndexMap<&str, &str>)> = metadata
+        .iter()
+        .filter_map(|(group, metadata)| {
+            group
+                .strip_prefix(EXTENSION_PREFIX)
+                .map(|name| (name, metadata))
+        })
+        .collect();
+    let extension_points: Vec<&str> = extensions.iter().map(|(name, _)| *name).collect();
+    let existing_paths = probe_extension_paths(ctx, &extensions);
+
+    extensions
+        .into_iter()
+        .map(|(name, extension_metadata)| {
+            let directory = extension_metadata
+                .get("directory")
+                .copied()
+                .unwrap_or_default();
+            let allowed_versions = allowed_versions(ctx, extension_metadata);
+            let arches = extension_arches(ctx, directory);
+            let subdirectories = extension_metadata.get("subdirectories") == Some(&"true");
+            let expected_prefix = format!("{name}.");
+
+            let installed = ctx
+                .available_runtimes
+                .iter()
+                .filter(|extension| {
+                    *extension == name
+                        || (subdirectories
+                            && extension.starts_with(&expected_prefix)
+                            && !is_nested_extension_point(extension, name, &extension_points))
+                })
+                .filter(|extension| {
+                    find_extension_path(ctx, extension, &arches, &allowed_versions, &existing_paths)
+                        .is_some()
+                })
+                .cloned()
+                .collect();
+
+            let condition = extension_metadata
+                .get("download-if")
+                .or_else(|| extension_metadata.get("enable-if"));
+            let preferred_impl =
+                condition.and_then(|condition| conditions::preferred_impl(condition));
+
+            let required = extension_metadata.get("no-autodownload") != Some(&"true")
+                && condition.is_none_or(|condition| {
+                    conditions::extension_enabled(
+                        condition,
+                        name,
+                        preferred_impl.as_deref().unwrap_or(name),
+                    )
+                });
+
+            let version = allowed_versions.split(';').next().unwrap_or_default();
+            let suggested_ref = match (subdirectories, preferred_impl) {
+                (false, _) => Some(format!("{name}/{}/{version}", ctx.arch)),
+                (true, Some(impl_name)) => {
+                    Some(format!("{name}.{impl_name}/{}/{version}", ctx.arch))
+                }
+                (true, None) => None,
+            };
+
+            ExtensionPointStatus {
+                name,
+                installed,
+                required,
+                suggested_ref,
+            }
+        })
+        .collect()
+}
+
+fn allowed_versions(ctx: &ExtensionContext, extension_metadata: &IndexMap<&str, &str>) -> String {
+    extension_metadata
+        .get("versions")
+        .or_else(|| extension_metadata.get("version"))
+        .map(|version| version.to_string())
+        .unwrap_or_else(|| ctx.runtime_version.to_owned())
+}
+
+fn extension_arches<'a>(ctx: &ExtensionContext<'a>, directory: &str) -> Vec<&'a str> {
+    let secondary_arch = secondary_arch_for_dir(ctx.arch, directory);
+    [ctx.arch].into_iter().chain(secondary_arch).collect()
+}
+
+/// Nested extension points such as `GL.Debug` share the prefix of their parent, but are set up separately
+fn is_nested_extension_point(extension: &str, name: &str, extension_points: &[&str]) -> bool {
+    extension_points.iter().any(|point| {
+        point.len() > name.len()
+            && (extension == *point || extension.starts_with(&format!("{point}.")))
+    })
+}
+
+/// Mounts the debug info and sources extensions of the runtime and app (given with its branch), which are
+/// installed as runtimes. They're mounted even when the metadata doesn't declare them, as older builds don't.
+pub fn setup_debug_extensions(
+    bwrap: &mut BwrapBuilder,
+    ctx: &ExtensionContext,
+    runtime_id: &str,
+    app: Option<(&str, &str)>,
+) {
+    let refs = [(runtime_id, ctx.runtime_version, "/usr")]
+        .into_iter()
+        .chain(app.map(|(id, branch)| (id, branch, "/app")));
+    for (id, version, base) in refs {
+        for (suffix, directory) in DEBUG_EXTENSIONS {
+            let name = format!("{id}.{suffix}");
+            let Some(path) = extension_path_candidates(ctx, &name, &[ctx.arch], version)
+                .find(|path| path.is_dir())
+            else {
+                crate::info!("{name} isn't installed, install it for the {suffix} files of {id}");
+                continue;
+            };
+            crate::debug!("Mounting {name} from {}", path.display());
+            bwrap.ro_bind(&path, Path::new(base).join(directory));
+        }
+    }
+}
+
+/// An extension mounted on request rather than through the metadata, found by `resolve_extra_extensions`
+pub struct ExtraExtension {
+    name: String,
+    path: PathBuf,
+    mount_path: PathBuf,
+    add_ld_path: Option<String>,
+}
+
+/// Finds the extensions given as `ID[//BRANCH]` which the metadata doesn't declare, e.g. an SDK extension in an app
+/// using the Platform. The branch defaults to the runtime's, or any installed one. They're mounted where the
+/// extension point of the ref they extend (their `[ExtensionOf]`) puts them, or in `lib/sdk` for SDK extensions
+/// of SDKs which aren't installed. Mount points missing in the runtime or app are made creatable by overlaying
+/// their closest existing parent, which has to happen before the declared extensions are mounted into it.
+pub fn resolve_extra_extensions(
+    bwrap: &mut BwrapBuilder,
+    ctx: &ExtensionContext,
+    runtime_files_path: &Path,
+    app_files_path: Option<&Path>,
+    extensions: &[String],
+) -> anyhow::Result<Vec<ExtraExtension>> {
+    let mut overlays: Vec<PathBuf> = Vec::new();
+    let mut resolved = Vec::with_capacity(extensions.len());
+    for extension in extensions {
+        let (name, branch) = match extension.split_once("//") {
+            Some((name, branch)) => (name, Some(branch)),
+            None => (extension.as_str(), None),
+        };
+        let path = find_extra_extension(ctx, name, branch).with_context(|| {
+            format!(
+                "{extension} isn't installed for {}, install it with `flatbox install {name}//{}`",
+                ctx.arch,
+                branch.unwrap_or(ctx.runtime_version)
+            )
+        })?;
+        let (mount_path, add_ld_path) = extra_extension_mount(ctx, name, &path)?;
+
+        let (base, files_path) = if mount_path.starts_with("/app") {
+            let app_files_path = app_files_path
+                .with_context(|| format!("{name} extends an app and can't be used without one"))?;
+            (Path::new("/app"), app_files_path)
+        } else {
+            (Path::new("/usr"), runtime_files_path)
+        };
+        let relative = mount_path.strip_prefix(base).unwrap_or(&mount_path);
+        if !files_path.join(relative).is_dir() {
+            let existing = relative
+                .ancestors()
+                .find(|ancestor| files_path.join(ancestor).is_dir())
+                .unwrap_or(Path::new(""));
+            let overlay = base.join(existing);
+            if !overlays
+                .iter()
+                .any(|overlaid| overlay.starts_with(overlaid))
+            {
+                crate::debug!("Overlaying {} to mount {name} in it", overlay.display());
+                bwrap.tmp_overlay(files_path.join(existing), &overlay);
+                overlays.push(overlay);
+            }
+        }
+
+        resolved.push(ExtraExtension {
+            name: name.to_owned(),
+            path,
+            mount_path,
+            add_ld_path,
+        });
+    }
+    Ok(resolved)
+}
+
+/// The files of an installed extension of the arch of the runtime, preferring the given branch and then the one
+/// of the runtime
+fn find_extra_extension(
+    ctx: &ExtensionContext,
+    name: &str,
+    branch: Option<&str>,
+) -> Option<PathBuf> {
+    let mut branches = vec![branch.unwrap_or(ctx.runtime_version).to_owned()];
+    if branch.is_none() {
+        for dir in ctx.install_dirs {
+            let Ok(entries) = fs::read_dir(dir.join("runtime").join(name).join(ctx.arch)) else {
+                continue;
+            };
+            let mut installed: Vec<String> = entries
+                .flatten()
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .collect();
+            installed.sort();
+            branches.extend(installed);
+        }
+    }
+    extension_path_candidates(ctx, name, &[ctx.arch], &branches.join(";"))
+        .find(|path| path.is_dir())
+}
+
+/// Where an extension is mounted according to the extension point of the ref it extends, and the library dir it
+/// adds
+fn extra_extension_mount(
+    ctx: &ExtensionContext,
+    name: &str,
+    files_path: &Path,
+) -> anyhow::Result<(PathBuf, Option<String>)> {
+    let deployment = files_path.parent().unwrap_or(files_path);
+    let metadata_path = deployment.join("metadata");
+    let raw_metadata = fs::read_to_string(&metadata_path).unwrap_or_default();
+    let metadata = parse_keyfile(&raw_metadata).map_err(|err| err.in_file(&metadata_path))?;
+
+    // e.g. `runtime/org.freedesktop.Sdk/x86_64/24.08`
+    if let Some(extended) = metadata
+        .get("ExtensionOf")
+        .and_then(|group| group.get("ref"))
+    {
+        let (kind, extended_ref) = extended.split_once('/').unwrap_or(("runtime", extended));
+        let extended_metadata =
+            crate::find_install_path(extended_ref, kind == "app", ctx.install_dirs)
+                .and_then(|path| fs::read_to_string(path.join("active").join("metadata")).ok());
+        if let Some(raw_extended_metadata) = extended_metadata
+            && let Ok(extended_metadata) = parse_keyfile(&raw_extended_metadata)
+        {
+            let base = if kind == "app" { "/app" } else { "/usr" };
+            for (group, point) in &extended_metadata {
+                let Some(point_name) = group.strip_prefix(EXTENSION_PREFIX) else {
+                    continue;
+                };
+                let Some(directory) = point.get("directory") else {
+                    continue;
+                };
+                let mut mount_path = Path::new(base).join(directory);
+                if point_name != name {
+                    let Some(impl_name) = name
+                        .strip_prefix(point_name)
+                        .and_then(|rest| rest.strip_prefix('.'))
+                        .filter(|_| point.get("subdirectories") == Some(&"true"))
+                    else {
+                        continue;
+                    };
+                    mount_path.push(impl_name);
+                    if let Some(suffix) = point.get("subdirectory-suffix") {
+                        mount_path.push(suffix);
+                    }
+                }
+                let add_ld_path = point.get("add-ld-path").map(|path| path.to_string());
+                return Ok((mount_path, add_ld_path));
+            }
+        }
+    }
+
+    if let Some((_, impl_name)) = name.split_once(SDK_EXTENSION_INFIX) {
+        return Ok((
+            Path::new("/usr").join(SDK_EXTENSION_DIR).join(impl_name),
+            None,
+        ));
+    }
+    Err(FlatboxError::ExtensionPointNotFound {
+        name: name.to_owned(),
+    }
+    .into())
+}
+
+/// Mounts the extensions found by `resolve_extra_extensions`, after the declared ones so they aren't hidden by
+/// them. Their tools and libraries are exported like the ones of SDK extensions.
+pub fn mount_extra_extensions(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    extensions: &[ExtraExtension],
+) -> anyhow::Result<()> {
+    for extension in extensions {
+        crate::debug!(
+            "Mounting {} from {} at {}",
+            extension.name,
+            extension.path.display(),
+            extension.mount_path.display()
+        );
+        let subpaths = deploy::installed_subpaths(&extension.path);
+        bind_installed_files(bwrap, &extension.path, &extension.mount_path, &subpaths);
+        add_sdk_extension_env(environment, &extension.path, &extension.mount_path);
+
+        if let Some(add_ld_path) = extension
+            .add_ld_path
+            .as_deref()
+            .filter(|add_ld_path| deploy::subpath_installed(&subpaths, add_ld_path))
+        {
+            let source = if extension.mount_path.starts_with("/app") {
+                ExtensionSource::App
+            } else {
+                ExtensionSource::Runtime
+            };
+            let ld_contents = format!("{}\n", extension.mount_path.join(add_ld_path).display());
+            let filename = format!("{}-{}.conf", source.ld_conf_prefix(), extension.name);
+            bwrap.ro_bind_data(
+                Path::new(LD_SO_CONF_DIR).join(filename),
+                ld_contents.as_bytes(),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Exports the paths of `EXTENSION_ENV_PATHS` found in an extension mounted in `directory`
+fn add_extension_env_paths(
+    environment: &mut Environment,
+    directory: &str,
+    source_path: &Path,
+    mount_path: &Path,
+) {
+    for (env_directory, var, subpath) in EXTENSION_ENV_PATHS {
+        if Path::new(directory).ends_with(env_directory) && source_path.join(subpath).is_dir() {
+            environment.append_path(var, mount_path.join(subpath));
+        }
+    }
+}
+
+/// SDK extensions (e.g. `org.freedesktop.Sdk.Extension.rust-stable`) don't set up their environment on their own,
+/// so their conventional tool and library paths are exported
+fn add_sdk_extension_env(environment: &mut Environment, source_path: &Path, mount_path: &Path) {
+    for (var, subpath) in SDK_EXTENSION_ENV_PATHS {
+        if source_path.join(subpath).is_dir() {
+            environment.append_path(var, mount_path.join(subpath));
+        }
+    }
+
+    if let Ok(entries) = fs::read_dir(source_path.join("lib")) {
+        for entry in entries.flatten() {
+            let file_name = entry.file_name();
+            if file_name
+                .to_str()
+                .is_some_and(|name| name.starts_with("python3"))
+                && entry.path().join("site-packages").is_dir()
+            {
+                let site_packages = mount_path.join("lib").join(file_name).join("site-packages");
+                environment.append_path("PYTHONPATH", site_packages);
+            }
+        }
+    }
+}
+
+fn is_locale_extension(name: &str, extension_metadata: &IndexMap<&str, &str>) -> bool {
+    extension_metadata
+        .get("locale-subset")
+        .map(|value| *value == "true")
+        .unwrap_or_else(|| name.ends_with(".Locale"))
+}
+
+/// Mounts the files of an extension or app, only binding the subpaths which were installed (e.g. with
+/// `flatpak install --subpath`) when they are restricted, as the rest of the files are missing
+pub(crate) fn bind_installed_files(
+    bwrap: &mut BwrapBuilder,
+    files_path: &Path,
+    mount_path: &Path,
+    subpaths: &[String],
+) {
+    if subpaths.is_empty() {
+        bwrap.ro_bind(files_path, mount_path);
+        return;
+    }
+    for subpath in subpaths {
+        let subpath_source = files_path.join(subpath);
+        if subpath_source.exists() {
+            bwrap.ro_bind(&subpath_source, mount_path.join(subpath));
+        }
+    }
+}
+
+/// Mounts only the language subdirectories of a locale extension which match the configured languages and
+/// were installed
+fn mount_locale_subset(
+    bwrap: &mut BwrapBuilder,
+    ctx: &ExtensionContext,
+    extension_path: &Path,
+    mount_path: &Path,
+    subpaths: &[String],
+) {
+    let languages = locale::configured_languages(ctx.install_dirs);
+    if languages.is_empty() {
+        bind_installed_files(bwrap, extension_path, mount_path, subpaths);
+        return;
+    }
+
+    for language in languages {
+        let language_path = extension_path.join(&language);
+        if deploy::subpath_installed(subpaths, &language) && language_path.is_dir() {
+            bwrap.ro_bind(&language_path, mount_path.join(&language));
+        }
+    }
+}
+
+/// The paths an extension may be installed at, in the order of preference
+fn extension_path_candidates<'a>(
+    ctx: &'a ExtensionContext,
+    extension: &'a str,
+    arches: &'a [&str],
+    allowed_versions: &'a str,
+) -> impl Iterator<Item = PathBuf> + 'a {
+    arches.iter().flat_map(move |arch| {
+        allowed_versions.split(';').flat_map(move |version| {
+            ctx.install_dirs.iter().map(move |dir| {
+                dir.join("runtime")
+                    .join(extension)
+                    .join(arch)
+                    .join(version)
+                    .join("active")
+                    .join("files")
+            })
+        })
+    })
+}
+
+/// Checks all the paths the extension points and their implementations may be installed at at once,
+/// as checking them one by one is slow on network file systems. Only the ones found in the index are checked.
+fn probe_extension_paths(
+    ctx: &ExtensionContext,
+    extensions: &[(&str, &IndexMap<&str, &str>)],
+) -> HashSet<PathBuf> {
+    let mut ref_paths = Vec::new();
+    for (name, extension_metadata) in extensions {
+        let directory = extension_metadata
+            .get("directory")
+            .copied()
+            .unwrap_or_default();
+        let allowed_versions = allowed_versions(ctx, extension_metadata);
+        let arches = extension_arches(ctx, directory);
+        let subdirectories = extension_metadata.get("subdirectories") == Some(&"true");
+        let expected_prefix = format!("{name}.");
+        for extension in ctx.available_runtimes.iter().filter(|extension| {
+            *extension == name || (subdirectories && extension.starts_with(&expected_prefix))
+        }) {
+            for arch in &arches {
+                for version in allowed_versions.split(';') {
+                    ref_paths.push(
+                        Path::new(extension)
+                            .join(arch)
+                            .join(version)
+                            .join("active")
+                            .join("files"),
+                    );
+                }
+            }
+        }
+    }
+
+    let runtime_dirs: Vec<PathBuf> = ctx
+        .install_dirs
+        .iter()
+        .map(|dir| dir.join("runtime"))
+        .collect();
+    let lookups: Vec<(&Path, &Path)> = runtime_dirs
+        .iter()
+        .flat_map(|dir| {
+            ref_paths
+                .iter()
+                .map(move |path| (dir.as_path(), path.as_path()))
+        })
+        .collect();
+    index::prefetch(&lookups);
+    let candidates = lookups
+        .into_iter()
+        .filter(|(dir, path)| index::may_exist(dir, path))
+        .map(|(dir, path)| dir.join(path))
+        .collect();
+    probe::existing_paths(candidates)
+}
+
+fn find_extension_path(
+    ctx: &ExtensionContext,
+    extension: &str,
+    arches: &[&str],
+    allowed_versions: &str,
+    existing_paths: &HashSet<PathBuf>,
+) -> Option<PathBuf> {
+    extension_path_candidates(ctx, extension, arches, allowed_versions)
+        .find(|path| existing_paths.contains(path))
+}
+
+fn secondary_arch_triplet(arch: &str) -> Option<&'static str> {
+    MULTIARCH
+        .iter()
+        .find(|(primary, _, _)| *primary == arch)
+        .map(|(_, _, triplet)| *triplet)
+}
+
+/// Extensions placed in the secondary architecture's library dir can also be built for that architecture
+fn secondary_arch_for_dir(arch: &str, directory: &str) -> Option<&'static str> {
+    MULTIARCH
+        .iter()
+        .find(|(primary, _, triplet)| {
+            *primary == arch && Path::new(directory).starts_with(Path::new("lib").join(triplet))
+        })
+        .map(|(_, secondary, _)| *secondary)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{environment::Environment, extensions::add_extension_env_paths};
+    use std::{ffi::OsStr, fs, path::Path};
+    use tempdir::TempDir;
+
+    #[test]
+    fn exports_ffmpeg_gstreamer_plugins() {
+        let extension = TempDir::new("flatbox-extension").unwrap();
+        let mount_path = Path::new("/usr/lib/x86_64-linux-gnu/ffmpeg");
+        let mut environment = Environment::default();
+        add_extension_env_paths(&mut environment, "lib/ffmpeg", extension.path(), mount_path);
+        assert_eq!(environment.get("GST_PLUGIN_SYSTEM_PATH"), None);
+
+        fs::create_dir(extension.path().join("gstreamer-1.0")).unwrap();
+        add_extension_env_paths(&mut environment, "lib/ffmpeg", extension.path(), mount_path);
+        assert_eq!(
+            environment.get("GST_PLUGIN_SYSTEM_PATH"),
+            Some(OsStr::new("/usr/lib/x86_64-linux-gnu/ffmpeg/gstreamer-1.0"))
+        );
+    }
+}
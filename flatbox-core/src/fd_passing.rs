@@ -0,0 +1,151 @@
+use std::{
+    io::{self, Write},
+    mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    ptr,
+};
+
+/// Control message buffer for the fds, aligned for `cmsghdr`
+fn control_buffer(fds: usize) -> Vec<u64> {
+    let space = unsafe { libc::CMSG_SPACE((fds * mem::size_of::<RawFd>()) as u32) } as usize;
+    vec![0; space.div_ceil(mem::size_of::<u64>())]
+}
+
+/// Sends data and the fds (`SCM_RIGHTS`) along its first byte
+pub fn send_with_fds(socket: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let mut control = control_buffer(fds.len());
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr().cast_mut().cast(),
+        iov_len: data.len(),
+    };
+    let mut msg = unsafe { mem::zeroed::<libc::msghdr>() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(control.as_slice()) as _;
+    let sent = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of_val(fds) as u32) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast(), fds.len());
+        libc::sendmsg(socket.as_raw_fd(), &msg, 0)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    (&*socket).write_all(&data[sent as usize..])
+}
+
+/// Receives data and up to `max_fds` fds
+pub fn recv_with_fds(
+    socket: &UnixStream,
+    buf: &mut [u8],
+    max_fds: usize,
+) -> io::Result<(usize, Vec<OwnedFd>)> {
+    let mut control = control_buffer(max_fds);
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut msg = unsafe { mem::zeroed::<libc::msghdr>() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(control.as_slice()) as _;
+    let received = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data: *const RawFd = libc::CMSG_DATA(cmsg).cast();
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((received as usize, fds))
+}
+
+/// Moves each fd to its target number (pairs of the fd and the target), e.g. in `pre_exec`. The fds are first
+/// moved above the highest target, so none is overwritten by another one's target before it's placed, and
+/// each is placed with `dup2`, which clears the close-on-exec flag of received fds even when one is already
+/// at its target. Only makes syscalls, the pairs are updated in place.
+pub fn place_fds(fds: &mut [(RawFd, RawFd)]) -> io::Result<()> {
+    let min_fd = fds
+        .iter()
+        .map(|(_, target)| target + 1)
+        .max()
+        .unwrap_or(3)
+        .max(3);
+    for (fd, _) in fds.iter_mut() {
+        *fd = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, min_fd) };
+        if *fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    for (fd, target) in fds.iter() {
+        if unsafe { libc::dup2(*fd, *target) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe { libc::close(*fd) };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fd_passing::place_fds;
+    use std::{
+        fs::File,
+        io::{Read, Write},
+        os::fd::{AsRawFd, FromRawFd, RawFd},
+    };
+
+    /// A pipe with its read end at `fd`, received (close-on-exec) like the fds passed to the command
+    fn pipe_at(fd: RawFd) -> File {
+        let mut ends = [0; 2];
+        assert_eq!(
+            unsafe { libc::pipe2(ends.as_mut_ptr(), libc::O_CLOEXEC) },
+            0
+        );
+        assert!(unsafe { libc::dup3(ends[0], fd, libc::O_CLOEXEC) } >= 0);
+        unsafe { libc::close(ends[0]) };
+        unsafe { File::from_raw_fd(ends[1]) }
+    }
+
+    #[test]
+    fn places_fds() {
+        let (first, second, third) = (900, 901, 902);
+        let mut first_writer = pipe_at(first);
+        let mut second_writer = pipe_at(second);
+        let mut third_writer = pipe_at(third);
+        // Two fds swap places, the third one is already at its target
+        place_fds(&mut [(first, second), (second, first), (third, third)]).unwrap();
+        first_writer.write_all(b"1").unwrap();
+        second_writer.write_all(b"2").unwrap();
+        third_writer.write_all(b"3").unwrap();
+        for (fd, expected) in [(first, b"2"), (second, b"1"), (third, b"3")] {
+            assert_eq!(
+                unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC,
+                0
+            );
+            let mut reader = unsafe { File::from_raw_fd(fd) };
+            let mut byte = [0];
+            reader.read_exact(&mut byte).unwrap();
+            assert_eq!(&byte, expected);
+            assert_eq!(reader.as_raw_fd(), fd);
+        }
+    }
+}
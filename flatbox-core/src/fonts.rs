@@ -0,0 +1,148 @@
+use crate::bwrap::BwrapBuilder;
+use std::{
+    env,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+const SYSTEM_FONT_DIRS: [(&str, &str); 2] = [
+    ("/usr/share/fonts", "/run/host/fonts"),
+    ("/usr/local/share/fonts", "/run/host/local-fonts"),
+];
+const SYSTEM_FONT_CACHE_DIRS: [&str; 2] = ["/var/cache/fontconfig", "/usr/lib/fontconfig/cache"];
+const FONT_DIRS_XML_PATH: &str = "/run/host/font-dirs.xml";
+
+/// Exposes host fonts the same way as flatpak does.
+/// The runtime's fontconfig configuration includes `/run/host/font-dirs.xml`,
+/// which remaps the mounted dirs to their host paths so the existing font caches stay valid.
+pub fn setup_fonts(bwrap: &mut BwrapBuilder, home: Option<&Path>) -> anyhow::Result<()> {
+    let mut remapped_dirs = Vec::new();
+
+    for (source, target) in SYSTEM_FONT_DIRS {
+        if Path::new(source).is_dir() {
+            bwrap.ro_bind(source, target);
+            remapped_dirs.push((source.to_owned(), target));
+        }
+    }
+
+    if let Some(cache_dir) = SYSTEM_FONT_CACHE_DIRS
+        .into_iter()
+        .find(|dir| Path::new(dir).is_dir())
+    {
+        bwrap.ro_bind(cache_dir, "/run/host/fonts-cache");
+    }
+
+    if let Some(home) = home {
+        let data_home = env::var_os("XDG_DATA_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from);
+        if let Some(user_fonts) = user_font_dir(home, data_home) {
+            bwrap.ro_bind(&user_fonts, "/run/host/user-fonts");
+            // The host path the fonts were cached under
+            remapped_dirs.push((user_fonts.display().to_string(), "/run/host/user-fonts"));
+        }
+
+        let user_cache = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| home.join(".cache"))
+            .join("fontconfig");
+        if user_cache.is_dir() {
+            bwrap.ro_bind(&user_cache, "/run/host/user-fonts-cache");
+        }
+    }
+
+    if !remapped_dirs.is_empty() {
+        bwrap.ro_bind_data(FONT_DIRS_XML_PATH, font_dirs_xml(&remapped_dirs).as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// The user's fonts dir, `$XDG_DATA_HOME/fonts` (`~/.local/share/fonts` by default) or the legacy `~/.fonts`
+fn user_font_dir(home: &Path, data_home: Option<PathBuf>) -> Option<PathBuf> {
+    let data_home = data_home.unwrap_or_else(|| home.join(".local").join("share"));
+    [data_home.join("fonts"), home.join(".fonts")]
+        .into_iter()
+        .find(|path| path.is_dir())
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn font_dirs_xml(remapped_dirs: &[(String, &str)]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE fontconfig SYSTEM \"urn:fontconfig:fonts.dtd\">\n\
+         <fontconfig>\n",
+    );
+    for (as_path, dir) in remapped_dirs {
+        writeln!(
+            xml,
+            "\t<remap-dir as-path=\"{}\">{}</remap-dir>",
+            escape_xml(as_path),
+            escape_xml(dir)
+        )
+        .unwrap();
+    }
+    xml.push_str("</fontconfig>\n");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fonts::{font_dirs_xml, user_font_dir};
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn generate_font_dirs_xml() {
+        let xml = font_dirs_xml(&[
+            ("/usr/share/fonts".to_owned(), "/run/host/fonts"),
+            ("~/.local/share/fonts".to_owned(), "/run/host/user-fonts"),
+        ]);
+        assert_eq!(
+            "<?xml version=\"1.0\"?>
+<!DOCTYPE fontconfig SYSTEM \"urn:fontconfig:fonts.dtd\">
+<fontconfig>
+\t<remap-dir as-path=\"/usr/share/fonts\">/run/host/fonts</remap-dir>
+\t<remap-dir as-path=\"~/.local/share/fonts\">/run/host/user-fonts</remap-dir>
+</fontconfig>
+",
+            xml
+        );
+    }
+
+    #[test]
+    fn remaps_user_fonts_to_their_host_path() {
+        let home = TempDir::new("flatbox-fonts").unwrap();
+        let data_home = TempDir::new("flatbox-fonts & more").unwrap();
+        fs::create_dir(home.path().join(".fonts")).unwrap();
+        assert_eq!(
+            user_font_dir(home.path(), Some(data_home.path().to_owned())),
+            Some(home.path().join(".fonts"))
+        );
+
+        let user_fonts = data_home.path().join("fonts");
+        fs::create_dir(&user_fonts).unwrap();
+        let user_fonts = user_font_dir(home.path(), Some(data_home.path().to_owned())).unwrap();
+        assert_eq!(user_fonts, data_home.path().join("fonts"));
+
+        let xml = font_dirs_xml(&[(user_fonts.display().to_string(), "/run/host/user-fonts")]);
+        let as_path = user_fonts.display().to_string().replace('&', "&amp;");
+        assert!(xml.contains(&format!(
+            "<remap-dir as-path=\"{as_path}\">/run/host/user-fonts</remap-dir>"
+        )));
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{bwrap::BwrapBuilder, documents::DocumentPortal, error::FlatboxError};
+use anyhow::Context;
+use std::{
+    ffi::OsStr,
+    fmt::Write,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+const FORWARDED_FILES_DIR: &str = "/run/flatpak/forwarded";
+const FILE_URI_SCHEME: &str = "file://";
+
+#[derive(Debug, PartialEq, Eq)]
+enum ForwardedArg<'a> {
+    Plain(&'a str),
+    /// A host path between `@@` markers
+    File(&'a str),
+    /// A URI between `@@u` and `@@` markers
+    Uri(&'a str),
+}
+
+/// Splits the arguments at flatpak's `@@`/`@@u` file forwarding markers, which are removed
+fn split_forwarded_args<'a>(args: &'a [String]) -> anyhow::Result<Vec<ForwardedArg<'a>>> {
+    let mut section: Option<fn(&'a str) -> ForwardedArg<'a>> = None;
+    let mut split = Vec::with_capacity(args.len());
+    for arg in args {
+        match (arg.as_str(), section) {
+            ("@@", Some(_)) => section = None,
+            ("@@", None) => section = Some(ForwardedArg::File),
+            ("@@u", None) => section = Some(ForwardedArg::Uri),
+            ("@@u", Some(_)) => {
+                return Err(FlatboxError::InvalidForwardedArgs {
+                    message: "Unexpected @@u inside of a forwarded section".to_owned(),
+                }
+                .into());
+            }
+            (arg, Some(forwarded)) => split.push(forwarded(arg)),
+            (arg, None) => split.push(ForwardedArg::Plain(arg)),
+        }
+    }
+    Ok(split)
+}
+
+/// Makes the files passed between `@@` markers available in the sandbox and replaces them with their sandbox path,
+/// e.g. for the `Exec` lines of desktop files. Only `file://` URIs are forwarded, others are passed unchanged.
+/// Files are exported through the document portal when there is one, like flatpak does, so the app keeps
+/// access to them when it's restarted; directories and files the portal fails to export are mounted instead.
+pub fn forward_files(
+    bwrap: &mut BwrapBuilder,
+    args: &[String],
+    mut documents: Option<DocumentPortal>,
+) -> anyhow::Result<Vec<String>> {
+    let mut forwarded_count = 0;
+    let mut forward = |path: &Path| -> anyhow::Result<PathBuf> {
+        let path = fs::canonicalize(path)
+            .with_context(|| format!("Could not forward {}", path.display()))?;
+        if let Some(documents) = &mut documents
+            && path.is_file()
+        {
+            match documents.export(&path) {
+                Ok(sandbox_path) => return Ok(sandbox_path),
+                Err(err) => crate::warn!(
+                    "Could not export {} through the document portal: {err:#}",
+                    path.display()
+                ),
+            }
+        }
+        let name = path.file_name().unwrap_or(OsStr::new("root"));
+        let sandbox_path = Path::new(FORWARDED_FILES_DIR)
+            .join(forwarded_count.to_string())
+            .join(name);
+        bwrap.bind(&path, &sandbox_path);
+        forwarded_count += 1;
+        Ok(sandbox_path)
+    };
+
+    split_forwarded_args(args)?
+        .into_iter()
+        .map(|arg| match arg {
+            ForwardedArg::Plain(arg) => Ok(arg.to_owned()),
+            ForwardedArg::File(path) => {
+                Ok(forward(Path::new(path))?.to_string_lossy().into_owned())
+            }
+            ForwardedArg::Uri(uri) => match file_uri_path(uri) {
+                Some(path) => Ok(path_to_file_uri(&forward(&path)?)),
+                None => Ok(uri.to_owned()),
+            },
+        })
+        .collect()
+}
+
+/// Decodes the local path of a `file://` URI
+fn file_uri_path(uri: &str) -> Option<PathBuf> {
+    let rest = uri.strip_prefix(FILE_URI_SCHEME)?;
+    let path = rest.strip_prefix("localhost").unwrap_or(rest);
+    if !path.starts_with('/') {
+        return None;
+    }
+
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            decoded.push(u8::from_str_radix(str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    Some(PathBuf::from(OsStr::from_bytes(&decoded)))
+}
+
+fn path_to_file_uri(path: &Path) -> String {
+    let mut uri = FILE_URI_SCHEME.to_owned();
+    for byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(char::from(*byte));
+            }
+            _ => write!(uri, "%{byte:02X}").unwrap(),
+        }
+    }
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::forwarding::{ForwardedArg, file_uri_path, path_to_file_uri, split_forwarded_args};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn forwarded_sections() {
+        let args = [
+            "--new-window",
+            "@@",
+            "a.txt",
+            "b.txt",
+            "@@",
+            "@@u",
+            "file:///c",
+            "@@",
+            "-v",
+        ]
+        .map(str::to_owned);
+        assert_eq!(
+            split_forwarded_args(&args).unwrap(),
+            [
+                ForwardedArg::Plain("--new-window"),
+                ForwardedArg::File("a.txt"),
+                ForwardedArg::File("b.txt"),
+                ForwardedArg::Uri("file:///c"),
+                ForwardedArg::Plain("-v"),
+            ]
+        );
+    }
+
+    #[test]
+    fn file_uris() {
+        assert_eq!(
+            file_uri_path("file:///home/user/My%20File.txt"),
+            Some(PathBuf::from("/home/user/My File.txt"))
+        );
+        assert_eq!(
+            file_uri_path("file://localhost/tmp/a"),
+            Some(PathBuf::from("/tmp/a"))
+        );
+        assert_eq!(file_uri_path("https://example.com/a"), None);
+        assert_eq!(
+            path_to_file_uri(Path::new("/run/flatpak/forwarded/0/My File.txt")),
+            "file:///run/flatpak/forwarded/0/My%20File.txt"
+        );
+    }
+}
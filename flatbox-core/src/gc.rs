@@ -0,0 +1,297 @@
+use crate::{cache, cgroup, oci_image, ostree, sandbox::APP_SHM_PREFIX};
+use std::{
+    collections::HashSet,
+    env,
+    fs::{self, File},
+    io,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, SystemTime},
+};
+
+/// Temp dirs of launches are named `flatbox-<name>-<pid>.<random>`, and partially written cache files
+/// `<name>.<pid>.tmp`, so the ones of crashed launches can be told apart from the ones in use
+const TEMP_PREFIX: &str = "flatbox-";
+const PARTIAL_SUFFIX: &str = ".tmp";
+/// Cache dirs holding partial files, the cache dir itself and some of its subdirs
+const PARTIAL_DIRS: [&str; 3] = ["", ostree::CHECKOUTS_DIR, oci_image::IMAGES_DIR];
+/// Cache dirs whose entries are removed when they weren't used for a while
+const EXPIRING_DIRS: [&str; 3] = [
+    ostree::CHECKOUTS_DIR,
+    ostree::BUNDLES_DIR,
+    oci_image::IMAGES_DIR,
+];
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Holds the `/dev/shm` dirs of the apps with `per-app-dev-shm`
+const SHM_DIR: &str = "/dev/shm";
+/// When the last automatic collection ran, in the cache
+const STAMP_FILE: &str = "gc-stamp";
+const AUTO_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The prefix of a temp dir of this launch
+pub(crate) fn temp_prefix(name: &str) -> String {
+    format!("{TEMP_PREFIX}{name}-{}", process::id())
+}
+
+/// Marks a cache entry as used now, so it doesn't expire
+pub(crate) fn touch(path: &Path) {
+    let _ = File::open(path).and_then(|file| file.set_modified(SystemTime::now()));
+}
+
+/// Removes the state crashed launches left behind: their temp dirs (the setup dirs of sandboxes, extracted
+/// bundles and images), partially written cache files and limited cgroups, the `/dev/shm` dirs of the apps
+/// no instance runs of anymore, and the checkouts and images which weren't used for `max_age`. Returns what was removed, or would be when it's a dry run.
+pub fn collect(max_age: Duration, dry_run: bool) -> Vec<PathBuf> {
+    let stale = stale_files(
+        &env::temp_dir(),
+        Path::new(SHM_DIR),
+        cache::cache_dir().as_deref(),
+        max_age,
+    );
+    let mut removed = Vec::new();
+    for path in stale {
+        if dry_run {
+            removed.push(path);
+            continue;
+        }
+        match remove_tree(&path) {
+            Ok(()) => removed.push(path),
+            Err(err) => crate::warn!("Could not remove {}: {err}", path.display()),
+        }
+    }
+    // Only empty cgroups can be removed, the ones still running processes stay
+    for (cgroup, pid) in cgroup::leftover_cgroups() {
+        if !is_alive(pid) && (dry_run || fs::remove_dir(&cgroup).is_ok()) {
+            removed.push(cgroup);
+        }
+    }
+    removed
+}
+
+/// The temp dirs and partial files of dead launches, the unused `/dev/shm` dirs of apps and the expired cache
+/// entries
+fn stale_files(
+    temp_dir: &Path,
+    shm_dir: &Path,
+    cache_dir: Option<&Path>,
+    max_age: Duration,
+) -> Vec<PathBuf> {
+    let mut stale = Vec::new();
+    for entry in read_dir(temp_dir) {
+        let name = entry
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        if let Some(pid) = name
+            .strip_prefix(TEMP_PREFIX)
+            .and_then(|name| name.split_once('.'))
+            .and_then(|(name, _)| name.rsplit_once('-'))
+            .and_then(|(_, pid)| pid.parse().ok())
+            && is_stale(&entry, pid)
+        {
+            stale.push(entry);
+        }
+    }
+
+    let app_shm_dirs: Vec<PathBuf> = read_dir(shm_dir)
+        .into_iter()
+        .filter(|entry| {
+            entry
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with(APP_SHM_PREFIX))
+                && fs::symlink_metadata(entry).is_ok_and(|metadata| {
+                    metadata.is_dir() && metadata.uid() == unsafe { libc::getuid() }
+                })
+        })
+        .collect();
+    if !app_shm_dirs.is_empty() {
+        let mounted = mounted_shm_dirs();
+        stale.extend(app_shm_dirs.into_iter().filter(|dir| {
+            !dir.file_name()
+                .is_some_and(|name| mounted.contains(&*name.to_string_lossy()))
+        }));
+    }
+
+    for dir in cache_dir
+        .iter()
+        .flat_map(|cache_dir| PARTIAL_DIRS.map(|dir| cache_dir.join(dir)))
+    {
+        for entry in read_dir(&dir) {
+            let name = entry
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            if let Some(pid) = name
+                .strip_suffix(PARTIAL_SUFFIX)
+                .and_then(|name| name.rsplit_once('.'))
+                .and_then(|(_, pid)| pid.parse().ok())
+                && is_stale(&entry, pid)
+            {
+                stale.push(entry);
+            }
+        }
+    }
+    let now = SystemTime::now();
+    for dir in cache_dir
+        .iter()
+        .flat_map(|cache_dir| EXPIRING_DIRS.map(|dir| cache_dir.join(dir)))
+    {
+        for entry in read_dir(&dir) {
+            let unused = fs::symlink_metadata(&entry)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| now.duration_since(modified).unwrap_or_default() > max_age);
+            if unused && !entry.to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+                stale.push(entry);
+            }
+        }
+    }
+    stale
+}
+
+/// Collects the leftovers of crashed launches and the expired caches, at most once a day
+pub fn auto_collect() {
+    let Some(stamp) = cache::cache_dir().map(|dir| dir.join(STAMP_FILE)) else {
+        return;
+    };
+    let recent = fs::metadata(&stamp)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| modified.elapsed().unwrap_or_default() < AUTO_INTERVAL);
+    if recent {
+        return;
+    }
+    if let Some(dir) = stamp.parent()
+        && fs::create_dir_all(dir)
+            .and_then(|()| fs::write(&stamp, ""))
+            .is_err()
+    {
+        return;
+    }
+    for path in collect(DEFAULT_MAX_AGE, false) {
+        crate::debug!("Removed {}", path.display());
+    }
+}
+
+fn read_dir(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .map(|entries| entries.flatten().map(|entry| entry.path()).collect())
+        .unwrap_or_default()
+}
+
+/// Whether the file of a launch is ours and the launch is gone. Dirs with something still mounted in them
+/// (e.g. the extension images of a sandbox whose flatbox was killed) are left alone.
+fn is_stale(path: &Path, pid: u32) -> bool {
+    let owned = fs::symlink_metadata(path)
+        .is_ok_and(|metadata| metadata.uid() == unsafe { libc::getuid() });
+    owned && !is_alive(pid) && !has_mounts(path)
+}
+
+fn is_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// The names of the dirs in `/dev/shm` bound to the `/dev/shm` of a sandbox, in the mount namespaces of all
+/// the processes which can be inspected
+fn mounted_shm_dirs() -> HashSet<String> {
+    let mut namespaces = HashSet::new();
+    let mut mounted = HashSet::new();
+    for process in read_dir(Path::new("/proc")) {
+        // Processes in the same namespace have the same mounts
+        let Ok(namespace) = fs::read_link(process.join("ns/mnt")) else {
+            continue;
+        };
+        if !namespaces.insert(namespace) {
+            continue;
+        }
+        let Ok(mountinfo) = fs::read_to_string(process.join("mountinfo")) else {
+            continue;
+        };
+        for line in mountinfo.lines() {
+            let mut fields = line.split(' ').skip(3);
+            if let (Some(root), Some(SHM_DIR)) = (fields.next(), fields.next())
+                && let Some(name) = Path::new(root).file_name()
+            {
+                mounted.insert(name.to_string_lossy().into_owned());
+            }
+        }
+    }
+    mounted
+}
+
+fn has_mounts(path: &Path) -> bool {
+    let Ok(mountinfo) = fs::read_to_string("/proc/self/mountinfo") else {
+        return true;
+    };
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .any(|mount_point| Path::new(mount_point).starts_with(path))
+}
+
+/// Removes a file or a dir tree, including read-only dirs (e.g. of checkouts)
+fn remove_tree(path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return fs::remove_file(path);
+    }
+    if fs::remove_dir_all(path).is_ok() {
+        return Ok(());
+    }
+    make_writable(path);
+    fs::remove_dir_all(path)
+}
+
+fn make_writable(dir: &Path) {
+    if let Ok(metadata) = fs::symlink_metadata(dir)
+        && metadata.is_dir()
+    {
+        let mut permissions = metadata.permissions();
+        permissions.set_mode(permissions.mode() | 0o700);
+        let _ = fs::set_permissions(dir, permissions);
+        for entry in read_dir(dir) {
+            make_writable(&entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gc::{stale_files, temp_prefix},
+        ostree,
+    };
+    use std::{fs, process, time::Duration};
+    use tempdir::TempDir;
+
+    #[test]
+    fn finds_stale_files() {
+        let temp = TempDir::new("flatbox-gc").unwrap();
+        let cache = TempDir::new("flatbox-gc").unwrap();
+        // pid_max is at most 2^22, so no process has this pid
+        let dead_setup = temp.path().join("flatbox-setup-99999999.AbCd");
+        let live_setup = temp.path().join(format!("{}.AbCd", temp_prefix("setup")));
+        let dead_partial = cache.path().join("dir-listings.toml.99999999.tmp");
+        let live_partial = cache
+            .path()
+            .join(format!("dir-listings.toml.{}.tmp", process::id()));
+        let checkout = cache.path().join(ostree::CHECKOUTS_DIR).join("0123abcd");
+        let shm = TempDir::new("flatbox-gc").unwrap();
+        let app_shm = shm.path().join("flatbox-org.example.App");
+        for dir in [&dead_setup, &live_setup, &checkout, &app_shm] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(&dead_partial, "").unwrap();
+        fs::write(&live_partial, "").unwrap();
+
+        let stale = stale_files(temp.path(), shm.path(), Some(cache.path()), Duration::MAX);
+        assert_eq!(stale, [dead_setup, app_shm, dead_partial]);
+        let stale = stale_files(temp.path(), shm.path(), Some(cache.path()), Duration::ZERO);
+        assert!(stale.contains(&checkout));
+    }
+}
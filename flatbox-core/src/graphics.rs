@@ -0,0 +1,318 @@
+use crate::{
+    bwrap::BwrapBuilder, environment::Environment, error::FlatboxError, extensions::LD_SO_CONF_DIR,
+    video,
+};
+use anyhow::Context;
+use indexmap::IndexMap;
+use std::{
+    env::consts::ARCH,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const HOST_GRAPHICS_LIB_DIR: &str = "/run/host/graphics/lib";
+const HOST_GRAPHICS_LD_CONF: &str = "runtime-host-graphics.conf";
+/// Userspace driver libraries, matched by filename prefix
+const DRIVER_LIB_PREFIXES: [&str; 15] = [
+    "libEGL_",
+    "libGLX_",
+    "libGLESv1_CM_nvidia",
+    "libGLESv2_nvidia",
+    "libnvidia-",
+    "libcuda",
+    "libnvcuvid",
+    "libnvoptix",
+    "libvulkan_",
+    "libgallium",
+    "libgbm",
+    "libglapi",
+    "libdrm",
+    "libxatracker",
+    "libvdpau_",
+];
+/// Libraries which always have to come from the runtime: glibc and the GLVND dispatch libraries
+const RUNTIME_LIB_PREFIXES: [&str; 17] = [
+    "ld-linux",
+    "libc.so",
+    "libm.so",
+    "libmvec.so",
+    "libdl.so",
+    "libpthread.so",
+    "librt.so",
+    "libresolv.so",
+    "libutil.so",
+    "libanl.so",
+    "libnsl.so",
+    "libGL.so",
+    "libGLX.so",
+    "libEGL.so",
+    "libOpenGL.so",
+    "libGLESv2.so",
+    "libGLdispatch.so",
+];
+/// Host driver module dirs inside of the library dir, and the variable pointing the loader to them.
+/// The video decode variables are set separately, as `dri` also contains the VA-API drivers.
+const DRIVER_MODULE_DIRS: [(&str, Option<&str>); 3] = [
+    ("dri", Some("LIBGL_DRIVERS_PATH")),
+    ("gbm", Some("GBM_BACKENDS_PATH")),
+    ("vdpau", None),
+];
+
+struct IcdKind {
+    host_dirs: [&'static str; 2],
+    mount_dir: &'static str,
+    env_var: &'static str,
+    /// Whether the variable lists the individual files instead of the directory
+    list_files: bool,
+}
+
+const ICD_KINDS: [IcdKind; 3] = [
+    IcdKind {
+        host_dirs: ["/etc/vulkan/icd.d", "/usr/share/vulkan/icd.d"],
+        mount_dir: "/run/host/graphics/vulkan/icd.d",
+        env_var: "VK_DRIVER_FILES",
+        list_files: true,
+    },
+    IcdKind {
+        host_dirs: ["/etc/glvnd/egl_vendor.d", "/usr/share/glvnd/egl_vendor.d"],
+        mount_dir: "/run/host/graphics/glvnd/egl_vendor.d",
+        env_var: "__EGL_VENDOR_LIBRARY_DIRS",
+        list_files: false,
+    },
+    IcdKind {
+        host_dirs: [
+            "/etc/egl/egl_external_platform.d",
+            "/usr/share/egl/egl_external_platform.d",
+        ],
+        mount_dir: "/run/host/graphics/egl/egl_external_platform.d",
+        env_var: "__EGL_EXTERNAL_PLATFORM_CONFIG_DIRS",
+        list_files: false,
+    },
+];
+
+/// Exposes the host's GL and Vulkan userspace drivers under `/run/host/graphics` instead of using the GL extensions.
+/// The driver libraries and their dependencies that the runtime lacks are bound into a single directory
+/// that is added to the linker path, and the loader ICD files are rewritten to point into it.
+pub fn setup_host_graphics(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    runtime_files: &Path,
+) -> anyhow::Result<()> {
+    let Some(lib_dir) = host_lib_dir() else {
+        return Err(FlatboxError::HostGlNotFound.into());
+    };
+
+    let mut libs: IndexMap<String, PathBuf> = IndexMap::new();
+
+    for kind in &ICD_KINDS {
+        let mut mounted_files: Vec<String> = Vec::new();
+
+        for host_dir in kind.host_dirs {
+            let Ok(entries) = fs::read_dir(host_dir) else {
+                continue;
+            };
+            let mut entries: Vec<_> = entries.flatten().map(|entry| entry.path()).collect();
+            entries.sort();
+
+            for path in entries {
+                let Some(filename) = path.file_name().and_then(|name| name.to_str()) else {
+                    continue;
+                };
+                let mount_path = format!("{}/{filename}", kind.mount_dir);
+                // Files in /etc override the ones with the same name in /usr/share
+                if !filename.ends_with(".json") || mounted_files.contains(&mount_path) {
+                    continue;
+                }
+
+                let contents = fs::read_to_string(&path)
+                    .with_context(|| format!("Could not read {}", path.display()))?;
+                let (contents, library) = rewrite_library_path(&contents, HOST_GRAPHICS_LIB_DIR);
+                if let Some(library) = library
+                    && let Some(name) = library.file_name().and_then(|name| name.to_str())
+                    && library.exists()
+                {
+                    libs.insert(name.to_owned(), library.clone());
+                }
+
+                bwrap.ro_bind_data(&mount_path, contents.as_bytes())?;
+                mounted_files.push(mount_path);
+            }
+        }
+
+        if mounted_files.is_empty() {
+            continue;
+        }
+        if kind.list_files {
+            environment.set(kind.env_var, mounted_files.join(":"));
+        } else {
+            environment.set(kind.env_var, kind.mount_dir);
+        }
+    }
+
+    let entries =
+        fs::read_dir(&lib_dir).with_context(|| format!("Could not read {}", lib_dir.display()))?;
+    for entry in entries.flatten() {
+        if let Some(name) = entry.file_name().to_str()
+            && name.contains(".so")
+            && DRIVER_LIB_PREFIXES
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+        {
+            libs.insert(name.to_owned(), entry.path());
+        }
+    }
+
+    let mut ldd_roots: Vec<PathBuf> = libs.values().cloned().collect();
+    for (dir, env_var) in DRIVER_MODULE_DIRS {
+        let module_dir = lib_dir.join(dir);
+        let Ok(entries) = fs::read_dir(&module_dir) else {
+            continue;
+        };
+        ldd_roots.extend(entries.flatten().map(|entry| entry.path()));
+
+        let mount_dir = format!("{HOST_GRAPHICS_LIB_DIR}/{dir}");
+        bwrap.ro_bind(&module_dir, &mount_dir);
+        if let Some(env_var) = env_var {
+            environment.set(env_var, mount_dir);
+        }
+    }
+    video::add_driver_env(environment, &lib_dir, Path::new(HOST_GRAPHICS_LIB_DIR));
+
+    let runtime_lib_dirs = [
+        runtime_files.join("lib").join(format!("{ARCH}-linux-gnu")),
+        runtime_files.join("lib"),
+    ];
+    for (name, path) in host_dependencies(&ldd_roots) {
+        let provided_by_runtime = runtime_lib_dirs.iter().any(|dir| dir.join(&name).exists());
+        if !provided_by_runtime {
+            libs.entry(name).or_insert(path);
+        }
+    }
+
+    for (name, path) in &libs {
+        if RUNTIME_LIB_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+        {
+            continue;
+        }
+        // Every name is bound to the resolved file, so the versioned names don't depend on host symlinks
+        let source = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+        bwrap.ro_bind(source, format!("{HOST_GRAPHICS_LIB_DIR}/{name}"));
+    }
+
+    bwrap.ro_bind_data(
+        Path::new(LD_SO_CONF_DIR).join(HOST_GRAPHICS_LD_CONF),
+        format!("{HOST_GRAPHICS_LIB_DIR}\n").as_bytes(),
+    )?;
+
+    Ok(())
+}
+
+/// The host library dir containing the GLVND libraries, e.g. `/usr/lib/x86_64-linux-gnu` or `/usr/lib64`
+fn host_lib_dir() -> Option<PathBuf> {
+    [
+        PathBuf::from(format!("/usr/lib/{ARCH}-linux-gnu")),
+        PathBuf::from("/usr/lib64"),
+        PathBuf::from("/usr/lib"),
+    ]
+    .into_iter()
+    .find(|dir| dir.join("libEGL.so.1").exists() || dir.join("libGLX.so.0").exists())
+}
+
+/// Resolves the shared library dependencies of the given host libraries with `ldd`
+fn host_dependencies(libs: &[PathBuf]) -> IndexMap<String, PathBuf> {
+    let mut dependencies = IndexMap::new();
+    if libs.is_empty() {
+        return dependencies;
+    }
+
+    let Ok(output) = Command::new("ldd").args(libs).output() else {
+        crate::warn!("Could not run ldd, the host graphics driver dependencies are not exposed");
+        return dependencies;
+    };
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some((name, rest)) = line.trim().split_once(" => ")
+            && let Some(path) = rest.split_whitespace().next()
+            && path.starts_with('/')
+        {
+            dependencies.insert(name.to_owned(), PathBuf::from(path));
+        }
+    }
+
+    dependencies
+}
+
+/// Rewrites an absolute `library_path` in an ICD JSON file to point into `lib_dir`.
+/// Returns the new contents and the original library path if it was rewritten.
+fn rewrite_library_path(json: &str, lib_dir: &str) -> (String, Option<PathBuf>) {
+    let Some(key_start) = json.find("\"library_path\"") else {
+        return (json.to_owned(), None);
+    };
+    let after_key = key_start + "\"library_path\"".len();
+    let Some(value_start) = json[after_key..]
+        .find('"')
+        .map(|offset| after_key + offset + 1)
+    else {
+        return (json.to_owned(), None);
+    };
+    let Some(value_end) = json[value_start..]
+        .find('"')
+        .map(|offset| value_start + offset)
+    else {
+        return (json.to_owned(), None);
+    };
+
+    let library = Path::new(&json[value_start..value_end]);
+    let Some(filename) = library
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|_| library.is_absolute())
+    else {
+        return (json.to_owned(), None);
+    };
+
+    let rewritten = format!(
+        "{}{lib_dir}/{filename}{}",
+        &json[..value_start],
+        &json[value_end..]
+    );
+    (rewritten, Some(library.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::graphics::rewrite_library_path;
+    use std::path::PathBuf;
+
+    #[test]
+    fn rewrite_icd_library_path() {
+        let json = r#"{
+    "ICD": {
+        "api_version": "1.4.303",
+        "library_path": "/usr/lib/x86_64-linux-gnu/libvulkan_radeon.so"
+    },
+    "file_format_version": "1.0.1"
+}"#;
+        let (rewritten, library) = rewrite_library_path(json, "/run/host/graphics/lib");
+        assert!(
+            rewritten.contains(r#""library_path": "/run/host/graphics/lib/libvulkan_radeon.so""#)
+        );
+        assert!(rewritten.contains(r#""file_format_version": "1.0.1""#));
+        assert_eq!(
+            library,
+            Some(PathBuf::from(
+                "/usr/lib/x86_64-linux-gnu/libvulkan_radeon.so"
+            ))
+        );
+
+        let json =
+            r#"{"file_format_version": "1.0.0", "ICD": {"library_path": "libEGL_mesa.so.0"}}"#;
+        assert_eq!(
+            rewrite_library_path(json, "/run/host/graphics/lib"),
+            (json.to_owned(), None)
+        );
+    }
+}
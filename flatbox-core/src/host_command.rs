@@ -0,0 +1,39 @@
+use crate::{bwrap::BwrapBuilder, environment::Environment, error::FlatboxError, wrap};
+use anyhow::Context;
+use std::env;
+
+/// The socket of the host command helper, in the setup dir of the sandbox
+pub const SOCKET_NAME: &str = "host-command.sock";
+/// Where the socket is mounted in the sandbox, and the variable naming it
+const SOCKET_PATH: &str = "/run/flatbox/host-command.sock";
+pub const SOCKET_ENV: &str = "FLATBOX_HOST_COMMAND_SOCKET";
+/// The commands running a command on the host. `flatpak-spawn --host` is supported for the tools (e.g. IDEs)
+/// which call it.
+const LAUNCHERS: [&str; 2] = ["flatbox-spawn", "flatpak-spawn"];
+
+/// Lets the sandbox run commands on the host through the helper listening on the socket in the setup dir, with
+/// `flatbox-spawn --host COMMAND` (the `host-spawn` command of the host's flatbox). The socket has to be created
+/// before the sandbox is started.
+pub fn setup_host_commands(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    host_root_exposed: bool,
+) -> anyhow::Result<()> {
+    if !host_root_exposed {
+        return Err(FlatboxError::HostRootRequired {
+            what: "Running commands on the host".to_owned(),
+        }
+        .into());
+    }
+    let flatbox = env::current_exe().context("Could not find the flatbox executable")?;
+    let mut command = wrap::host_binary_command(&flatbox)?;
+    command.push("host-spawn".to_owned());
+    for launcher in LAUNCHERS {
+        wrap::install_launcher(bwrap, environment, launcher, &command)?;
+    }
+
+    let socket = bwrap.data_path(SOCKET_NAME);
+    bwrap.ro_bind(&socket, SOCKET_PATH);
+    environment.set(SOCKET_ENV, SOCKET_PATH);
+    Ok(())
+}
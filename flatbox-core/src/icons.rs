@@ -0,0 +1,32 @@
+use crate::bwrap::BwrapBuilder;
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+const SYSTEM_ICONS_DIR: (&str, &str) = ("/usr/share/icons", "/run/host/share/icons");
+
+/// Exposes the host's icon and cursor themes under `/run/host`.
+/// User themes are included so they are available even when the home directory is hidden.
+pub fn setup_icons(bwrap: &mut BwrapBuilder, home: Option<&Path>) {
+    let (system_icons, system_icons_target) = SYSTEM_ICONS_DIR;
+    if Path::new(system_icons).is_dir() {
+        bwrap.ro_bind(system_icons, system_icons_target);
+    }
+
+    if let Some(home) = home {
+        let data_home = env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| home.join(".local").join("share"));
+
+        let user_icon_dirs = [
+            (data_home.join("icons"), "/run/host/user-share/icons"),
+            (home.join(".icons"), "/run/host/user-icons"),
+        ];
+        for (source, target) in user_icon_dirs {
+            if source.is_dir() {
+                bwrap.ro_bind(&source, target);
+            }
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use crate::{
+    error::FlatboxError,
+    gc,
+    oci::Json,
+    plan::SandboxPlan,
+    rootfs,
+    sha256::{self, Sha256},
+};
+use anyhow::Context;
+use std::{ffi::OsString, fs, path::Path, process::Command};
+use tempdir::TempDir;
+
+const BLOBS_DIR: &str = "blobs/sha256";
+const LAYOUT_VERSION: &str = "1.0.0";
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+const LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// What an image runs, baked into its config
+pub struct ImageConfig<'a> {
+    /// Architecture in flatpak's naming
+    pub arch: &'a str,
+    pub entrypoint: &'a [String],
+    /// Default arguments of the entrypoint, replaced by the ones given to the container
+    pub cmd: &'a [String],
+}
+
+/// A blob of the image layout, referenced by its digest
+struct Blob {
+    digest: String,
+    size: u64,
+}
+
+impl Blob {
+    fn descriptor(&self, media_type: &str) -> Vec<(&'static str, Json)> {
+        vec![
+            ("mediaType", Json::string(media_type)),
+            ("digest", Json::String(self.digest.clone())),
+            ("size", Json::Number(self.size)),
+        ]
+    }
+}
+
+/// Writes an OCI image layout to `out` with the filesystem of the sandbox as its single layer, tagged `tag`.
+/// The environment and working dir of the plan are set in the image config. The files generated for the
+/// sandbox are found in `data_dirs`.
+pub fn export_layout(
+    out: &Path,
+    tag: &str,
+    plan: &SandboxPlan,
+    data_dirs: &[&Path],
+    config: &ImageConfig,
+) -> anyhow::Result<()> {
+    let blobs_dir = out.join(BLOBS_DIR);
+    fs::create_dir_all(&blobs_dir)
+        .with_context(|| format!("Could not create {}", blobs_dir.display()))?;
+
+    let layer_path = blobs_dir.join("layer.tar");
+    rootfs::export(&layer_path, plan, data_dirs)?;
+    let (digest, size) = sha256::file_digest(&layer_path)
+        .with_context(|| format!("Could not read {}", layer_path.display()))?;
+    let layer = Blob {
+        digest: format!("sha256:{digest}"),
+        size,
+    };
+    let blob_path = blobs_dir.join(digest);
+    fs::rename(&layer_path, &blob_path)
+        .with_context(|| format!("Could not write {}", blob_path.display()))?;
+
+    let config = write_blob(&blobs_dir, &image_config(plan, config, &layer))?;
+    let manifest = write_blob(
+        &blobs_dir,
+        &Json::Object(vec![
+            ("schemaVersion", Json::Number(2)),
+            ("mediaType", Json::string(MANIFEST_MEDIA_TYPE)),
+            ("config", Json::Object(config.descriptor(CONFIG_MEDIA_TYPE))),
+            (
+                "layers",
+                Json::Array(vec![Json::Object(layer.descriptor(LAYER_MEDIA_TYPE))]),
+            ),
+        ]),
+    )?;
+
+    let mut manifest_descriptor = manifest.descriptor(MANIFEST_MEDIA_TYPE);
+    manifest_descriptor.push((
+        "annotations",
+        Json::Object(vec![(REF_NAME_ANNOTATION, Json::string(tag))]),
+    ));
+    write_json(
+        &out.join("index.json"),
+        &Json::Object(vec![
+            ("schemaVersion", Json::Number(2)),
+            (
+                "manifests",
+                Json::Array(vec![Json::Object(manifest_descriptor)]),
+            ),
+        ]),
+    )?;
+    write_json(
+        &out.join("oci-layout"),
+        &Json::Object(vec![("imageLayoutVersion", Json::string(LAYOUT_VERSION))]),
+    )
+}
+
+/// Writes the image to a temporary layout and pulls it into podman's storage as `tag`
+pub fn import_podman(
+    tag: &str,
+    plan: &SandboxPlan,
+    data_dirs: &[&Path],
+    config: &ImageConfig,
+) -> anyhow::Result<()> {
+    let tempdir = TempDir::new(&gc::temp_prefix("image")).context("Could not create tempdir")?;
+    let layout = tempdir.path().join("layout");
+    export_layout(&layout, tag, plan, data_dirs, config)?;
+
+    let mut source = OsString::from("oci:");
+    source.push(&layout);
+    source.push(format!(":{tag}"));
+    let output = Command::new("podman")
+        .args(["pull", "--quiet"])
+        .arg(&source)
+        .output()
+        .context("Could not run podman")?;
+    if !output.status.success() {
+        return Err(FlatboxError::ToolFailed {
+            tool: "podman".to_owned(),
+            action: "import the image".to_owned(),
+            details: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        }
+        .into());
+    }
+    let id = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    let status = Command::new("podman")
+        .args(["tag", &id, tag])
+        .status()
+        .context("Could not run podman")?;
+    if !status.success() {
+        return Err(FlatboxError::ToolFailed {
+            tool: "podman".to_owned(),
+            action: format!("tag the image as {tag}"),
+            details: status.to_string(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// The architecture in the naming of OCI images
+pub(crate) fn oci_arch(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "i386" => "386",
+        arch => arch,
+    }
+}
+
+fn image_config(plan: &SandboxPlan, config: &ImageConfig, layer: &Blob) -> Json {
+    // Only the variables set for the sandbox, the host's environment it'd inherit doesn't belong in the image
+    let env = plan.env.iter().filter_map(|(key, value)| {
+        let value = value.as_ref()?;
+        Some(format!(
+            "{}={}",
+            key.to_string_lossy(),
+            value.to_string_lossy()
+        ))
+    });
+    let mut container_config = vec![
+        ("Env", Json::strings(env)),
+        ("Entrypoint", Json::strings(config.entrypoint)),
+        ("Cmd", Json::strings(config.cmd)),
+    ];
+    if let Some(chdir) = &plan.chdir {
+        container_config.push(("WorkingDir", Json::string(chdir)));
+    }
+
+    Json::Object(vec![
+        ("architecture", Json::string(oci_arch(config.arch))),
+        ("os", Json::string("linux")),
+        ("config", Json::Object(container_config)),
+        (
+            "rootfs",
+            Json::Object(vec![
+                ("type", Json::string("layers")),
+                ("diff_ids", Json::strings([&layer.digest])),
+            ]),
+        ),
+    ])
+}
+
+fn to_string(json: &Json) -> String {
+    let mut contents = String::new();
+    json.write(&mut contents, 0);
+    contents.push('\n');
+    contents
+}
+
+fn write_json(path: &Path, json: &Json) -> anyhow::Result<()> {
+    fs::write(path, to_string(json)).with_context(|| format!("Could not write {}", path.display()))
+}
+
+fn write_blob(blobs_dir: &Path, json: &Json) -> anyhow::Result<Blob> {
+    let contents = to_string(json);
+    let mut sha256 = Sha256::new();
+    sha256.update(contents.as_bytes());
+    let digest = sha256.finish();
+    let path = blobs_dir.join(&digest);
+    fs::write(&path, &contents).with_context(|| format!("Could not write {}", path.display()))?;
+    Ok(Blob {
+        digest: format!("sha256:{digest}"),
+        size: contents.len() as u64,
+    })
+}
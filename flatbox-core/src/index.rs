@@ -0,0 +1,213 @@
+use crate::{cache, probe};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// Levels of a ref's path in an installation which are indexed: its name, arch and branch
+const INDEXED_LEVELS: usize = 3;
+
+/// Entries of the `app` and `runtime` dirs of the installations and of the ref dirs in them, each read at most
+/// once per run. Looking a ref up in the index avoids statting every path it may be installed at, e.g. all
+/// versions and arches of an extension in every installation.
+static INDEX: Mutex<Option<HashMap<PathBuf, Indexed>>> = Mutex::new(None);
+
+/// The entries of an indexed dir, `None` when it exists but can't be read
+type Entries = Option<Arc<HashSet<OsString>>>;
+
+struct Indexed {
+    entries: Entries,
+    /// Modification time of the dir when it was read, only known for the ones read by `index_installations`
+    modified: Option<SystemTime>,
+}
+
+fn cached(dir: &Path) -> Option<Entries> {
+    INDEX
+        .lock()
+        .unwrap()
+        .as_ref()?
+        .get(dir)
+        .map(|indexed| indexed.entries.clone())
+}
+
+fn insert(dir: PathBuf, entries: Entries) {
+    INDEX
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(
+            dir,
+            Indexed {
+                entries,
+                modified: None,
+            },
+        );
+}
+
+fn modified(dir: &Path) -> Option<SystemTime> {
+    fs::metadata(dir)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Reads the dirs into the index in parallel. The `app` and `runtime` dirs of the installations (the first
+/// level) are listed through the listings cache.
+fn read_dirs(dirs: &[PathBuf], level: usize) {
+    if level == 0 {
+        match cache::dir_listings(dirs) {
+            Ok(listings) => {
+                for (dir, listing) in dirs.iter().zip(listings) {
+                    let entries = listing.into_iter().map(OsString::from).collect();
+                    insert(dir.clone(), Some(Arc::new(entries)));
+                }
+                return;
+            }
+            Err(err) => crate::debug!("Could not list the installations: {err:#}"),
+        }
+    }
+    for (dir, entries) in dirs.iter().zip(probe::read_dirs(dirs)) {
+        let entries = match entries {
+            Some(Ok(entries)) => Some(entries.into_iter().map(|entry| entry.file_name).collect()),
+            Some(Err(_)) => None,
+            None if !dir.exists() => Some(HashSet::new()),
+            None => None,
+        };
+        insert(dir.clone(), entries.map(Arc::new));
+    }
+}
+
+/// Looks the path up as far as it's indexed. Returns whether it may exist, or the dir and its level which
+/// has to be read to tell.
+fn lookup(kind_dir: &Path, path: &Path) -> Result<bool, (PathBuf, usize)> {
+    let mut dir = kind_dir.to_owned();
+    for (level, component) in path.components().take(INDEXED_LEVELS).enumerate() {
+        let Component::Normal(name) = component else {
+            // Not a ref path, so it's left to the caller to check
+            return Ok(true);
+        };
+        match cached(&dir) {
+            Some(Some(entries)) if !entries.contains(name) => return Ok(false),
+            Some(Some(_)) => dir.push(name),
+            Some(None) => return Ok(true),
+            None => return Err((dir, level)),
+        }
+    }
+    Ok(true)
+}
+
+/// Indexes what's needed to look up all of the paths relative to the `app` or `runtime` dir of installations,
+/// reading the dirs of each level in parallel
+pub fn prefetch(paths: &[(&Path, &Path)]) {
+    for level in 0..INDEXED_LEVELS {
+        let dirs: HashSet<PathBuf> = paths
+            .iter()
+            .filter_map(|(kind_dir, path)| match lookup(kind_dir, path) {
+                Err((dir, dir_level)) if dir_level == level => Some(dir),
+                _ => None,
+            })
+            .collect();
+        read_dirs(&dirs.into_iter().collect::<Vec<_>>(), level);
+    }
+}
+
+/// Indexes all the refs of the installations up front, for a long running process which launches many of them.
+/// `refresh` has to be called before looking them up later.
+pub fn index_installations(install_dirs: &[PathBuf]) {
+    let mut dirs: Vec<PathBuf> = install_dirs
+        .iter()
+        .flat_map(|dir| [dir.join("app"), dir.join("runtime")])
+        .collect();
+    for level in 0..INDEXED_LEVELS {
+        let unindexed: Vec<PathBuf> = dirs
+            .iter()
+            .filter(|dir| cached(dir).is_none())
+            .cloned()
+            .collect();
+        // Taken before reading, so changes made meanwhile are noticed by the next refresh
+        let modified = probe::parallel_map(&unindexed, |dir| modified(dir));
+        read_dirs(&unindexed, level);
+        if let Some(index) = INDEX.lock().unwrap().as_mut() {
+            for (dir, modified) in unindexed.iter().zip(modified) {
+                if let Some(indexed) = index.get_mut(dir) {
+                    indexed.modified = modified;
+                }
+            }
+        }
+
+        dirs = dirs
+            .iter()
+            .flat_map(|dir| {
+                let entries = cached(dir).flatten().unwrap_or_default();
+                entries
+                    .iter()
+                    .map(|entry| dir.join(entry))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+    }
+}
+
+/// Drops the dirs which were modified since they were indexed, or whose modification time isn't known, so
+/// the refs installed or removed since then are noticed. Re-indexes the installations afterwards.
+pub fn refresh(install_dirs: &[PathBuf]) {
+    let indexed: Vec<(PathBuf, Option<SystemTime>)> = INDEX
+        .lock()
+        .unwrap()
+        .iter()
+        .flatten()
+        .map(|(dir, indexed)| (dir.clone(), indexed.modified))
+        .collect();
+    let current = probe::parallel_map(&indexed, |(dir, _)| modified(dir));
+    if let Some(index) = INDEX.lock().unwrap().as_mut() {
+        for ((dir, modified), current) in indexed.into_iter().zip(current) {
+            if modified.is_none() || modified != current {
+                index.remove(&dir);
+            }
+        }
+    }
+    index_installations(install_dirs);
+}
+
+/// Whether the path relative to an installation's `app` or `runtime` dir may exist, according to the index.
+/// Only the ref's name, arch and branch are looked up, so the path still has to be checked when it may exist.
+pub fn may_exist(kind_dir: &Path, path: &Path) -> bool {
+    loop {
+        match lookup(kind_dir, path) {
+            Ok(may_exist) => return may_exist,
+            Err((dir, level)) => read_dirs(&[dir], level),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::may_exist;
+    use std::{fs, path::Path};
+    use tempdir::TempDir;
+
+    #[test]
+    fn looks_up_refs_in_the_index() {
+        let install_dir = TempDir::new("flatbox-index").unwrap();
+        let runtime_dir = install_dir.path().join("runtime");
+        fs::create_dir_all(runtime_dir.join("org.test.Platform/x86_64/1/active/files")).unwrap();
+
+        assert!(may_exist(&runtime_dir, Path::new("org.test.Platform")));
+        assert!(may_exist(
+            &runtime_dir,
+            Path::new("org.test.Platform/x86_64/1/active/files")
+        ));
+        assert!(!may_exist(
+            &runtime_dir,
+            Path::new("org.test.Platform/x86_64/2/active/files")
+        ));
+        assert!(!may_exist(&runtime_dir, Path::new("org.test.Sdk/x86_64/1")));
+        assert!(!may_exist(
+            &install_dir.path().join("app"),
+            Path::new("org.test.App")
+        ));
+    }
+}
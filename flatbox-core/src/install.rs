@@ -0,0 +1,365 @@
+use crate::{
+    arch,
+    error::FlatboxError,
+    keyfile::parse_keyfile,
+    ostree::{self, repo_arg},
+    picker, system,
+};
+use anyhow::Context;
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// The installation flatbox installs to, in `$XDG_DATA_HOME` (or the state dir in system mode). It has the layout of a flatpak installation,
+/// so its refs are found like the ones installed by flatpak.
+const INSTALLATION_DIR: &str = "flatbox/installation";
+const REF_KINDS: [&str; 2] = ["app", "runtime"];
+
+/// The installation of `flatbox install`, whether or not it exists yet
+pub fn managed_installation() -> Option<PathBuf> {
+    if system::is_system_mode() {
+        return Some(system::installation_dir());
+    }
+    Some(crate::data_home()?.join(INSTALLATION_DIR))
+}
+
+/// A remote configured in the repo of an installation, e.g. by `flatpak remote-add`
+#[derive(Debug)]
+pub struct Remote {
+    pub name: String,
+    pub url: String,
+    pub gpg_verify: bool,
+    /// The keys the commits of the remote are signed with, imported by flatpak
+    keyring: Option<PathBuf>,
+}
+
+/// The enabled remotes of the installations. A remote configured in several installations is taken from
+/// the first one.
+pub fn configured_remotes(install_dirs: &[PathBuf]) -> Vec<Remote> {
+    let mut remotes: Vec<Remote> = Vec::new();
+    for repo in install_dirs.iter().map(|dir| dir.join("repo")) {
+        let Ok(contents) = fs::read_to_string(repo.join("config")) else {
+            continue;
+        };
+        let keyfile = match parse_keyfile(&contents) {
+            Ok(keyfile) => keyfile,
+            Err(err) => {
+                crate::warn!("Ignoring the remotes of {}: {err}", repo.display());
+                continue;
+            }
+        };
+        for (group, values) in &keyfile {
+            let Some(name) = group
+                .strip_prefix("remote \"")
+                .and_then(|rest| rest.strip_suffix('"'))
+            else {
+                continue;
+            };
+            let Some(url) = values.get("url") else {
+                continue;
+            };
+            if values.get("xa.disable") == Some(&"true")
+                || remotes.iter().any(|remote| remote.name == name)
+            {
+                continue;
+            }
+            let keyring = repo.join(format!("{name}.trustedkeys.gpg"));
+            remotes.push(Remote {
+                name: name.to_owned(),
+                url: url.to_string(),
+                gpg_verify: values.get("gpg-verify") != Some(&"false"),
+                keyring: keyring.exists().then_some(keyring),
+            });
+        }
+    }
+    remotes
+}
+
+/// A ref as given to `flatbox install`: `[app/|runtime/]id[/arch[/branch]]`, with empty parts matching any
+#[derive(Debug, PartialEq, Eq)]
+struct PartialRef<'a> {
+    kind: Option<&'a str>,
+    id: &'a str,
+    arch: String,
+    branch: Option<&'a str>,
+}
+
+impl<'a> PartialRef<'a> {
+    fn parse(reference: &'a str, default_arch: &str) -> Self {
+        let (kind, rest) = match reference.split_once('/') {
+            Some((kind, rest)) if REF_KINDS.contains(&kind) => (Some(kind), rest),
+            _ => (None, reference),
+        };
+        let mut parts = rest.split('/');
+        let id = parts.next().unwrap_or_default();
+        let arch = parts
+            .next()
+            .filter(|arch| !arch.is_empty())
+            .unwrap_or(default_arch)
+            .to_owned();
+        let branch = parts.next().filter(|branch| !branch.is_empty());
+        Self {
+            kind,
+            id,
+            arch,
+            branch,
+        }
+    }
+
+    /// Whether the full `kind/id/arch/branch` ref of a remote is one this ref can stand for
+    fn matches(&self, full_ref: &str) -> bool {
+        let parts: Vec<&str> = full_ref.split('/').collect();
+        let [kind, id, arch, branch] = parts[..] else {
+            return false;
+        };
+        REF_KINDS.contains(&kind)
+            && self.kind.is_none_or(|expected| expected == kind)
+            && id == self.id
+            && arch == self.arch
+            && self.branch.is_none_or(|expected| expected == branch)
+    }
+}
+
+/// A ref deployed by `install`
+pub struct Deployment {
+    /// The full `kind/id/arch/branch` ref
+    pub reference: String,
+    pub remote: String,
+    pub commit: String,
+    /// The `active` dir of the ref
+    pub path: PathBuf,
+    /// Whether a new commit was deployed, rather than the ref being up to date
+    pub updated: bool,
+}
+
+/// Pulls a ref from the remotes (or only the given one) into the managed installation and deploys it.
+/// `reference` can be partial, e.g. `org.freedesktop.Platform//24.08`, when it matches a single ref.
+pub fn install(
+    reference: &str,
+    remote: Option<&str>,
+    arch: Option<&str>,
+    install_dirs: &[PathBuf],
+) -> anyhow::Result<Deployment> {
+    let installation =
+        managed_installation().context("Could not determine the installation dir")?;
+    let repo = installation.join("repo");
+    let repo_arg = &repo_arg(&repo);
+    if !repo.join("config").exists() {
+        fs::create_dir_all(&repo)
+            .with_context(|| format!("Could not create {}", repo.display()))?;
+        ostree::ostree(&["init".as_ref(), repo_arg, "--mode=bare-user-only".as_ref()])?;
+    }
+
+    let mut remotes = configured_remotes(install_dirs);
+    if let Some(name) = remote {
+        remotes.retain(|remote| remote.name == name);
+        if remotes.is_empty() {
+            return Err(FlatboxError::RemoteNotFound {
+                name: Some(name.to_owned()),
+            }
+            .into());
+        }
+    } else if remotes.is_empty() {
+        return Err(FlatboxError::RemoteNotFound { name: None }.into());
+    }
+
+    let default_arch = arch.map_or_else(arch::host_arch, str::to_owned);
+    let partial = PartialRef::parse(reference, &default_arch);
+    let mut candidates = Vec::new();
+    for remote in &remotes {
+        add_remote(repo_arg, remote)?;
+        let refs = match ostree::ostree(&[
+            "remote".as_ref(),
+            "refs".as_ref(),
+            repo_arg,
+            remote.name.as_ref(),
+        ]) {
+            Ok(refs) => refs,
+            Err(err) if remotes.len() > 1 => {
+                crate::warn!("Skipping remote {}: {err:#}", remote.name);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        let prefix = format!("{}:", remote.name);
+        candidates.extend(
+            refs.lines()
+                .map(|line| line.trim().strip_prefix(&prefix).unwrap_or(line.trim()))
+                .filter(|full_ref| partial.matches(full_ref))
+                .map(|full_ref| (remote.name.clone(), full_ref.to_owned())),
+        );
+    }
+    let (remote, full_ref) = match candidates.len() {
+        0 => {
+            return Err(FlatboxError::RemoteRefNotFound {
+                reference: reference.to_owned(),
+                arch: default_arch,
+                remotes: remotes.into_iter().map(|remote| remote.name).collect(),
+            }
+            .into());
+        }
+        1 => candidates.remove(0),
+        _ => {
+            let choices: Vec<String> = candidates
+                .iter()
+                .map(|(remote, full_ref)| format!("{remote}:{full_ref}"))
+                .collect();
+            let choice = picker::pick(&format!("Several refs match {reference}"), &choices)?;
+            candidates.swap_remove(choice)
+        }
+    };
+
+    crate::info!("Pulling {full_ref} from {remote}");
+    let remote_ref = format!("{remote}:{full_ref}");
+    ostree::ostree_status(&[
+        "pull".as_ref(),
+        repo_arg,
+        remote.as_ref(),
+        full_ref.as_ref(),
+    ])
+    .with_context(|| format!("Could not pull {remote_ref}"))?;
+    let commit = ostree::ostree(&["rev-parse".as_ref(), repo_arg, remote_ref.as_ref()])?;
+    let (path, updated) = deploy(&installation, &repo, &full_ref, &commit)?;
+    Ok(Deployment {
+        reference: full_ref,
+        remote,
+        commit,
+        path,
+        updated,
+    })
+}
+
+/// Adds the remote to the managed repo, with the keys flatpak imported for it
+fn add_remote(repo_arg: &OsString, remote: &Remote) -> anyhow::Result<()> {
+    let mut args: Vec<OsString> = vec![
+        "remote".into(),
+        "add".into(),
+        repo_arg.clone(),
+        "--if-not-exists".into(),
+    ];
+    match (&remote.keyring, remote.gpg_verify) {
+        (_, false) => args.push("--no-gpg-verify".into()),
+        (Some(keyring), true) => {
+            let mut import = OsString::from("--gpg-import=");
+            import.push(keyring);
+            args.push(import);
+        }
+        (None, true) => crate::warn!(
+            "No keys were imported for remote {}, its commits can't be verified",
+            remote.name
+        ),
+    }
+    args.extend([remote.name.as_str().into(), remote.url.as_str().into()]);
+    let args: Vec<&OsStr> = args.iter().map(OsString::as_os_str).collect();
+    ostree::ostree(&args)
+        .map(drop)
+        .with_context(|| format!("Could not add remote {}", remote.name))
+}
+
+/// Checks the commit out to `<ref>/<commit>` in the installation and makes it the `active` one, as flatpak
+/// deploys refs. Returns the `active` dir and whether the commit is newly deployed.
+fn deploy(
+    installation: &Path,
+    repo: &Path,
+    reference: &str,
+    commit: &str,
+) -> anyhow::Result<(PathBuf, bool)> {
+    let ref_dir = installation.join(reference);
+    let active = ref_dir.join("active");
+    let previous = fs::read_link(&active).ok();
+    if previous.as_deref() == Some(Path::new(commit)) && active.join("metadata").exists() {
+        return Ok((active, false));
+    }
+
+    let deployment = ref_dir.join(commit);
+    if !deployment.join("metadata").exists() {
+        // Renamed into place once complete, so an interrupted checkout isn't used
+        let partial = ref_dir.join(format!("{commit}.{}.tmp", process::id()));
+        fs::create_dir_all(&ref_dir)
+            .with_context(|| format!("Could not create {}", ref_dir.display()))?;
+        ostree::ostree(&[
+            "checkout".as_ref(),
+            &repo_arg(repo),
+            "--user-mode".as_ref(),
+            commit.as_ref(),
+            partial.as_ref(),
+        ])?;
+        if !partial.join("metadata").exists() {
+            let _ = fs::remove_dir_all(&partial);
+            return Err(FlatboxError::NotFlatpakRef {
+                reference: reference.to_owned(),
+            }
+            .into());
+        }
+        let _ = fs::remove_dir_all(&deployment);
+        fs::rename(&partial, &deployment)
+            .with_context(|| format!("Could not move the checkout to {}", deployment.display()))?;
+    }
+
+    // Switched atomically, so running sandboxes keep a consistent deployment
+    let link = ref_dir.join(format!("active.{}.tmp", process::id()));
+    let _ = fs::remove_file(&link);
+    symlink(commit, &link).with_context(|| format!("Could not create {}", link.display()))?;
+    fs::rename(&link, &active).with_context(|| format!("Could not update {}", active.display()))?;
+
+    if let ["app", id, arch, branch] = reference.split('/').collect::<Vec<_>>()[..] {
+        let current = installation.join("app").join(id).join("current");
+        if fs::symlink_metadata(&current).is_err() {
+            symlink(Path::new(arch).join(branch), &current)
+                .with_context(|| format!("Could not create {}", current.display()))?;
+        }
+    }
+    if let Some(previous) = previous.filter(|previous| previous != Path::new(commit)) {
+        let previous = ref_dir.join(previous);
+        if let Err(err) = fs::remove_dir_all(&previous) {
+            crate::warn!("Could not remove {}: {err}", previous.display());
+        }
+    }
+    Ok((active, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::install::PartialRef;
+
+    #[test]
+    fn partial_refs() {
+        let refs = [
+            "app/org.example.App/x86_64/stable",
+            "app/org.example.App/x86_64/beta",
+            "app/org.example.App/aarch64/stable",
+            "runtime/org.freedesktop.Platform/x86_64/23.08",
+            "runtime/org.freedesktop.Platform/x86_64/24.08",
+            "runtime/org.freedesktop.Platform.GL.default/x86_64/24.08",
+            "appstream/x86_64",
+        ];
+        let matching = |reference: &str| -> Vec<&str> {
+            let partial = PartialRef::parse(reference, "x86_64");
+            refs.into_iter()
+                .filter(|full_ref| partial.matches(full_ref))
+                .collect()
+        };
+
+        assert_eq!(
+            matching("org.freedesktop.Platform//24.08"),
+            ["runtime/org.freedesktop.Platform/x86_64/24.08"]
+        );
+        assert_eq!(
+            matching("org.example.App"),
+            [
+                "app/org.example.App/x86_64/stable",
+                "app/org.example.App/x86_64/beta"
+            ]
+        );
+        assert_eq!(
+            matching("app/org.example.App/aarch64"),
+            ["app/org.example.App/aarch64/stable"]
+        );
+        assert!(matching("runtime/org.example.App").is_empty());
+        assert!(matching("org.example").is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+use crate::keyfile::parse_keyfile;
+use anyhow::Context;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_CONFIG_DIR: &str = "/etc/flatpak";
+const INSTALLATIONS_SUBDIR: &str = "installations.d";
+
+/// An additional system installation declared in flatpak's `installations.d`
+#[derive(Debug, PartialEq, Eq)]
+pub struct Installation {
+    pub name: String,
+    pub path: PathBuf,
+    pub priority: i64,
+}
+
+/// The custom installations of the flatpak config dir (`FLATPAK_CONFIG_DIR` or `/etc/flatpak`), highest priority first
+pub fn custom_installations() -> Vec<Installation> {
+    let config_dir = env::var_os("FLATPAK_CONFIG_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map_or_else(|| PathBuf::from(DEFAULT_CONFIG_DIR), PathBuf::from);
+
+    let mut conf_files: Vec<PathBuf> = fs::read_dir(config_dir.join(INSTALLATIONS_SUBDIR))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "conf"))
+        .collect();
+    conf_files.sort();
+
+    let mut installations = Vec::new();
+    for conf_file in conf_files {
+        match read_installations(&conf_file) {
+            Ok(found) => installations.extend(found),
+            Err(err) => crate::warn!("Ignoring installations in {}: {err:#}", conf_file.display()),
+        }
+    }
+    // Stable, so installations with the same priority keep the order of their files
+    installations.sort_by_key(|installation| -installation.priority);
+    installations
+}
+
+fn read_installations(path: &Path) -> anyhow::Result<Vec<Installation>> {
+    let contents = fs::read_to_string(path).context("Could not read file")?;
+    parse_installations(&contents)
+}
+
+/// Parses the `[Installation "name"]` groups of an `installations.d` file
+fn parse_installations(contents: &str) -> anyhow::Result<Vec<Installation>> {
+    let keyfile = parse_keyfile(contents)?;
+    keyfile
+        .iter()
+        .filter_map(|(group, values)| {
+            let name = group.strip_prefix("Installation \"")?.strip_suffix('"')?;
+            Some((name, values))
+        })
+        .map(|(name, values)| {
+            let path = values
+                .get("Path")
+                .with_context(|| format!("Installation {name} has no Path"))?;
+            let priority = match values.get("Priority") {
+                Some(priority) => priority
+                    .parse()
+                    .with_context(|| format!("Invalid priority of installation {name}"))?,
+                None => 0,
+            };
+            Ok(Installation {
+                name: name.to_owned(),
+                path: PathBuf::from(path),
+                priority,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::installations::{Installation, parse_installations};
+    use std::path::PathBuf;
+
+    #[test]
+    fn parse_installations_conf() {
+        let contents = r#"
+[Installation "extra"]
+Path=/mnt/data/flatpak
+DisplayName=Extra Installation
+StorageType=harddisk
+Priority=10
+
+[Installation "sdcard"]
+Path=/run/media/sdcard/flatpak
+"#;
+        assert_eq!(
+            parse_installations(contents).unwrap(),
+            [
+                Installation {
+                    name: "extra".to_owned(),
+                    path: PathBuf::from("/mnt/data/flatpak"),
+                    priority: 10,
+                },
+                Installation {
+                    name: "sdcard".to_owned(),
+                    path: PathBuf::from("/run/media/sdcard/flatpak"),
+                    priority: 0,
+                },
+            ]
+        );
+    }
+}
@@ -1,29 +1,62 @@
-use anyhow::Context;
+use crate::error::FlatboxError;
 use indexmap::IndexMap;
+use std::{error::Error, fmt, path::Path};
+
+/// A malformed line of a keyfile
+#[derive(Debug)]
+pub struct ParseError {
+    /// 1-based
+    pub line: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    /// Names the file the keyfile was read from
+    pub fn in_file(self, path: &Path) -> FlatboxError {
+        FlatboxError::MetadataParse {
+            path: path.to_path_buf(),
+            line: self.line,
+            message: self.message,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at line {}", self.message, self.line)
+    }
+}
+
+impl Error for ParseError {}
 
-pub fn parse_keyfile(source: &str) -> anyhow::Result<IndexMap<&str, IndexMap<&str, &str>>> {
+pub fn parse_keyfile(source: &str) -> Result<IndexMap<&str, IndexMap<&str, &str>>, ParseError> {
     let mut out = IndexMap::new();
 
     let mut lines = source
         .lines()
         .map(|line| line.trim_ascii())
         .enumerate()
-        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
         .peekable();
 
     while let Some((i, line)) = lines.next() {
         let section_name = line
             .strip_prefix('[')
             .and_then(|name| name.strip_suffix(']'))
-            .with_context(|| format!("Expected section name, found '{line}' at line {i}"))?;
+            .ok_or_else(|| ParseError {
+                line: i,
+                message: format!("expected a section name, found '{line}'"),
+            })?;
 
         let mut section = IndexMap::new();
 
         while lines.peek().is_some_and(|(_, line)| !line.starts_with('[')) {
             let (i, line) = lines.next().unwrap();
-            let (key, value) = line
-                .split_once('=')
-                .with_context(|| format!("Unexpected line format '{line}' at {i}"))?;
+            let (key, value) = line.split_once('=').ok_or_else(|| ParseError {
+                line: i,
+                message: format!("expected KEY=VALUE, found '{line}'"),
+            })?;
 
             section.insert(key.trim_ascii(), value.trim_ascii());
         }
@@ -143,4 +176,24 @@ autodelete = true
                 .unwrap()
         );
     }
+
+    #[test]
+    fn skips_comments() {
+        let data = parse_keyfile("# Leading\n[core]\n  # Indented\nmode=bare-user-only\n").unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data["core"].len(), 1);
+        assert_eq!(data["core"]["mode"], "bare-user-only");
+    }
+
+    #[test]
+    fn parse_error_lines() {
+        let err = parse_keyfile("# Comment\n[Application]\nname = org.example.App\nbroken\n")
+            .unwrap_err();
+        assert_eq!(err.line, 4);
+        assert_eq!(
+            err.to_string(),
+            "expected KEY=VALUE, found 'broken' at line 4"
+        );
+        assert_eq!(parse_keyfile("name = value").unwrap_err().line, 1);
+    }
 }
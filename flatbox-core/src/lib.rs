@@ -0,0 +1,218 @@
+//! Composes the sandboxes of flatbox: resolves Flatpak apps and runtimes in the installations, reads their
+//! metadata and extensions, and turns them into a `SandboxPlan` run by bwrap, the native backend or a container.
+//! `SandboxBuilder` is the entry point, the other modules are the building blocks used by it.
+
+pub mod alsa;
+pub mod arch;
+pub mod build;
+pub mod bwrap;
+pub mod cache;
+pub mod cgroup;
+pub mod conditions;
+pub mod container;
+pub mod coredump;
+pub mod dbus;
+pub mod dbus_proxy;
+pub mod deploy;
+pub mod documents;
+pub mod environment;
+pub mod error;
+pub mod extension_image;
+pub mod extensions;
+pub mod fd_passing;
+pub mod fonts;
+pub mod forwarding;
+pub mod gc;
+pub mod graphics;
+pub mod host_command;
+pub mod icons;
+pub mod image;
+pub mod index;
+pub mod install;
+pub mod installations;
+pub mod keyfile;
+pub mod locale;
+pub mod logging;
+pub mod native;
+pub mod nested;
+pub mod nvidia;
+pub mod oci;
+pub mod oci_image;
+pub mod options;
+pub mod ostree;
+pub mod perf;
+pub mod permissions;
+pub mod picker;
+pub mod plan;
+pub mod prepared;
+pub mod priority;
+pub mod probe;
+pub mod pty;
+pub mod refs;
+pub mod rlimit;
+pub mod rootfs;
+pub mod run;
+pub mod sandbox;
+pub mod sha256;
+pub mod sockets;
+pub mod system;
+pub mod verify;
+pub mod video;
+pub mod wrap;
+
+pub use error::FlatboxError;
+pub use options::SandboxOptions;
+pub use run::RunOptions;
+pub use sandbox::{Sandbox, SandboxBuilder};
+
+use refs::AppRef;
+use std::{
+    env,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub const DEFAULT_INSTALL_PATH: &str = "/var/lib/flatpak";
+
+/// `$XDG_DATA_HOME`, defaulting to `~/.local/share`
+pub fn data_home() -> Option<PathBuf> {
+    env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("share")))
+}
+
+/// The system and user installations, at the same locations as used by flatpak
+/// (which can be moved with `FLATPAK_SYSTEM_DIR` and `FLATPAK_USER_DIR`, the user installation is only used
+/// outside of system mode unless it's moved), the one of `flatbox install`,
+/// the custom installations of
+/// `installations.d` and the extra dirs. Only the named custom installation and the extra dirs are used when one is given.
+pub fn flatpak_install_dirs(
+    extra_install_dirs: &[PathBuf],
+    installation: Option<&str>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let custom_installations = installations::custom_installations();
+    if let Some(name) = installation {
+        let Some(installation) = custom_installations
+            .into_iter()
+            .find(|installation| installation.name == name)
+        else {
+            return Err(FlatboxError::InstallationNotFound {
+                name: name.to_owned(),
+            }
+            .into());
+        };
+        return Ok([installation.path]
+            .into_iter()
+            .chain(extra_install_dirs.iter().cloned())
+            .collect());
+    }
+
+    let system_install_dir = env::var_os("FLATPAK_SYSTEM_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map_or_else(|| PathBuf::from(DEFAULT_INSTALL_PATH), PathBuf::from);
+
+    let user_install_dir = env::var_os("FLATPAK_USER_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            if system::is_system_mode() {
+                return None;
+            }
+            Some(data_home()?.join("flatpak"))
+        })
+        .filter(|path| path.exists());
+    let managed_install_dir = install::managed_installation().filter(|path| path.exists());
+
+    Ok([system_install_dir]
+        .into_iter()
+        .chain(user_install_dir)
+        .chain(managed_install_dir)
+        .chain(
+            custom_installations
+                .into_iter()
+                .map(|installation| installation.path),
+        )
+        .chain(extra_install_dirs.iter().cloned())
+        .collect())
+}
+
+/// Finds the active deployment of an app, of the requested branch or the one of its `current` deployment.
+/// It's looked up for the requested arch, or the host architecture when not given, falling back to `current`.
+pub fn find_app_path(app: &AppRef, install_dirs: &[PathBuf]) -> anyhow::Result<PathBuf> {
+    let arch = app.arch.clone().unwrap_or_else(arch::host_arch);
+    let mut deployments = Vec::new();
+    for app_dir in install_dirs.iter().map(|dir| dir.join("app").join(&app.id)) {
+        let current = fs::read_link(app_dir.join("current")).ok();
+        let branch = app
+            .branch
+            .as_deref()
+            .map(OsStr::new)
+            .or_else(|| current.as_deref().and_then(Path::file_name));
+        if let Some(branch) = branch
+            && let Ok(deployment) = fs::canonicalize(app_dir.join(&arch).join(branch))
+            && deployment.join("active").exists()
+            && !deployments.contains(&deployment)
+        {
+            deployments.push(deployment);
+        }
+    }
+    if deployments.len() > 1 {
+        let choices: Vec<String> = deployments
+            .iter()
+            .map(|deployment| deployment.display().to_string())
+            .collect();
+        let choice = picker::pick(
+            &format!("App {} is installed in several installations", app.id),
+            &choices,
+        )?;
+        return Ok(deployments.swap_remove(choice).join("active"));
+    }
+    if let Some(deployment) = deployments.pop() {
+        return Ok(deployment.join("active"));
+    }
+    if app.arch.is_some() || app.branch.is_some() {
+        return Err(FlatboxError::AppNotFound {
+            app: format!(
+                "{}/{arch}/{}",
+                app.id,
+                app.branch.as_deref().unwrap_or("current")
+            ),
+            searched_dirs: install_dirs.to_vec(),
+        }
+        .into());
+    }
+
+    let app_dir = find_install_path(&app.id, true, install_dirs).ok_or_else(|| {
+        FlatboxError::AppNotFound {
+            app: app.id.clone(),
+            searched_dirs: install_dirs.to_vec(),
+        }
+    })?;
+    Ok(app_dir.join("current").join("active"))
+}
+
+pub fn list_available_runtimes(install_dirs: &[PathBuf]) -> anyhow::Result<Vec<String>> {
+    let runtime_dirs: Vec<PathBuf> = install_dirs.iter().map(|dir| dir.join("runtime")).collect();
+    Ok(cache::dir_listings(&runtime_dirs)?.concat())
+}
+
+pub fn find_install_path(
+    name: impl AsRef<Path>,
+    is_app: bool,
+    install_dirs: &[PathBuf],
+) -> Option<PathBuf> {
+    let infix = if is_app { "app" } else { "runtime" };
+    for dir in install_dirs {
+        let kind_dir = dir.join(infix);
+        if !index::may_exist(&kind_dir, name.as_ref()) {
+            continue;
+        }
+        let path = kind_dir.join(name.as_ref());
+        if path.exists() {
+            return Some(path);
+        }
+    }
+    None
+}
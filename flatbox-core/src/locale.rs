@@ -0,0 +1,163 @@
+use crate::{environment::Environment, keyfile::parse_keyfile};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+const LOCALE_ENV_VARS: [&str; 4] = ["LANGUAGE", "LC_ALL", "LC_MESSAGES", "LANG"];
+const TERMINAL_ENV_VARS: [&str; 2] = ["TERM", "COLORTERM"];
+const FALLBACK_LOCALE: &str = "C.UTF-8";
+
+/// Languages for which locale data should be made available.
+/// These are read from the flatpak installations' `xa.languages`/`xa.extra-languages` settings,
+/// falling back to the languages of the current locale when none are configured.
+/// An empty list means that all languages should be used.
+pub fn configured_languages(install_dirs: &[PathBuf]) -> Vec<String> {
+    let mut languages = Vec::new();
+
+    for dir in install_dirs {
+        let Ok(raw_config) = fs::read_to_string(dir.join("repo").join("config")) else {
+            continue;
+        };
+        let Ok(config) = parse_keyfile(&raw_config) else {
+            continue;
+        };
+        let Some(core) = config.get("core") else {
+            continue;
+        };
+
+        for key in ["xa.languages", "xa.extra-languages"] {
+            if let Some(value) = core.get(key) {
+                for language in value.split(';').filter(|lang| !lang.is_empty()) {
+                    if language == "*" {
+                        return Vec::new();
+                    }
+                    // Extra languages can be full locales, but locale data is split by language only
+                    let language = locale_language(language);
+                    if !languages.iter().any(|existing| *existing == language) {
+                        languages.push(language.to_owned());
+                    }
+                }
+            }
+        }
+    }
+
+    if languages.is_empty() {
+        for var in LOCALE_ENV_VARS {
+            if let Ok(value) = env::var(var) {
+                for locale in value.split(':') {
+                    let language = locale_language(locale);
+                    if !language.is_empty()
+                        && language != "C"
+                        && language != "POSIX"
+                        && !languages.iter().any(|existing| *existing == language)
+                    {
+                        languages.push(language.to_owned());
+                    }
+                }
+            }
+        }
+    }
+
+    languages
+}
+
+/// Passes the host's terminal and locale settings to the sandbox.
+/// Locales which the runtime has no data for are replaced by `C.UTF-8`, so that at least UTF-8 output keeps working.
+pub fn setup_locale_env(
+    environment: &mut Environment,
+    runtime_files_path: &Path,
+    install_dirs: &[PathBuf],
+) {
+    for var in TERMINAL_ENV_VARS {
+        if let Some(value) = env::var_os(var) {
+            environment.set(var, value);
+        }
+    }
+
+    let languages = configured_languages(install_dirs);
+    for (var, value) in env::vars() {
+        if var != "LANG" && !var.starts_with("LC_") {
+            continue;
+        }
+        if value.is_empty() || locale_available(&value, runtime_files_path, &languages) {
+            environment.set(var, value);
+        } else {
+            crate::warn!(
+                "Locale '{value}' of {var} is not available in the runtime, using {FALLBACK_LOCALE}"
+            );
+            environment.set(var, FALLBACK_LOCALE);
+        }
+    }
+}
+
+/// Checks whether the runtime provides data for `locale`.
+/// Runtimes ship the compiled locales in `lib/locale`, most of them as links into the locale extension,
+/// in which case only the `languages` selected for mounting are available.
+fn locale_available(locale: &str, runtime_files_path: &Path, languages: &[String]) -> bool {
+    if matches!(locale_language(locale), "C" | "POSIX") {
+        return true;
+    }
+
+    let locale_dir = runtime_files_path
+        .join("lib")
+        .join("locale")
+        .join(normalize_locale(locale));
+    match fs::symlink_metadata(locale_dir) {
+        Ok(metadata) if metadata.is_symlink() => {
+            languages.is_empty() || languages.iter().any(|lang| lang == locale_language(locale))
+        }
+        Ok(_) => true,
+        Err(_) => false,
+    }
+}
+
+/// Converts a locale name to the form glibc uses for its locale dirs, e.g. `de_DE.UTF-8` to `de_DE.utf8`
+pub fn normalize_locale(locale: &str) -> String {
+    let (name, modifier) = match locale.split_once('@') {
+        Some((name, modifier)) => (name, Some(modifier)),
+        None => (locale, None),
+    };
+    let mut normalized = match name.split_once('.') {
+        Some((base, codeset)) => {
+            let codeset: String = codeset
+                .chars()
+                .filter(char::is_ascii_alphanumeric)
+                .map(|c| c.to_ascii_lowercase())
+                .collect();
+            format!("{base}.{codeset}")
+        }
+        None => name.to_owned(),
+    };
+    if let Some(modifier) = modifier {
+        normalized.push('@');
+        normalized.push_str(modifier);
+    }
+    normalized
+}
+
+/// Extracts the language part of a locale name such as `pt_BR.UTF-8@euro`
+pub fn locale_language(locale: &str) -> &str {
+    locale.split(['_', '.', '@']).next().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::locale::{locale_language, normalize_locale};
+
+    #[test]
+    fn extract_locale_language() {
+        assert_eq!("pt", locale_language("pt_BR.UTF-8"));
+        assert_eq!("sr", locale_language("sr@latin"));
+        assert_eq!("de", locale_language("de"));
+        assert_eq!("C", locale_language("C.UTF-8"));
+    }
+
+    #[test]
+    fn normalize_locale_codeset() {
+        assert_eq!("de_DE.utf8", normalize_locale("de_DE.UTF-8"));
+        assert_eq!("sr_RS.utf8@latin", normalize_locale("sr_RS.UTF-8@latin"));
+        assert_eq!("ja_JP.eucjp", normalize_locale("ja_JP.EUC-JP"));
+        assert_eq!("en_US", normalize_locale("en_US"));
+    }
+}
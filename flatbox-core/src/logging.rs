@@ -0,0 +1,319 @@
+use crate::oci::write_json_string;
+use clap::ValueEnum;
+use std::{
+    cell::RefCell,
+    fmt::{self, Write as _},
+    io::{self, Write as _},
+    str::FromStr,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Environment variable with the log filter, e.g. `FLATBOX_LOG=warn,extensions=debug`
+pub const LOG_ENV: &str = "FLATBOX_LOG";
+/// Target of the messages logged outside of any span
+const DEFAULT_TARGET: &str = "flatbox";
+
+static LOGGER: RwLock<Option<Logger>> = RwLock::new(None);
+/// Durations of the spans, in the order they were entered, once `record_timings` was called
+static TIMINGS: Mutex<Option<Vec<Timing>>> = Mutex::new(None);
+
+thread_local! {
+    /// Names of the entered spans, innermost last
+    static SPANS: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+const LEVELS: [(&str, Level); 5] = [
+    ("error", Level::Error),
+    ("warn", Level::Warn),
+    ("info", Level::Info),
+    ("debug", Level::Debug),
+    ("trace", Level::Trace),
+];
+
+impl Level {
+    /// The level enabled by passing `-v` the given number of times
+    pub fn from_verbosity(count: u8) -> Self {
+        match count {
+            0 => Level::Warn,
+            1 => Level::Info,
+            2 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        LEVELS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(value))
+            .map(|(_, level)| *level)
+            .ok_or_else(|| {
+                format!("expected error, warn, info, debug or trace as the level, found '{value}'")
+            })
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, _) = LEVELS.iter().find(|(_, level)| level == self).unwrap();
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// `LEVEL target: message` lines
+    #[default]
+    Text,
+    /// One JSON object per line, with the time, level, target, spans and message
+    Json,
+}
+
+/// Which messages are logged: a default level and ones for targets, written like `info,extensions=debug`.
+/// A message's target is the one given to the macro or the innermost span, and it's also logged when an
+/// enclosing span's level allows it, so `extensions=debug` covers everything logged while setting up extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    default: Level,
+    targets: Vec<(String, Level)>,
+}
+
+impl Filter {
+    pub fn new(default: Level) -> Self {
+        Self {
+            default,
+            targets: Vec::new(),
+        }
+    }
+
+    fn level(&self, target: Option<&str>, spans: &[&str]) -> Level {
+        target
+            .into_iter()
+            .chain(spans.iter().rev().copied())
+            .find_map(|name| {
+                self.targets
+                    .iter()
+                    .find(|(target, _)| target == name)
+                    .map(|(_, level)| *level)
+            })
+            .unwrap_or(self.default)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut filter = Filter::new(Level::Warn);
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => filter.targets.push((target.to_owned(), level.parse()?)),
+                None => filter.default = directive.parse()?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+struct Logger {
+    filter: Filter,
+    format: LogFormat,
+}
+
+/// Sets which log messages are written and how. Until it's called, warnings and errors are logged as text.
+pub fn init(filter: Filter, format: LogFormat) {
+    *LOGGER.write().unwrap() = Some(Logger { filter, format });
+}
+
+fn with_spans<T>(f: impl FnOnce(&[&'static str]) -> T) -> T {
+    SPANS.with(|spans| f(&spans.borrow()))
+}
+
+pub fn enabled(level: Level, target: Option<&str>) -> bool {
+    let max = match LOGGER.read().unwrap().as_ref() {
+        Some(logger) => with_spans(|spans| logger.filter.level(target, spans)),
+        None => Level::Warn,
+    };
+    level <= max
+}
+
+/// Writes a message, use the macros like `debug!` instead
+#[doc(hidden)]
+pub fn write(level: Level, target: Option<&str>, args: fmt::Arguments) {
+    let format = LOGGER
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|logger| logger.format)
+        .unwrap_or_default();
+    let line = with_spans(|spans| {
+        let target = target.or(spans.last().copied()).unwrap_or(DEFAULT_TARGET);
+        match format {
+            LogFormat::Text => {
+                format!("{} {target}: {args}\n", level.to_string().to_uppercase())
+            }
+            LogFormat::Json => json_line(level, target, spans, &args.to_string()),
+        }
+    });
+    // Written at once, so lines of different threads don't interleave
+    let _ = io::stderr().lock().write_all(line.as_bytes());
+}
+
+fn json_line(level: Level, target: &str, spans: &[&str], message: &str) -> String {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut line = String::new();
+    write!(line, "{{\"time\":{:.6},\"level\":", time.as_secs_f64()).unwrap();
+    write_json_string(&mut line, &level.to_string());
+    line.push_str(",\"target\":");
+    write_json_string(&mut line, target);
+    line.push_str(",\"spans\":[");
+    for (i, span) in spans.iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        write_json_string(&mut line, span);
+    }
+    line.push_str("],\"message\":");
+    write_json_string(&mut line, message);
+    line.push_str("}\n");
+    line
+}
+
+/// A phase of the setup, which is the target of the messages logged while it's entered. Its duration is logged
+/// at the debug level when it's dropped.
+pub struct Span {
+    start: Instant,
+    timing: Option<usize>,
+}
+
+pub fn span(name: &'static str) -> Span {
+    let depth = SPANS.with(|spans| {
+        let mut spans = spans.borrow_mut();
+        spans.push(name);
+        spans.len() - 1
+    });
+    crate::trace!("started");
+    let timing = TIMINGS.lock().unwrap().as_mut().map(|timings| {
+        timings.push(Timing {
+            name,
+            depth,
+            duration: Duration::ZERO,
+        });
+        timings.len() - 1
+    });
+    Span {
+        start: Instant::now(),
+        timing,
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        crate::debug!("finished in {elapsed:.1?}");
+        if let Some(index) = self.timing
+            && let Some(timings) = TIMINGS.lock().unwrap().as_mut()
+        {
+            timings[index].duration = elapsed;
+        }
+        SPANS.with(|spans| spans.borrow_mut().pop());
+    }
+}
+
+/// How long a span took
+#[derive(Debug, Clone)]
+pub struct Timing {
+    pub name: &'static str,
+    /// Number of spans it was entered in
+    pub depth: usize,
+    pub duration: Duration,
+}
+
+/// Starts recording how long the spans entered from now on take, regardless of the log level
+pub fn record_timings() {
+    TIMINGS.lock().unwrap().get_or_insert_with(Vec::new);
+}
+
+/// Adds durations measured outside of a span, when they're recorded
+pub fn add_timings(timings: impl IntoIterator<Item = Timing>) {
+    if let Some(recorded) = TIMINGS.lock().unwrap().as_mut() {
+        recorded.extend(timings);
+    }
+}
+
+/// The recorded durations of the spans, in the order they were entered
+pub fn timings() -> Vec<Timing> {
+    TIMINGS.lock().unwrap().clone().unwrap_or_default()
+}
+
+#[macro_export]
+#[doc(hidden)]
+macro_rules! log_event {
+    ($level:expr, target: $target:expr, $($arg:tt)+) => {
+        if $crate::logging::enabled($level, Some($target)) {
+            $crate::logging::write($level, Some($target), format_args!($($arg)+));
+        }
+    };
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::logging::enabled($level, None) {
+            $crate::logging::write($level, None, format_args!($($arg)+));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log_event!($crate::logging::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log_event!($crate::logging::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log_event!($crate::logging::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log_event!($crate::logging::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log_event!($crate::logging::Level::Trace, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::{Filter, Level};
+
+    #[test]
+    fn filter_directives() {
+        let filter: Filter = "info,extensions=debug,mounts=error".parse().unwrap();
+        assert_eq!(filter.level(None, &[]), Level::Info);
+        assert_eq!(filter.level(None, &["sandbox", "extensions"]), Level::Debug);
+        assert_eq!(filter.level(Some("mounts"), &["extensions"]), Level::Error);
+        assert_eq!(filter.level(Some("dbus"), &["extensions"]), Level::Debug);
+        assert_eq!("".parse::<Filter>().unwrap(), Filter::new(Level::Warn));
+        assert!("extensions=loud".parse::<Filter>().is_err());
+        assert_eq!(Level::from_verbosity(2), Level::Debug);
+    }
+}
@@ -0,0 +1,703 @@
+use crate::{
+    error::FlatboxError,
+    plan::{BindMode, MountOp, SandboxPlan},
+};
+use anyhow::Context;
+use std::{
+    ffi::{CStr, CString, OsStr},
+    fs, io, mem,
+    os::unix::{ffi::OsStrExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::Command,
+    ptr,
+};
+
+/// Host dir the new root is assembled in. It's only covered with a tmpfs in the sandbox's mount namespace.
+const BASE_DIR: &str = "/tmp";
+const NEW_ROOT: &str = "/newroot";
+const OLD_ROOT: &str = "/oldroot";
+/// Device nodes bound from the host into a minimal `/dev`, like bwrap's `--dev`
+const DEV_NODES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
+const DEV_SYMLINKS: [(&str, &str); 6] = [
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+    ("core", "/proc/kcore"),
+    ("ptmx", "pts/ptmx"),
+];
+/// Not defined by libc for every target
+const ST_RELATIME: libc::c_ulong = 4096;
+
+/// Creates a command which sets up the namespaces and mounts of the plan itself before executing `argv`
+pub fn command(plan: &SandboxPlan, argv: &[impl AsRef<OsStr>]) -> anyhow::Result<Command> {
+    let (program, args) = argv.split_first().context("No command to run")?;
+    let mut command = Command::new(program);
+    command.args(args);
+    for (key, value) in &plan.env {
+        match value {
+            Some(value) => command.env(key, value),
+            None => command.env_remove(key),
+        };
+    }
+
+    let setup = Setup::new(plan)?;
+    let priority = plan.priority;
+    // SAFETY: the setup runs in the forked child, where it only makes syscalls with the data prepared here
+    unsafe {
+        // Before entering the user namespace, where lowering the values needs privileges
+        command.pre_exec(move || {
+            priority.apply()?;
+            setup.run()
+        });
+    }
+    Ok(command)
+}
+
+/// A single syscall of the setup, with all of its arguments prepared before forking
+#[derive(Debug)]
+enum Step {
+    /// Creates a directory, unless it already exists
+    Mkdir {
+        path: CString,
+        mode: libc::mode_t,
+    },
+    /// Creates an empty file to bind a file over, unless it already exists
+    Touch {
+        path: CString,
+    },
+    Mount {
+        source: Option<CString>,
+        dest: CString,
+        fstype: Option<CString>,
+        flags: libc::c_ulong,
+        data: Option<CString>,
+    },
+    /// Applies mount attributes (`MOUNT_ATTR_*`) to a mount and its submounts
+    SetAttr {
+        path: CString,
+        attr: u64,
+    },
+    /// Mounts a new procfs, which requires owning the pid namespace, or else binds the host's
+    Proc {
+        dest: CString,
+        fallback: CString,
+    },
+    Symlink {
+        target: CString,
+        dest: CString,
+    },
+    WriteFile {
+        path: CString,
+        contents: Vec<u8>,
+    },
+    Chdir {
+        path: CString,
+    },
+    PivotRoot {
+        new_root: CString,
+        put_old: CString,
+    },
+    Umount {
+        path: CString,
+    },
+    /// Brings up the loopback interface of a new network namespace
+    LoopbackUp,
+    NewSession,
+}
+
+#[derive(Debug)]
+struct Setup {
+    clone_flags: libc::c_int,
+    steps: Vec<Step>,
+}
+
+impl Setup {
+    fn new(plan: &SandboxPlan) -> anyhow::Result<Self> {
+        let mut clone_flags = libc::CLONE_NEWUSER | libc::CLONE_NEWNS;
+        if plan.unshare_net {
+            clone_flags |= libc::CLONE_NEWNET;
+        }
+        if plan.unshare_ipc {
+            clone_flags |= libc::CLONE_NEWIPC;
+        }
+
+        let mut steps = Vec::new();
+        // Map the user to itself, which also drops the namespace's capabilities on exec
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        steps.push(Step::WriteFile {
+            path: c_string("/proc/self/setgroups")?,
+            contents: b"deny".to_vec(),
+        });
+        steps.push(Step::WriteFile {
+            path: c_string("/proc/self/uid_map")?,
+            contents: format!("{uid} {uid} 1").into_bytes(),
+        });
+        steps.push(Step::WriteFile {
+            path: c_string("/proc/self/gid_map")?,
+            contents: format!("{gid} {gid} 1").into_bytes(),
+        });
+        if plan.unshare_net {
+            steps.push(Step::LoopbackUp);
+        }
+
+        // Like bwrap, build the new root in a tmpfs with the host's root moved aside to /oldroot
+        steps.push(mount(
+            None::<&str>,
+            "/",
+            None,
+            libc::MS_SLAVE | libc::MS_REC,
+            None,
+        )?);
+        steps.push(mount(
+            Some("tmpfs"),
+            BASE_DIR,
+            Some("tmpfs"),
+            libc::MS_NOSUID | libc::MS_NODEV,
+            None,
+        )?);
+        steps.push(Step::Chdir {
+            path: c_string(BASE_DIR)?,
+        });
+        for dir in ["newroot", "oldroot"] {
+            steps.push(Step::Mkdir {
+                path: c_string(dir)?,
+                mode: 0o755,
+            });
+        }
+        steps.push(mount(
+            Some("newroot"),
+            "newroot",
+            None,
+            libc::MS_BIND | libc::MS_REC,
+            None,
+        )?);
+        steps.push(Step::PivotRoot {
+            new_root: c_string(BASE_DIR)?,
+            put_old: c_string("oldroot")?,
+        });
+        steps.push(Step::Chdir {
+            path: c_string("/")?,
+        });
+
+        for (i, op) in plan.mounts.iter().enumerate() {
+            add_mount_steps(&mut steps, op, i)
+                .with_context(|| format!("Could not prepare {op:?}"))?;
+        }
+
+        steps.push(Step::Umount {
+            path: c_string(OLD_ROOT)?,
+        });
+        steps.push(Step::Chdir {
+            path: c_string(NEW_ROOT)?,
+        });
+        steps.push(Step::PivotRoot {
+            new_root: c_string(".")?,
+            put_old: c_string(".")?,
+        });
+        steps.push(Step::Umount {
+            path: c_string(".")?,
+        });
+        steps.push(Step::Chdir {
+            path: c_string(plan.chdir.as_deref().unwrap_or(Path::new("/")))?,
+        });
+        if plan.new_session {
+            steps.push(Step::NewSession);
+        }
+
+        Ok(Self { clone_flags, steps })
+    }
+
+    fn run(&self) -> io::Result<()> {
+        if unsafe { libc::unshare(self.clone_flags) } != 0 {
+            return Err(report("create the namespaces", None));
+        }
+        for step in &self.steps {
+            step.run()?;
+        }
+        Ok(())
+    }
+}
+
+fn add_mount_steps(steps: &mut Vec<Step>, op: &MountOp, index: usize) -> anyhow::Result<()> {
+    match op {
+        MountOp::Bind { source, dest, mode } => {
+            // Symlinks are resolved on the host, as absolute ones would point into the setup root
+            let source = fs::canonicalize(source)
+                .with_context(|| format!("Can't find source path {}", source.display()))?;
+            let host_source = Path::new(OLD_ROOT).join(relative(&source));
+            let metadata = fs::metadata(&source)
+                .with_context(|| format!("Can't find source path {}", source.display()))?;
+            let dest = sandbox_path(dest);
+            add_parent_dirs(steps, &dest)?;
+            if metadata.is_dir() {
+                steps.push(Step::Mkdir {
+                    path: c_string(&dest)?,
+                    mode: 0o755,
+                });
+            } else {
+                steps.push(Step::Touch {
+                    path: c_string(&dest)?,
+                });
+            }
+            steps.push(mount(
+                Some(&host_source),
+                &dest,
+                None,
+                libc::MS_BIND | libc::MS_REC,
+                None,
+            )?);
+            let attr = match mode {
+                BindMode::ReadWrite => libc::MOUNT_ATTR_NODEV,
+                BindMode::ReadOnly => libc::MOUNT_ATTR_NODEV | libc::MOUNT_ATTR_RDONLY,
+                BindMode::Device => 0,
+            };
+            if attr != 0 {
+                steps.push(Step::SetAttr {
+                    path: c_string(&dest)?,
+                    attr,
+                });
+            }
+        }
+        MountOp::Tmpfs { dest, perms } => {
+            let dest = sandbox_path(dest);
+            add_dir(steps, &dest, 0o755)?;
+            steps.push(mount(
+                Some("tmpfs"),
+                &dest,
+                Some("tmpfs"),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                Some(&format!("mode={:04o}", perms.unwrap_or(0o755))),
+            )?);
+        }
+        MountOp::Dir { dest, perms } => {
+            add_dir(steps, &sandbox_path(dest), perms.unwrap_or(0o755))?;
+        }
+        MountOp::Dev { dest } => {
+            let dest = sandbox_path(dest);
+            add_dir(steps, &dest, 0o755)?;
+            steps.push(mount(
+                Some("tmpfs"),
+                &dest,
+                Some("tmpfs"),
+                libc::MS_NOSUID,
+                Some("mode=0755"),
+            )?);
+            for node in DEV_NODES {
+                let node_dest = dest.join(node);
+                steps.push(Step::Touch {
+                    path: c_string(&node_dest)?,
+                });
+                steps.push(mount(
+                    Some(&Path::new(OLD_ROOT).join("dev").join(node)),
+                    &node_dest,
+                    None,
+                    libc::MS_BIND,
+                    None,
+                )?);
+            }
+            steps.push(Step::Mkdir {
+                path: c_string(dest.join("shm"))?,
+                mode: 0o1777,
+            });
+            steps.push(Step::Mkdir {
+                path: c_string(dest.join("pts"))?,
+                mode: 0o755,
+            });
+            steps.push(mount(
+                Some("devpts"),
+                dest.join("pts"),
+                Some("devpts"),
+                libc::MS_NOSUID | libc::MS_NOEXEC,
+                Some("newinstance,ptmxmode=0666,mode=620"),
+            )?);
+            for (name, target) in DEV_SYMLINKS {
+                steps.push(Step::Symlink {
+                    target: c_string(target)?,
+                    dest: c_string(dest.join(name))?,
+                });
+            }
+        }
+        MountOp::Proc { dest } => {
+            let dest = sandbox_path(dest);
+            add_dir(steps, &dest, 0o755)?;
+            steps.push(Step::Proc {
+                dest: c_string(&dest)?,
+                fallback: c_string(Path::new(OLD_ROOT).join("proc"))?,
+            });
+        }
+        MountOp::Symlink { target, dest } => {
+            let dest = sandbox_path(dest);
+            add_parent_dirs(steps, &dest)?;
+            steps.push(Step::Symlink {
+                target: c_string(target)?,
+                dest: c_string(&dest)?,
+            });
+        }
+        MountOp::TmpOverlay { source, dest } => {
+            // The upper layer lives in the tmpfs of the setup root, which is kept alive by the overlay
+            let overlay_dir = PathBuf::from(format!("/overlay-{index}"));
+            for dir in [
+                overlay_dir.clone(),
+                overlay_dir.join("upper"),
+                overlay_dir.join("work"),
+            ] {
+                steps.push(Step::Mkdir {
+                    path: c_string(dir)?,
+                    mode: 0o755,
+                });
+            }
+            let source = fs::canonicalize(source)
+                .with_context(|| format!("Can't find overlay source {}", source.display()))?;
+            let dest = sandbox_path(dest);
+            add_dir(steps, &dest, 0o755)?;
+            let options = format!(
+                "lowerdir={},upperdir={},workdir={},userxattr",
+                escape_overlay_path(&Path::new(OLD_ROOT).join(relative(&source)))?,
+                escape_overlay_path(&overlay_dir.join("upper"))?,
+                escape_overlay_path(&overlay_dir.join("work"))?,
+            );
+            steps.push(mount(
+                Some("overlay"),
+                &dest,
+                Some("overlay"),
+                0,
+                Some(&options),
+            )?);
+        }
+        MountOp::Overlay {
+            source,
+            upper,
+            work,
+            dest,
+        } => {
+            let host_path = |path: &Path| -> anyhow::Result<String> {
+                let path = fs::canonicalize(path)
+                    .with_context(|| format!("Can't find overlay dir {}", path.display()))?;
+                escape_overlay_path(&Path::new(OLD_ROOT).join(relative(&path)))
+            };
+            let options = format!(
+                "lowerdir={},upperdir={},workdir={},userxattr",
+                host_path(source)?,
+                host_path(upper)?,
+                host_path(work)?,
+            );
+            let dest = sandbox_path(dest);
+            add_dir(steps, &dest, 0o755)?;
+            steps.push(mount(
+                Some("overlay"),
+                &dest,
+                Some("overlay"),
+                0,
+                Some(&options),
+            )?);
+        }
+    }
+    Ok(())
+}
+
+/// Path of the sandbox path `path` while the new root is being set up
+fn sandbox_path(path: &Path) -> PathBuf {
+    Path::new(NEW_ROOT).join(relative(path))
+}
+
+fn relative(path: &Path) -> &Path {
+    path.strip_prefix("/").unwrap_or(path)
+}
+
+fn add_parent_dirs(steps: &mut Vec<Step>, path: &Path) -> anyhow::Result<()> {
+    let mut parents: Vec<&Path> = path
+        .ancestors()
+        .skip(1)
+        .take_while(|parent| parent.starts_with(NEW_ROOT) && *parent != Path::new(NEW_ROOT))
+        .collect();
+    parents.reverse();
+    for parent in parents {
+        steps.push(Step::Mkdir {
+            path: c_string(parent)?,
+            mode: 0o755,
+        });
+    }
+    Ok(())
+}
+
+fn add_dir(steps: &mut Vec<Step>, path: &Path, mode: libc::mode_t) -> anyhow::Result<()> {
+    add_parent_dirs(steps, path)?;
+    steps.push(Step::Mkdir {
+        path: c_string(path)?,
+        mode,
+    });
+    Ok(())
+}
+
+fn mount(
+    source: Option<impl AsRef<OsStr>>,
+    dest: impl AsRef<OsStr>,
+    fstype: Option<&str>,
+    flags: libc::c_ulong,
+    data: Option<&str>,
+) -> anyhow::Result<Step> {
+    Ok(Step::Mount {
+        source: source.map(c_string).transpose()?,
+        dest: c_string(dest)?,
+        fstype: fstype.map(c_string).transpose()?,
+        flags,
+        data: data.map(c_string).transpose()?,
+    })
+}
+
+fn c_string(value: impl AsRef<OsStr>) -> anyhow::Result<CString> {
+    CString::new(value.as_ref().as_bytes())
+        .with_context(|| format!("Invalid path {:?}", value.as_ref()))
+}
+
+/// Escapes the characters separating overlayfs options and layers
+fn escape_overlay_path(path: &Path) -> anyhow::Result<String> {
+    let Some(path) = path.to_str() else {
+        return Err(FlatboxError::NonUtf8Path {
+            path: path.to_owned(),
+        }
+        .into());
+    };
+    Ok(path
+        .chars()
+        .flat_map(|c| {
+            let escape = matches!(c, ',' | ':' | '\\').then_some('\\');
+            escape.into_iter().chain([c])
+        })
+        .collect())
+}
+
+impl Step {
+    fn run(&self) -> io::Result<()> {
+        let ok = |ret: libc::c_int| ret == 0;
+        let ok_or_exists = |ret: libc::c_int| {
+            ret == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EEXIST)
+        };
+
+        unsafe {
+            match self {
+                Step::Mkdir { path, mode } => {
+                    if !ok_or_exists(libc::mkdir(path.as_ptr(), *mode)) {
+                        return Err(report("create directory", Some(path)));
+                    }
+                    // The mode isn't masked by the umask, like with bwrap's --perms
+                    if *mode != 0o755 && !ok(libc::chmod(path.as_ptr(), *mode)) {
+                        return Err(report("set the permissions of", Some(path)));
+                    }
+                }
+                Step::Touch { path } => {
+                    // Like bwrap, an existing file is used as is, it can be on a read-only mount
+                    let mut stat: libc::stat = mem::zeroed();
+                    if ok(libc::stat(path.as_ptr(), &mut stat))
+                        && stat.st_mode & libc::S_IFMT != libc::S_IFDIR
+                    {
+                        return Ok(());
+                    }
+                    let fd = libc::open(
+                        path.as_ptr(),
+                        libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC | libc::O_NOCTTY,
+                        0o444,
+                    );
+                    if fd < 0 {
+                        return Err(report("create file", Some(path)));
+                    }
+                    libc::close(fd);
+                }
+                Step::Mount {
+                    source,
+                    dest,
+                    fstype,
+                    flags,
+                    data,
+                } => {
+                    let as_ptr = |value: &Option<CString>| {
+                        value.as_ref().map_or(ptr::null(), |value| value.as_ptr())
+                    };
+                    if !ok(libc::mount(
+                        as_ptr(source),
+                        dest.as_ptr(),
+                        as_ptr(fstype),
+                        *flags,
+                        as_ptr(data).cast(),
+                    )) {
+                        return Err(report("mount", Some(dest)));
+                    }
+                }
+                Step::SetAttr { path, attr } => set_attr(path, *attr)?,
+                Step::Proc { dest, fallback } => {
+                    let flags = libc::MS_NOSUID | libc::MS_NOEXEC | libc::MS_NODEV;
+                    if !ok(libc::mount(
+                        c"proc".as_ptr(),
+                        dest.as_ptr(),
+                        c"proc".as_ptr(),
+                        flags,
+                        ptr::null(),
+                    )) && !ok(libc::mount(
+                        fallback.as_ptr(),
+                        dest.as_ptr(),
+                        ptr::null(),
+                        libc::MS_BIND | libc::MS_REC,
+                        ptr::null(),
+                    )) {
+                        return Err(report("mount proc on", Some(dest)));
+                    }
+                }
+                Step::Symlink { target, dest } => {
+                    if !ok_or_exists(libc::symlink(target.as_ptr(), dest.as_ptr())) {
+                        return Err(report("create symlink", Some(dest)));
+                    }
+                }
+                Step::WriteFile { path, contents } => {
+                    let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    if fd < 0 {
+                        return Err(report("open", Some(path)));
+                    }
+                    let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+                    if written < 0 {
+                        let err = report("write", Some(path));
+                        libc::close(fd);
+                        return Err(err);
+                    }
+                    libc::close(fd);
+                }
+                Step::Chdir { path } => {
+                    if !ok(libc::chdir(path.as_ptr())) {
+                        return Err(report("change directory to", Some(path)));
+                    }
+                }
+                Step::PivotRoot { new_root, put_old } => {
+                    if libc::syscall(libc::SYS_pivot_root, new_root.as_ptr(), put_old.as_ptr()) != 0
+                    {
+                        return Err(report("pivot the root to", Some(new_root)));
+                    }
+                }
+                Step::Umount { path } => {
+                    if !ok(libc::umount2(path.as_ptr(), libc::MNT_DETACH)) {
+                        return Err(report("unmount", Some(path)));
+                    }
+                }
+                Step::LoopbackUp => loopback_up()?,
+                Step::NewSession => {
+                    if libc::setsid() < 0 {
+                        return Err(report("start a new session", None));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+unsafe fn set_attr(path: &CStr, attr: u64) -> io::Result<()> {
+    let mount_attr = libc::mount_attr {
+        attr_set: attr,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            libc::AT_RECURSIVE,
+            &mount_attr as *const libc::mount_attr,
+            mem::size_of::<libc::mount_attr>(),
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    if io::Error::last_os_error().raw_os_error() != Some(libc::ENOSYS) {
+        return Err(report("set the mount attributes of", Some(path)));
+    }
+
+    // Kernels before 5.12 can only remount the top mount, which has to keep its locked flags
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(report("stat", Some(path)));
+    }
+    let kept_flags = [
+        (libc::ST_RDONLY, libc::MS_RDONLY),
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (ST_RELATIME, libc::MS_RELATIME),
+    ];
+    let mut flags = libc::MS_REMOUNT | libc::MS_BIND;
+    for (st_flag, ms_flag) in kept_flags {
+        if stat.f_flag & st_flag != 0 {
+            flags |= ms_flag;
+        }
+    }
+    if attr & libc::MOUNT_ATTR_RDONLY != 0 {
+        flags |= libc::MS_RDONLY;
+    }
+    if attr & libc::MOUNT_ATTR_NODEV != 0 {
+        flags |= libc::MS_NODEV;
+    }
+    let ret = unsafe { libc::mount(ptr::null(), path.as_ptr(), ptr::null(), flags, ptr::null()) };
+    if ret != 0 {
+        return Err(report("remount", Some(path)));
+    }
+    Ok(())
+}
+
+unsafe fn loopback_up() -> io::Result<()> {
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(report("create a socket for the loopback interface", None));
+        }
+        let mut request: libc::ifreq = mem::zeroed();
+        for (dest, byte) in request.ifr_name.iter_mut().zip(b"lo") {
+            *dest = *byte as libc::c_char;
+        }
+        let result = if libc::ioctl(fd, libc::SIOCGIFFLAGS, &mut request) != 0 {
+            Err(report("get the flags of the loopback interface", None))
+        } else {
+            request.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+            if libc::ioctl(fd, libc::SIOCSIFFLAGS, &request) != 0 {
+                Err(report("bring up the loopback interface", None))
+            } else {
+                Ok(())
+            }
+        };
+        libc::close(fd);
+        result
+    }
+}
+
+/// Prints which setup step failed, as only the error code gets passed to the parent.
+/// Only writes to stderr directly, as the child can't allocate.
+pub fn report(action: &str, path: Option<&CStr>) -> io::Error {
+    let err = io::Error::last_os_error();
+    let write = |bytes: &[u8]| unsafe {
+        libc::write(libc::STDERR_FILENO, bytes.as_ptr().cast(), bytes.len());
+    };
+    write(b"flatbox: could not ");
+    write(action.as_bytes());
+    if let Some(path) = path {
+        write(b" ");
+        write(path.to_bytes());
+    }
+    write(b"\n");
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::native::escape_overlay_path;
+    use std::path::Path;
+
+    #[test]
+    fn overlay_path_escaping() {
+        assert_eq!(
+            escape_overlay_path(Path::new("/oldroot/a,b:c\\d")).unwrap(),
+            "/oldroot/a\\,b\\:c\\\\d"
+        );
+    }
+}
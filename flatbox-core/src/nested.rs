@@ -0,0 +1,64 @@
+use crate::error::FlatboxError;
+use std::{env, fmt, path::Path};
+
+/// Present in Flatpak sandboxes, and in flatbox ones for the apps checking for it
+const FLATPAK_INFO_PATH: &str = "/.flatpak-info";
+
+/// The sandbox flatbox was started in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OuterSandbox {
+    /// The Flatpak sandbox of an app
+    Flatpak(String),
+    Flatbox,
+}
+
+impl fmt::Display for OuterSandbox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OuterSandbox::Flatpak(app_id) => write!(f, "the Flatpak sandbox of {app_id}"),
+            OuterSandbox::Flatbox => write!(f, "a flatbox sandbox"),
+        }
+    }
+}
+
+/// The sandbox flatbox runs in, if any. The variables alone aren't trusted, as they may be passed on to
+/// commands run on the host.
+pub fn outer_sandbox() -> Option<OuterSandbox> {
+    if !Path::new(FLATPAK_INFO_PATH).exists() {
+        return None;
+    }
+    if env::var_os("FLATBOX_ENV").is_some_and(|value| value == "1") {
+        return Some(OuterSandbox::Flatbox);
+    }
+    env::var("FLATPAK_ID")
+        .ok()
+        .filter(|app_id| !app_id.is_empty())
+        .map(OuterSandbox::Flatpak)
+}
+
+/// Whether a new user namespace can be created, which every backend needs for a sandbox inside of another
+/// one (setuid bwrap doesn't work there either, as sandboxes don't allow gaining privileges). Flatpak's
+/// seccomp filter forbids it, a flatbox sandbox allows it when the host does.
+pub fn can_create_user_namespace() -> bool {
+    match unsafe { libc::fork() } {
+        -1 => false,
+        0 => unsafe { libc::_exit(i32::from(libc::unshare(libc::CLONE_NEWUSER) != 0)) },
+        pid => {
+            let mut status = 0;
+            let waited = unsafe { libc::waitpid(pid, &mut status, 0) };
+            waited == pid && libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+        }
+    }
+}
+
+/// Checks that a sandbox can be created when flatbox runs in one already, instead of failing in bwrap
+pub fn check_nesting() -> Result<(), FlatboxError> {
+    let Some(outer) = outer_sandbox() else {
+        return Ok(());
+    };
+    if !can_create_user_namespace() {
+        return Err(FlatboxError::NestedSandbox { outer });
+    }
+    crate::info!("Running nested inside of {outer}");
+    Ok(())
+}
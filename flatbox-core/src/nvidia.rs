@@ -0,0 +1,47 @@
+use crate::bwrap::BwrapBuilder;
+use std::{fs, path::Path, process::Command};
+
+const NVIDIA_MODULE_PATH: &str = "/sys/module/nvidia";
+const NVIDIA_UVM_MODULE_PATH: &str = "/sys/module/nvidia_uvm";
+const NVIDIA_UVM_DEVICE: &str = "/dev/nvidia-uvm";
+
+pub fn is_driver_loaded() -> bool {
+    Path::new(NVIDIA_MODULE_PATH).exists()
+}
+
+/// Version of the loaded kernel driver, e.g. `550.78`
+pub fn driver_version() -> Option<String> {
+    fs::read_to_string(Path::new(NVIDIA_MODULE_PATH).join("version"))
+        .ok()
+        .map(|version| version.trim().to_owned())
+}
+
+/// Makes sure the device nodes used by the proprietary driver exist.
+/// When the sandbox uses a private `/dev`, the nodes are also bound into it.
+pub fn setup_devices(bwrap: &mut BwrapBuilder, private_dev: bool) {
+    // The uvm node (needed for CUDA) is only created on demand by the setuid nvidia-modprobe helper,
+    // which can't run inside of the sandbox
+    if !Path::new(NVIDIA_UVM_DEVICE).exists() {
+        let result = Command::new("nvidia-modprobe")
+            .args(["-u", "-c=0"])
+            .status();
+        if !result.is_ok_and(|status| status.success())
+            && Path::new(NVIDIA_UVM_MODULE_PATH).exists()
+        {
+            crate::warn!("Could not create {NVIDIA_UVM_DEVICE}, CUDA may not be available");
+        }
+    }
+
+    if private_dev && let Ok(entries) = fs::read_dir("/dev") {
+        for entry in entries.flatten() {
+            if entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with("nvidia"))
+            {
+                let path = entry.path();
+                bwrap.dev_bind(&path, &path);
+            }
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use crate::{
+    cgroup::CPU_PERIOD_US,
+    plan::{BindMode, MountOp, SandboxPlan},
+};
+use anyhow::Context;
+use std::{
+    ffi::OsStr,
+    fmt::Write,
+    fs,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+
+const OCI_VERSION: &str = "1.0.2";
+const ROOTFS_DIR: &str = "rootfs";
+/// Capabilities given to the process with the `devel` feature, for debuggers and profilers
+const DEVEL_CAPABILITIES: [&str; 2] = ["CAP_SYS_PTRACE", "CAP_PERFMON"];
+pub(crate) const DATA_DIR: &str = "data";
+
+/// The subset of JSON needed to write the runtime config
+#[derive(Debug)]
+pub(crate) enum Json {
+    Bool(bool),
+    Number(u64),
+    Integer(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    pub(crate) fn string(value: impl AsRef<OsStr>) -> Self {
+        Self::String(value.as_ref().to_string_lossy().into_owned())
+    }
+
+    pub(crate) fn strings<T: AsRef<OsStr>>(values: impl IntoIterator<Item = T>) -> Self {
+        Self::Array(values.into_iter().map(Self::string).collect())
+    }
+
+    pub(crate) fn write(&self, out: &mut String, indent: usize) {
+        let pad = "  ".repeat(indent + 1);
+        match self {
+            Json::Bool(value) => write!(out, "{value}").unwrap(),
+            Json::Number(value) => write!(out, "{value}").unwrap(),
+            Json::Integer(value) => write!(out, "{value}").unwrap(),
+            Json::String(value) => write_json_string(out, value),
+            Json::Array(values) if values.is_empty() => out.push_str("[]"),
+            Json::Array(values) => {
+                out.push_str("[\n");
+                for (i, value) in values.iter().enumerate() {
+                    out.push_str(&pad);
+                    value.write(out, indent + 1);
+                    out.push_str(if i + 1 < values.len() { ",\n" } else { "\n" });
+                }
+                write!(out, "{}]", "  ".repeat(indent)).unwrap();
+            }
+            Json::Object(fields) => {
+                out.push_str("{\n");
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    out.push_str(&pad);
+                    write_json_string(out, key);
+                    out.push_str(": ");
+                    value.write(out, indent + 1);
+                    out.push_str(if i + 1 < fields.len() { ",\n" } else { "\n" });
+                }
+                write!(out, "{}}}", "  ".repeat(indent)).unwrap();
+            }
+        }
+    }
+}
+
+pub fn write_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if u32::from(c) < 0x20 => write!(out, "\\u{:04x}", u32::from(c)).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Writes an OCI runtime bundle running `argv` in the sandbox of the plan.
+///
+/// The sandbox paths which OCI mounts can't express (directories, symlinks and the tmpfs mounts containing them)
+/// are created in the bundle's rootfs instead, and the generated files are copied next to it,
+/// so the bundle stays usable after flatbox exits.
+pub fn export_bundle(
+    out: &Path,
+    plan: &SandboxPlan,
+    setup_dir: &Path,
+    argv: &[String],
+) -> anyhow::Result<()> {
+    fs::create_dir_all(out).with_context(|| format!("Could not create {}", out.display()))?;
+    // The runtimes don't consistently resolve relative mount sources against the bundle
+    let out =
+        &fs::canonicalize(out).with_context(|| format!("Could not resolve {}", out.display()))?;
+
+    let mut mounts = Vec::new();
+    for (i, op) in plan
+        .create_rootfs(&out.join(ROOTFS_DIR))?
+        .into_iter()
+        .enumerate()
+    {
+        match op {
+            MountOp::Bind { source, dest, mode } => {
+                let Some(source) = bundle_source(out, source, setup_dir)? else {
+                    crate::warn!(
+                        "Skipping {}, it's only available while flatbox is running",
+                        dest.display()
+                    );
+                    continue;
+                };
+                let options: &[&str] = match mode {
+                    BindMode::ReadWrite => &["rbind", "nodev"],
+                    BindMode::ReadOnly => &["rbind", "ro", "nodev"],
+                    BindMode::Device => &["rbind"],
+                };
+                mounts.push(mount(dest, "bind", &source, options));
+            }
+            MountOp::Tmpfs { dest, perms } => {
+                let mode = format!("mode={:04o}", perms.unwrap_or(0o755));
+                mounts.push(mount(dest, "tmpfs", "tmpfs", &["nosuid", "nodev", &mode]));
+            }
+            MountOp::Dev { dest } => {
+                // The runtime creates the default device nodes
+                mounts.push(mount(dest, "tmpfs", "tmpfs", &["nosuid", "mode=0755"]));
+                mounts.push(mount(
+                    &dest.join("pts"),
+                    "devpts",
+                    "devpts",
+                    &[
+                        "nosuid",
+                        "noexec",
+                        "newinstance",
+                        "ptmxmode=0666",
+                        "mode=0620",
+                    ],
+                ));
+                mounts.push(mount(
+                    &dest.join("shm"),
+                    "tmpfs",
+                    "shm",
+                    &["nosuid", "nodev", "mode=1777"],
+                ));
+            }
+            MountOp::Proc { dest } => {
+                mounts.push(mount(dest, "proc", "proc", &["nosuid", "noexec", "nodev"]));
+            }
+            MountOp::TmpOverlay { source, dest } => {
+                let overlay_dir = out.join(format!("overlay-{i}"));
+                let (upper, work) = (overlay_dir.join("upper"), overlay_dir.join("work"));
+                for dir in [&upper, &work] {
+                    fs::create_dir_all(dir)
+                        .with_context(|| format!("Could not create {}", dir.display()))?;
+                }
+                mounts.push(mount(
+                    dest,
+                    "overlay",
+                    "overlay",
+                    &[
+                        &format!("lowerdir={}", source.display()),
+                        &format!("upperdir={}", upper.display()),
+                        &format!("workdir={}", work.display()),
+                    ],
+                ));
+            }
+            MountOp::Overlay {
+                source,
+                upper,
+                work,
+                dest,
+            } => {
+                mounts.push(mount(
+                    dest,
+                    "overlay",
+                    "overlay",
+                    &[
+                        &format!("lowerdir={}", source.display()),
+                        &format!("upperdir={}", upper.display()),
+                        &format!("workdir={}", work.display()),
+                    ],
+                ));
+            }
+            // Created in the rootfs
+            MountOp::Dir { .. } | MountOp::Symlink { .. } => {}
+        }
+    }
+
+    let config = runtime_config(plan, argv, mounts);
+    let mut contents = String::new();
+    config.write(&mut contents, 0);
+    contents.push('\n');
+    let config_path = out.join("config.json");
+    fs::write(&config_path, contents)
+        .with_context(|| format!("Could not write {}", config_path.display()))
+}
+
+fn runtime_config(plan: &SandboxPlan, argv: &[String], mounts: Vec<Json>) -> Json {
+    let env = plan
+        .environment()
+        .into_iter()
+        .map(|(key, value)| format!("{}={}", key.to_string_lossy(), value.to_string_lossy()));
+
+    let rlimits = plan
+        .rlimits
+        .iter()
+        .map(|rlimit| {
+            Json::Object(vec![
+                ("type", Json::String(rlimit.constant_name())),
+                ("soft", Json::Number(rlimit.soft)),
+                ("hard", Json::Number(rlimit.hard)),
+            ])
+        })
+        .collect();
+
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let mut namespaces = vec!["pid", "mount"];
+    if uid != 0 {
+        namespaces.push("user");
+    }
+    if plan.unshare_net {
+        namespaces.push("network");
+    }
+    if plan.unshare_ipc {
+        namespaces.push("ipc");
+    }
+    let namespaces = namespaces
+        .into_iter()
+        .map(|namespace| Json::Object(vec![("type", Json::string(namespace))]))
+        .collect();
+    // Rootless runtimes can only map the user itself
+    let id_mapping = |id: u32| {
+        Json::Array(vec![Json::Object(vec![
+            ("containerID", Json::Number(id.into())),
+            ("hostID", Json::Number(id.into())),
+            ("size", Json::Number(1)),
+        ])])
+    };
+    let mut linux = vec![("namespaces", Json::Array(namespaces))];
+    if uid != 0 {
+        linux.push(("uidMappings", id_mapping(uid)));
+        linux.push(("gidMappings", id_mapping(gid)));
+    }
+    let mut resources = Vec::new();
+    if let Some(size) = plan.limits.memory_max {
+        resources.push((
+            "memory",
+            Json::Object(vec![("limit", Json::Number(size.0))]),
+        ));
+    }
+    if let Some(quota) = plan.limits.cpu_quota {
+        resources.push((
+            "cpu",
+            Json::Object(vec![
+                ("quota", Json::Number(quota.quota_us())),
+                ("period", Json::Number(CPU_PERIOD_US)),
+            ]),
+        ));
+    }
+    if let Some(pids) = plan.limits.pids_max {
+        resources.push((
+            "pids",
+            Json::Object(vec![("limit", Json::Number(pids.into()))]),
+        ));
+    }
+    if !resources.is_empty() {
+        linux.push(("resources", Json::Object(resources)));
+    }
+
+    let mut process = vec![
+        ("terminal", Json::Bool(false)),
+        (
+            "user",
+            Json::Object(vec![
+                ("uid", Json::Number(uid.into())),
+                ("gid", Json::Number(gid.into())),
+            ]),
+        ),
+        ("args", Json::strings(argv)),
+        ("rlimits", Json::Array(rlimits)),
+        ("env", Json::strings(env)),
+        (
+            "cwd",
+            Json::string(plan.chdir.as_deref().unwrap_or(Path::new("/"))),
+        ),
+    ];
+    if let Some(oom_score_adj) = plan.priority.oom_score_adj {
+        process.push(("oomScoreAdj", Json::Integer(oom_score_adj.into())));
+    }
+    if plan.devel {
+        let capabilities = ["bounding", "effective", "permitted"]
+            .into_iter()
+            .map(|set| (set, Json::strings(DEVEL_CAPABILITIES)))
+            .collect();
+        process.push(("capabilities", Json::Object(capabilities)));
+    }
+    if plan.priority.nice.is_some() || plan.priority.io.is_some() {
+        crate::warn!(
+            "The bundle can't set the nice value and I/O priority, set them when running it"
+        );
+    }
+
+    Json::Object(vec![
+        ("ociVersion", Json::string(OCI_VERSION)),
+        ("process", Json::Object(process)),
+        (
+            "root",
+            Json::Object(vec![
+                ("path", Json::string(ROOTFS_DIR)),
+                ("readonly", Json::Bool(false)),
+            ]),
+        ),
+        ("mounts", Json::Array(mounts)),
+        ("linux", Json::Object(linux)),
+    ])
+}
+
+fn mount(dest: &Path, kind: &str, source: impl AsRef<OsStr>, options: &[&str]) -> Json {
+    Json::Object(vec![
+        ("destination", Json::string(dest)),
+        ("type", Json::string(kind)),
+        ("source", Json::string(source)),
+        ("options", Json::strings(options)),
+    ])
+}
+
+/// Source of a bind mount in the bundle. Generated files are copied into the bundle, while
+/// the sockets in the setup dir (such as D-Bus proxies) go away with flatbox and are skipped.
+pub(crate) fn bundle_source(
+    out: &Path,
+    source: &Path,
+    setup_dir: &Path,
+) -> anyhow::Result<Option<PathBuf>> {
+    let Ok(name) = source.strip_prefix(setup_dir) else {
+        return Ok(Some(source.to_owned()));
+    };
+    let metadata = fs::symlink_metadata(source)
+        .with_context(|| format!("Could not read {}", source.display()))?;
+    if metadata.file_type().is_socket() || !metadata.is_file() {
+        return Ok(None);
+    }
+
+    let data_dir = out.join(DATA_DIR);
+    fs::create_dir_all(&data_dir)
+        .with_context(|| format!("Could not create {}", data_dir.display()))?;
+    let copy = data_dir.join(name);
+    fs::copy(source, &copy).with_context(|| format!("Could not copy {}", source.display()))?;
+    Ok(Some(copy))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::oci::Json;
+
+    #[test]
+    fn json_output() {
+        let value = Json::Object(vec![
+            ("args", Json::strings(["sh", "-c", "echo \"hi\"\n"])),
+            ("terminal", Json::Bool(false)),
+            ("mounts", Json::Array(Vec::new())),
+        ]);
+        let mut out = String::new();
+        value.write(&mut out, 0);
+        assert_eq!(
+            out,
+            r#"{
+  "args": [
+    "sh",
+    "-c",
+    "echo \"hi\"\n"
+  ],
+  "terminal": false,
+  "mounts": []
+}"#
+        );
+    }
+}
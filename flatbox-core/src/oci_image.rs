@@ -0,0 +1,710 @@
+use crate::{cache, error::FlatboxError, gc, image::oci_arch, sha256};
+use anyhow::Context;
+use std::{
+    fmt::Write,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+use tempdir::TempDir;
+
+/// The root filesystems unpacked from images, by the digest of their manifest (or config for docker archives)
+pub(crate) const IMAGES_DIR: &str = "images";
+const ROOTFS_DIR: &str = "rootfs";
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Hides the contents of the dir it's in from the lower layers
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+const INDEX_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// An image as given to `--oci-image`, in the transport syntax of skopeo and podman
+#[derive(Debug, PartialEq, Eq)]
+enum ImageSource<'a> {
+    /// An OCI image layout dir, `oci:PATH[:REF]`
+    Layout { path: PathBuf, tag: Option<&'a str> },
+    /// A tarball of `docker save` or `podman save`, `docker-archive:PATH[:NAME:TAG]`
+    DockerArchive { path: PathBuf, tag: Option<&'a str> },
+}
+
+impl<'a> ImageSource<'a> {
+    fn parse(source: &'a str) -> anyhow::Result<Self> {
+        let Some((transport, rest)) = source.split_once(':') else {
+            return Err(FlatboxError::InvalidImageReference {
+                reference: source.to_owned(),
+            }
+            .into());
+        };
+        let (path, tag) = split_reference(rest, |path| path.exists());
+        match transport {
+            "oci" => Ok(Self::Layout { path, tag }),
+            "docker-archive" => Ok(Self::DockerArchive { path, tag }),
+            _ => Err(FlatboxError::InvalidImageReference {
+                reference: source.to_owned(),
+            }
+            .into()),
+        }
+    }
+}
+
+/// Splits `PATH[:REF]` at the first colon before which the path exists, as both can contain colons
+fn split_reference(rest: &str, exists: impl Fn(&Path) -> bool) -> (PathBuf, Option<&str>) {
+    if exists(Path::new(rest)) {
+        return (PathBuf::from(rest), None);
+    }
+    rest.match_indices(':')
+        .map(|(index, _)| (&rest[..index], &rest[index + 1..]))
+        .find(|(path, _)| exists(Path::new(path)))
+        .map_or_else(
+            || (PathBuf::from(rest), None),
+            |(path, tag)| (PathBuf::from(path), Some(tag).filter(|tag| !tag.is_empty())),
+        )
+}
+
+/// The parts of an image needed to unpack it
+struct Image {
+    /// Identifies the image in the cache
+    digest: String,
+    /// Layer tarballs, lowest first
+    layers: Vec<PathBuf>,
+    config: PathBuf,
+    /// The docker archive the layers are still to be extracted from, to the dir they're expected in
+    archive: Option<(PathBuf, PathBuf)>,
+}
+
+/// Unpacks the root filesystem of an image into the cache, unless it already is, and returns it. The
+/// environment variables of the image config are written to the `metadata` of the rootfs, like the ones of
+/// a runtime. Multi-arch images are resolved for `arch`, in flatpak's naming.
+pub fn unpack(source: &str, arch: &str) -> anyhow::Result<PathBuf> {
+    let tempdir = TempDir::new(&gc::temp_prefix("image")).context("Could not create tempdir")?;
+    let image = match ImageSource::parse(source)? {
+        ImageSource::Layout { path, tag } => layout_image(&path, tag, arch)?,
+        ImageSource::DockerArchive { path, tag } => {
+            docker_archive_image(&path, tag, tempdir.path())?
+        }
+    };
+
+    let images_dir = cache::cache_dir()
+        .context("Could not determine the cache dir")?
+        .join(IMAGES_DIR);
+    let image_dir = images_dir.join(&image.digest);
+    let rootfs = image_dir.join(ROOTFS_DIR);
+    if rootfs.is_dir() {
+        crate::debug!("Using the rootfs of {source} at {}", rootfs.display());
+        gc::touch(&image_dir);
+        return Ok(rootfs);
+    }
+
+    crate::info!("Unpacking {source} to {}", rootfs.display());
+    // Renamed into place once complete, so an interrupted unpacking isn't used
+    let partial = images_dir.join(format!("{}.{}.tmp", image.digest, process::id()));
+    let partial_rootfs = partial.join(ROOTFS_DIR);
+    fs::create_dir_all(&partial_rootfs)
+        .with_context(|| format!("Could not create {}", partial_rootfs.display()))?;
+    let result = extract_layers(&image)
+        .and_then(|()| {
+            image
+                .layers
+                .iter()
+                .try_for_each(|layer| apply_layer(&partial_rootfs, layer))
+        })
+        .and_then(|()| write_metadata(&partial_rootfs, &image.config));
+    if let Err(err) = result {
+        remove_tree(&partial);
+        return Err(err.context(format!("Could not unpack {source}")));
+    }
+    if let Err(err) = fs::rename(&partial, &image_dir) {
+        // Unpacked concurrently by another launch
+        remove_tree(&partial);
+        if !rootfs.is_dir() {
+            return Err(err)
+                .with_context(|| format!("Could not move the rootfs to {}", rootfs.display()));
+        }
+    }
+    Ok(rootfs)
+}
+
+fn read_json(path: &Path) -> anyhow::Result<Value> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    parse_json(&contents).with_context(|| format!("Could not parse {}", path.display()))
+}
+
+fn blob_path(layout: &Path, digest: &str) -> anyhow::Result<PathBuf> {
+    let Some(("sha256", hex)) = digest.split_once(':') else {
+        return Err(FlatboxError::UnsupportedDigest {
+            digest: digest.to_owned(),
+        }
+        .into());
+    };
+    Ok(layout.join("blobs").join("sha256").join(hex))
+}
+
+/// Finds the manifest of the tag (or the only one) in an OCI image layout
+fn layout_image(layout: &Path, tag: Option<&str>, arch: &str) -> anyhow::Result<Image> {
+    let index = read_json(&layout.join("index.json"))?;
+    let manifests = index.get("manifests").map(Value::items).unwrap_or_default();
+    let mut candidates: Vec<&Value> = manifests
+        .iter()
+        .filter(|manifest| {
+            tag.is_none_or(|tag| {
+                manifest
+                    .get("annotations")
+                    .and_then(|annotations| annotations.get(REF_NAME_ANNOTATION))
+                    .and_then(Value::as_str)
+                    == Some(tag)
+            })
+        })
+        .collect();
+    let descriptor = match candidates.len() {
+        1 => candidates.remove(0),
+        0 => {
+            return Err(FlatboxError::ImageNotFound {
+                path: layout.to_owned(),
+                tag: tag.map(str::to_owned),
+            }
+            .into());
+        }
+        _ => {
+            return Err(FlatboxError::AmbiguousImage {
+                path: layout.to_owned(),
+                reference: format!("oci:{}:TAG", layout.display()),
+            }
+            .into());
+        }
+    };
+
+    let manifest = read_manifest(layout, descriptor)?;
+    let media_type = manifest
+        .get("mediaType")
+        .or_else(|| descriptor.get("mediaType"))
+        .and_then(Value::as_str);
+    if !media_type.is_some_and(|media_type| INDEX_MEDIA_TYPES.contains(&media_type)) {
+        return manifest_image(layout, descriptor, &manifest);
+    }
+
+    // A multi-arch image, the manifest of the architecture is in the layout too
+    let platform_descriptor = manifest
+        .get("manifests")
+        .map(Value::items)
+        .unwrap_or_default()
+        .iter()
+        .find(|manifest| {
+            let platform = manifest.get("platform");
+            platform
+                .and_then(|platform| platform.get("architecture"))
+                .and_then(Value::as_str)
+                == Some(oci_arch(arch))
+                && platform
+                    .and_then(|platform| platform.get("os"))
+                    .and_then(Value::as_str)
+                    .is_none_or(|os| os == "linux")
+        })
+        .with_context(|| format!("The image has no manifest for {arch}"))?;
+    let platform_manifest = read_manifest(layout, platform_descriptor)?;
+    manifest_image(layout, platform_descriptor, &platform_manifest)
+}
+
+fn read_manifest(layout: &Path, descriptor: &Value) -> anyhow::Result<Value> {
+    let digest = descriptor
+        .get("digest")
+        .and_then(Value::as_str)
+        .context("The manifest has no digest")?;
+    read_json(&blob_path(layout, digest)?)
+}
+
+fn manifest_image(layout: &Path, descriptor: &Value, manifest: &Value) -> anyhow::Result<Image> {
+    let digest = descriptor
+        .get("digest")
+        .and_then(Value::as_str)
+        .context("The manifest has no digest")?;
+    let config = manifest
+        .get("config")
+        .and_then(|config| config.get("digest"))
+        .and_then(Value::as_str)
+        .context("The manifest has no config")?;
+    let layers = manifest
+        .get("layers")
+        .map(Value::items)
+        .unwrap_or_default()
+        .iter()
+        .map(|layer| {
+            let digest = layer
+                .get("digest")
+                .and_then(Value::as_str)
+                .context("A layer has no digest")?;
+            blob_path(layout, digest)
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Image {
+        digest: digest.replace(':', "-"),
+        layers,
+        config: blob_path(layout, config)?,
+        archive: None,
+    })
+}
+
+/// Finds the image of the tag (or the only one) in a docker archive. Its manifest and config are extracted to
+/// `dir`, the layers only once they're needed.
+fn docker_archive_image(archive: &Path, tag: Option<&str>, dir: &Path) -> anyhow::Result<Image> {
+    tar(archive, dir, &["manifest.json"])?;
+    let manifest = read_json(&dir.join("manifest.json"))?;
+    let images = manifest.items();
+    let image = match tag {
+        Some(tag) => images
+            .iter()
+            .find(|image| {
+                image
+                    .get("RepoTags")
+                    .map(Value::items)
+                    .unwrap_or_default()
+                    .iter()
+                    .any(|repo_tag| repo_tag.as_str() == Some(tag))
+            })
+            .ok_or_else(|| FlatboxError::ImageNotFound {
+                path: archive.to_owned(),
+                tag: Some(tag.to_owned()),
+            })?,
+        None => match images {
+            [image] => image,
+            _ => {
+                return Err(FlatboxError::AmbiguousImage {
+                    path: archive.to_owned(),
+                    reference: format!("docker-archive:{}:NAME:TAG", archive.display()),
+                }
+                .into());
+            }
+        },
+    };
+    let config_member = image
+        .get("Config")
+        .and_then(Value::as_str)
+        .context("The image has no config")?;
+    tar(archive, dir, &[config_member])?;
+    let config = dir.join(config_member);
+    // The image id, which is the digest of its config
+    let (digest, _) = sha256::file_digest(&config)
+        .with_context(|| format!("Could not read {}", config.display()))?;
+    let layers = image
+        .get("Layers")
+        .map(Value::items)
+        .unwrap_or_default()
+        .iter()
+        .map(|layer| Ok(dir.join(layer.as_str().context("Invalid layer")?)))
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Image {
+        digest: format!("sha256-{digest}"),
+        layers,
+        config,
+        archive: Some((archive.to_owned(), dir.to_owned())),
+    })
+}
+
+fn extract_layers(image: &Image) -> anyhow::Result<()> {
+    let Some((archive, dir)) = &image.archive else {
+        return Ok(());
+    };
+    let members: Vec<&str> = image
+        .layers
+        .iter()
+        .filter_map(|layer| layer.strip_prefix(dir).ok()?.to_str())
+        .collect();
+    tar(archive, dir, &members)
+}
+
+/// Runs `tar --extract`, which detects the compression of the layers by itself. `args` are options and the
+/// members to extract.
+fn tar(archive: &Path, dir: &Path, args: &[&str]) -> anyhow::Result<()> {
+    let status = Command::new("tar")
+        .args(["--extract", "--no-same-owner", "--preserve-permissions"])
+        .arg("--file")
+        .arg(archive)
+        .arg("--directory")
+        .arg(dir)
+        .args(args)
+        .status()
+        .context("Could not run tar")?;
+    if !status.success() {
+        return Err(FlatboxError::ToolFailed {
+            tool: "tar".to_owned(),
+            action: format!("extract {}", archive.display()),
+            details: status.to_string(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Extracts a layer over the lower ones, removing the files its whiteouts delete
+fn apply_layer(rootfs: &Path, layer: &Path) -> anyhow::Result<()> {
+    crate::debug!("Applying layer {}", layer.display());
+    let output = Command::new("tar")
+        .arg("--list")
+        .arg("--file")
+        .arg(layer)
+        .output()
+        .context("Could not run tar")?;
+    if !output.status.success() {
+        return Err(FlatboxError::ToolFailed {
+            tool: "tar".to_owned(),
+            action: format!("read {}", layer.display()),
+            details: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        }
+        .into());
+    }
+    for entry in String::from_utf8_lossy(&output.stdout).lines() {
+        let path = Path::new(entry.trim_start_matches("./"));
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let dir = rootfs.join(path.parent().unwrap_or(Path::new("")));
+        if name == OPAQUE_WHITEOUT {
+            for lower in fs::read_dir(&dir).into_iter().flatten().flatten() {
+                remove_tree(&lower.path());
+            }
+        } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            remove_tree(&dir.join(hidden));
+        }
+    }
+
+    // Device nodes can't be created without privileges, the sandbox gets its own /dev anyway
+    tar(
+        layer,
+        rootfs,
+        &[
+            "--anchored",
+            "--exclude=dev/*",
+            "--exclude=./dev/*",
+            "--no-anchored",
+            &format!("--exclude={WHITEOUT_PREFIX}*"),
+        ],
+    )?;
+    make_writable(rootfs)
+}
+
+/// Adds write permission for the user to the dirs, so the next layers can change them
+fn make_writable(dir: &Path) -> anyhow::Result<()> {
+    let metadata =
+        fs::symlink_metadata(dir).with_context(|| format!("Could not read {}", dir.display()))?;
+    if !metadata.is_dir() {
+        return Ok(());
+    }
+    let mode = metadata.permissions().mode();
+    if mode & 0o700 != 0o700 {
+        fs::set_permissions(dir, fs::Permissions::from_mode(mode | 0o700))
+            .with_context(|| format!("Could not set the permissions of {}", dir.display()))?;
+    }
+    for entry in fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))? {
+        make_writable(&entry?.path())?;
+    }
+    Ok(())
+}
+
+fn remove_tree(path: &Path) {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => return,
+    };
+    if let Err(err) = result {
+        crate::warn!("Could not remove {}: {err}", path.display());
+    }
+}
+
+/// Writes the environment of the image config as the `metadata` read for a rootfs
+fn write_metadata(rootfs: &Path, config: &Path) -> anyhow::Result<()> {
+    let config = read_json(config)?;
+    let env = config
+        .get("config")
+        .and_then(|config| config.get("Env"))
+        .map(Value::items)
+        .unwrap_or_default();
+    let mut metadata = String::from("[Environment]\n");
+    for var in env.iter().filter_map(Value::as_str) {
+        if let Some((key, value)) = var.split_once('=')
+            && !value.contains('\n')
+        {
+            writeln!(metadata, "{key}={value}").unwrap();
+        }
+    }
+    let path = rootfs.join("metadata");
+    fs::write(&path, metadata).with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// A parsed JSON document, for the manifests and configs of images
+#[derive(Debug, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The values of an array, none for anything else
+    fn items(&self) -> &[Value] {
+        match self {
+            Value::Array(values) => values,
+            _ => &[],
+        }
+    }
+}
+
+fn parse_json(source: &str) -> anyhow::Result<Value> {
+    let mut parser = JsonParser {
+        source: source.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.source.len() {
+        return Err(parser.error("Trailing characters").into());
+    }
+    Ok(value)
+}
+
+struct JsonParser<'a> {
+    source: &'a [u8],
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn error(&self, message: &str) -> FlatboxError {
+        FlatboxError::InvalidJson {
+            offset: self.pos,
+            message: message.to_owned(),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .source
+            .get(self.pos)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.source.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), FlatboxError> {
+        if self.peek() != Some(byte) {
+            return Err(self.error(&format!("Expected '{}'", byte as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, literal: &str, value: Value) -> Result<Value, FlatboxError> {
+        if !self.source[self.pos..].starts_with(literal.as_bytes()) {
+            return Err(self.error("Invalid value"));
+        }
+        self.pos += literal.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value, FlatboxError> {
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(fields));
+                }
+                loop {
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((key, self.value()?));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b'}')?;
+                Ok(Value::Object(fields))
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b']')?;
+                Ok(Value::Array(values))
+            }
+            Some(b'"') => self.string().map(Value::String),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(_) => {
+                let start = self.pos;
+                while self
+                    .source
+                    .get(self.pos)
+                    .is_some_and(|byte| b"+-.eE".contains(byte) || byte.is_ascii_digit())
+                {
+                    self.pos += 1;
+                }
+                std::str::from_utf8(&self.source[start..self.pos])
+                    .ok()
+                    .and_then(|number| number.parse().ok())
+                    .map(Value::Number)
+                    .ok_or_else(|| FlatboxError::InvalidJson {
+                        offset: start,
+                        message: "Invalid value".to_owned(),
+                    })
+            }
+            None => Err(self.error("Unexpected end of the document")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, FlatboxError> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            let Some(&byte) = self.source.get(self.pos) else {
+                return Err(self.error("Unterminated string"));
+            };
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.source.get(self.pos).copied();
+                    self.pos += 1;
+                    let unescaped = match escape {
+                        Some(b'n') => '\n',
+                        Some(b't') => '\t',
+                        Some(b'r') => '\r',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'u') => {
+                            let mut code = self.hex4()?;
+                            // A surrogate pair encodes a character outside the basic plane
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.source[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                code =
+                                    0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00));
+                            }
+                            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        Some(byte) => byte as char,
+                        None => return Err(self.error("Unterminated string")),
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(unescaped.encode_utf8(&mut buf).as_bytes());
+                }
+                byte => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("Invalid UTF-8 in string"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, FlatboxError> {
+        let code = self
+            .source
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("Invalid escape"))?;
+        self.pos += 4;
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::FlatboxError,
+        oci_image::{Value, parse_json, split_reference},
+    };
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn parses_json() {
+        let value = parse_json(
+            r#"{"schemaVersion": 2, "manifests": [{"digest": "sha256:ab", "size": 1.5e3,
+                "annotations": {"org.opencontainers.image.ref.name": "latest"}}],
+                "escaped": "tab\té😀\"", "empty": [], "flags": [true, false, null]}"#,
+        )
+        .unwrap();
+        let manifest = &value.get("manifests").unwrap().items()[0];
+        assert_eq!(
+            manifest.get("digest").and_then(Value::as_str),
+            Some("sha256:ab")
+        );
+        assert_eq!(manifest.get("size"), Some(&Value::Number(1500.0)));
+        assert_eq!(
+            value.get("escaped").and_then(Value::as_str),
+            Some("tab\t\u{e9}\u{1f600}\"")
+        );
+        assert!(value.get("empty").unwrap().items().is_empty());
+        assert_eq!(
+            value.get("flags").unwrap().items(),
+            [Value::Bool(true), Value::Bool(false), Value::Null]
+        );
+        assert!(parse_json("{\"a\": 1,}").is_err());
+        let err = parse_json("[1] 2").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(FlatboxError::InvalidJson { offset: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn splits_references() {
+        let exists = |path: &Path| path == Path::new("/tmp/images:v2/debian.tar");
+        assert_eq!(
+            split_reference("/tmp/images:v2/debian.tar:debian:12", exists),
+            (
+                PathBuf::from("/tmp/images:v2/debian.tar"),
+                Some("debian:12")
+            )
+        );
+        assert_eq!(
+            split_reference("/tmp/images:v2/debian.tar", exists),
+            (PathBuf::from("/tmp/images:v2/debian.tar"), None)
+        );
+        assert_eq!(
+            split_reference("layout:latest", |path| path == Path::new("layout")),
+            (PathBuf::from("layout"), Some("latest"))
+        );
+    }
+}
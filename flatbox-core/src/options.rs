@@ -0,0 +1,421 @@
+use crate::{
+    build::BuildDir,
+    cgroup::{CpuQuota, MemorySize, ResourceLimits},
+    extension_image::ExtensionImage,
+    permissions::{DEVICES, FEATURES, SHARES, SOCKETS},
+    priority::{IoPriority, Priority},
+    rlimit::Rlimit,
+    wrap::Wrapper,
+};
+use anyhow::Context;
+use clap::{Parser, ValueEnum, builder::PossibleValuesParser};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// How the sandbox is set up, with the same options as `flatbox run`
+#[derive(Debug, Default, Parser)]
+pub struct SandboxOptions {
+    /// Flatpak app id (com.example.example) to use as the environment.
+    /// A full or partial ref (com.example.example/x86_64/beta, com.example.example//beta) selects the arch and branch.
+    #[arg(long)]
+    pub app: Option<String>,
+    /// Flatpak runtime id (org.gnome.Platform/x86_64/48) to use as the environment. Mutually exclusive with `--app`.
+    /// The arch and branch can be left out (org.gnome.Platform, org.gnome.Platform//48) to use the host's arch and the newest installed branch.
+    #[arg(long)]
+    pub runtime: Option<String>,
+    /// Run the app of a single-file bundle (app.flatpak) without installing it. It's extracted with `ostree`
+    /// into the cache once, its runtime has to be installed.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["app", "runtime"])]
+    pub bundle: Option<PathBuf>,
+    /// Run an app committed to an OSTree repo without deploying it, e.g. the repo of `flatpak-builder --repo`.
+    /// The commit of `--ref` is checked out into the cache once, its runtime has to be installed.
+    #[arg(long, value_name = "PATH", requires = "ostree_ref", conflicts_with_all = ["app", "runtime", "bundle"])]
+    pub ostree_repo: Option<PathBuf>,
+    /// Ref of the app in `--ostree-repo`, e.g. `app/org.example.App/x86_64/master`
+    #[arg(long = "ref", value_name = "REF", requires = "ostree_repo")]
+    pub ostree_ref: Option<String>,
+    /// Use a root filesystem dir (e.g. a chroot made by debootstrap or mkosi) as the environment instead of a
+    /// runtime. Its `usr` (or the dir itself when it has none) is mounted as /usr and its `etc` and `var` are
+    /// used like the ones of a runtime. A flatpak `metadata` file in it is read for the runtime's environment
+    /// and extensions.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["app", "runtime", "bundle", "ostree_repo"])]
+    pub rootfs: Option<PathBuf>,
+    /// Use the root filesystem of a container image as the environment, like `--rootfs`: an OCI image layout
+    /// (`oci:PATH[:TAG]`) or a `docker save` archive (`docker-archive:PATH[:NAME:TAG]`). It's unpacked into the
+    /// cache once per image digest, and the environment variables of the image are set.
+    #[arg(long, value_name = "IMAGE", conflicts_with_all = ["app", "runtime", "bundle", "ostree_repo", "rootfs"])]
+    pub oci_image: Option<String>,
+    /// Additional Flatpak installation dirs (/var/lib/flatpak and $HOME/.local/share/flatpak, or $FLATPAK_SYSTEM_DIR and $FLATPAK_USER_DIR, are used by default)
+    #[arg(long)]
+    pub flatpak_install_path: Vec<PathBuf>,
+    /// Only use the custom installation NAME declared in /etc/flatpak/installations.d (and the extra install dirs).
+    #[arg(long, value_name = "NAME")]
+    pub installation: Option<String>,
+    /// Architecture of the app and runtime (x86_64, i386, aarch64, ...), defaults to the host's.
+    #[arg(long)]
+    pub arch: Option<String>,
+    /// Branch of the app to use instead of the current one (e.g. stable or beta).
+    #[arg(long, requires = "app")]
+    pub branch: Option<String>,
+    /// Run the app in its SDK (the `sdk` of its metadata) instead of its runtime and allow debugging and profiling
+    /// syscalls, like `flatpak run --devel`. The host's debuginfod servers are used, or elfutils' federating one.
+    #[arg(long, default_value_t)]
+    pub devel: bool,
+    /// Run the command in a tool like strace, ltrace or valgrind, as `TOOL[:ARGS]` with comma separated arguments
+    /// (e.g. `strace:-f,-e,trace=file`). The tool of the runtime (e.g. an SDK with `--devel`) is used, or the
+    /// host's. The current dir is mounted writable at /run/flatbox/output, where relative output files of strace,
+    /// ltrace and valgrind are written (by default strace.log, ltrace.log and valgrind.PID.log).
+    #[arg(long, value_name = "TOOL[:ARGS]")]
+    pub wrap: Option<Wrapper>,
+    /// Allow profiling with perf: perf events are permitted, perf is taken from the runtime or the host, the
+    /// kernel's symbol files are visible and perf's build-id cache is kept in ~/.cache/flatbox/perf-buildid.
+    /// Implied by `--wrap perf`, which records the command to perf.data in the current dir.
+    #[arg(long, default_value_t)]
+    pub perf: bool,
+    /// Allow the sandbox to run commands on the host with `flatbox-spawn --host COMMAND` (or `flatpak-spawn
+    /// --host`), e.g. for the terminals of IDEs and git hooks. This deliberately breaks out of the sandbox.
+    #[arg(long, default_value_t)]
+    pub host_commands: bool,
+    /// The build dir of `flatbox build`, mounted at /app instead of an installed app
+    #[arg(skip)]
+    pub build: Option<BuildDir>,
+    /// Mount the debug info and sources extensions of the runtime and app, and run the command in gdb. Set by
+    /// `flatbox debug`.
+    #[arg(skip)]
+    pub debug: bool,
+    /// How the sandbox is created. The native backend doesn't need bwrap, but requires unprivileged user namespaces.
+    /// The container backends run the same mounts in a podman or systemd-nspawn container.
+    #[arg(long, value_enum, default_value_t)]
+    pub backend: Backend,
+    /// Maximum memory the sandbox can use (e.g. `512M` or `4G`). Like the other resource limits, it's applied
+    /// to the systemd scope with `--systemd-scope`, and to a new cgroup otherwise.
+    #[arg(long, value_name = "SIZE")]
+    pub memory_limit: Option<MemorySize>,
+    /// CPU time the sandbox can use, as a percentage of one CPU (e.g. `50%`, or `200%` for two CPUs).
+    #[arg(long, value_name = "PERCENT")]
+    pub cpu_quota: Option<CpuQuota>,
+    /// Maximum number of processes and threads in the sandbox.
+    #[arg(long, value_name = "N")]
+    pub pids_limit: Option<u32>,
+    /// Resource limit of the sandboxed process, as `NAME=LIMIT` or `NAME=SOFT:HARD` (e.g. `nofile=524288`,
+    /// `core=0`). Takes a comma separated list and can be repeated.
+    #[arg(long, value_name = "NAME=LIMIT", value_delimiter = ',')]
+    pub rlimit: Vec<Rlimit>,
+    /// Write the cores of crashing processes to this dir on the host, which is created if needed. The core limit is
+    /// raised, the dir is mounted where `kernel.core_pattern` puts cores (the command is started in it with a
+    /// relative pattern) and the cores are listed when the sandbox exits.
+    #[arg(long, value_name = "DIR")]
+    pub coredump_dir: Option<PathBuf>,
+    /// Scheduling niceness of the sandbox, from -20 (highest priority) to 19 (lowest). Raising the priority
+    /// needs privileges.
+    #[arg(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    pub nice: Option<i32>,
+    /// I/O scheduling class of the sandbox: `idle`, `best-effort[:LEVEL]` or `realtime[:LEVEL]`, with levels
+    /// from 0 (highest) to 7. `idle` keeps background jobs from slowing down the desktop.
+    #[arg(long, value_name = "CLASS[:LEVEL]")]
+    pub ionice: Option<IoPriority>,
+    /// OOM score adjustment of the sandbox, from -1000 (never killed) to 1000 (killed first). Use a positive
+    /// value to have memory hungry sandboxes killed before the host session.
+    #[arg(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-1000..=1000))]
+    pub oom_score_adj: Option<i32>,
+    /// Run the command in a new session without a controlling terminal, like bwrap's `--new-session`, so it
+    /// can't inject input into the host's terminal. Interactive programs lose job control, see `--pty`.
+    #[arg(long, default_value_t)]
+    pub new_session: bool,
+    /// When running on a system with AppArmor active, this makes sure the application runs with unconfined privileges.
+    /// It can be used to avoid applying unprivileged profiles normally intended for user Flatpak apps.
+    #[arg(long, default_value_t)]
+    pub apparmor_unconfined: bool,
+    /// Do not expose the host root directories (`/home`, `/sys`, `/run`, `/run/host/root` etc.) to the sandbox.
+    /// The environment will only consist of the runtime with a private `/proc`, `/dev` and `/tmp`.
+    #[arg(long, default_value_t)]
+    pub no_host_root: bool,
+    /// Hide the contents of `$HOME` from the sandbox by mounting an empty directory over it.
+    #[arg(long, default_value_t, conflicts_with_all = ["home_ro", "ephemeral_home", "private_home"])]
+    pub no_home: bool,
+    /// Mount `$HOME` read-only inside the sandbox.
+    #[arg(long, default_value_t, conflicts_with_all = ["ephemeral_home", "private_home"])]
+    pub home_ro: bool,
+    /// Mount a temporary directory at `$HOME`. Nothing written to the home directory persists after the sandbox exits.
+    #[arg(long, default_value_t, conflicts_with = "private_home")]
+    pub ephemeral_home: bool,
+    /// Directory used to seed the ephemeral home. Its contents are visible in the sandbox, but modifications are discarded.
+    #[arg(long, requires = "ephemeral_home")]
+    pub home_template: Option<PathBuf>,
+    /// Use the app data directory (`~/.var/app/<app id>`) as `$HOME` and hide the rest of the real home directory.
+    #[arg(long, default_value_t, requires = "app")]
+    pub private_home: bool,
+    /// Do not create the app data directories (`~/.var/app/<app id>/{data,config,cache,.local/state}`) before launching.
+    #[arg(long, default_value_t)]
+    pub no_create_app_dirs: bool,
+    /// Share the host's whole runtime dir (`/run/user/<uid>`) instead of a private one
+    /// which only contains the sockets of the display server, sound server, session bus etc.
+    #[arg(long, default_value_t, conflicts_with = "no_host_root")]
+    pub share_runtime_dir: bool,
+    /// Only expose an allowlist of host `/run` paths (`udev`, `systemd/resolve` and `dbus`) instead of all of them,
+    /// hiding host control sockets and secrets. `/run` is also hidden in `/run/host/root`.
+    #[arg(long, default_value_t, conflicts_with = "no_host_root")]
+    pub strict_run: bool,
+    /// Additional path under `/run` to expose in strict mode.
+    #[arg(long, value_name = "PATH", requires = "strict_run")]
+    pub run_allow: Vec<String>,
+    /// Mount a private tmpfs at `/tmp` instead of sharing the host's.
+    #[arg(long, default_value_t)]
+    pub private_tmp: bool,
+    /// Mount a private tmpfs at `/dev/shm` instead of sharing the host's.
+    #[arg(long, default_value_t, conflicts_with = "share_dev_shm")]
+    pub private_dev_shm: bool,
+    /// Always share the host's `/dev/shm`, even if the app requests the `per-app-dev-shm` feature.
+    /// Needed for apps that use shared memory for IPC with processes outside of the sandbox.
+    #[arg(long, default_value_t)]
+    pub share_dev_shm: bool,
+    /// Additional host `/etc` file to use instead of the runtime's one (`resolv.conf`, `hosts`, `host.conf` and `gai.conf` are always used).
+    #[arg(long, value_name = "NAME", conflicts_with = "no_host_etc")]
+    pub host_etc: Vec<String>,
+    /// Use the runtime's network configuration files in `/etc` instead of the host's.
+    #[arg(long, default_value_t)]
+    pub no_host_etc: bool,
+    /// Use the CA certificates bundled with the runtime instead of the host's trust store.
+    ///
+    /// By default the host's bundle is placed over the runtime's, and /etc/ssl/certs, /etc/pki/tls
+    /// and /etc/ca-certificates, with the /usr/share/ca-certificates their links may point into, are
+    /// shared read-only where both the host and the runtime have them.
+    #[arg(long, default_value_t)]
+    pub runtime_certs: bool,
+    /// Source of the GL and Vulkan drivers. `host` skips the GL extensions and uses the host's userspace drivers,
+    /// which is useful when no matching GL extension is installed or the host drivers are newer.
+    #[arg(long, value_enum, default_value_t = GlMode::Extension)]
+    pub gl: GlMode,
+    /// Attach an extension image read-only at a mountpoint, as `PATH:MOUNTPOINT` (e.g.
+    /// `toolchain.raw:/opt/toolchain`): a squashfs or erofs image, a systemd-sysext image (whose `usr` is
+    /// mounted) or a dir. Images are loop mounted as root and mounted with squashfuse or erofsfuse otherwise.
+    /// Their library dirs are added to the ld.so.conf. Can be repeated.
+    #[arg(long, value_name = "PATH:MOUNTPOINT")]
+    pub extension_image: Vec<ExtensionImage>,
+    /// Mount an installed extension the metadata doesn't declare, as `ID[//BRANCH]` (e.g.
+    /// `org.freedesktop.Sdk.Extension.rust-stable` to bring a compiler into an app using the Platform). It's mounted
+    /// where the runtime it extends mounts it, and its `bin` is added to the PATH. Can be repeated.
+    #[arg(long, value_name = "ID[//BRANCH]")]
+    pub with_extension: Vec<String>,
+    /// Bind a host file or dir read-only over a path of the runtime or app, as `TARGET=SOURCE` (e.g.
+    /// `/usr/lib/x86_64-linux-gnu/libfoo.so=build/libfoo.so`), to test a locally built library or tool. Applied
+    /// after the runtime, app and extensions are mounted. Can be repeated.
+    #[arg(long, value_name = "TARGET=SOURCE", value_parser = parse_bind_over)]
+    pub bind_over: Vec<(PathBuf, PathBuf)>,
+    /// Make /usr writable with an overlay over the runtime, e.g. to `pip install` or patch files without changing
+    /// the deployment. The changes are discarded when the sandbox exits, or kept in DIR when given
+    /// (`--writable-usr=DIR`).
+    #[arg(long, value_name = "DIR", num_args = 0..=1, require_equals = true)]
+    pub writable_usr: Option<Option<PathBuf>>,
+    /// Make /app writable with an overlay over the app, like `--writable-usr`.
+    #[arg(long, value_name = "DIR", num_args = 0..=1, require_equals = true, requires = "app")]
+    pub writable_app: Option<Option<PathBuf>>,
+    /// Forward the host's ssh agent (`SSH_AUTH_SOCK`) into the sandbox.
+    #[arg(long, default_value_t)]
+    pub ssh_agent: bool,
+    /// Forward the host's gpg agent into the sandbox, e.g. for signing commits.
+    #[arg(long, default_value_t)]
+    pub gpg_agent: bool,
+    /// Grant access to a socket, like `flatpak run --socket`. Applied on top of the app metadata and overrides.
+    #[arg(long, value_name = "SOCKET", value_parser = PossibleValuesParser::new(SOCKETS))]
+    pub socket: Vec<String>,
+    /// Revoke access to a socket, like `flatpak run --nosocket`.
+    #[arg(long, value_name = "SOCKET", value_parser = PossibleValuesParser::new(SOCKETS))]
+    pub nosocket: Vec<String>,
+    /// Grant access to a device, like `flatpak run --device`. Only has an effect with `--no-host-root`,
+    /// as the host's `/dev` is shared otherwise.
+    #[arg(long, value_name = "DEVICE", value_parser = PossibleValuesParser::new(DEVICES))]
+    pub device: Vec<String>,
+    /// Revoke access to a device, like `flatpak run --nodevice`.
+    #[arg(long, value_name = "DEVICE", value_parser = PossibleValuesParser::new(DEVICES))]
+    pub nodevice: Vec<String>,
+    /// Share a subsystem with the host, like `flatpak run --share`.
+    #[arg(long, value_name = "SUBSYSTEM", value_parser = PossibleValuesParser::new(SHARES))]
+    pub share: Vec<String>,
+    /// Unshare a subsystem with the host, like `flatpak run --unshare`.
+    #[arg(long, value_name = "SUBSYSTEM", value_parser = PossibleValuesParser::new(SHARES))]
+    pub unshare: Vec<String>,
+    /// Share the network and IPC and expose the session and system buses only when they're granted, like
+    /// `flatpak run`, instead of unless they're revoked.
+    #[arg(long, default_value_t)]
+    pub strict_shares: bool,
+    /// Allow a feature, like `flatpak run --allow`.
+    #[arg(long, value_name = "FEATURE", value_parser = PossibleValuesParser::new(FEATURES))]
+    pub allow: Vec<String>,
+    /// Disallow a feature, like `flatpak run --disallow`.
+    #[arg(long, value_name = "FEATURE", value_parser = PossibleValuesParser::new(FEATURES))]
+    pub disallow: Vec<String>,
+    /// Expose a filesystem location, like `flatpak run --filesystem` (e.g. `xdg-download`, `~/src:ro`, `/mnt:create`).
+    #[arg(long, value_name = "FILESYSTEM")]
+    pub filesystem: Vec<String>,
+    /// Hide a filesystem location, like `flatpak run --nofilesystem`.
+    #[arg(long, value_name = "FILESYSTEM")]
+    pub nofilesystem: Vec<String>,
+    /// Set an environment variable in the sandbox, overriding the runtime's and flatbox's defaults. An empty value unsets it.
+    #[arg(long, value_name = "VAR=VALUE", value_parser = parse_env_assignment)]
+    pub env: Vec<(String, String)>,
+    /// Unset an environment variable in the sandbox.
+    #[arg(long, value_name = "VAR")]
+    pub unset_env: Vec<String>,
+    /// Load environment variables from a dotenv style file (`VAR=VALUE` per line). `--env` takes precedence.
+    #[arg(long, value_name = "PATH")]
+    pub env_file: Vec<PathBuf>,
+    /// Keep host environment variables matching PATTERN (`*` and `?` globs), even when flatbox or the runtime would override or unset them.
+    #[arg(long, value_name = "PATTERN")]
+    pub keep_env: Vec<String>,
+    /// Add a directory to the start of PATH.
+    #[arg(long, value_name = "DIR")]
+    pub prepend_path: Vec<PathBuf>,
+    /// Add a directory to the end of PATH.
+    #[arg(long, value_name = "DIR")]
+    pub append_path: Vec<PathBuf>,
+    /// Add a directory to the start of LD_LIBRARY_PATH.
+    #[arg(long, value_name = "DIR")]
+    pub prepend_ld_library_path: Vec<PathBuf>,
+    /// Add a directory to the end of LD_LIBRARY_PATH.
+    #[arg(long, value_name = "DIR")]
+    pub append_ld_library_path: Vec<PathBuf>,
+    /// Working directory of the command. Defaults to the current directory when it's visible in the sandbox,
+    /// and the home directory otherwise.
+    #[arg(long, value_name = "PATH")]
+    pub cwd: Option<PathBuf>,
+    /// Forward the files between `@@` and the `file://` URIs between `@@u` and `@@` in the arguments into the sandbox,
+    /// replacing them with their path inside of it.
+    #[arg(long, default_value_t)]
+    pub file_forwarding: bool,
+    /// Command to run instead of the app's default one. All positional arguments are passed to it.
+    #[arg(long = "command", value_name = "COMMAND")]
+    pub command_override: Option<String>,
+    /// Command to run, defaults to the `command` declared in the app metadata
+    pub command: Option<String>,
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub args: Vec<String>,
+}
+
+fn parse_env_assignment(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected VAR=VALUE, found '{value}'")),
+    }
+}
+
+fn parse_bind_over(value: &str) -> Result<(PathBuf, PathBuf), String> {
+    match value.split_once('=') {
+        Some((target, source)) if target.starts_with('/') && !source.is_empty() => {
+            Ok((target.into(), source.into()))
+        }
+        _ => Err(format!(
+            "expected TARGET=SOURCE with an absolute TARGET, found '{value}'"
+        )),
+    }
+}
+
+impl SandboxOptions {
+    pub fn priority(&self) -> Priority {
+        Priority {
+            nice: self.nice,
+            io: self.ionice,
+            oom_score_adj: self.oom_score_adj,
+        }
+    }
+
+    pub fn resource_limits(&self) -> ResourceLimits {
+        ResourceLimits {
+            memory_max: self.memory_limit,
+            cpu_quota: self.cpu_quota,
+            pids_max: self.pids_limit,
+        }
+    }
+
+    /// The command and its arguments, falling back to the app's declared command when none is given
+    pub fn command_line(&self, default_command: Option<&str>) -> anyhow::Result<Vec<String>> {
+        let command = match (&self.command_override, &self.command) {
+            (Some(command), first_arg) => [command.clone()]
+                .into_iter()
+                .chain(first_arg.clone())
+                .collect::<Vec<_>>(),
+            (None, Some(command)) => vec![command.clone()],
+            (None, None) => vec![
+                default_command
+                    .context("No command given and the app metadata doesn't declare one")?
+                    .to_owned(),
+            ],
+        };
+        Ok(command
+            .into_iter()
+            .chain(self.args.iter().cloned())
+            .collect())
+    }
+
+    pub fn home_mode(&self) -> HomeMode {
+        if self.no_home {
+            HomeMode::None
+        } else if self.private_home {
+            HomeMode::Private
+        } else if self.ephemeral_home {
+            HomeMode::Ephemeral {
+                template: self.home_template.clone(),
+            }
+        } else if self.home_ro {
+            HomeMode::ReadOnly
+        } else {
+            HomeMode::ReadWrite
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Create the sandbox with the bwrap binary
+    #[default]
+    Bwrap,
+    /// Create the namespaces and mounts directly, without needing bwrap to be installed
+    Native,
+    /// Run in a rootless podman container
+    Podman,
+    /// Run in a systemd-nspawn container, registered with systemd-machined (needs root)
+    #[value(name = "systemd-nspawn")]
+    Nspawn,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GlMode {
+    /// Use the GL extensions of the runtime (org.freedesktop.Platform.GL.*)
+    #[default]
+    Extension,
+    /// Pass the host's drivers through under `/run/host/graphics`
+    Host,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HomeMode {
+    ReadWrite,
+    ReadOnly,
+    Ephemeral { template: Option<PathBuf> },
+    Private,
+    None,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::SandboxOptions;
+    use clap::Parser;
+
+    fn command_line(args: &[&str]) -> Vec<String> {
+        SandboxOptions::try_parse_from(["run", "--app", "org.example.App"].iter().chain(args))
+            .unwrap()
+            .command_line(Some("example"))
+            .unwrap()
+    }
+
+    #[test]
+    fn default_and_explicit_commands() {
+        assert_eq!(command_line(&[]), ["example"]);
+        assert_eq!(command_line(&["ls", "-la", "/app"]), ["ls", "-la", "/app"]);
+        assert_eq!(
+            command_line(&["--command", "sh", "--", "-c", "echo 'a b'"]),
+            ["sh", "-c", "echo 'a b'"]
+        );
+    }
+}
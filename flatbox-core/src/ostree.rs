@@ -0,0 +1,188 @@
+use crate::{cache, error::FlatboxError, gc, sha256::Sha256};
+use anyhow::Context;
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+use tempdir::TempDir;
+
+/// Checkouts of the commits run without installing them, in the cache dir. A checkout has the layout of
+/// a deployment, with the `metadata` and `files` of the app.
+pub(crate) const CHECKOUTS_DIR: &str = "checkouts";
+/// The commits of the bundles which were checked out, by their path, size and modification time
+pub(crate) const BUNDLES_DIR: &str = "bundles";
+
+/// The `--repo` option of the ostree commands
+pub(crate) fn repo_arg(repo: &Path) -> OsString {
+    let mut repo_arg = OsString::from("--repo=");
+    repo_arg.push(repo);
+    repo_arg
+}
+
+/// Runs `ostree` and returns its output
+pub(crate) fn ostree(args: &[&OsStr]) -> anyhow::Result<String> {
+    let output = Command::new("ostree")
+        .args(args)
+        .output()
+        .context("Could not run ostree, is it installed?")?;
+    if !output.status.success() {
+        return Err(FlatboxError::ToolFailed {
+            tool: "ostree".to_owned(),
+            action: args[0].to_string_lossy().into_owned(),
+            details: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        }
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Runs `ostree` with its output on the terminal, for the progress of long operations
+pub(crate) fn ostree_status(args: &[&OsStr]) -> anyhow::Result<()> {
+    let status = Command::new("ostree")
+        .args(args)
+        .status()
+        .context("Could not run ostree, is it installed?")?;
+    if !status.success() {
+        return Err(FlatboxError::ToolFailed {
+            tool: "ostree".to_owned(),
+            action: args[0].to_string_lossy().into_owned(),
+            details: status.to_string(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn cache_dir(name: &str) -> anyhow::Result<PathBuf> {
+    let dir = cache::cache_dir()
+        .context("Could not determine the cache dir")?
+        .join(name);
+    fs::create_dir_all(&dir).with_context(|| format!("Could not create {}", dir.display()))?;
+    Ok(dir)
+}
+
+/// Checks the commit of the repo out into the cache, unless it already is, and returns the checkout
+fn checkout_commit(repo: &Path, commit: &str) -> anyhow::Result<PathBuf> {
+    let checkout = cache_dir(CHECKOUTS_DIR)?.join(commit);
+    if checkout.join("metadata").exists() {
+        crate::debug!("Using the checkout of {commit} at {}", checkout.display());
+        gc::touch(&checkout);
+        return Ok(checkout);
+    }
+
+    crate::info!("Checking out {commit} to {}", checkout.display());
+    // Renamed into place once complete, so an interrupted checkout isn't used
+    let partial = checkout.with_extension(format!("{}.tmp", process::id()));
+    ostree(&[
+        "checkout".as_ref(),
+        &repo_arg(repo),
+        "--user-mode".as_ref(),
+        commit.as_ref(),
+        partial.as_ref(),
+    ])?;
+    if !partial.join("metadata").exists() {
+        let _ = fs::remove_dir_all(&partial);
+        return Err(FlatboxError::NotFlatpakRef {
+            reference: commit.to_owned(),
+        }
+        .into());
+    }
+    if let Err(err) = fs::rename(&partial, &checkout) {
+        // Checked out concurrently by another launch
+        let _ = fs::remove_dir_all(&partial);
+        if !checkout.join("metadata").exists() {
+            return Err(err)
+                .with_context(|| format!("Could not move the checkout to {}", checkout.display()));
+        }
+    }
+    Ok(checkout)
+}
+
+/// Checks the commit of a ref in a repo (e.g. the one of flatpak-builder) out into the cache, unless it already
+/// is, and returns the checkout. Checkouts of a bare-user repo hardlink the files when the cache is on the same
+/// file system.
+pub fn checkout_ref(repo: &Path, reference: &str) -> anyhow::Result<PathBuf> {
+    let commit = ostree(&["rev-parse".as_ref(), &repo_arg(repo), reference.as_ref()])
+        .with_context(|| format!("Could not find {reference} in {}", repo.display()))?;
+    checkout_commit(repo, &commit)
+}
+
+/// Identifies the version of a bundle file without reading it
+fn bundle_key(bundle: &Path) -> anyhow::Result<String> {
+    let path =
+        fs::canonicalize(bundle).with_context(|| format!("Could not find {}", bundle.display()))?;
+    let metadata =
+        fs::metadata(&path).with_context(|| format!("Could not read {}", path.display()))?;
+    let mut sha256 = Sha256::new();
+    sha256.update(path.as_os_str().as_encoded_bytes());
+    sha256.update(
+        format!(
+            "\0{}\0{}.{}",
+            metadata.size(),
+            metadata.mtime(),
+            metadata.mtime_nsec()
+        )
+        .as_bytes(),
+    );
+    Ok(sha256.finish())
+}
+
+/// The commit object of a repo holding a single commit
+fn only_commit(repo: &Path) -> anyhow::Result<String> {
+    let mut commits = Vec::new();
+    let objects = repo.join("objects");
+    for prefix in
+        fs::read_dir(&objects).with_context(|| format!("Could not read {}", objects.display()))?
+    {
+        let prefix = prefix?.path();
+        for object in fs::read_dir(&prefix)? {
+            let name = object?.file_name();
+            if let Some(rest) = name.to_str().and_then(|name| name.strip_suffix(".commit")) {
+                let prefix = prefix.file_name().unwrap_or_default().to_string_lossy();
+                commits.push(format!("{prefix}{rest}"));
+            }
+        }
+    }
+    match <[String; 1]>::try_from(commits) {
+        Ok([commit]) => Ok(commit),
+        Err(commits) => Err(FlatboxError::InvalidBundle {
+            commits: commits.len(),
+        }
+        .into()),
+    }
+}
+
+/// Checks the app of a single-file bundle (`flatpak build-bundle`) out into the cache, unless it already is,
+/// and returns the checkout. The bundle is a static delta, which is applied to an empty repo to get the commit.
+pub fn checkout_bundle(bundle: &Path) -> anyhow::Result<PathBuf> {
+    let key_path = cache_dir(BUNDLES_DIR)?.join(bundle_key(bundle)?);
+    if let Ok(commit) = fs::read_to_string(&key_path) {
+        let checkout = cache_dir(CHECKOUTS_DIR)?.join(commit.trim());
+        if checkout.join("metadata").exists() {
+            gc::touch(&key_path);
+            gc::touch(&checkout);
+            return Ok(checkout);
+        }
+    }
+
+    let tempdir = TempDir::new(&gc::temp_prefix("bundle")).context("Could not create tempdir")?;
+    let repo = tempdir.path().join("repo");
+    let repo_arg = repo_arg(&repo);
+    ostree(&["init".as_ref(), &repo_arg, "--mode=bare-user-only".as_ref()])?;
+    crate::info!("Extracting {}", bundle.display());
+    ostree(&[
+        "static-delta".as_ref(),
+        "apply-offline".as_ref(),
+        &repo_arg,
+        bundle.as_ref(),
+    ])
+    .with_context(|| format!("Could not extract {}", bundle.display()))?;
+    let commit = only_commit(&repo)?;
+    let checkout = checkout_commit(&repo, &commit)?;
+    fs::write(&key_path, &commit)
+        .with_context(|| format!("Could not write {}", key_path.display()))?;
+    Ok(checkout)
+}
@@ -0,0 +1,63 @@
+use crate::{bwrap::BwrapBuilder, cache, environment::Environment, wrap};
+use anyhow::Context;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The build-id cache of perf, in the cache dir so it's kept between sandboxes
+const BUILDID_CACHE_DIR: &str = "perf-buildid";
+const BUILDID_MOUNT_DIR: &str = "/run/flatbox/perf-buildid";
+/// Where perf looks for the kernel image and `System.map`
+const KERNEL_SYMBOL_DIRS: [&str; 1] = ["/boot"];
+const PERF_EVENT_PARANOID: &str = "/proc/sys/kernel/perf_event_paranoid";
+const KPTR_RESTRICT: &str = "/proc/sys/kernel/kptr_restrict";
+
+fn read_sysctl(path: &str) -> Option<i32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Sets the sandbox up for profiling with perf: allows perf events with the container backends, makes perf
+/// available (the runtime's, or a launcher of the host's), exposes the kernel's symbol files and keeps the
+/// build-id cache writable
+pub fn setup_perf(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    runtime_files_path: &Path,
+    host_root_exposed: bool,
+) -> anyhow::Result<()> {
+    bwrap.devel();
+
+    let perf = wrap::tool_command(bwrap, runtime_files_path, "perf", host_root_exposed)?;
+    if !perf[0].starts_with("/usr/") {
+        wrap::install_launcher(bwrap, environment, "perf", &perf)?;
+    }
+
+    for dir in KERNEL_SYMBOL_DIRS {
+        if Path::new(dir).is_dir() {
+            bwrap.ro_bind(dir, dir);
+        }
+    }
+
+    if let Some(cache_dir) = cache::cache_dir() {
+        let buildid_dir: PathBuf = cache_dir.join(BUILDID_CACHE_DIR);
+        fs::create_dir_all(&buildid_dir)
+            .with_context(|| format!("Could not create {}", buildid_dir.display()))?;
+        bwrap.bind(&buildid_dir, BUILDID_MOUNT_DIR);
+        environment.set("PERF_BUILDID_DIR", BUILDID_MOUNT_DIR);
+    }
+
+    // Unprivileged users can only profile their own processes in user space with the default of 2
+    if let Some(paranoid) = read_sysctl(PERF_EVENT_PARANOID)
+        && paranoid > 1
+    {
+        crate::info!(
+            "kernel.perf_event_paranoid is {paranoid}, so only user space can be profiled. Lower it with \
+             `sysctl kernel.perf_event_paranoid=1` to include the kernel."
+        );
+    }
+    if read_sysctl(KPTR_RESTRICT).is_some_and(|restrict| restrict > 0) {
+        crate::info!("kernel.kptr_restrict hides the addresses of kernel symbols from perf");
+    }
+    Ok(())
+}
@@ -0,0 +1,495 @@
+use crate::{
+    bwrap::BwrapBuilder, keyfile::parse_keyfile, options::SandboxOptions, sockets::RuntimeDir,
+};
+use anyhow::Context;
+use indexmap::IndexMap;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+pub const SOCKETS: [&str; 11] = [
+    "x11",
+    "wayland",
+    "fallback-x11",
+    "pulseaudio",
+    "session-bus",
+    "system-bus",
+    "ssh-auth",
+    "pcsc",
+    "cups",
+    "gpg-agent",
+    "inherit-wayland-socket",
+];
+pub const DEVICES: [&str; 6] = ["dri", "all", "kvm", "shm", "input", "usb"];
+pub const SHARES: [&str; 2] = ["network", "ipc"];
+pub const FEATURES: [&str; 5] = [
+    "devel",
+    "multiarch",
+    "bluetooth",
+    "canbus",
+    "per-app-dev-shm",
+];
+/// A runtime has no permissions of its own, so running one directly grants what a typical desktop app needs
+const RUNTIME_DEFAULT_SOCKETS: [&str; 5] =
+    ["wayland", "x11", "pulseaudio", "session-bus", "system-bus"];
+const RUNTIME_DEFAULT_SHARES: [&str; 2] = ["network", "ipc"];
+/// The sockets which are exposed unless revoked, along with all of `SHARES`, unless `--strict-shares` is given
+const HOST_SHARED_SOCKETS: [&str; 2] = ["session-bus", "system-bus"];
+const RUNTIME_DEFAULT_DEVICES: [&str; 1] = ["dri"];
+/// `--nofilesystem=host:reset` revokes the filesystems of the metadata and overrides, which flatpak-builder
+/// passes to `flatpak build`
+const FILESYSTEM_RESET: &str = "host:reset";
+/// The session bus policies of `[Session Bus Policy]`, as the options of xdg-dbus-proxy
+const BUS_POLICIES: [&str; 3] = ["see", "talk", "own"];
+/// What every app may do on a filtered session bus, the same as in flatpak: use the portals
+const SESSION_BUS_FILTER: [&str; 3] = [
+    "--filter",
+    "--call=org.freedesktop.portal.*=*",
+    "--broadcast=org.freedesktop.portal.*=@/org/freedesktop/portal/*",
+];
+/// XDG user dirs and their variable in `user-dirs.dirs`
+const XDG_USER_DIRS: [(&str, &str); 8] = [
+    ("xdg-desktop", "XDG_DESKTOP_DIR"),
+    ("xdg-documents", "XDG_DOCUMENTS_DIR"),
+    ("xdg-download", "XDG_DOWNLOAD_DIR"),
+    ("xdg-music", "XDG_MUSIC_DIR"),
+    ("xdg-pictures", "XDG_PICTURES_DIR"),
+    ("xdg-public-share", "XDG_PUBLICSHARE_DIR"),
+    ("xdg-templates", "XDG_TEMPLATES_DIR"),
+    ("xdg-videos", "XDG_VIDEOS_DIR"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilesystemAccess {
+    ReadOnly,
+    ReadWrite,
+    Create,
+    /// Hidden from the sandbox
+    Denied,
+}
+
+/// The sandbox permissions, composed the same way as by `flatpak run`:
+/// the app's `[Context]` metadata, then the system and user overrides, then the command line.
+/// Each layer grants or revokes (`!name`) individual entries of the previous ones.
+#[derive(Debug, Default)]
+pub struct Permissions {
+    shared: IndexMap<String, bool>,
+    sockets: IndexMap<String, bool>,
+    devices: IndexMap<String, bool>,
+    features: IndexMap<String, bool>,
+    filesystems: IndexMap<String, FilesystemAccess>,
+    /// `[Environment]` entries, an empty value unsets the variable
+    env: IndexMap<String, String>,
+    /// `[Session Bus Policy]` entries: the policy (see, talk, own or none) of well-known names
+    session_bus_policy: IndexMap<String, String>,
+    /// Shares and buses which are neither granted nor revoked aren't the host's (`--strict-shares`)
+    strict_shares: bool,
+}
+
+impl Permissions {
+    pub fn runtime_default() -> Self {
+        let mut permissions = Self::default();
+        for socket in RUNTIME_DEFAULT_SOCKETS {
+            permissions.sockets.insert(socket.to_owned(), true);
+        }
+        for device in RUNTIME_DEFAULT_DEVICES {
+            permissions.devices.insert(device.to_owned(), true);
+        }
+        for share in RUNTIME_DEFAULT_SHARES {
+            permissions.shared.insert(share.to_owned(), true);
+        }
+        permissions
+    }
+
+    /// Applies a `[Context]` group from app metadata or an override file
+    pub fn merge_context(&mut self, context: &IndexMap<&str, &str>) {
+        for (key, values) in context {
+            let values = values
+                .split(';')
+                .map(str::trim_ascii)
+                .filter(|value| !value.is_empty());
+            match *key {
+                "filesystems" => {
+                    for value in values {
+                        let (path, access) = parse_filesystem(value);
+                        self.filesystems.insert(path.to_owned(), access);
+                    }
+                }
+                "shared" | "sockets" | "devices" | "features" => {
+                    let list = match *key {
+                        "shared" => &mut self.shared,
+                        "sockets" => &mut self.sockets,
+                        "devices" => &mut self.devices,
+                        _ => &mut self.features,
+                    };
+                    for value in values {
+                        match value.strip_prefix('!') {
+                            Some(value) => list.insert(value.to_owned(), false),
+                            None => list.insert(value.to_owned(), true),
+                        };
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Applies an `[Environment]` group from app metadata or an override file
+    pub fn merge_environment(&mut self, environment: &IndexMap<&str, &str>) {
+        for (key, value) in environment {
+            self.env.insert((*key).to_owned(), (*value).to_owned());
+        }
+    }
+
+    /// Applies a `[Session Bus Policy]` group from app metadata or an override file
+    pub fn merge_session_bus_policy(&mut self, policy: &IndexMap<&str, &str>) {
+        for (name, policy) in policy {
+            self.session_bus_policy
+                .insert((*name).to_owned(), policy.trim().to_owned());
+        }
+    }
+
+    /// Applies the global and per-app override files (as written by `flatpak override`) of each installation
+    pub fn merge_overrides(
+        &mut self,
+        app_id: &str,
+        install_dirs: &[PathBuf],
+    ) -> anyhow::Result<()> {
+        for install_dir in install_dirs {
+            for name in ["global", app_id] {
+                let path = install_dir.join("overrides").join(name);
+                let Ok(contents) = fs::read_to_string(&path) else {
+                    continue;
+                };
+                let overrides = parse_keyfile(&contents).map_err(|err| err.in_file(&path))?;
+                if let Some(context) = overrides.get("Context") {
+                    self.merge_context(context);
+                }
+                if let Some(environment) = overrides.get("Environment") {
+                    self.merge_environment(environment);
+                }
+                if let Some(policy) = overrides.get("Session Bus Policy") {
+                    self.merge_session_bus_policy(policy);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the `flatpak run` style permission flags
+    pub fn merge_args(&mut self, options: &SandboxOptions) {
+        let lists = [
+            (&mut self.sockets, &options.socket, &options.nosocket),
+            (&mut self.devices, &options.device, &options.nodevice),
+            (&mut self.shared, &options.share, &options.unshare),
+            (&mut self.features, &options.allow, &options.disallow),
+        ];
+        for (list, granted, revoked) in lists {
+            for name in granted {
+                list.insert(name.clone(), true);
+            }
+            for name in revoked {
+                list.insert(name.clone(), false);
+            }
+        }
+
+        if options
+            .nofilesystem
+            .iter()
+            .any(|path| path == FILESYSTEM_RESET)
+        {
+            self.filesystems.clear();
+        }
+        for value in &options.filesystem {
+            let (path, access) = parse_filesystem(value);
+            self.filesystems.insert(path.to_owned(), access);
+        }
+        for path in options
+            .nofilesystem
+            .iter()
+            .filter(|path| *path != FILESYSTEM_RESET)
+        {
+            self.filesystems
+                .insert(path.clone(), FilesystemAccess::Denied);
+        }
+
+        if options.devel {
+            self.features.insert("devel".to_owned(), true);
+        }
+        self.strict_shares = options.strict_shares;
+        if options.ssh_agent {
+            self.sockets.insert("ssh-auth".to_owned(), true);
+        }
+        if options.gpg_agent {
+            self.sockets.insert("gpg-agent".to_owned(), true);
+        }
+    }
+
+    pub fn env(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.env
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// The xdg-dbus-proxy options filtering the session bus for an app: it owns its id (and the names below it,
+    /// e.g. for MPRIS), can use the portals, and gets the policies of `[Session Bus Policy]`
+    pub fn session_bus_filter(&self, app_id: &str) -> Vec<String> {
+        let mut filter: Vec<String> = SESSION_BUS_FILTER.map(str::to_owned).into();
+        filter.push(format!("--own={app_id}"));
+        filter.push(format!("--own={app_id}.*"));
+        for (name, policy) in &self.session_bus_policy {
+            if BUS_POLICIES.contains(&policy.as_str()) {
+                filter.push(format!("--{policy}={name}"));
+            }
+        }
+        filter
+    }
+
+    /// Whether a socket is granted. The buses are also exposed when they aren't revoked, unless with
+    /// `--strict-shares`.
+    pub fn has_socket(&self, socket: &str) -> bool {
+        self.sockets
+            .get(socket)
+            .copied()
+            .unwrap_or_else(|| !self.strict_shares && HOST_SHARED_SOCKETS.contains(&socket))
+    }
+
+    pub fn has_device(&self, device: &str) -> bool {
+        self.devices.get(device) == Some(&true)
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.get(feature) == Some(&true)
+    }
+
+    /// Whether a subsystem is shared with the host, which it is unless it's revoked. With `--strict-shares`,
+    /// it's only shared when it's granted, like in flatpak.
+    pub fn is_shared(&self, share: &str) -> bool {
+        self.shared
+            .get(share)
+            .copied()
+            .unwrap_or(!self.strict_shares)
+    }
+}
+
+/// Splits a filesystem permission (`xdg-download/foo:ro`, `!home`) into its path and access mode
+fn parse_filesystem(value: &str) -> (&str, FilesystemAccess) {
+    if let Some(path) = value.strip_prefix('!') {
+        return (path, FilesystemAccess::Denied);
+    }
+    match value.rsplit_once(':') {
+        Some((path, "ro")) => (path, FilesystemAccess::ReadOnly),
+        Some((path, "rw")) => (path, FilesystemAccess::ReadWrite),
+        Some((path, "create")) => (path, FilesystemAccess::Create),
+        _ => (value, FilesystemAccess::ReadWrite),
+    }
+}
+
+/// Exposes the granted filesystem locations and hides the denied ones.
+/// Locations are mounted from the least to the most specific, so e.g. a read-only subdirectory of a writable home stays read-only.
+pub fn setup_filesystems(
+    bwrap: &mut BwrapBuilder,
+    permissions: &Permissions,
+    home: Option<&Path>,
+    runtime_dir: &RuntimeDir,
+) -> anyhow::Result<()> {
+    let mut mounts: Vec<(PathBuf, PathBuf, FilesystemAccess)> = Vec::new();
+    for (spec, access) in &permissions.filesystems {
+        match resolve_filesystem(spec, home, runtime_dir) {
+            Some((source, target)) => mounts.push((source, target, *access)),
+            None => crate::warn!("Unsupported filesystem permission '{spec}'"),
+        }
+    }
+    mounts.sort_by_key(|(_, target, _)| target.components().count());
+
+    for (source, target, access) in mounts {
+        match access {
+            FilesystemAccess::Denied => {
+                if source.is_dir() {
+                    bwrap.tmpfs(&target);
+                }
+            }
+            FilesystemAccess::Create => {
+                fs::create_dir_all(&source)
+                    .with_context(|| format!("Could not create {}", source.display()))?;
+                bwrap.bind(&source, &target);
+            }
+            FilesystemAccess::ReadWrite if source.exists() => {
+                bwrap.bind(&source, &target);
+            }
+            FilesystemAccess::ReadOnly if source.exists() => {
+                bwrap.ro_bind(&source, &target);
+            }
+            FilesystemAccess::ReadWrite | FilesystemAccess::ReadOnly => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves a filesystem permission to its path on the host and inside of the sandbox
+fn resolve_filesystem(
+    spec: &str,
+    home: Option<&Path>,
+    runtime_dir: &RuntimeDir,
+) -> Option<(PathBuf, PathBuf)> {
+    let (base, subpath) = spec.split_once('/').unwrap_or((spec, ""));
+
+    let (source, target) = match base {
+        "host" if subpath.is_empty() => (PathBuf::from("/"), PathBuf::from("/run/host/root")),
+        "host-os" if subpath.is_empty() => (PathBuf::from("/usr"), PathBuf::from("/run/host/usr")),
+        "host-etc" if subpath.is_empty() => (PathBuf::from("/etc"), PathBuf::from("/run/host/etc")),
+        "" => (PathBuf::from("/"), PathBuf::from("/")),
+        "xdg-run" => (runtime_dir.host.clone(), runtime_dir.sandbox.clone()),
+        _ => {
+            let home = home?;
+            let path = match base {
+                "home" | "~" => home.to_owned(),
+                "xdg-config" => xdg_base_dir("XDG_CONFIG_HOME", home, ".config"),
+                "xdg-data" => xdg_base_dir("XDG_DATA_HOME", home, ".local/share"),
+                "xdg-cache" => xdg_base_dir("XDG_CACHE_HOME", home, ".cache"),
+                _ => {
+                    let var = XDG_USER_DIRS
+                        .iter()
+                        .find(|(name, _)| *name == base)
+                        .map(|(_, var)| *var)?;
+                    xdg_user_dir(var, home)?
+                }
+            };
+            (path.clone(), path)
+        }
+    };
+
+    if subpath.is_empty() {
+        Some((source, target))
+    } else {
+        Some((source.join(subpath), target.join(subpath)))
+    }
+}
+
+fn xdg_base_dir(var: &str, home: &Path, default: &str) -> PathBuf {
+    env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| home.join(default))
+}
+
+/// Looks up an XDG user dir (e.g. `XDG_DOWNLOAD_DIR`) from `user-dirs.dirs`
+fn xdg_user_dir(var: &str, home: &Path) -> Option<PathBuf> {
+    let config = xdg_base_dir("XDG_CONFIG_HOME", home, ".config").join("user-dirs.dirs");
+    let contents = fs::read_to_string(config).ok()?;
+    parse_user_dirs(&contents, var, home)
+}
+
+fn parse_user_dirs(contents: &str, var: &str, home: &Path) -> Option<PathBuf> {
+    contents.lines().find_map(|line| {
+        let (key, value) = line.trim().split_once('=')?;
+        if key != var {
+            return None;
+        }
+        let value = value.trim().trim_matches('"');
+        match value.strip_prefix("$HOME") {
+            Some(relative) => Some(home.join(relative.trim_start_matches('/'))),
+            None => Some(PathBuf::from(value)).filter(|path| path.is_absolute()),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::permissions::{FilesystemAccess, Permissions, parse_filesystem, parse_user_dirs};
+    use indexmap::IndexMap;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn merge_context_layers() {
+        let mut permissions = Permissions::default();
+        permissions.merge_context(&IndexMap::from([
+            ("shared", "network;ipc;"),
+            ("sockets", "x11;wayland;pulseaudio;"),
+            ("filesystems", "xdg-download;~/.config/app:ro;"),
+        ]));
+        permissions.merge_context(&IndexMap::from([
+            ("shared", "!network;"),
+            ("sockets", "!x11;"),
+            ("filesystems", "!xdg-download;"),
+        ]));
+
+        assert!(!permissions.is_shared("network"));
+        assert!(permissions.is_shared("ipc"));
+        assert!(!permissions.has_socket("x11"));
+        assert!(permissions.has_socket("wayland"));
+        assert!(permissions.has_socket("session-bus"));
+        assert!(!permissions.has_socket("ssh-auth"));
+        permissions.strict_shares = true;
+        assert!(!permissions.is_shared("network"));
+        assert!(!permissions.has_socket("session-bus"));
+        assert_eq!(
+            permissions.filesystems.get("xdg-download"),
+            Some(&FilesystemAccess::Denied)
+        );
+        assert_eq!(
+            permissions.filesystems.get("~/.config/app"),
+            Some(&FilesystemAccess::ReadOnly)
+        );
+    }
+
+    #[test]
+    fn session_bus_filter() {
+        let mut permissions = Permissions::default();
+        permissions.merge_session_bus_policy(&IndexMap::from([
+            ("org.freedesktop.Notifications", "talk"),
+            ("org.mpris.MediaPlayer2.example", "own"),
+            ("org.gnome.SessionManager", "talk"),
+        ]));
+        permissions
+            .merge_session_bus_policy(&IndexMap::from([("org.gnome.SessionManager", "none")]));
+        assert_eq!(
+            permissions.session_bus_filter("org.example.App")[3..],
+            [
+                "--own=org.example.App",
+                "--own=org.example.App.*",
+                "--talk=org.freedesktop.Notifications",
+                "--own=org.mpris.MediaPlayer2.example",
+            ]
+        );
+    }
+
+    #[test]
+    fn filesystem_access() {
+        assert_eq!(
+            parse_filesystem("home"),
+            ("home", FilesystemAccess::ReadWrite)
+        );
+        assert_eq!(
+            parse_filesystem("host-etc:ro"),
+            ("host-etc", FilesystemAccess::ReadOnly)
+        );
+        assert_eq!(
+            parse_filesystem("xdg-documents/foo:create"),
+            ("xdg-documents/foo", FilesystemAccess::Create)
+        );
+        assert_eq!(
+            parse_filesystem("!host"),
+            ("host", FilesystemAccess::Denied)
+        );
+    }
+
+    #[test]
+    fn user_dirs() {
+        let contents = "# Generated by xdg-user-dirs-update\n\
+                        XDG_DOWNLOAD_DIR=\"$HOME/Downloads\"\n\
+                        XDG_MUSIC_DIR=\"/srv/music\"\n";
+        let home = Path::new("/home/user");
+        assert_eq!(
+            parse_user_dirs(contents, "XDG_DOWNLOAD_DIR", home),
+            Some(PathBuf::from("/home/user/Downloads"))
+        );
+        assert_eq!(
+            parse_user_dirs(contents, "XDG_MUSIC_DIR", home),
+            Some(PathBuf::from("/srv/music"))
+        );
+        assert_eq!(parse_user_dirs(contents, "XDG_VIDEOS_DIR", home), None);
+    }
+}
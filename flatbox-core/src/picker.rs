@@ -0,0 +1,40 @@
+use crate::error::FlatboxError;
+use anyhow::Context;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// Lets the user choose one of several candidates on the terminal and returns its index.
+/// Without a terminal to ask on, fails with the list of candidates instead.
+pub fn pick(prompt: &str, candidates: &[String]) -> anyhow::Result<usize> {
+    if !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+        return Err(FlatboxError::AmbiguousChoice {
+            prompt: prompt.to_owned(),
+            candidates: candidates.to_vec(),
+        }
+        .into());
+    }
+
+    let list = candidates
+        .iter()
+        .enumerate()
+        .map(|(i, candidate)| format!("  {}) {candidate}", i + 1))
+        .collect::<Vec<_>>()
+        .join("\n");
+    eprintln!("{prompt}:\n{list}");
+    loop {
+        eprint!("Choose [1-{}]: ", candidates.len());
+        io::stderr().flush().context("Could not write prompt")?;
+        let mut line = String::new();
+        if io::stdin()
+            .lock()
+            .read_line(&mut line)
+            .context("Could not read choice")?
+            == 0
+        {
+            return Err(FlatboxError::NoChoice.into());
+        }
+        match line.trim().parse::<usize>() {
+            Ok(choice) if (1..=candidates.len()).contains(&choice) => return Ok(choice - 1),
+            _ => eprintln!("Invalid choice '{}'", line.trim()),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use crate::{cgroup::ResourceLimits, priority::Priority, rlimit::Rlimit};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    os::unix::fs::{PermissionsExt, symlink},
+    path::{Path, PathBuf},
+};
+
+/// How a host path is bound into the sandbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BindMode {
+    ReadWrite,
+    ReadOnly,
+    /// Read-write, with device nodes usable
+    Device,
+}
+
+/// A step of setting up the sandbox filesystem. Steps are applied in order, so later mounts cover earlier ones.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum MountOp {
+    Bind {
+        source: PathBuf,
+        dest: PathBuf,
+        mode: BindMode,
+    },
+    Tmpfs {
+        dest: PathBuf,
+        perms: Option<u32>,
+    },
+    Dir {
+        dest: PathBuf,
+        perms: Option<u32>,
+    },
+    /// A minimal `/dev` with the basic device nodes and a private pty instance
+    Dev {
+        dest: PathBuf,
+    },
+    Proc {
+        dest: PathBuf,
+    },
+    Symlink {
+        target: PathBuf,
+        dest: PathBuf,
+    },
+    /// An overlay of `source` with a temporary writable upper layer
+    TmpOverlay {
+        source: PathBuf,
+        dest: PathBuf,
+    },
+    /// An overlay of `source` with a writable upper layer on the host, which keeps the changes. The work dir
+    /// has to be on the same filesystem as the upper dir.
+    Overlay {
+        source: PathBuf,
+        upper: PathBuf,
+        work: PathBuf,
+        dest: PathBuf,
+    },
+}
+
+/// Everything needed to create a sandbox, independent of the backend creating it
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SandboxPlan {
+    pub mounts: Vec<MountOp>,
+    /// Variables to set (`Some`) or unset (`None`) in the inherited environment, applied in order
+    #[serde(with = "env_changes")]
+    pub env: Vec<(OsString, Option<OsString>)>,
+    pub unshare_net: bool,
+    pub unshare_ipc: bool,
+    /// Detaches the sandbox from the controlling terminal
+    pub new_session: bool,
+    pub chdir: Option<PathBuf>,
+    pub limits: ResourceLimits,
+    /// Set in order, so later ones of the same resource take precedence
+    pub rlimits: Vec<Rlimit>,
+    pub priority: Priority,
+    /// Allows the debugging and profiling syscalls (ptrace, perf_event_open) which container backends filter
+    pub devel: bool,
+}
+
+impl SandboxPlan {
+    /// Creates the directories and symlinks of the plan in `rootfs`, for container backends which can only mount
+    /// onto an existing root, and returns the mounts left for them to do.
+    /// Tmpfs mounts containing directories or symlinks become directories of the rootfs, as they'd hide them.
+    pub fn create_rootfs(&self, rootfs: &Path) -> anyhow::Result<Vec<&MountOp>> {
+        fs::create_dir_all(rootfs)
+            .with_context(|| format!("Could not create {}", rootfs.display()))?;
+
+        let mut mounts = Vec::new();
+        for (i, op) in self.mounts.iter().enumerate() {
+            match op {
+                MountOp::Tmpfs { dest, perms } => {
+                    let has_rootfs_entries = self.mounts[i + 1..].iter().any(|later| match later {
+                        MountOp::Dir { dest: path, .. } | MountOp::Symlink { dest: path, .. } => {
+                            path.starts_with(dest) && path != dest
+                        }
+                        _ => false,
+                    });
+                    if has_rootfs_entries {
+                        create_dir(rootfs, dest, perms.unwrap_or(0o755))?;
+                    } else {
+                        mounts.push(op);
+                    }
+                }
+                MountOp::Dir { dest, perms } => create_dir(rootfs, dest, perms.unwrap_or(0o755))?,
+                MountOp::Symlink { target, dest } => {
+                    let path = rootfs.join(relative(dest));
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)
+                            .with_context(|| format!("Could not create {}", parent.display()))?;
+                    }
+                    let _ = fs::remove_file(&path);
+                    symlink(target, &path)
+                        .with_context(|| format!("Could not create symlink {}", path.display()))?;
+                }
+                _ => mounts.push(op),
+            }
+        }
+        Ok(mounts)
+    }
+
+    /// The environment of the sandboxed command, the inherited one with the changes of the plan
+    pub fn environment(&self) -> Vec<(OsString, OsString)> {
+        let mut environment: Vec<_> = env::vars_os().collect();
+        for (key, value) in &self.env {
+            environment.retain(|(existing, _)| existing != key);
+            if let Some(value) = value {
+                environment.push((key.clone(), value.clone()));
+            }
+        }
+        environment
+    }
+}
+
+/// Serializes the environment changes as tables, as TOML has no null to unset a variable with
+mod env_changes {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, ser};
+    use std::ffi::OsString;
+
+    #[derive(Deserialize, Serialize)]
+    struct EnvChange {
+        key: String,
+        value: Option<String>,
+    }
+
+    pub fn serialize<S: Serializer>(
+        env: &[(OsString, Option<OsString>)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let utf8 = |value: &OsString| {
+            value
+                .to_str()
+                .map(str::to_owned)
+                .ok_or_else(|| ser::Error::custom(format!("{} isn't UTF-8", value.display())))
+        };
+        env.iter()
+            .map(|(key, value)| {
+                Ok(EnvChange {
+                    key: utf8(key)?,
+                    value: value.as_ref().map(utf8).transpose()?,
+                })
+            })
+            .collect::<Result<Vec<_>, S::Error>>()?
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(OsString, Option<OsString>)>, D::Error> {
+        Ok(Vec::<EnvChange>::deserialize(deserializer)?
+            .into_iter()
+            .map(|change| (change.key.into(), change.value.map(Into::into)))
+            .collect())
+    }
+}
+
+fn create_dir(rootfs: &Path, dest: &Path, mode: u32) -> anyhow::Result<()> {
+    let path = rootfs.join(relative(dest));
+    fs::create_dir_all(&path).with_context(|| format!("Could not create {}", path.display()))?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Could not set the permissions of {}", path.display()))
+}
+
+fn relative(path: &Path) -> &Path {
+    path.strip_prefix("/").unwrap_or(path)
+}
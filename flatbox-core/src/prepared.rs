@@ -0,0 +1,152 @@
+use crate::{
+    bwrap::BwrapBuilder,
+    error::FlatboxError,
+    oci,
+    plan::{MountOp, SandboxPlan},
+    sandbox::Sandbox,
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The sandbox in a prepared dir, next to the `data` dir with the generated files
+const PLAN_FILE: &str = "plan.toml";
+/// Changed whenever prepared sandboxes can't be read by newer versions anymore
+const FORMAT_VERSION: u32 = 1;
+
+/// A sandbox composed by `flatbox prepare`, which is launched later without resolving it again
+#[derive(Debug, Deserialize, Serialize)]
+struct PreparedSandbox {
+    version: u32,
+    command: Vec<String>,
+    #[serde(default)]
+    apparmor_unconfined: bool,
+    plan: SandboxPlan,
+}
+
+/// Saves the sandbox to the dir `out`, with copies of its generated files. The sockets of the helpers, such
+/// as the D-Bus proxies, are only available while flatbox runs and are left out.
+pub fn save(sandbox: &Sandbox, out: &Path) -> anyhow::Result<()> {
+    fs::create_dir_all(out).with_context(|| format!("Could not create {}", out.display()))?;
+    let out =
+        &fs::canonicalize(out).with_context(|| format!("Could not resolve {}", out.display()))?;
+
+    let mut mounts = Vec::new();
+    for op in &sandbox.plan().mounts {
+        let mut op = op.clone();
+        if let MountOp::Bind { source, dest, .. } = &mut op {
+            let Some(copy) = oci::bundle_source(out, source, sandbox.setup_dir())? else {
+                crate::warn!(
+                    "Skipping {}, it's only available while flatbox is running",
+                    dest.display()
+                );
+                continue;
+            };
+            // Relative to the prepared dir, so it can be moved
+            *source = copy.strip_prefix(out).map(Path::to_owned).unwrap_or(copy);
+        }
+        mounts.push(op);
+    }
+
+    let prepared = PreparedSandbox {
+        version: FORMAT_VERSION,
+        command: sandbox.command_line().to_vec(),
+        apparmor_unconfined: sandbox.bwrap().is_apparmor_unconfined(),
+        plan: SandboxPlan {
+            mounts,
+            ..sandbox.plan().clone()
+        },
+    };
+    let path = out.join(PLAN_FILE);
+    fs::write(
+        &path,
+        toml::to_string(&prepared).context("Could not serialize the sandbox")?,
+    )
+    .with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// The dir with the generated files of a sandbox saved by `save`
+pub fn data_dir(dir: &Path) -> PathBuf {
+    dir.join(oci::DATA_DIR)
+}
+
+/// Loads a sandbox saved by `save`
+pub fn load(dir: &Path) -> anyhow::Result<Sandbox> {
+    let path = dir.join(PLAN_FILE);
+    let contents =
+        fs::read_to_string(&path).with_context(|| format!("Could not read {}", path.display()))?;
+    let mut prepared: PreparedSandbox =
+        toml::from_str(&contents).with_context(|| format!("Could not parse {}", path.display()))?;
+    if prepared.version != FORMAT_VERSION {
+        return Err(FlatboxError::PreparedByOtherVersion {
+            path: dir.to_owned(),
+        }
+        .into());
+    }
+
+    for op in &mut prepared.plan.mounts {
+        if let MountOp::Bind { source, .. } = op
+            && source.is_relative()
+        {
+            *source = dir.join(&*source);
+        }
+    }
+    let bwrap = BwrapBuilder::from_plan(prepared.plan, prepared.apparmor_unconfined);
+    Ok(Sandbox::new(bwrap, prepared.command))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        plan::{BindMode, MountOp, SandboxPlan},
+        prepared::PreparedSandbox,
+        rlimit::Rlimit,
+    };
+
+    #[test]
+    fn plan_round_trip() {
+        let prepared = PreparedSandbox {
+            version: 1,
+            command: vec!["sh".to_owned(), "-c".to_owned(), "echo hi".to_owned()],
+            apparmor_unconfined: false,
+            plan: SandboxPlan {
+                mounts: vec![
+                    MountOp::Bind {
+                        source: "/var/lib/flatpak/runtime/org.test.Platform".into(),
+                        dest: "/usr".into(),
+                        mode: BindMode::ReadOnly,
+                    },
+                    MountOp::Tmpfs {
+                        dest: "/tmp".into(),
+                        perms: None,
+                    },
+                    MountOp::Dir {
+                        dest: "/run/user/1000".into(),
+                        perms: Some(0o700),
+                    },
+                    MountOp::Dev {
+                        dest: "/dev".into(),
+                    },
+                ],
+                env: vec![
+                    ("PATH".into(), Some("/app/bin:/usr/bin".into())),
+                    ("LD_PRELOAD".into(), None),
+                ],
+                unshare_net: true,
+                chdir: Some("/app".into()),
+                rlimits: vec!["nofile=1024:4096".parse::<Rlimit>().unwrap()],
+                ..SandboxPlan::default()
+            },
+        };
+        let loaded: PreparedSandbox = toml::from_str(&toml::to_string(&prepared).unwrap()).unwrap();
+        assert_eq!(loaded.command, prepared.command);
+        assert_eq!(loaded.plan.mounts, prepared.plan.mounts);
+        assert_eq!(loaded.plan.env, prepared.plan.env);
+        assert_eq!(loaded.plan.rlimits, prepared.plan.rlimits);
+        assert!(loaded.plan.unshare_net && !loaded.plan.unshare_ipc);
+        assert_eq!(loaded.plan.chdir, prepared.plan.chdir);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::native;
+use serde::{Deserialize, Serialize};
+use std::{fmt, io, str::FromStr};
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+const DEFAULT_IO_LEVEL: u8 = 4;
+const IO_CLASSES: [(&str, u32); 3] = [("realtime", 1), ("best-effort", 2), ("idle", 3)];
+
+/// An I/O scheduling class and level, written like for `ionice`: `idle`, `best-effort[:LEVEL]` or
+/// `realtime[:LEVEL]`, where the level goes from 0 (highest) to 7
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IoPriority {
+    /// Index into `IO_CLASSES`
+    class: usize,
+    level: u8,
+}
+
+impl IoPriority {
+    /// The value of the `ioprio_set` syscall
+    fn value(self) -> libc::c_int {
+        let (_, class) = IO_CLASSES[self.class];
+        ((class << IOPRIO_CLASS_SHIFT) | u32::from(self.level)) as libc::c_int
+    }
+
+    fn is_idle(self) -> bool {
+        IO_CLASSES[self.class].0 == "idle"
+    }
+}
+
+impl FromStr for IoPriority {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "expected idle, best-effort[:LEVEL] or realtime[:LEVEL] with a level from 0 to 7, found '{value}'"
+            )
+        };
+        let (name, level) = match value.split_once(':') {
+            Some((name, level)) => (name, Some(level.parse().map_err(|_| invalid())?)),
+            None => (value, None),
+        };
+        let class = IO_CLASSES
+            .iter()
+            .position(|(known, _)| *known == name)
+            .ok_or_else(invalid)?;
+        let mut priority = IoPriority { class, level: 0 };
+        match level {
+            // The idle class has no levels
+            Some(_) if priority.is_idle() => return Err(invalid()),
+            Some(level) if level > 7 => return Err(invalid()),
+            Some(level) => priority.level = level,
+            None if !priority.is_idle() => priority.level = DEFAULT_IO_LEVEL,
+            None => {}
+        }
+        Ok(priority)
+    }
+}
+
+impl fmt::Display for IoPriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_idle() {
+            f.write_str(IO_CLASSES[self.class].0)
+        } else {
+            write!(f, "{}:{}", IO_CLASSES[self.class].0, self.level)
+        }
+    }
+}
+
+impl TryFrom<String> for IoPriority {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<IoPriority> for String {
+    fn from(priority: IoPriority) -> Self {
+        priority.to_string()
+    }
+}
+
+/// Scheduling priorities and OOM killer preference of the sandbox
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Priority {
+    pub nice: Option<i32>,
+    pub io: Option<IoPriority>,
+    pub oom_score_adj: Option<i32>,
+}
+
+impl Priority {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Applies the priorities to the calling process, from where they are inherited by the sandbox.
+    /// Only makes syscalls, so it can run in the forked child before exec.
+    pub fn apply(&self) -> io::Result<()> {
+        if let Some(nice) = self.nice
+            && unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) } != 0
+        {
+            return Err(native::report("set the nice value", None));
+        }
+        if let Some(io) = self.io
+            && unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, io.value()) }
+                != 0
+        {
+            return Err(native::report("set the I/O priority", None));
+        }
+        if let Some(oom_score_adj) = self.oom_score_adj {
+            let mut buf = [0; 12];
+            let value = format_int(oom_score_adj, &mut buf);
+            let fd = unsafe { libc::open(c"/proc/self/oom_score_adj".as_ptr(), libc::O_WRONLY) };
+            let written =
+                fd >= 0 && unsafe { libc::write(fd, value.as_ptr().cast(), value.len()) } >= 0;
+            if fd >= 0 {
+                unsafe { libc::close(fd) };
+            }
+            if !written {
+                return Err(native::report("set the OOM score adjustment", None));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats `value` into `buf` without allocating
+fn format_int(value: i32, buf: &mut [u8; 12]) -> &[u8] {
+    let mut start = buf.len();
+    let mut rest = value.unsigned_abs();
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    if value < 0 {
+        start -= 1;
+        buf[start] = b'-';
+    }
+    &buf[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::priority::{IoPriority, format_int};
+
+    #[test]
+    fn priority_values() {
+        let idle: IoPriority = "idle".parse().unwrap();
+        assert_eq!(idle.value(), 3 << 13);
+        assert_eq!(idle.to_string(), "idle");
+        let best_effort: IoPriority = "best-effort".parse().unwrap();
+        assert_eq!(best_effort.to_string(), "best-effort:4");
+        assert_eq!("realtime:0".parse::<IoPriority>().unwrap().value(), 1 << 13);
+        assert!("best-effort:8".parse::<IoPriority>().is_err());
+        assert!("idle:3".parse::<IoPriority>().is_err());
+        assert!("low".parse::<IoPriority>().is_err());
+
+        let mut buf = [0; 12];
+        assert_eq!(format_int(-1000, &mut buf), b"-1000");
+        assert_eq!(format_int(0, &mut buf), b"0");
+        assert_eq!(format_int(i32::MIN, &mut buf), b"-2147483648");
+    }
+}
@@ -0,0 +1,76 @@
+use std::{collections::HashSet, ffi::OsString, fs, io, panic, path::PathBuf, thread};
+
+/// Threads used for file system calls, which mostly wait on the file system (e.g. NFS), not the CPU
+const MAX_THREADS: usize = 8;
+/// Fewer items than this per thread aren't worth starting one, as local file systems answer in microseconds
+const MIN_ITEMS_PER_THREAD: usize = 4;
+
+/// Calls `f` for every item on a few threads, for slow file system calls. The results are in the order
+/// of the items.
+pub fn parallel_map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let threads = (items.len() / MIN_ITEMS_PER_THREAD).min(MAX_THREADS);
+    if threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let chunk_size = items.len().div_ceil(threads);
+    thread::scope(|scope| {
+        let chunks: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| chunk.iter().map(&f).collect::<Vec<_>>()))
+            .collect();
+        chunks
+            .into_iter()
+            .flat_map(|chunk| chunk.join().unwrap_or_else(|err| panic::resume_unwind(err)))
+            .collect()
+    })
+}
+
+/// The ones of the paths which exist
+pub fn existing_paths(paths: Vec<PathBuf>) -> HashSet<PathBuf> {
+    let exists = parallel_map(&paths, |path| path.exists());
+    paths
+        .into_iter()
+        .zip(exists)
+        .filter_map(|(path, exists)| exists.then_some(path))
+        .collect()
+}
+
+/// An entry of a directory read by `read_dirs`
+pub struct DirEntry {
+    pub file_name: OsString,
+    pub is_file: bool,
+}
+
+/// The entries of each of the directories, `None` for the ones which can't be opened
+pub fn read_dirs(dirs: &[PathBuf]) -> Vec<Option<io::Result<Vec<DirEntry>>>> {
+    parallel_map(dirs, |dir| {
+        let entries = fs::read_dir(dir).ok()?;
+        Some(
+            entries
+                .map(|entry| {
+                    let entry = entry?;
+                    Ok(DirEntry {
+                        file_name: entry.file_name(),
+                        is_file: entry.file_type()?.is_file(),
+                    })
+                })
+                .collect(),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::probe::parallel_map;
+
+    #[test]
+    fn parallel_map_keeps_order() {
+        let items: Vec<u32> = (0..100).collect();
+        let doubled = parallel_map(&items, |item| item * 2);
+        assert_eq!(
+            doubled,
+            items.iter().map(|item| item * 2).collect::<Vec<_>>()
+        );
+        assert_eq!(parallel_map(&[1], |item| item + 1), [2]);
+    }
+}
@@ -0,0 +1,203 @@
+use anyhow::Context;
+use std::{
+    ffi::CStr,
+    fs::File,
+    io::{self, IsTerminal, Read, Write},
+    mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::process::CommandExt,
+    },
+    process::{Command, Stdio},
+    ptr, thread,
+};
+
+/// A pseudo-terminal for the sandbox, so it can't inject input into the host's terminal (with `TIOCSTI`)
+/// or change its settings, while interactive programs still get a controlling terminal with job control
+pub struct Pty {
+    master: File,
+    slave: File,
+}
+
+impl Pty {
+    /// Opens a pseudo-terminal with the window size of the host's terminal
+    pub fn open() -> anyhow::Result<Self> {
+        let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+        if master < 0 {
+            return Err(io::Error::last_os_error()).context("Could not open a pseudo-terminal");
+        }
+        let master = unsafe { File::from_raw_fd(master) };
+        let mut name = [0 as libc::c_char; 64];
+        unsafe {
+            if libc::grantpt(master.as_raw_fd()) != 0
+                || libc::unlockpt(master.as_raw_fd()) != 0
+                || libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len()) != 0
+            {
+                return Err(io::Error::last_os_error())
+                    .context("Could not set up the pseudo-terminal");
+            }
+        }
+        let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+        let slave = unsafe {
+            libc::open(
+                name.as_ptr(),
+                libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC,
+            )
+        };
+        if slave < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Could not open {}", name.to_string_lossy()));
+        }
+        let pty = Self {
+            master,
+            slave: unsafe { File::from_raw_fd(slave) },
+        };
+        if let Some(host) = host_terminal() {
+            copy_window_size(host, pty.master.as_raw_fd());
+        }
+        Ok(pty)
+    }
+
+    /// Makes the pseudo-terminal the standard streams and controlling terminal of `command`,
+    /// which runs in a new session
+    pub fn attach(&self, command: &mut Command) -> anyhow::Result<()> {
+        let clone = || {
+            self.slave
+                .try_clone()
+                .context("Could not duplicate the pseudo-terminal")
+        };
+        command
+            .stdin(Stdio::from(clone()?))
+            .stdout(Stdio::from(clone()?))
+            .stderr(Stdio::from(clone()?));
+        // SAFETY: only makes syscalls in the forked child
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Forwards the input and window size changes of the host's terminal to the pseudo-terminal and its output
+    /// back, until the sandbox closes it. The host's terminal is in raw mode meanwhile, so all keys reach
+    /// the sandbox. Must be called after starting the sandbox, so only it still has the pseudo-terminal open.
+    pub fn proxy(self) -> anyhow::Result<PtyProxy> {
+        let Self { master, slave } = self;
+        drop(slave);
+
+        let saved_termios = if io::stdin().is_terminal() {
+            Some(make_raw(libc::STDIN_FILENO)?)
+        } else {
+            None
+        };
+
+        if let Some(host) = host_terminal() {
+            let resize = master
+                .try_clone()
+                .context("Could not duplicate the pseudo-terminal")?;
+            // Blocked before starting the other threads, so only this one receives it
+            let mut signals = unsafe { mem::zeroed::<libc::sigset_t>() };
+            unsafe {
+                libc::sigemptyset(&mut signals);
+                libc::sigaddset(&mut signals, libc::SIGWINCH);
+                libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+            }
+            thread::spawn(move || {
+                let mut signal = 0;
+                while unsafe { libc::sigwait(&signals, &mut signal) } == 0 {
+                    // Resizing the pseudo-terminal sends SIGWINCH to the sandbox
+                    copy_window_size(host, resize.as_raw_fd());
+                }
+            });
+        }
+
+        let mut input = master
+            .try_clone()
+            .context("Could not duplicate the pseudo-terminal")?;
+        thread::spawn(move || {
+            let _ = io::copy(&mut io::stdin().lock(), &mut input);
+        });
+
+        let mut output = master;
+        let output = thread::spawn(move || {
+            let mut buf = [0; 4096];
+            let mut stdout = io::stdout().lock();
+            // Reading fails with EIO once the sandbox closed all of its ends
+            while let Ok(len @ 1..) = output.read(&mut buf) {
+                if stdout
+                    .write_all(&buf[..len])
+                    .and_then(|_| stdout.flush())
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        Ok(PtyProxy {
+            output: Some(output),
+            saved_termios,
+        })
+    }
+}
+
+/// Restores the host's terminal when dropped
+pub struct PtyProxy {
+    output: Option<thread::JoinHandle<()>>,
+    saved_termios: Option<libc::termios>,
+}
+
+impl PtyProxy {
+    /// Waits until all the output of the sandbox is written
+    pub fn finish(mut self) {
+        if let Some(output) = self.output.take() {
+            let _ = output.join();
+        }
+    }
+}
+
+impl Drop for PtyProxy {
+    fn drop(&mut self) {
+        if let Some(termios) = &self.saved_termios {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, termios) };
+        }
+    }
+}
+
+fn host_terminal() -> Option<RawFd> {
+    if io::stdout().is_terminal() {
+        Some(libc::STDOUT_FILENO)
+    } else if io::stdin().is_terminal() {
+        Some(libc::STDIN_FILENO)
+    } else {
+        None
+    }
+}
+
+fn copy_window_size(from: RawFd, to: RawFd) {
+    let mut size = unsafe { mem::zeroed::<libc::winsize>() };
+    unsafe {
+        if libc::ioctl(from, libc::TIOCGWINSZ, &mut size) == 0 {
+            libc::ioctl(to, libc::TIOCSWINSZ, &size);
+        }
+    }
+}
+
+/// Switches the terminal to raw mode and returns its previous settings
+fn make_raw(fd: RawFd) -> anyhow::Result<libc::termios> {
+    let mut termios = unsafe { mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+        return Err(io::Error::last_os_error()).context("Could not read the terminal settings");
+    }
+    let saved = termios;
+    unsafe { libc::cfmakeraw(&mut termios) };
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+        return Err(io::Error::last_os_error())
+            .context("Could not switch the terminal to raw mode");
+    }
+    Ok(saved)
+}
@@ -0,0 +1,148 @@
+use crate::{error::FlatboxError, picker};
+use std::{cmp::Ordering, fs, path::PathBuf};
+
+/// An app given by its id or by a full or partial `id/arch/branch` ref
+#[derive(Debug, PartialEq, Eq)]
+pub struct AppRef {
+    pub id: String,
+    pub arch: Option<String>,
+    pub branch: Option<String>,
+}
+
+impl AppRef {
+    /// Combines the `--app` value with the `--arch` and `--branch` flags, which have to agree with it
+    pub fn parse(app: &str, arch: Option<&str>, branch: Option<&str>) -> anyhow::Result<Self> {
+        let mut parts = app.split('/');
+        let id = parts.next().unwrap_or_default().to_owned();
+        let mut merge = |flag: Option<&str>, name: &str| -> anyhow::Result<Option<String>> {
+            match (parts.next().filter(|part| !part.is_empty()), flag) {
+                (Some(part), Some(flag)) if part != flag => Err(FlatboxError::InvalidOptions {
+                    message: format!("The {name} of {app} conflicts with --{name} {flag}"),
+                }
+                .into()),
+                (part, flag) => Ok(part.or(flag).map(str::to_owned)),
+            }
+        };
+        let arch = merge(arch, "arch")?;
+        let branch = merge(branch, "branch")?;
+        Ok(Self { id, arch, branch })
+    }
+}
+
+/// Expands a partial runtime ref (`name`, `name/arch` or `name//branch`) to a full `name/arch/branch` one.
+/// When several branches are installed, the newest one is picked as long as their versions can be compared.
+pub fn resolve_runtime_ref(
+    reference: &str,
+    default_arch: &str,
+    install_dirs: &[PathBuf],
+) -> anyhow::Result<String> {
+    let mut parts = reference.split('/');
+    let name = parts.next().unwrap_or_default();
+    let arch = parts
+        .next()
+        .filter(|arch| !arch.is_empty())
+        .unwrap_or(default_arch);
+    if let Some(branch) = parts.next().filter(|branch| !branch.is_empty()) {
+        return Ok(format!("{name}/{arch}/{branch}"));
+    }
+
+    let mut branches = installed_branches(name, arch, install_dirs);
+    let branch = match branches.as_slice() {
+        [] => {
+            return Err(FlatboxError::RuntimeNotFound {
+                runtime: format!("{name}/{arch}"),
+                searched_dirs: install_dirs.to_vec(),
+            }
+            .into());
+        }
+        [branch] => branch.clone(),
+        _ => {
+            if branches
+                .iter()
+                .any(|branch| branch_version(branch).is_none())
+            {
+                let refs: Vec<String> = branches
+                    .iter()
+                    .map(|branch| format!("{name}/{arch}/{branch}"))
+                    .collect();
+                let choice = picker::pick(
+                    &format!("Runtime {name} has several branches installed for {arch}"),
+                    &refs,
+                )?;
+                return Ok(refs[choice].clone());
+            }
+            branches.sort_by(|a, b| compare_branches(a, b));
+            let newest = branches.pop().unwrap_or_default();
+            crate::warn!(
+                "Using the newest installed branch {name}/{arch}/{newest} (also installed: {})",
+                branches.join(", ")
+            );
+            newest
+        }
+    };
+    Ok(format!("{name}/{arch}/{branch}"))
+}
+
+/// Branches of a runtime with an active deployment in any of the installations
+fn installed_branches(name: &str, arch: &str, install_dirs: &[PathBuf]) -> Vec<String> {
+    let mut branches = Vec::new();
+    for dir in install_dirs {
+        let arch_dir = dir.join("runtime").join(name).join(arch);
+        for entry in fs::read_dir(arch_dir).into_iter().flatten().flatten() {
+            if let Ok(branch) = entry.file_name().into_string()
+                && entry.path().join("active").exists()
+                && !branches.contains(&branch)
+            {
+                branches.push(branch);
+            }
+        }
+    }
+    branches
+}
+
+/// Numeric components of versioned branches such as `24.08` or `5.15-24.08`
+fn branch_version(branch: &str) -> Option<Vec<u64>> {
+    branch
+        .split(['.', '-'])
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+fn compare_branches(a: &str, b: &str) -> Ordering {
+    branch_version(a).cmp(&branch_version(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::refs::{AppRef, branch_version, compare_branches};
+    use std::cmp::Ordering;
+
+    #[test]
+    fn app_refs() {
+        assert_eq!(
+            AppRef::parse("org.example.App/x86_64/beta", None, None).unwrap(),
+            AppRef {
+                id: "org.example.App".to_owned(),
+                arch: Some("x86_64".to_owned()),
+                branch: Some("beta".to_owned()),
+            }
+        );
+        assert_eq!(
+            AppRef::parse("org.example.App//beta", Some("i386"), None).unwrap(),
+            AppRef {
+                id: "org.example.App".to_owned(),
+                arch: Some("i386".to_owned()),
+                branch: Some("beta".to_owned()),
+            }
+        );
+        assert!(AppRef::parse("org.example.App//beta", None, Some("stable")).is_err());
+    }
+
+    #[test]
+    fn branch_ordering() {
+        assert_eq!(Ordering::Less, compare_branches("23.08", "24.08"));
+        assert_eq!(Ordering::Greater, compare_branches("48", "9"));
+        assert_eq!(Ordering::Less, compare_branches("5.15-23.08", "6.8"));
+        assert_eq!(None, branch_version("stable"));
+    }
+}
@@ -0,0 +1,187 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{fmt, io, str::FromStr};
+
+const UNLIMITED: &str = "unlimited";
+/// The resources which can be limited, by their `ulimit` style name
+const RESOURCES: [(&str, i32); 16] = [
+    ("as", libc::RLIMIT_AS as i32),
+    ("core", libc::RLIMIT_CORE as i32),
+    ("cpu", libc::RLIMIT_CPU as i32),
+    ("data", libc::RLIMIT_DATA as i32),
+    ("fsize", libc::RLIMIT_FSIZE as i32),
+    ("locks", libc::RLIMIT_LOCKS as i32),
+    ("memlock", libc::RLIMIT_MEMLOCK as i32),
+    ("msgqueue", libc::RLIMIT_MSGQUEUE as i32),
+    ("nice", libc::RLIMIT_NICE as i32),
+    ("nofile", libc::RLIMIT_NOFILE as i32),
+    ("nproc", libc::RLIMIT_NPROC as i32),
+    ("rss", libc::RLIMIT_RSS as i32),
+    ("rtprio", libc::RLIMIT_RTPRIO as i32),
+    ("rttime", libc::RLIMIT_RTTIME as i32),
+    ("sigpending", libc::RLIMIT_SIGPENDING as i32),
+    ("stack", libc::RLIMIT_STACK as i32),
+];
+
+/// A resource limit of the sandboxed process, written as `NAME=LIMIT` (setting the soft and hard limit) or
+/// `NAME=SOFT:HARD`, where a limit can be `unlimited`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rlimit {
+    /// Index into `RESOURCES`
+    resource: usize,
+    pub soft: u64,
+    pub hard: u64,
+}
+
+impl Rlimit {
+    pub fn name(&self) -> &'static str {
+        RESOURCES[self.resource].0
+    }
+
+    /// Sets the limit for flatbox, to be inherited by the sandbox
+    pub fn apply(&self) -> anyhow::Result<()> {
+        let limit = libc::rlimit {
+            rlim_cur: self.soft,
+            rlim_max: self.hard,
+        };
+        if unsafe { libc::setrlimit(RESOURCES[self.resource].1 as _, &limit) } != 0 {
+            return Err(io::Error::last_os_error()).with_context(|| {
+                format!(
+                    "Could not set the {} limit to {self}, raising hard limits needs privileges",
+                    self.name()
+                )
+            });
+        }
+        Ok(())
+    }
+
+    /// The current limit of flatbox for a resource, with the soft limit raised to the hard one, which needs no
+    /// privileges
+    pub fn raised(name: &str) -> anyhow::Result<Self> {
+        let resource = RESOURCES
+            .iter()
+            .position(|(known, _)| *known == name)
+            .with_context(|| format!("Unknown resource {name}"))?;
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if unsafe { libc::getrlimit(RESOURCES[resource].1 as _, &mut limit) } != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Could not get the {name} limit"));
+        }
+        Ok(Rlimit {
+            resource,
+            soft: limit.rlim_max,
+            hard: limit.rlim_max,
+        })
+    }
+
+    /// The name used by OCI and systemd, e.g. `RLIMIT_NOFILE`
+    pub fn constant_name(&self) -> String {
+        format!("RLIMIT_{}", self.name().to_ascii_uppercase())
+    }
+
+    pub fn is_unlimited(value: u64) -> bool {
+        value == libc::RLIM_INFINITY
+    }
+}
+
+fn parse_limit(value: &str) -> Option<u64> {
+    if value == UNLIMITED || value == "infinity" {
+        Some(libc::RLIM_INFINITY)
+    } else {
+        value.parse().ok()
+    }
+}
+
+fn write_limit(f: &mut fmt::Formatter<'_>, value: u64) -> fmt::Result {
+    if Rlimit::is_unlimited(value) {
+        f.write_str(UNLIMITED)
+    } else {
+        write!(f, "{value}")
+    }
+}
+
+impl FromStr for Rlimit {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let Some((name, limits)) = value.split_once('=') else {
+            return Err(format!(
+                "expected NAME=LIMIT or NAME=SOFT:HARD, found '{value}'"
+            ));
+        };
+        let Some(resource) = RESOURCES.iter().position(|(known, _)| *known == name) else {
+            let names: Vec<&str> = RESOURCES.iter().map(|(name, _)| *name).collect();
+            return Err(format!(
+                "unknown resource '{name}', expected one of {}",
+                names.join(", ")
+            ));
+        };
+        let (soft, hard) = limits.split_once(':').unwrap_or((limits, limits));
+        match (parse_limit(soft), parse_limit(hard)) {
+            (Some(soft), Some(hard)) if soft <= hard => Ok(Rlimit {
+                resource,
+                soft,
+                hard,
+            }),
+            (Some(_), Some(_)) => Err(format!(
+                "the soft limit of '{value}' is above the hard limit"
+            )),
+            _ => Err(format!(
+                "expected a number or '{UNLIMITED}' as the limit, found '{value}'"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Rlimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}=", self.name())?;
+        write_limit(f, self.soft)?;
+        if self.hard != self.soft {
+            f.write_str(":")?;
+            write_limit(f, self.hard)?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for Rlimit {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Rlimit> for String {
+    fn from(rlimit: Rlimit) -> Self {
+        rlimit.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rlimit::Rlimit;
+
+    #[test]
+    fn rlimit_parsing() {
+        let nofile: Rlimit = "nofile=524288".parse().unwrap();
+        assert_eq!((nofile.soft, nofile.hard), (524288, 524288));
+        assert_eq!(nofile.constant_name(), "RLIMIT_NOFILE");
+
+        let core: Rlimit = "core=0:unlimited".parse().unwrap();
+        assert_eq!(core.soft, 0);
+        assert!(Rlimit::is_unlimited(core.hard));
+        assert_eq!(core.to_string(), "core=0:unlimited");
+        assert_eq!(nofile.to_string(), "nofile=524288");
+
+        assert!("nofile=2048:1024".parse::<Rlimit>().is_err());
+        assert!("files=1024".parse::<Rlimit>().is_err());
+        assert!("nproc".parse::<Rlimit>().is_err());
+        assert!("nproc=lots".parse::<Rlimit>().is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use crate::{
+    error::FlatboxError,
+    plan::{MountOp, SandboxPlan},
+};
+use anyhow::Context;
+use std::{
+    ffi::OsString,
+    fs,
+    os::unix::fs::{PermissionsExt, symlink},
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+use tempdir::TempDir;
+
+/// Dirs of the sandbox holding the environment, which are copied into the rootfs. Everything else bound from
+/// the host (home, `/sys`, the sockets, ...) only gets an empty mount point.
+const ENVIRONMENT_DIRS: [&str; 3] = ["/usr", "/app", "/etc"];
+/// The host's own config bound into the sandbox (users, `shadow`, the machine id) is left out
+const HOST_CONFIG_DIR: &str = "/etc";
+/// Symlinks followed when resolving a path in the rootfs, like the kernel's limit
+const MAX_SYMLINKS: usize = 40;
+const TARBALL_EXTENSIONS: [&str; 4] = [".tar", ".tar.gz", ".tar.xz", ".tar.zst"];
+
+/// Whether the rootfs is written as a tarball rather than a dir tree
+pub fn is_tarball(out: &Path) -> bool {
+    let name = out.file_name().unwrap_or_default().to_string_lossy();
+    TARBALL_EXTENSIONS
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
+
+/// Writes the filesystem of the sandbox to `out`, as a dir tree or a tarball (compressed by its extension)
+/// with the files owned by root.
+/// The files generated for the sandbox are found in `data_dirs`.
+pub fn export(out: &Path, plan: &SandboxPlan, data_dirs: &[&Path]) -> anyhow::Result<()> {
+    if !is_tarball(out) {
+        return materialize(out, plan, data_dirs);
+    }
+    let parent = out
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let tempdir = TempDir::new_in(parent, "flatbox-rootfs").context("Could not create tempdir")?;
+    let rootfs = tempdir.path().join("rootfs");
+    materialize(&rootfs, plan, data_dirs)?;
+
+    crate::info!("Writing {}", out.display());
+    let status = Command::new("tar")
+        .args([
+            "--create",
+            "--auto-compress",
+            "--owner=0",
+            "--group=0",
+            "--file",
+        ])
+        .arg(out)
+        .arg("--directory")
+        .arg(&rootfs)
+        .arg(".")
+        .status()
+        .context("Could not run tar")?;
+    if !status.success() {
+        return Err(FlatboxError::ToolFailed {
+            tool: "tar".to_owned(),
+            action: format!("write {}", out.display()),
+            details: status.to_string(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Creates the filesystem of the sandbox in the new dir `rootfs`, applying the steps of the plan in order.
+/// Bind mounts of the environment and of generated files are copied, later ones replacing what they cover
+/// like mounts would.
+pub fn materialize(rootfs: &Path, plan: &SandboxPlan, data_dirs: &[&Path]) -> anyhow::Result<()> {
+    if fs::read_dir(rootfs).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(FlatboxError::RootfsNotEmpty {
+            path: rootfs.to_owned(),
+        }
+        .into());
+    }
+    fs::create_dir_all(rootfs).with_context(|| format!("Could not create {}", rootfs.display()))?;
+
+    for op in &plan.mounts {
+        match op {
+            MountOp::Bind { source, dest, .. }
+            | MountOp::TmpOverlay { source, dest }
+            | MountOp::Overlay { source, dest, .. } => {
+                let path = resolve(rootfs, dest)?;
+                let generated = data_dirs.iter().any(|dir| source.starts_with(dir));
+                let environment = ENVIRONMENT_DIRS.iter().any(|dir| dest.starts_with(dir))
+                    && !source.starts_with(HOST_CONFIG_DIR);
+                if generated || environment {
+                    crate::info!("Copying {} to {}", source.display(), dest.display());
+                    replace(&path)?;
+                    copy_tree(source, &path, true)?;
+                } else if source.is_dir() {
+                    crate::debug!("Leaving out {}, it's bound from the host", dest.display());
+                    replace(&path)?;
+                    create_dir(&path, 0o755)?;
+                }
+            }
+            MountOp::Tmpfs { dest, perms } | MountOp::Dir { dest, perms } => {
+                let path = resolve(rootfs, dest)?;
+                // A tmpfs hides what's below it
+                if matches!(op, MountOp::Tmpfs { .. }) {
+                    replace(&path)?;
+                }
+                create_dir(&path, perms.unwrap_or(0o755))?;
+            }
+            MountOp::Dev { dest } | MountOp::Proc { dest } => {
+                let path = resolve(rootfs, dest)?;
+                replace(&path)?;
+                create_dir(&path, 0o755)?;
+            }
+            MountOp::Symlink { target, dest } => {
+                let path = resolve(rootfs, dest)?;
+                replace(&path)?;
+                create_parent(&path)?;
+                symlink(target, &path)
+                    .with_context(|| format!("Could not create symlink {}", path.display()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The location of the sandbox's `path` in `rootfs`. The symlinks of its parents are resolved within the
+/// rootfs, as absolute ones would lead out of it on the host.
+fn resolve(rootfs: &Path, path: &Path) -> anyhow::Result<PathBuf> {
+    let Some(name) = path.file_name() else {
+        return Ok(rootfs.to_owned());
+    };
+    let mut resolved = PathBuf::new();
+    let mut pending: Vec<OsString> = path
+        .parent()
+        .unwrap_or(Path::new(""))
+        .components()
+        .rev()
+        .map(|component| component.as_os_str().to_owned())
+        .collect();
+    let mut symlinks = 0;
+    while let Some(component) = pending.pop() {
+        match Path::new(&component).components().next() {
+            Some(Component::Normal(name)) => {
+                let candidate = resolved.join(name);
+                match fs::read_link(rootfs.join(&candidate)) {
+                    Ok(target) => {
+                        symlinks += 1;
+                        if symlinks > MAX_SYMLINKS {
+                            return Err(FlatboxError::SymlinkLoop {
+                                path: path.to_owned(),
+                            }
+                            .into());
+                        }
+                        pending.extend(
+                            target
+                                .components()
+                                .rev()
+                                .map(|component| component.as_os_str().to_owned()),
+                        );
+                    }
+                    Err(_) => resolved = candidate,
+                }
+            }
+            Some(Component::RootDir) => resolved = PathBuf::new(),
+            Some(Component::ParentDir) => {
+                resolved.pop();
+            }
+            _ => {}
+        }
+    }
+    Ok(rootfs.join(resolved).join(name))
+}
+
+/// Removes what's at `path`, for something to take its place
+fn replace(path: &Path) -> anyhow::Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => return Ok(()),
+    };
+    result.with_context(|| format!("Could not remove {}", path.display()))
+}
+
+fn create_parent(path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Could not create {}", parent.display()))?;
+    }
+    Ok(())
+}
+
+fn create_dir(path: &Path, mode: u32) -> anyhow::Result<()> {
+    fs::create_dir_all(path).with_context(|| format!("Could not create {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Could not set the permissions of {}", path.display()))
+}
+
+/// Copies a file or dir tree, keeping symlinks and permissions. Like a bind mount, a symlink at `source`
+/// itself is followed. Sockets, fifos and devices are left out.
+fn copy_tree(source: &Path, dest: &Path, follow: bool) -> anyhow::Result<()> {
+    let metadata = if follow {
+        fs::metadata(source)
+    } else {
+        fs::symlink_metadata(source)
+    }
+    .with_context(|| format!("Could not read {}", source.display()))?;
+
+    if metadata.is_symlink() {
+        let target = fs::read_link(source)
+            .with_context(|| format!("Could not read {}", source.display()))?;
+        symlink(target, dest).with_context(|| format!("Could not create {}", dest.display()))
+    } else if metadata.is_dir() {
+        create_parent(dest)?;
+        fs::create_dir(dest).with_context(|| format!("Could not create {}", dest.display()))?;
+        let entries =
+            fs::read_dir(source).with_context(|| format!("Could not read {}", source.display()))?;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("Could not read {}", source.display()))?;
+            copy_tree(&entry.path(), &dest.join(entry.file_name()), false)?;
+        }
+        // Kept writable by the user, so later steps can add to it
+        let mode = metadata.permissions().mode() | 0o200;
+        fs::set_permissions(dest, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Could not set the permissions of {}", dest.display()))
+    } else if metadata.is_file() {
+        create_parent(dest)?;
+        fs::copy(source, dest)
+            .map(drop)
+            .with_context(|| format!("Could not copy {}", source.display()))
+    } else {
+        crate::debug!("Leaving out {}, it's not a regular file", source.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rootfs::resolve;
+    use std::{fs, os::unix::fs::symlink, path::Path};
+    use tempdir::TempDir;
+
+    #[test]
+    fn resolves_symlinks_within_the_rootfs() {
+        let rootfs = TempDir::new("flatbox-rootfs").unwrap();
+        let rootfs = rootfs.path();
+        fs::create_dir_all(rootfs.join("usr/lib")).unwrap();
+        symlink("/usr/lib", rootfs.join("lib")).unwrap();
+        symlink("../share", rootfs.join("usr/lib/up")).unwrap();
+
+        assert_eq!(
+            resolve(rootfs, Path::new("/lib/x86_64-linux-gnu")).unwrap(),
+            rootfs.join("usr/lib/x86_64-linux-gnu")
+        );
+        assert_eq!(
+            resolve(rootfs, Path::new("/lib/up/fonts")).unwrap(),
+            rootfs.join("usr/share/fonts")
+        );
+        assert_eq!(
+            resolve(rootfs, Path::new("/../../etc/passwd")).unwrap(),
+            rootfs.join("etc/passwd")
+        );
+        // The last component is replaced, not followed
+        assert_eq!(
+            resolve(rootfs, Path::new("/lib")).unwrap(),
+            rootfs.join("lib")
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use crate::{
+    error::FlatboxError,
+    logging::{self, Timing},
+    options::Backend,
+    pty::Pty,
+    sandbox::Sandbox,
+};
+use anyhow::Context;
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::process::CommandExt,
+    },
+    process::{Child, Command, ExitStatus},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Time the sandbox gets to exit after SIGTERM when it timed out, before it's killed
+const TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How `Sandbox::run` starts the sandbox and waits for it
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    pub backend: Backend,
+    /// Terminates the whole sandbox when the command doesn't exit in time
+    pub timeout: Option<Duration>,
+    /// Gives the sandbox a pseudo-terminal proxied to the host's, instead of the host's terminal
+    pub pty: bool,
+    /// Adds how long creating the sandbox and ldconfig took to the recorded `logging::timings`
+    pub time_startup: bool,
+}
+
+impl Sandbox {
+    /// Starts the sandbox and waits until it exits. When it doesn't exit within the timeout, it's terminated
+    /// and `FlatboxError::TimedOut` is returned.
+    pub fn run(mut self, options: &RunOptions) -> anyhow::Result<ExitStatus> {
+        let backend = options.backend;
+        let probe = if options.time_startup {
+            Some(StartupProbe::new(&mut self)?)
+        } else {
+            None
+        };
+        let span = logging::span("command");
+        let (mut cmd, _data) = self.into_command(backend)?;
+        drop(span);
+        crate::info!("Generated cmd: {cmd:#?}");
+        if let Some(probe) = &probe {
+            probe.attach(&mut cmd);
+        }
+
+        let pty = if options.pty {
+            let pty = Pty::open()?;
+            // Also puts the sandbox into its own process group, for the timeout
+            pty.attach(&mut cmd)?;
+            Some(pty)
+        } else {
+            if options.timeout.is_some() {
+                // So the whole sandbox can be signalled, not only bwrap
+                cmd.process_group(0);
+            }
+            None
+        };
+        let span = logging::span("spawn");
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(err) if backend == Backend::Native => {
+                return Err(err).context("Could not create the sandbox");
+            }
+            Err(source) => {
+                return Err(FlatboxError::BwrapSpawn {
+                    program: cmd.get_program().to_string_lossy().into_owned(),
+                    source,
+                }
+                .into());
+            }
+        };
+        drop(span);
+        let startup = probe.map(|probe| probe.watch(Instant::now()));
+
+        // The command holds a copy of the pseudo-terminal, which has to be closed to notice the sandbox closing it
+        drop(cmd);
+        let pty_proxy = pty.map(Pty::proxy).transpose()?;
+
+        let status = match options.timeout {
+            Some(timeout) => wait_with_timeout(&mut child, timeout)?,
+            None => Some(child.wait()?),
+        };
+        if let Some(pty_proxy) = pty_proxy {
+            pty_proxy.finish();
+        }
+        if let Some(startup) = startup {
+            logging::add_timings(startup.finish());
+        }
+        status.ok_or_else(|| {
+            FlatboxError::TimedOut {
+                timeout: options.timeout.unwrap_or_default(),
+            }
+            .into()
+        })
+    }
+}
+
+/// Waits for the child leading its own process group. When it doesn't exit in time, the whole group is sent
+/// SIGTERM, and SIGKILL after the grace period, and `None` is returned.
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let Some(deadline) = Instant::now().checked_add(timeout) else {
+        // Too far ahead to ever be reached
+        return child.wait().map(Some);
+    };
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        thread::sleep(WAIT_POLL_INTERVAL);
+    }
+
+    let group = -(child.id() as libc::pid_t);
+    unsafe { libc::kill(group, libc::SIGTERM) };
+    let deadline = Instant::now() + TIMEOUT_GRACE_PERIOD;
+    // The group exists as long as any process of the sandbox does, including the unreaped child
+    while unsafe { libc::kill(group, 0) } == 0 {
+        child.try_wait()?;
+        if Instant::now() >= deadline {
+            unsafe { libc::kill(group, libc::SIGKILL) };
+            break;
+        }
+        thread::sleep(WAIT_POLL_INTERVAL);
+    }
+    child.wait()?;
+    Ok(None)
+}
+
+/// Measures the phases of the startup after the sandbox was composed: starting bwrap, bwrap creating
+/// the sandbox, and ldconfig. The sandbox writes a byte to a pipe before and after running ldconfig.
+struct StartupProbe {
+    read: File,
+    write: OwnedFd,
+}
+
+impl StartupProbe {
+    /// Sets up the sandbox to report its progress, must be called before creating its command
+    fn new(sandbox: &mut Sandbox) -> anyhow::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error()).context("Could not create a pipe");
+        }
+        let read = unsafe { File::from_raw_fd(fds[0]) };
+        let write = unsafe { OwnedFd::from_raw_fd(fds[1]) };
+        // The shell only redirects single digit fds
+        let write = if write.as_raw_fd() < 10 {
+            write
+        } else {
+            let fd = unsafe { libc::fcntl(write.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 3) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error()).context("Could not duplicate the pipe");
+            }
+            unsafe { OwnedFd::from_raw_fd(fd) }
+        };
+        sandbox.report_ldconfig(write.as_raw_fd())?;
+        Ok(Self { read, write })
+    }
+
+    /// Passes the pipe on to the sandbox only, the helpers started before keep it closed
+    fn attach(&self, command: &mut Command) {
+        let fd = self.write.as_raw_fd();
+        // SAFETY: only makes a syscall in the forked child
+        unsafe {
+            command.pre_exec(move || {
+                if libc::fcntl(fd, libc::F_SETFD, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Collects the progress of the sandbox started at `spawned`, must be called after starting it
+    fn watch(self, spawned: Instant) -> StartupWatch {
+        let Self { mut read, write } = self;
+        drop(write);
+        let progress = thread::spawn(move || {
+            let mut times = Vec::new();
+            let mut byte = [0];
+            while let Ok(1) = read.read(&mut byte) {
+                times.push(Instant::now());
+            }
+            times
+        });
+        StartupWatch { spawned, progress }
+    }
+}
+
+struct StartupWatch {
+    spawned: Instant,
+    progress: JoinHandle<Vec<Instant>>,
+}
+
+impl StartupWatch {
+    /// The durations of creating the sandbox and of ldconfig, as far as the sandbox reported them.
+    /// Waits until it closed the pipe, which it does before starting the command.
+    fn finish(self) -> Vec<Timing> {
+        let times = self.progress.join().unwrap_or_default();
+        [self.spawned]
+            .iter()
+            .chain(&times)
+            .zip(&times)
+            .zip(["sandbox", "ldconfig"])
+            .map(|((start, end), name)| Timing {
+                name,
+                depth: 0,
+                duration: end.duration_since(*start),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,1553 @@
+use crate::{
+    alsa, arch, build,
+    bwrap::{BwrapBuilder, BwrapData},
+    coredump, deploy,
+    documents::DocumentPortal,
+    environment::{Environment, parse_env_file},
+    error::FlatboxError,
+    extension_image,
+    extensions::{self, ExtensionContext},
+    find_app_path, find_install_path, flatpak_install_dirs, fonts, forwarding, graphics,
+    host_command, icons,
+    keyfile::parse_keyfile,
+    list_available_runtimes, locale, logging, nested, nvidia, oci_image,
+    options::{Backend, GlMode, HomeMode, SandboxOptions},
+    ostree, perf,
+    permissions::{self, Permissions},
+    plan::SandboxPlan,
+    refs::{self, AppRef},
+    sockets::{self, RuntimeDir},
+    system, video, wrap,
+};
+use anyhow::Context;
+use indexmap::IndexMap;
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, Read},
+    os::{fd::RawFd, unix::fs::DirBuilderExt},
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+
+const ROOT_USR_MERGED_DIRS: [&str; 5] = ["bin", "lib", "lib32", "lib64", "sbin"];
+const FORBIDDEN_HOST_ROOT_DIRS: [&str; 5] = ["app", "usr", "run", "etc", "var"];
+const FORBIDDEN_RUN_DIRS: [&str; 2] = ["flatpak", "host"];
+/// Devices which can be granted in a private `/dev`, besides the render nodes of `dri`
+const PRIVATE_DEV_DEVICES: [(&str, &str); 4] = [
+    ("kvm", "/dev/kvm"),
+    ("shm", "/dev/shm"),
+    ("input", "/dev/input"),
+    ("usb", "/dev/bus/usb"),
+];
+/// Paths under `/run` which are exposed in strict mode. The system bus (`dbus`) is added when granted.
+const STRICT_RUN_ALLOWLIST: [&str; 2] = ["udev", "systemd/resolve"];
+const EXPOSED_ETC_PATHS: [&str; 3] = ["passwd", "group", "shadow"];
+const GENERATED_ETC_FILES: [&str; 1] = ["localtime"];
+const TZ_RUNTIME_SUBDIR: &str = "share/zoneinfo";
+const TZ_HOST_DIR: &str = "/usr/share/zoneinfo";
+const TZ_HOST_MOUNT_DIR: &str = "/run/host/share/zoneinfo";
+const CA_BUNDLE_PATHS: [&str; 4] = [
+    "ssl/certs/ca-certificates.crt",
+    "pki/tls/certs/ca-bundle.crt",
+    "ca-certificates/extracted/tls-ca-bundle.pem",
+    "ssl/cert.pem",
+];
+/// Directories of single certificates, e.g. the hashed links used as an OpenSSL `CApath`, and the
+/// Debian store those links point into
+const CA_DIRS: [&str; 4] = [
+    "/etc/ssl/certs",
+    "/etc/pki/tls",
+    "/etc/ca-certificates",
+    "/usr/share/ca-certificates",
+];
+const HOST_ETC_FILES: [&str; 4] = ["resolv.conf", "hosts", "host.conf", "gai.conf"];
+/// The `/dev/shm` of an app with `per-app-dev-shm` is `/dev/shm/flatbox-<app-id>` on the host
+pub(crate) const APP_SHM_PREFIX: &str = "flatbox-";
+const APP_DATA_SUBDIRS: [&str; 5] = ["data", "config", "cache", "cache/tmp", ".local/state"];
+/// Run by `sh -c` in the sandbox with the command line as the arguments
+const LDCONFIG_SCRIPT: &str = "ldconfig && exec \"$@\"";
+const VAR_OVERRIDDEN_DIRS: [&str; 2] = ["run", "tmp"];
+/// Dirs of the runtime's `/var` which get mounts of flatbox inside them, like the machine id
+const VAR_EXPANDED_DIRS: [&str; 1] = ["/var/lib"];
+/// Branch of the runtime ref standing for a rootfs without metadata
+const ROOTFS_BRANCH: &str = "local";
+pub(crate) const DEBUGGER: &str = "gdb";
+/// Run by gdb before loading the program: the dirs of the debug extensions, and the build dir of flatpak-builder
+/// mapped to the sources of the app's debug extension
+const DEBUGGER_INIT_COMMANDS: [&str; 2] = [
+    "set debug-file-directory /app/lib/debug:/usr/lib/debug",
+    "set substitute-path /run/build /app/lib/debug/source",
+];
+/// Variables of the host's debugging tools kept with the `devel` feature
+const DEVEL_ENV_VARS: [&str; 3] = [
+    "DEBUGINFOD_URLS",
+    "DEBUGINFOD_TIMEOUT",
+    "DEBUGINFOD_PROGRESS",
+];
+/// Federates the debuginfod servers of the major distributions
+const DEFAULT_DEBUGINFOD_URLS: &str = "https://debuginfod.elfutils.org/";
+const APP_VAR_BINDINGS: [(&str, &str); 4] = [
+    ("data", "/var/data"),
+    ("config", "/var/config"),
+    ("cache", "/var/cache"),
+    ("cache/tmp", "/var/tmp"),
+];
+const PATH_BINDINDGS: [(&str, &str, bool); 1] = [("/", "/run/host/root", true)];
+const MACHINE_ID_PATHS: [&str; 2] = ["/etc/machine-id", "/var/lib/dbus/machine-id"];
+const DEFAULT_ENV: [(&str, Option<&str>); 45] = [
+    ("FLATBOX_ENV", Some("1")),
+    ("FLATBOX_SCOPE", None),
+    ("PATH", Some("/app/bin:/usr/bin")),
+    ("LD_LIBRARY_PATH", None),
+    ("LD_PRELOAD", None),
+    ("LD_AUDIT", None),
+    ("XDG_CONFIG_DIRS", Some("/app/etc/xdg:/etc/xdg")),
+    ("XDG_DATA_DIRS", Some("/app/share:/usr/share")),
+    ("SHELL", Some("/bin/sh")),
+    ("TEMP", None),
+    ("TEMPDIR", None),
+    ("TMP", None),
+    ("TMPDIR", None),
+    ("container", None),
+    ("TZDIR", None),
+    ("PYTHONPATH", None),
+    ("PYTHONPYCACHEPREFIX", None),
+    ("PERLLIB", None),
+    ("PERL5LIB", None),
+    (
+        "XCURSOR_PATH",
+        Some(
+            "~/.local/share/icons:~/.icons:/run/host/user-share/icons:/run/host/user-icons:/run/host/share/icons:/usr/share/icons",
+        ),
+    ),
+    ("GST_PLUGIN_PATH_1_0", None),
+    ("GST_REGISTRY", None),
+    ("GST_REGISTRY_1_0", None),
+    ("GST_PLUGIN_PATH", None),
+    ("GST_PLUGIN_SYSTEM_PATH", None),
+    ("GST_PLUGIN_SCANNER", None),
+    ("GST_PLUGIN_SCANNER_1_0", None),
+    ("GST_PLUGIN_SYSTEM_PATH_1_0", None),
+    ("GST_PRESET_PATH", None),
+    ("GST_PTP_HELPER", None),
+    ("GST_PTP_HELPER_1_0", None),
+    ("GST_INSTALL_PLUGINS_HELPER", None),
+    ("KRB5CCNAME", None),
+    ("XKB_CONFIG_ROOT", None),
+    ("GIO_EXTRA_MODULES", None),
+    ("GDK_BACKEND", None),
+    ("VK_ADD_DRIVER_FILES", None),
+    ("VK_ADD_LAYER_PATH", None),
+    ("VK_DRIVER_FILES", None),
+    ("VK_ICD_FILENAMES", None),
+    ("VK_LAYER_PATH", None),
+    ("__EGL_EXTERNAL_PLATFORM_CONFIG_DIRS", None),
+    ("__EGL_EXTERNAL_PLATFORM_CONFIG_FILENAMES", None),
+    ("__EGL_VENDOR_LIBRARY_DIRS", None),
+    ("__EGL_VENDOR_LIBRARY_FILENAMES", None),
+];
+
+/// Composes the sandbox of an app or runtime from `SandboxOptions`: finds it in the installations and sets up
+/// the runtime, host directories, home, sockets, extensions and environment like `flatbox run` does
+#[derive(Debug, Default)]
+pub struct SandboxBuilder {
+    options: SandboxOptions,
+}
+
+impl SandboxBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(options: SandboxOptions) -> Self {
+        Self { options }
+    }
+
+    /// Uses an app as the environment, given as an id or a full or partial ref
+    pub fn app(mut self, app: impl Into<String>) -> Self {
+        self.options.app = Some(app.into());
+        self
+    }
+
+    /// Uses a runtime as the environment, given as a full or partial ref
+    pub fn runtime(mut self, runtime: impl Into<String>) -> Self {
+        self.options.runtime = Some(runtime.into());
+        self
+    }
+
+    /// Runs `command` with `args` instead of the command declared by the app
+    pub fn command(
+        mut self,
+        command: impl Into<String>,
+        args: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.options.command_override = Some(command.into());
+        self.options.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn options_mut(&mut self) -> &mut SandboxOptions {
+        &mut self.options
+    }
+
+    /// Composes the sandbox, along with the command line to run in it
+    pub fn build(self) -> anyhow::Result<Sandbox> {
+        nested::check_nesting()?;
+        let mut options = self.options;
+        let span = logging::span("resolve");
+        // The rest of the setup only needs the app id, the arch of an app ref also applies to its runtime
+        let app_ref = options
+            .app
+            .as_deref()
+            .map(|app| AppRef::parse(app, options.arch.as_deref(), options.branch.as_deref()))
+            .transpose()?;
+        if let Some(app_ref) = &app_ref {
+            options.app = Some(app_ref.id.clone());
+            options.arch.clone_from(&app_ref.arch);
+        }
+
+        let install_span = logging::span("install-dirs");
+        let install_dirs = flatpak_install_dirs(
+            &options.flatpak_install_path,
+            options.installation.as_deref(),
+        )?;
+
+        let available_runtimes =
+            list_available_runtimes(&install_dirs).context("Could not list runtimes")?;
+        drop(install_span);
+
+        let app_path = match (&app_ref, &options.bundle, &options.ostree_repo) {
+            (Some(app_ref), _, _) => {
+                let app_path = find_app_path(app_ref, &install_dirs)?;
+                crate::debug!("Using app {} from {}", app_ref.id, app_path.display());
+                Some(app_path)
+            }
+            (None, Some(bundle), _) => {
+                let app_path = ostree::checkout_bundle(bundle)?;
+                crate::debug!(
+                    "Using the app of {} checked out at {}",
+                    bundle.display(),
+                    app_path.display()
+                );
+                Some(app_path)
+            }
+            (None, None, Some(repo)) => {
+                let Some(reference) = options.ostree_ref.as_deref() else {
+                    return Err(FlatboxError::InvalidOptions {
+                        message: "--ostree-repo needs a --ref".to_owned(),
+                    }
+                    .into());
+                };
+                let app_path = ostree::checkout_ref(repo, reference)?;
+                crate::debug!(
+                    "Using {reference} of {} checked out at {}",
+                    repo.display(),
+                    app_path.display()
+                );
+                Some(app_path)
+            }
+            (None, None, None) => match &options.build {
+                Some(build) if !build::is_build_dir(&build.path) => {
+                    return Err(FlatboxError::NotABuildDir {
+                        path: build.path.clone(),
+                    }
+                    .into());
+                }
+                Some(build) => Some(fs::canonicalize(&build.path)?),
+                None => None,
+            },
+        };
+
+        if let Some(image) = &options.oci_image {
+            let default_arch = options.arch.clone().unwrap_or_else(arch::host_arch);
+            options.rootfs = Some(oci_image::unpack(image, &default_arch)?);
+        }
+
+        let raw_app_metadata: Option<String>;
+        let (runtime, app_files_path, app_metadata) = match (&app_path, &options.runtime) {
+            (Some(_), _) | (_, Some(_)) if options.rootfs.is_some() => {
+                return Err(FlatboxError::InvalidOptions {
+                    message: "A rootfs can't be used with an app or runtime".to_owned(),
+                }
+                .into());
+            }
+            (Some(app_path), None) => {
+                let app_metadata_path = app_path.join("metadata");
+
+                let metadata_span = logging::span("metadata");
+                raw_app_metadata =
+                    Some(fs::read_to_string(&app_metadata_path).with_context(|| {
+                        format!("Could not read {}", app_metadata_path.display())
+                    })?);
+                let app_metadata = parse_keyfile(raw_app_metadata.as_ref().unwrap())
+                    .map_err(|err| err.in_file(&app_metadata_path))?;
+                drop(metadata_span);
+
+                let application_key = |key: &str| {
+                    app_metadata
+                        .get("Application")
+                        .and_then(|app| app.get(key))
+                        .map(ToString::to_string)
+                        .ok_or_else(|| FlatboxError::MissingMetadataKey {
+                            path: app_metadata_path.clone(),
+                            group: "Application".to_owned(),
+                            key: key.to_owned(),
+                        })
+                };
+                // Builds and --devel run in the SDK
+                let app_runtime = if options.build.is_some() || options.devel {
+                    application_key("sdk")?
+                } else {
+                    application_key("runtime")?
+                };
+                if options.app.is_none() {
+                    options.app = Some(application_key("name")?);
+                }
+
+                let app_files_path = app_path.join("files");
+
+                (app_runtime, Some(app_files_path), Some(app_metadata))
+            }
+            (None, Some(runtime)) => {
+                let default_arch = options.arch.clone().unwrap_or_else(arch::host_arch);
+                let runtime = refs::resolve_runtime_ref(runtime, &default_arch, &install_dirs)?;
+                (runtime, None, None)
+            }
+            (Some(_), Some(_)) => {
+                return Err(FlatboxError::InvalidOptions {
+                    message: "Only app or runtime flags can be used at once".to_owned(),
+                }
+                .into());
+            }
+            (None, None) => match &options.rootfs {
+                Some(rootfs) => {
+                    let default_arch = options.arch.clone().unwrap_or_else(arch::host_arch);
+                    (rootfs_runtime_ref(rootfs, &default_arch), None, None)
+                }
+                None => {
+                    return Err(FlatboxError::InvalidOptions {
+                        message: "Either app, bundle, runtime or rootfs has to be specified"
+                            .to_owned(),
+                    }
+                    .into());
+                }
+            },
+        };
+        let runtime = match &options.arch {
+            Some(arch) => arch::ref_with_arch(&runtime, arch),
+            None => runtime,
+        };
+
+        let command_line = options.command_line(
+            app_metadata
+                .as_ref()
+                .and_then(|meta| meta.get("Application"))
+                .and_then(|app| app.get("command"))
+                .copied(),
+        )?;
+
+        // The runtime's files are mounted as /usr, and its `etc` and `var` are found in `runtime_root`
+        let (runtime_files_path, runtime_root, runtime_metadata_path) = match &options.rootfs {
+            Some(rootfs) => {
+                let rootfs = fs::canonicalize(rootfs)
+                    .with_context(|| format!("Could not find {}", rootfs.display()))?;
+                crate::debug!("Using rootfs {}", rootfs.display());
+                let usr = rootfs.join("usr");
+                let usr = if usr.is_dir() { usr } else { rootfs.clone() };
+                (usr, rootfs.clone(), rootfs.join("metadata"))
+            }
+            None => {
+                let runtime_path = find_install_path(&runtime, false, &install_dirs)
+                    .ok_or_else(|| FlatboxError::RuntimeNotFound {
+                        runtime: runtime.clone(),
+                        searched_dirs: install_dirs.clone(),
+                    })?
+                    .join("active");
+                crate::debug!("Using runtime {runtime} from {}", runtime_path.display());
+                let files = runtime_path.join("files");
+                (files.clone(), files, runtime_path.join("metadata"))
+            }
+        };
+
+        let metadata_span = logging::span("metadata");
+        let raw_runtime_metadata = match fs::read_to_string(&runtime_metadata_path) {
+            Ok(raw) => raw,
+            // The metadata of a rootfs is optional
+            Err(err) if err.kind() == io::ErrorKind::NotFound && options.rootfs.is_some() => {
+                String::new()
+            }
+            Err(err) => return Err(err).context("Could not read runtime metadata"),
+        };
+        let runtime_metadata = parse_keyfile(&raw_runtime_metadata)
+            .map_err(|err| err.in_file(&runtime_metadata_path))?;
+        drop(metadata_span);
+
+        let runtime_env = runtime_metadata
+            .get("Environment")
+            .cloned()
+            .unwrap_or_default();
+
+        let mut permissions = match (&options.app, &app_metadata) {
+            (Some(app), Some(app_meta)) => {
+                let mut permissions = Permissions::default();
+                if let Some(context) = app_meta.get("Context") {
+                    permissions.merge_context(context);
+                }
+                if let Some(environment) = app_meta.get("Environment") {
+                    permissions.merge_environment(environment);
+                }
+                if let Some(policy) = app_meta.get("Session Bus Policy") {
+                    permissions.merge_session_bus_policy(policy);
+                }
+                // The overrides are meant for the installed app, not for its builds
+                if options.build.is_none() {
+                    permissions.merge_overrides(app, &install_dirs)?;
+                }
+                permissions
+            }
+            _ => Permissions::runtime_default(),
+        };
+        permissions.merge_args(&options);
+
+        let home = env::var("HOME").ok().map(PathBuf::from);
+        let app_dir = options.app.as_deref().and_then(app_data_dir);
+
+        if let Some(app_dir) = &app_dir
+            && !options.no_create_app_dirs
+        {
+            create_app_data_dirs(app_dir)?;
+        }
+
+        drop(span);
+        let span = logging::span("runtime");
+        let mut bwrap = BwrapBuilder::new();
+        let mut environment = Environment::default();
+
+        setup_env(
+            &mut environment,
+            runtime_env,
+            &permissions,
+            options.app.as_deref(),
+        );
+        locale::setup_locale_env(&mut environment, &runtime_files_path, &install_dirs);
+        environment.keep_host_vars(&options.keep_env);
+
+        let host_etc_files: Vec<String> = if options.no_host_etc {
+            Vec::new()
+        } else {
+            HOST_ETC_FILES
+                .into_iter()
+                .map(str::to_owned)
+                .chain(options.host_etc.iter().cloned())
+                .collect()
+        };
+
+        setup_runtime(
+            &mut bwrap,
+            &runtime_files_path,
+            &runtime_root,
+            // The files of a build dir are mounted writable with its var
+            app_files_path
+                .as_deref()
+                .filter(|_| options.build.is_none()),
+            &host_etc_files,
+            options.writable_usr.as_ref().map(Option::as_deref),
+            options.writable_app.as_ref().map(Option::as_deref),
+        )?;
+
+        if !options.runtime_certs {
+            setup_ca_certificates(&mut bwrap, &runtime_root);
+        }
+
+        setup_var(&mut bwrap, &runtime_root, app_dir.as_deref())?;
+        if let Some(build_dir) = &options.build {
+            let arch = runtime.split('/').nth(1).unwrap_or_default();
+            build::setup_build_dir(
+                &mut bwrap,
+                &mut environment,
+                build_dir,
+                options.app.as_deref().unwrap_or_default(),
+                arch,
+            )?;
+        }
+
+        if options.no_host_root {
+            setup_minimal_root(&mut bwrap);
+        } else {
+            let run_allowlist = options.strict_run.then(|| {
+                let system_bus = permissions.has_socket("system-bus").then_some("dbus");
+                STRICT_RUN_ALLOWLIST
+                    .into_iter()
+                    .chain(system_bus)
+                    .map(str::to_owned)
+                    .chain(options.run_allow.iter().cloned())
+                    .collect::<Vec<_>>()
+            });
+            setup_host_root_dirs(
+                &mut bwrap,
+                options.share_runtime_dir,
+                run_allowlist.as_deref(),
+                permissions.has_socket("system-bus"),
+            )?;
+            fonts::setup_fonts(&mut bwrap, home.as_deref())?;
+            icons::setup_icons(&mut bwrap, home.as_deref());
+        }
+
+        if !options.no_host_root {
+            let dev_shm = if options.private_dev_shm {
+                DevShm::Private
+            } else if options.share_dev_shm {
+                DevShm::Host
+            } else {
+                match &options.app {
+                    Some(app) if permissions.has_feature("per-app-dev-shm") => DevShm::PerApp(app),
+                    _ => DevShm::Host,
+                }
+            };
+            setup_tmp_dirs(&mut bwrap, options.private_tmp, dev_shm)?;
+        }
+
+        setup_machine_id(&mut bwrap)?;
+
+        if options.no_host_root {
+            setup_private_devices(&mut bwrap, &permissions);
+        }
+
+        if nvidia::is_driver_loaded() {
+            let bind_nodes = options.no_host_root
+                && (permissions.has_device("dri") || permissions.has_device("all"));
+            nvidia::setup_devices(&mut bwrap, bind_nodes);
+        }
+
+        if !permissions.is_shared("network") {
+            bwrap.unshare_net();
+        }
+        if permissions.has_feature("devel") {
+            bwrap.devel();
+        }
+        if !permissions.is_shared("ipc") {
+            bwrap.unshare_ipc();
+        }
+        if options.new_session {
+            bwrap.new_session();
+        }
+        bwrap.limits(options.resource_limits());
+        bwrap.rlimits(options.rlimit.clone());
+        bwrap.priority(options.priority());
+
+        setup_home(
+            &mut bwrap,
+            &mut environment,
+            &options.home_mode(),
+            !options.no_host_root,
+            options.app.as_deref(),
+        )?;
+
+        drop(span);
+        let span = logging::span("sockets");
+        let runtime_dir = RuntimeDir::new(options.share_runtime_dir)?;
+        runtime_dir.setup(&mut bwrap, &mut environment);
+        setup_sockets(
+            &mut bwrap,
+            &mut environment,
+            &options,
+            &permissions,
+            &runtime_dir,
+        )?;
+        permissions::setup_filesystems(&mut bwrap, &permissions, home.as_deref(), &runtime_dir)?;
+
+        drop(span);
+        let span = logging::span("extensions");
+        let mut extension_ctx =
+            ExtensionContext::new(&runtime, &available_runtimes, &install_dirs)?;
+        if options.gl == GlMode::Host {
+            extension_ctx.skip_gl_drivers = true;
+            graphics::setup_host_graphics(&mut bwrap, &mut environment, &runtime_files_path)
+                .context("Could not set up the host graphics drivers")?;
+        }
+
+        let extra_extensions = extensions::resolve_extra_extensions(
+            &mut bwrap,
+            &extension_ctx,
+            &runtime_files_path,
+            app_files_path.as_deref(),
+            &options.with_extension,
+        )?;
+
+        extensions::setup_runtime_extensions(
+            &mut bwrap,
+            &mut environment,
+            &extension_ctx,
+            &runtime_metadata,
+        )?;
+
+        if let Some(ref app_meta) = app_metadata {
+            extensions::setup_app_extensions(
+                &mut bwrap,
+                &mut environment,
+                &extension_ctx,
+                app_meta,
+            )?;
+        }
+
+        if options.debug {
+            // The branch of installed apps is the dir their `current` link points to
+            let app_branch = app_path
+                .as_deref()
+                .and_then(|path| fs::canonicalize(path).ok())
+                .and_then(|path| Some(path.parent()?.file_name()?.to_str()?.to_owned()));
+            let runtime_id = runtime.split('/').next().unwrap_or(&runtime);
+            extensions::setup_debug_extensions(
+                &mut bwrap,
+                &extension_ctx,
+                runtime_id,
+                options.app.as_deref().zip(app_branch.as_deref()),
+            );
+        }
+
+        extensions::mount_extra_extensions(&mut bwrap, &mut environment, &extra_extensions)?;
+        extension_image::setup_extension_images(&mut bwrap, &options.extension_image)?;
+        for (target, source) in &options.bind_over {
+            let source = fs::canonicalize(source).with_context(|| {
+                format!(
+                    "Could not find {} to bind over {}",
+                    source.display(),
+                    target.display()
+                )
+            })?;
+            bwrap.ro_bind(&source, target);
+        }
+
+        drop(span);
+        let span = logging::span("env");
+        add_ld_so_conf(&mut bwrap)?;
+
+        setup_timezone(
+            &mut bwrap,
+            &mut environment,
+            &runtime_files_path,
+            &runtime_root,
+        )?;
+
+        for env_file in &options.env_file {
+            let contents = fs::read_to_string(env_file)
+                .with_context(|| format!("Could not read env file {}", env_file.display()))?;
+            let vars = parse_env_file(&contents)
+                .with_context(|| format!("Could not parse env file {}", env_file.display()))?;
+            for (key, value) in vars {
+                environment.set(key, value);
+            }
+        }
+        for (key, value) in &options.env {
+            environment.set_or_unset(key, value);
+        }
+        for key in &options.unset_env {
+            environment.unset(key);
+        }
+        for path in &options.prepend_path {
+            environment.prepend_path("PATH", path);
+        }
+        for path in &options.append_path {
+            environment.append_path("PATH", path);
+        }
+        for path in &options.prepend_ld_library_path {
+            environment.prepend_path("LD_LIBRARY_PATH", path);
+        }
+        for path in &options.append_ld_library_path {
+            environment.append_path("LD_LIBRARY_PATH", path);
+        }
+
+        let perf_wrapped = options
+            .wrap
+            .as_ref()
+            .is_some_and(|wrapper| wrapper.tool == "perf");
+        if options.perf || perf_wrapped {
+            perf::setup_perf(
+                &mut bwrap,
+                &mut environment,
+                &runtime_files_path,
+                !options.no_host_root,
+            )?;
+        }
+        if options.host_commands {
+            host_command::setup_host_commands(&mut bwrap, &mut environment, !options.no_host_root)?;
+        }
+
+        environment.apply(&mut bwrap);
+        drop(span);
+
+        let cwd = options.cwd.clone().or_else(|| {
+            env::current_dir()
+                .ok()
+                .filter(|cwd| is_visible_host_path(cwd, &options, home.as_deref()))
+        });
+        if let Some(cwd) = cwd {
+            bwrap.chdir(cwd);
+        } else if let Some(home) = environment.get("HOME").map(Path::new).or(home.as_deref()) {
+            bwrap.chdir(home);
+        }
+        if let Some(coredump_dir) = &options.coredump_dir {
+            let mut rlimits = options.rlimit.clone();
+            coredump::setup_coredumps(&mut bwrap, &mut rlimits, coredump_dir)?;
+            bwrap.rlimits(rlimits);
+        }
+
+        let command_line = match &options.wrap {
+            Some(wrapper) => wrap::wrap_command_line(
+                &mut bwrap,
+                wrapper,
+                &runtime_files_path,
+                !options.no_host_root,
+                command_line,
+            )?,
+            None => command_line,
+        };
+
+        let command_line = if options.debug {
+            debug_command_line(&runtime_files_path, &runtime, command_line)?
+        } else {
+            command_line
+        };
+
+        let command_line = if options.file_forwarding {
+            let documents = options
+                .app
+                .as_deref()
+                .and_then(|app| DocumentPortal::for_app(&runtime_dir, app));
+            forwarding::forward_files(&mut bwrap, &command_line, documents)?
+        } else {
+            command_line
+        };
+
+        if options.apparmor_unconfined
+            && let Ok(current_profiles) =
+                fs::read_to_string("/sys/kernel/security/apparmor/profiles")
+            && current_profiles.contains("(unconfined)")
+        {
+            bwrap = bwrap.wrap_apparmor_unconfined();
+        }
+
+        Ok(Sandbox::new(bwrap, command_line))
+    }
+}
+
+/// A composed sandbox and the command line to run in it
+pub struct Sandbox {
+    bwrap: BwrapBuilder,
+    argv: Vec<String>,
+}
+
+impl Sandbox {
+    /// Arguments of the shell running ldconfig before the command
+    const LDCONFIG_ARGS: usize = 4;
+
+    pub(crate) fn new(bwrap: BwrapBuilder, command_line: Vec<String>) -> Self {
+        let argv = ["sh", "-c", LDCONFIG_SCRIPT, "sh"]
+            .into_iter()
+            .map(str::to_owned)
+            .chain(command_line)
+            .collect();
+        Sandbox { bwrap, argv }
+    }
+
+    pub fn plan(&self) -> &SandboxPlan {
+        self.bwrap.plan()
+    }
+
+    /// The directory with the generated files bound into the sandbox
+    pub fn setup_dir(&self) -> &Path {
+        self.bwrap.setup_dir()
+    }
+
+    pub fn argv(&self) -> &[String] {
+        &self.argv
+    }
+
+    /// The command run in the sandbox, without the ldconfig run before it
+    pub fn command_line(&self) -> &[String] {
+        &self.argv[Self::LDCONFIG_ARGS..]
+    }
+
+    /// Runs another command in the sandbox
+    pub fn set_command_line(&mut self, command_line: Vec<String>) {
+        self.argv.truncate(Self::LDCONFIG_ARGS);
+        self.argv.extend(command_line);
+    }
+
+    pub(crate) fn bwrap(&self) -> &BwrapBuilder {
+        &self.bwrap
+    }
+
+    /// Makes the sandbox write a byte to `fd` before and after running ldconfig, to measure how long creating
+    /// the sandbox and ldconfig take. `fd` has to be a single digit, which the shell can redirect, and is closed
+    /// before the command starts.
+    pub fn report_ldconfig(&mut self, fd: RawFd) -> Result<(), FlatboxError> {
+        if !(0..10).contains(&fd) {
+            return Err(FlatboxError::InvalidOptions {
+                message: format!(
+                    "fd {fd} can't be used in a shell redirection, it has to be below 10"
+                ),
+            });
+        }
+        // Writing fails harmlessly when the backend doesn't pass the fd on
+        self.argv[2] = format!(
+            "{{ printf . >&{fd}; }} 2>/dev/null; ldconfig && {{ printf . >&{fd}; }} 2>/dev/null; \
+             exec {fd}>&-; exec \"$@\""
+        );
+        Ok(())
+    }
+
+    /// Creates the command running the sandbox with the given backend. The returned data holds the generated
+    /// files and helper processes, and has to be kept until the sandbox exited.
+    pub fn into_command(self, backend: Backend) -> anyhow::Result<(Command, BwrapData)> {
+        self.bwrap.finish(backend, &self.argv)
+    }
+}
+
+/// The runtime ref standing for a rootfs: the one of its metadata, or one named after its dir
+fn rootfs_runtime_ref(rootfs: &Path, arch: &str) -> String {
+    let metadata = fs::read_to_string(rootfs.join("metadata")).unwrap_or_default();
+    let runtime = parse_keyfile(&metadata).ok().and_then(|metadata| {
+        let runtime = metadata.get("Runtime")?.get("runtime")?;
+        Some(runtime.to_string())
+    });
+    runtime.unwrap_or_else(|| {
+        let name = rootfs.file_name().unwrap_or_default().to_string_lossy();
+        format!("{name}/{arch}/{ROOTFS_BRANCH}")
+    })
+}
+
+fn setup_runtime(
+    bwrap: &mut BwrapBuilder,
+    runtime_files_path: &Path,
+    runtime_root: &Path,
+    app_files_path: Option<&Path>,
+    host_etc_files: &[String],
+    writable_usr: Option<Option<&Path>>,
+    writable_app: Option<Option<&Path>>,
+) -> anyhow::Result<()> {
+    match writable_usr {
+        Some(upper_dir) => mount_writable(bwrap, runtime_files_path, "/usr", upper_dir)?,
+        None => {
+            bwrap.ro_bind(runtime_files_path, "/usr");
+        }
+    }
+
+    if let Some(app_path) = app_files_path {
+        match writable_app {
+            Some(upper_dir) => mount_writable(bwrap, app_path, "/app", upper_dir)?,
+            None => {
+                let subpaths = deploy::installed_subpaths(app_path);
+                extensions::bind_installed_files(bwrap, app_path, Path::new("/app"), &subpaths);
+            }
+        }
+    }
+
+    let runtime_etc = fs::read_dir(runtime_root.join("etc"))
+        .with_context(|| format!("Could not read {}", runtime_root.join("etc").display()))?;
+
+    for entry in runtime_etc {
+        let entry = entry?;
+        if host_etc_files
+            .iter()
+            .map(String::as_str)
+            .chain(GENERATED_ETC_FILES)
+            .any(|name| entry.file_name() == name)
+        {
+            continue;
+        }
+        let path = entry.path();
+
+        let target_path = Path::new("/").join(
+            path.strip_prefix(runtime_root)
+                .expect("Could not strip etc path prefix"),
+        );
+
+        if let Ok(symlink_target) = fs::read_link(&path) {
+            bwrap.symlink(&symlink_target, target_path);
+        } else {
+            bwrap.ro_bind(&path, target_path);
+        }
+    }
+
+    for name in host_etc_files {
+        let host_path = Path::new("/etc").join(name);
+        if let Ok(resolved_path) = fs::canonicalize(&host_path) {
+            let mount_path = Path::new("/run/host/etc").join(name);
+            bwrap.ro_bind(&resolved_path, &mount_path);
+            bwrap.symlink(&mount_path, &host_path);
+        } else if runtime_root.join("etc").join(name).exists() {
+            bwrap.ro_bind(runtime_root.join("etc").join(name), &host_path);
+        }
+    }
+
+    for dir in ROOT_USR_MERGED_DIRS {
+        if runtime_files_path.join(dir).exists() {
+            bwrap.symlink(Path::new("/usr").join(dir), dir);
+        }
+    }
+
+    bwrap.ro_bind_data("/.flatpak-info", &[])?;
+
+    Ok(())
+}
+
+/// Mounts an overlay over `source` whose changes are kept in `upper_dir` on the host, or discarded without one
+fn mount_writable(
+    bwrap: &mut BwrapBuilder,
+    source: &Path,
+    dest: &str,
+    upper_dir: Option<&Path>,
+) -> anyhow::Result<()> {
+    let Some(upper_dir) = upper_dir else {
+        bwrap.tmp_overlay(source, dest);
+        return Ok(());
+    };
+    let (upper, work) = (upper_dir.join("upper"), upper_dir.join("work"));
+    for dir in [&upper, &work] {
+        fs::create_dir_all(dir).with_context(|| format!("Could not create {}", dir.display()))?;
+    }
+    crate::info!("Keeping the changes to {dest} in {}", upper.display());
+    bwrap.overlay(
+        source,
+        fs::canonicalize(&upper)?,
+        fs::canonicalize(&work)?,
+        dest,
+    );
+    Ok(())
+}
+
+fn setup_ca_certificates(bwrap: &mut BwrapBuilder, runtime_root: &Path) {
+    let host_etc = Path::new("/etc");
+    let runtime_etc = runtime_root.join("etc");
+    let runtime_bundles: Vec<PathBuf> = CA_BUNDLE_PATHS
+        .into_iter()
+        .filter(|path| fs::symlink_metadata(runtime_etc.join(path)).is_ok())
+        .map(|path| host_etc.join(path))
+        .collect();
+
+    // A directory replaces the runtime's only if the bundles the runtime expects in it still resolve
+    // within the shared directories, as nothing can be mounted over a dangling link of the host
+    let mut shared_dirs: Vec<&str> = CA_DIRS
+        .into_iter()
+        .filter(|dir| {
+            // The files of the runtime are its /usr
+            let runtime_dir = match Path::new(dir).strip_prefix("/usr") {
+                Ok(subdir) => runtime_root.join(subdir),
+                Err(_) => runtime_root.join(&dir[1..]),
+            };
+            Path::new(dir).is_dir() && runtime_dir.is_dir()
+        })
+        .collect();
+    loop {
+        let shared_roots: Vec<PathBuf> = shared_dirs
+            .iter()
+            .filter_map(|dir| fs::canonicalize(dir).ok())
+            .collect();
+        let count = shared_dirs.len();
+        shared_dirs.retain(|dir| {
+            runtime_bundles
+                .iter()
+                .filter(|path| path.starts_with(dir))
+                .all(|path| {
+                    fs::canonicalize(path).is_ok_and(|target| {
+                        shared_roots.iter().any(|root| target.starts_with(root))
+                    })
+                })
+        });
+        if shared_dirs.len() == count {
+            break;
+        }
+    }
+    for dir in &shared_dirs {
+        bwrap.ro_bind(dir, dir);
+    }
+
+    let Some(host_bundle) = CA_BUNDLE_PATHS
+        .iter()
+        .find_map(|path| fs::canonicalize(host_etc.join(path)).ok())
+    else {
+        return;
+    };
+
+    // The bundle is placed over every other location used by the runtime, regardless of which distro layout it follows
+    for path in runtime_bundles {
+        if !shared_dirs.iter().any(|dir| path.starts_with(dir)) {
+            bwrap.ro_bind(&host_bundle, path);
+        }
+    }
+}
+
+fn setup_var(
+    bwrap: &mut BwrapBuilder,
+    runtime_root: &Path,
+    app_dir: Option<&Path>,
+) -> anyhow::Result<()> {
+    bwrap.tmpfs("/var");
+    bind_var_entries(bwrap, runtime_root, &runtime_root.join("var"))?;
+
+    let mut has_var_tmp = false;
+    if let Some(app_dir) = app_dir {
+        for (subdir, target) in APP_VAR_BINDINGS {
+            let source = app_dir.join(subdir);
+            if source.is_dir() {
+                bwrap.bind(&source, target);
+                has_var_tmp |= target == "/var/tmp";
+            }
+        }
+        // Outside of the home dir in system mode, so it's made visible where the XDG dirs point to
+        if system::is_system_mode() && app_dir.is_dir() {
+            bwrap.bind(app_dir, app_dir);
+        }
+    }
+
+    if !has_var_tmp {
+        bwrap.perms(0o1777).dir("/var/tmp");
+    }
+    bwrap.symlink("../run", "/var/run");
+
+    Ok(())
+}
+
+/// Binds the entries of a `var` dir of the runtime. The ones of `VAR_EXPANDED_DIRS` are bound one by one, so
+/// more can be mounted next to them.
+fn bind_var_entries(
+    bwrap: &mut BwrapBuilder,
+    runtime_root: &Path,
+    dir: &Path,
+) -> anyhow::Result<()> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let entry = entry?;
+        if dir == runtime_root.join("var")
+            && VAR_OVERRIDDEN_DIRS
+                .iter()
+                .any(|name| entry.file_name() == *name)
+        {
+            continue;
+        }
+
+        let path = entry.path();
+        let target_path = Path::new("/").join(
+            path.strip_prefix(runtime_root)
+                .expect("Could not strip var path prefix"),
+        );
+
+        if let Ok(symlink_target) = fs::read_link(&path) {
+            bwrap.symlink(&symlink_target, target_path);
+        } else if VAR_EXPANDED_DIRS
+            .iter()
+            .any(|name| target_path == Path::new(name))
+        {
+            bwrap.dir(&target_path);
+            bind_var_entries(bwrap, runtime_root, &path)?;
+        } else {
+            bwrap.ro_bind(&path, target_path);
+        }
+    }
+    Ok(())
+}
+
+/// Runs the command in the gdb of the runtime (which has to be an SDK), looking up the separate debug info in
+/// the debug extensions and the sources of the app's modules where flatpak-builder puts them
+fn debug_command_line(
+    runtime_files_path: &Path,
+    runtime: &str,
+    command_line: Vec<String>,
+) -> anyhow::Result<Vec<String>> {
+    if !runtime_files_path.join("bin").join(DEBUGGER).exists() {
+        return Err(FlatboxError::NoDebugger {
+            runtime: runtime.to_owned(),
+        }
+        .into());
+    }
+    let mut debug_command_line = vec![DEBUGGER.to_owned()];
+    for command in DEBUGGER_INIT_COMMANDS {
+        debug_command_line.extend(["-iex".to_owned(), command.to_owned()]);
+    }
+    debug_command_line.push("--args".to_owned());
+    debug_command_line.extend(command_line);
+    Ok(debug_command_line)
+}
+
+fn add_ld_so_conf(bwrap: &mut BwrapBuilder) -> anyhow::Result<()> {
+    let contents = "\
+include /run/flatpak/ld.so.conf.d/app-*.conf
+include /app/etc/ld.so.conf
+/app/lib
+include /run/flatpak/ld.so.conf.d/runtime-*.conf
+";
+
+    bwrap.ro_bind_data("/etc/ld.so.conf", contents.as_bytes())?;
+    Ok(())
+}
+
+/// Exposes the host root directories. With a `run_allowlist`, only the listed paths of `/run` are exposed.
+fn setup_host_root_dirs(
+    bwrap: &mut BwrapBuilder,
+    share_runtime_dir: bool,
+    run_allowlist: Option<&[String]>,
+    system_bus: bool,
+) -> anyhow::Result<()> {
+    let root_dirs = fs::read_dir("/").context("Could not read root dir")?;
+    for entry in root_dirs {
+        let entry = entry.context("Could not evaluate root dir")?;
+        if let Some(filename) = entry.file_name().to_str() {
+            let entry_path = entry.path();
+            if FORBIDDEN_HOST_ROOT_DIRS.contains(&filename)
+                || ROOT_USR_MERGED_DIRS.contains(&filename)
+            {
+                continue;
+            }
+
+            if fs::exists(&entry_path).is_ok_and(|exists| exists) {
+                bwrap.bind(&entry_path, &entry_path);
+            }
+        }
+    }
+
+    if let Some(run_allowlist) = run_allowlist {
+        let runtime_dirs = share_runtime_dir.then(|| "user".to_owned());
+        for name in run_allowlist.iter().chain(runtime_dirs.as_ref()) {
+            let path = Path::new("/run").join(name);
+            if FORBIDDEN_RUN_DIRS
+                .iter()
+                .any(|dir| Path::new(name).starts_with(dir))
+            {
+                continue;
+            }
+
+            if path.exists() {
+                bwrap.bind(&path, &path);
+            }
+        }
+    } else {
+        let run_dirs = fs::read_dir("/run").context("Could not read root dir")?;
+        for entry in run_dirs {
+            let entry = entry.context("Could not evaluate run dir")?;
+            if let Some(filename) = entry.file_name().to_str() {
+                let entry_path = entry.path();
+                // The user runtime dirs are replaced by a private one unless requested otherwise, and the system
+                // bus is only reachable when granted
+                if FORBIDDEN_RUN_DIRS.contains(&filename)
+                    || (filename == "user" && !share_runtime_dir)
+                    || (filename == "dbus" && !system_bus)
+                {
+                    continue;
+                }
+
+                if fs::exists(&entry_path).is_ok_and(|exists| exists) {
+                    bwrap.bind(&entry_path, &entry_path);
+                }
+            }
+        }
+    }
+
+    for name in EXPOSED_ETC_PATHS {
+        let path = Path::new("/etc").join(name);
+        if path.exists() {
+            bwrap.ro_bind(&path, &path);
+        }
+    }
+
+    for (source, target, writable) in PATH_BINDINDGS {
+        if Path::new(source).exists() {
+            if writable {
+                bwrap.bind(source, target);
+            } else {
+                bwrap.ro_bind(source, target);
+            }
+        }
+    }
+
+    // Also hide /run in the view of the full host root
+    if run_allowlist.is_some() {
+        bwrap.tmpfs("/run/host/root/run");
+    }
+
+    bwrap.dev_bind("/dev", "/dev");
+
+    Ok(())
+}
+
+fn setup_machine_id(bwrap: &mut BwrapBuilder) -> anyhow::Result<()> {
+    if let Some(host_machine_id) = MACHINE_ID_PATHS
+        .into_iter()
+        .find(|path| fs::read_to_string(path).is_ok_and(|id| !id.trim().is_empty()))
+    {
+        for path in MACHINE_ID_PATHS {
+            bwrap.ro_bind(host_machine_id, path);
+        }
+    } else {
+        let machine_id =
+            generated_machine_id().context("Could not generate a substitute machine id")?;
+        let contents = format!("{machine_id}\n");
+        for path in MACHINE_ID_PATHS {
+            bwrap.ro_bind_data(path, contents.as_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns a random machine id which is persisted in the state dir, so it stays stable across launches
+fn generated_machine_id() -> anyhow::Result<String> {
+    let state_dir = state_dir().context("Could not determine the state directory")?;
+    let id_path = state_dir.join("machine-id");
+
+    if let Ok(id) = fs::read_to_string(&id_path)
+        && id.trim().len() == 32
+    {
+        return Ok(id.trim().to_owned());
+    }
+
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .context("Could not read random data")?;
+    let id: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+
+    create_private_dir(&state_dir)?;
+    fs::write(&id_path, format!("{id}\n"))
+        .with_context(|| format!("Could not write {}", id_path.display()))?;
+
+    Ok(id)
+}
+
+fn state_dir() -> Option<PathBuf> {
+    env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("state")))
+        .map(|dir| dir.join("flatbox"))
+}
+
+fn setup_minimal_root(bwrap: &mut BwrapBuilder) {
+    bwrap.proc("/proc");
+    bwrap.dev("/dev");
+    bwrap.tmpfs("/tmp");
+}
+
+enum DevShm<'a> {
+    Host,
+    Private,
+    PerApp(&'a str),
+}
+
+fn setup_tmp_dirs(
+    bwrap: &mut BwrapBuilder,
+    private_tmp: bool,
+    dev_shm: DevShm,
+) -> anyhow::Result<()> {
+    if private_tmp {
+        bwrap.tmpfs("/tmp");
+    }
+
+    match dev_shm {
+        DevShm::Host => (),
+        DevShm::Private => {
+            bwrap.tmpfs("/dev/shm");
+        }
+        DevShm::PerApp(app_id) => {
+            // Shared between all instances of the same app, but not with the rest of the host
+            let app_shm_dir = Path::new("/dev/shm").join(format!("{APP_SHM_PREFIX}{app_id}"));
+            create_private_dir(&app_shm_dir)?;
+            bwrap.bind(&app_shm_dir, "/dev/shm");
+        }
+    }
+
+    Ok(())
+}
+
+/// Populates the sandbox runtime dir with the sockets granted by the permissions
+fn setup_sockets(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    options: &SandboxOptions,
+    permissions: &Permissions,
+    runtime_dir: &RuntimeDir,
+) -> anyhow::Result<()> {
+    let run_shared = !options.no_host_root && !options.strict_run;
+
+    if permissions.has_socket("session-bus") {
+        sockets::setup_session_bus(bwrap, environment, runtime_dir);
+    } else if let Some(app) = &options.app {
+        // Like in flatpak, apps without the socket still get a filtered bus, on which they can own their
+        // names (e.g. when started by D-Bus activation)
+        sockets::setup_filtered_session_bus(
+            bwrap,
+            environment,
+            runtime_dir,
+            &permissions.session_bus_filter(app),
+        );
+    }
+    if let Some(app) = &options.app {
+        sockets::setup_document_portal(bwrap, runtime_dir, app);
+    }
+
+    let has_wayland = permissions.has_socket("wayland")
+        && sockets::setup_wayland(bwrap, environment, runtime_dir);
+    // Apps with the fallback-x11 socket only get X11 access when Wayland isn't available
+    let x11 = if permissions.has_socket("fallback-x11") {
+        !has_wayland
+    } else {
+        permissions.has_socket("x11")
+    };
+    if x11 {
+        let share_tmp = !options.no_host_root && !options.private_tmp;
+        sockets::setup_x11(bwrap, environment, runtime_dir, share_tmp)?;
+    } else {
+        environment.unset("DISPLAY");
+    }
+
+    if permissions.has_socket("pulseaudio") {
+        let has_pulseaudio = sockets::setup_pulseaudio(bwrap, environment, runtime_dir)?;
+        let has_pipewire = sockets::setup_pipewire(bwrap, environment, runtime_dir);
+        if !has_pulseaudio && !has_pipewire {
+            alsa::setup_alsa(bwrap, environment, options.no_host_root)?;
+        }
+    }
+
+    sockets::setup_a11y_bus(bwrap, environment, runtime_dir);
+    let home_visible = !options.no_host_root
+        && matches!(
+            options.home_mode(),
+            HomeMode::ReadWrite | HomeMode::ReadOnly
+        );
+    sockets::setup_dconf(bwrap, environment, runtime_dir, home_visible);
+
+    if permissions.has_socket("ssh-auth") {
+        sockets::setup_ssh_agent(bwrap, environment, runtime_dir);
+    }
+    if permissions.has_socket("gpg-agent") {
+        sockets::setup_gpg_agent(bwrap, runtime_dir);
+    }
+    if permissions.has_socket("cups") {
+        sockets::setup_cups(bwrap, environment, run_shared);
+    }
+    if permissions.has_socket("pcsc") {
+        sockets::setup_pcsc(bwrap, environment, run_shared);
+    }
+
+    Ok(())
+}
+
+/// Binds the devices granted by the permissions into a private `/dev`
+fn setup_private_devices(bwrap: &mut BwrapBuilder, permissions: &Permissions) {
+    if permissions.has_device("all") {
+        bwrap.dev_bind("/dev", "/dev");
+        return;
+    }
+
+    if permissions.has_device("dri") {
+        video::setup_render_nodes(bwrap);
+    }
+    for (device, path) in PRIVATE_DEV_DEVICES {
+        if permissions.has_device(device) && Path::new(path).exists() {
+            bwrap.dev_bind(path, path);
+        }
+    }
+}
+
+fn setup_home(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    mode: &HomeMode,
+    host_root: bool,
+    app_id: Option<&str>,
+) -> anyhow::Result<()> {
+    let Ok(home) = env::var("HOME") else {
+        return Ok(());
+    };
+    let home = Path::new(&home);
+
+    let mut targets = vec![home.to_path_buf()];
+    if host_root {
+        targets.push(Path::new("/run/host/root").join(home.strip_prefix("/").unwrap_or(home)));
+    }
+
+    for target in targets {
+        match mode {
+            // Exposed through the host root dirs already
+            HomeMode::ReadWrite => {}
+            HomeMode::ReadOnly => {
+                bwrap.ro_bind(home, &target);
+            }
+            HomeMode::Ephemeral { template: None } | HomeMode::None | HomeMode::Private => {
+                bwrap.tmpfs(&target);
+            }
+            HomeMode::Ephemeral {
+                template: Some(template),
+            } => {
+                if !template.is_dir() {
+                    return Err(FlatboxError::HomeTemplateNotDir {
+                        path: template.clone(),
+                    }
+                    .into());
+                }
+                bwrap.tmp_overlay(template, &target);
+            }
+        }
+    }
+
+    if *mode == HomeMode::Private {
+        let app_id = app_id.ok_or_else(|| FlatboxError::InvalidOptions {
+            message: "--private-home needs an app".to_owned(),
+        })?;
+        let app_dir = app_data_dir(app_id).ok_or_else(|| FlatboxError::InvalidOptions {
+            message: "--private-home needs a home dir (HOME)".to_owned(),
+        })?;
+        create_private_dir(&app_dir)?;
+
+        bwrap.bind(&app_dir, &app_dir);
+        environment.set("HOME", &app_dir);
+    }
+
+    Ok(())
+}
+
+/// Whether a host path refers to the same location inside of the sandbox.
+/// Paths replaced by the runtime or by private mounts aren't, even if they exist there.
+fn is_visible_host_path(path: &Path, options: &SandboxOptions, home: Option<&Path>) -> bool {
+    if let Some(home) = home
+        && path.starts_with(home)
+    {
+        return !options.no_host_root
+            && matches!(
+                options.home_mode(),
+                HomeMode::ReadWrite | HomeMode::ReadOnly
+            );
+    }
+    if options.no_host_root {
+        return false;
+    }
+
+    let Some(Component::Normal(root_dir)) = path.components().nth(1) else {
+        return false;
+    };
+    let root_dir = root_dir.to_str().unwrap_or_default();
+    !(FORBIDDEN_HOST_ROOT_DIRS.contains(&root_dir)
+        || ROOT_USR_MERGED_DIRS.contains(&root_dir)
+        || (root_dir == "tmp" && options.private_tmp))
+}
+
+/// `~/.var/app/<app-id>`, or the app's dir in the state dir in system mode
+fn app_data_dir(app_id: &str) -> Option<PathBuf> {
+    if system::is_system_mode() {
+        return Some(system::app_data_dir(app_id));
+    }
+    let home = env::var_os("HOME")?;
+    Some(Path::new(&home).join(".var").join("app").join(app_id))
+}
+
+fn create_app_data_dirs(app_dir: &Path) -> anyhow::Result<()> {
+    for subdir in APP_DATA_SUBDIRS {
+        create_private_dir(&app_dir.join(subdir))?;
+    }
+    Ok(())
+}
+
+/// Creates a directory and its missing parents with the 0700 mode recommended by the XDG base directory spec
+fn create_private_dir(path: &Path) -> anyhow::Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path)
+        .with_context(|| format!("Could not create {}", path.display()))
+}
+
+fn setup_env(
+    environment: &mut Environment,
+    runtime_env: IndexMap<&str, &str>,
+    permissions: &Permissions,
+    app_id: Option<&str>,
+) {
+    for (env, value) in DEFAULT_ENV {
+        match value {
+            Some(value) => environment.set(env, value),
+            None => environment.unset(env),
+        };
+    }
+
+    for (env, value) in runtime_env.into_iter().chain(permissions.env()) {
+        environment.set_or_unset(env, value);
+    }
+
+    if let Some(app_id_dir) = app_id.and_then(app_data_dir) {
+        environment.set("XDG_DATA_HOME", app_id_dir.join("data"));
+        environment.set("XDG_CONFIG_HOME", app_id_dir.join("config"));
+        environment.set("XDG_CACHE_HOME", app_id_dir.join("cache"));
+        environment.set("XDG_STATE_HOME", app_id_dir.join(".local").join("state"));
+    }
+
+    if permissions.has_feature("devel") {
+        environment.set("DEBUGINFOD_URLS", DEFAULT_DEBUGINFOD_URLS);
+        for var in DEVEL_ENV_VARS {
+            if let Some(value) = env::var_os(var) {
+                environment.set(var, value);
+            }
+        }
+    }
+}
+
+fn setup_timezone(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    runtime_files_path: &Path,
+    runtime_root: &Path,
+) -> anyhow::Result<()> {
+    let localtime_path = Path::new("/etc/localtime");
+    let Ok(resolved_localtime) = fs::canonicalize(localtime_path) else {
+        let runtime_localtime = runtime_root.join("etc").join("localtime");
+        if let Ok(symlink_target) = fs::read_link(&runtime_localtime) {
+            bwrap.symlink(symlink_target, localtime_path);
+        } else if runtime_localtime.exists() {
+            bwrap.ro_bind(runtime_localtime, localtime_path);
+        }
+        return Ok(());
+    };
+
+    let timezone = fs::read_link(localtime_path).ok().and_then(|target| {
+        let target = target.to_str()?;
+        let (_, name) = target.split_once("zoneinfo/")?;
+        Some(name.to_owned())
+    });
+
+    match timezone {
+        Some(timezone)
+            if runtime_files_path
+                .join(TZ_RUNTIME_SUBDIR)
+                .join(&timezone)
+                .exists() =>
+        {
+            let tz_dir = Path::new("/usr").join(TZ_RUNTIME_SUBDIR);
+            bwrap.symlink(tz_dir.join(&timezone), localtime_path);
+            environment.set("TZDIR", tz_dir);
+            set_timezone_env(environment, &timezone);
+        }
+        Some(timezone) if Path::new(TZ_HOST_DIR).is_dir() => {
+            bwrap.ro_bind(TZ_HOST_DIR, TZ_HOST_MOUNT_DIR);
+            bwrap.symlink(Path::new(TZ_HOST_MOUNT_DIR).join(&timezone), localtime_path);
+            environment.set("TZDIR", TZ_HOST_MOUNT_DIR);
+            set_timezone_env(environment, &timezone);
+        }
+        _ => {
+            let mount_path = Path::new("/run/host/etc/localtime");
+            bwrap.ro_bind(&resolved_localtime, mount_path);
+            bwrap.symlink(mount_path, localtime_path);
+        }
+    }
+
+    Ok(())
+}
+
+fn set_timezone_env(environment: &mut Environment, timezone: &str) {
+    // An explicitly set host timezone takes priority. The leading colon makes it a zoneinfo file,
+    // like flatpak sets it, rather than a POSIX rule.
+    if env::var_os("TZ").is_none() {
+        environment.set("TZ", format!(":{timezone}"));
+    }
+}
@@ -0,0 +1,622 @@
+use crate::{bwrap::BwrapBuilder, dbus_proxy::DbusProxy, environment::Environment};
+use anyhow::Context;
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+const DEFAULT_WAYLAND_DISPLAY: &str = "wayland-0";
+const PULSE_SOCKET: &str = "pulse/native";
+const DEFAULT_PIPEWIRE_REMOTE: &str = "pipewire-0";
+const PIPEWIRE_MANAGER_SUFFIX: &str = "-manager";
+const SSH_AGENT_SOCKET: &str = "ssh-auth";
+const CUPS_DEFAULT_SOCKET: &str = "/run/cups/cups.sock";
+const SESSION_BUS_SOCKET: &str = "bus";
+const DOCUMENT_PORTAL_DIR: &str = "doc";
+const PCSC_DEFAULT_SOCKET: &str = "/run/pcscd/pcscd.comm";
+const X11_SOCKET_DIR: &str = "/tmp/.X11-unix";
+const XAUTH_FAMILY_LOCAL: u16 = 256;
+const XAUTH_FAMILY_WILD: u16 = 65535;
+const A11Y_BUS_SOCKET: &str = "/run/flatpak/at-spi-bus";
+/// The accessibility bus methods apps are allowed to call, the same as in flatpak
+const A11Y_BUS_FILTER: [&str; 9] = [
+    "--filter",
+    "--sloppy-names",
+    "--call=org.a11y.atspi.Registry=org.a11y.atspi.Socket.Embed@/org/a11y/atspi/accessible/root",
+    "--call=org.a11y.atspi.Registry=org.a11y.atspi.Socket.Unembed@/org/a11y/atspi/accessible/root",
+    "--call=org.a11y.atspi.Registry=org.a11y.atspi.Registry.GetRegisteredEvents@/org/a11y/atspi/registry",
+    "--call=org.a11y.atspi.Registry=org.a11y.atspi.DeviceEventController.GetKeystrokeListeners@/org/a11y/atspi/registry/deviceeventcontroller",
+    "--call=org.a11y.atspi.Registry=org.a11y.atspi.DeviceEventController.GetDeviceEventListeners@/org/a11y/atspi/registry/deviceeventcontroller",
+    "--call=org.a11y.atspi.Registry=org.a11y.atspi.DeviceEventController.NotifyListenersSync@/org/a11y/atspi/registry/deviceeventcontroller",
+    "--call=org.a11y.atspi.Registry=org.a11y.atspi.DeviceEventController.NotifyListenersAsync@/org/a11y/atspi/registry/deviceeventcontroller",
+];
+/// Location of generated files when the runtime dir is shared with the host
+const SANDBOX_ONLY_DIR: &str = "/run/flatpak";
+
+/// The user's runtime dir (`XDG_RUNTIME_DIR`) on the host and inside of the sandbox
+pub struct RuntimeDir {
+    pub host: PathBuf,
+    pub sandbox: PathBuf,
+    /// Whether the host runtime dir is visible in the sandbox as a whole,
+    /// in which case sockets don't have to be bound individually
+    pub shared: bool,
+}
+
+impl RuntimeDir {
+    pub fn new(shared: bool) -> anyhow::Result<Self> {
+        let uid = fs::metadata("/proc/self")
+            .context("Could not determine the current user")?
+            .uid();
+        let default_dir = PathBuf::from(format!("/run/user/{uid}"));
+        let host = env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| default_dir.clone());
+        let sandbox = if shared { host.clone() } else { default_dir };
+
+        Ok(Self {
+            host,
+            sandbox,
+            shared,
+        })
+    }
+
+    /// Mounts the private runtime dir and points `XDG_RUNTIME_DIR` to the runtime dir inside of the sandbox.
+    /// Has to be called before anything is placed in the runtime dir.
+    pub fn setup(&self, bwrap: &mut BwrapBuilder, environment: &mut Environment) {
+        if !self.shared {
+            bwrap.perms(0o700).tmpfs(&self.sandbox);
+        }
+        environment.set("XDG_RUNTIME_DIR", &self.sandbox);
+    }
+
+    /// Makes a socket (or another file) from the host available in the sandbox runtime dir under the given name.
+    /// Returns its path inside of the sandbox.
+    fn bind_from_host(&self, bwrap: &mut BwrapBuilder, host_path: &Path, name: &Path) -> PathBuf {
+        if self.shared && host_path.starts_with(&self.host) {
+            return host_path.to_owned();
+        }
+        let sandbox_path = self.generated_file(name);
+        bwrap.ro_bind(host_path, &sandbox_path);
+        sandbox_path
+    }
+
+    /// Path for a file generated by flatbox. Generated files can't be placed in a shared runtime dir,
+    /// as bwrap would create their mount points on the host.
+    fn generated_file(&self, name: impl AsRef<Path>) -> PathBuf {
+        if self.shared {
+            Path::new(SANDBOX_ONLY_DIR).join(name)
+        } else {
+            self.sandbox.join(name)
+        }
+    }
+}
+
+pub fn is_socket(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+}
+
+/// Exposes the host's Wayland compositor socket and points `WAYLAND_DISPLAY` to it.
+/// Returns whether a Wayland socket is available.
+pub fn setup_wayland(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    runtime_dir: &RuntimeDir,
+) -> bool {
+    let display = env::var_os("WAYLAND_DISPLAY")
+        .filter(|display| !display.is_empty())
+        .unwrap_or_else(|| OsString::from(DEFAULT_WAYLAND_DISPLAY));
+    // WAYLAND_DISPLAY may also be an absolute path, in which case join() uses it as is
+    let host_socket = runtime_dir.host.join(&display);
+    if !is_socket(&host_socket) {
+        return false;
+    }
+
+    let name = Path::new(host_socket.file_name().unwrap_or(display.as_os_str())).to_owned();
+    let socket = runtime_dir.bind_from_host(bwrap, &host_socket, &name);
+    let display = socket.strip_prefix(&runtime_dir.sandbox).unwrap_or(&socket);
+    environment.set("WAYLAND_DISPLAY", display);
+    true
+}
+
+/// Exposes the host's PulseAudio (or pipewire-pulse) socket and authentication cookie.
+/// Returns whether a PulseAudio server is available.
+pub fn setup_pulseaudio(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    runtime_dir: &RuntimeDir,
+) -> anyhow::Result<bool> {
+    let host_socket = env::var("PULSE_SERVER")
+        .ok()
+        .and_then(|server| server.strip_prefix("unix:").map(PathBuf::from))
+        .unwrap_or_else(|| runtime_dir.host.join(PULSE_SOCKET));
+    if !is_socket(&host_socket) {
+        return Ok(false);
+    }
+
+    let socket = runtime_dir.bind_from_host(bwrap, &host_socket, Path::new(PULSE_SOCKET));
+    let mut server = OsString::from("unix:");
+    server.push(&socket);
+    environment.set("PULSE_SERVER", server);
+
+    if let Some(cookie) = pulse_cookie_path() {
+        let sandbox_cookie = runtime_dir.generated_file("pulse/cookie");
+        bwrap.ro_bind(&cookie, &sandbox_cookie);
+
+        let config_path = runtime_dir.generated_file("pulse/config");
+        let config = format!("cookie-file = {}\n", sandbox_cookie.display());
+        bwrap
+            .ro_bind_data(&config_path, config.as_bytes())
+            .context("Could not write PulseAudio client config")?;
+        environment.set("PULSE_CLIENT_CONFIG", config_path);
+    }
+
+    Ok(true)
+}
+
+fn pulse_cookie_path() -> Option<PathBuf> {
+    if let Some(cookie) = env::var_os("PULSE_COOKIE") {
+        return Some(PathBuf::from(cookie));
+    }
+
+    let home = PathBuf::from(env::var_os("HOME")?);
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home.join(".config"));
+    [
+        config_home.join("pulse").join("cookie"),
+        home.join(".pulse-cookie"),
+    ]
+    .into_iter()
+    .find(|path| path.is_file())
+}
+
+/// Exposes the host's PipeWire sockets, used for audio and screen capture.
+/// With pipewire-pulse, PulseAudio clients are served by `setup_pulseaudio` as usual.
+/// Returns whether a PipeWire server is available.
+pub fn setup_pipewire(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    runtime_dir: &RuntimeDir,
+) -> bool {
+    let remote = env::var_os("PIPEWIRE_REMOTE")
+        .filter(|remote| !remote.is_empty())
+        .unwrap_or_else(|| OsString::from(DEFAULT_PIPEWIRE_REMOTE));
+
+    let mut available = false;
+    for suffix in ["", PIPEWIRE_MANAGER_SUFFIX] {
+        let mut host_socket = runtime_dir.host.join(&remote).into_os_string();
+        host_socket.push(suffix);
+        let host_socket = PathBuf::from(host_socket);
+        if !is_socket(&host_socket) {
+            continue;
+        }
+
+        let Some(name) = host_socket.file_name().map(PathBuf::from) else {
+            continue;
+        };
+        let socket = runtime_dir.bind_from_host(bwrap, &host_socket, &name);
+        if suffix.is_empty() {
+            available = true;
+            if remote != DEFAULT_PIPEWIRE_REMOTE {
+                environment.set("PIPEWIRE_REMOTE", socket);
+            }
+        }
+    }
+    available
+}
+
+/// Connects the sandbox to the accessibility bus through a filtering proxy.
+/// When the runtime dir is shared, apps can already reach the bus directly, so nothing is done.
+pub fn setup_a11y_bus(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    runtime_dir: &RuntimeDir,
+) {
+    if runtime_dir.shared {
+        return;
+    }
+    let Some(bus_address) = a11y_bus_address() else {
+        return;
+    };
+
+    let proxy_socket = bwrap.data_path("a11y-bus");
+    match DbusProxy::spawn(&bus_address, &proxy_socket, A11Y_BUS_FILTER) {
+        Ok(proxy) => {
+            bwrap.add_dbus_proxy(proxy);
+            bwrap.ro_bind(&proxy_socket, A11Y_BUS_SOCKET);
+            environment.set("AT_SPI_BUS_ADDRESS", format!("unix:path={A11Y_BUS_SOCKET}"));
+        }
+        Err(err) => crate::warn!("Could not set up the accessibility bus: {err:#}"),
+    }
+}
+
+fn a11y_bus_address() -> Option<String> {
+    if let Ok(address) = env::var("AT_SPI_BUS_ADDRESS")
+        && !address.is_empty()
+    {
+        return Some(address);
+    }
+
+    let output = Command::new("dbus-send")
+        .args([
+            "--session",
+            "--print-reply=literal",
+            "--dest=org.a11y.Bus",
+            "/org/a11y/bus",
+            "org.a11y.Bus.GetAddress",
+        ])
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let address = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    (!address.is_empty()).then_some(address)
+}
+
+/// Forwards the host's ssh-agent socket from `SSH_AUTH_SOCK`
+pub fn setup_ssh_agent(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    runtime_dir: &RuntimeDir,
+) {
+    let Some(host_socket) = env::var_os("SSH_AUTH_SOCK").map(PathBuf::from) else {
+        crate::warn!("SSH_AUTH_SOCK is not set, not forwarding the ssh agent");
+        return;
+    };
+    if !is_socket(&host_socket) {
+        crate::warn!("No ssh agent is listening at {}", host_socket.display());
+        return;
+    }
+
+    let socket = runtime_dir.bind_from_host(bwrap, &host_socket, Path::new(SSH_AGENT_SOCKET));
+    environment.set("SSH_AUTH_SOCK", socket);
+}
+
+/// Forwards the host's gpg-agent socket.
+/// gpg inside of the sandbox looks the socket up at the same location as on the host,
+/// so the socket keeps its path relative to the runtime dir.
+pub fn setup_gpg_agent(bwrap: &mut BwrapBuilder, runtime_dir: &RuntimeDir) {
+    let host_socket = Command::new("gpgconf")
+        .args(["--list-dirs", "agent-socket"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()));
+    let Some(host_socket) = host_socket.filter(|socket| is_socket(socket)) else {
+        crate::warn!("Could not find the gpg-agent socket, not forwarding the gpg agent");
+        return;
+    };
+
+    match host_socket.strip_prefix(&runtime_dir.host) {
+        Ok(name) => {
+            runtime_dir.bind_from_host(bwrap, &host_socket, name);
+        }
+        // e.g. ~/.gnupg/S.gpg-agent, which is visible along with the home directory
+        Err(_) => {
+            bwrap.ro_bind(&host_socket, &host_socket);
+        }
+    }
+}
+
+/// Exposes the host's CUPS socket for printing. Remote CUPS servers only need `CUPS_SERVER`.
+/// `run_shared` is whether the host's `/run` is visible in the sandbox, in which case the socket is too.
+pub fn setup_cups(bwrap: &mut BwrapBuilder, environment: &mut Environment, run_shared: bool) {
+    let server = env::var("CUPS_SERVER")
+        .ok()
+        .or_else(configured_cups_server)
+        .unwrap_or_else(|| CUPS_DEFAULT_SOCKET.to_owned());
+
+    if !server.starts_with('/') {
+        environment.set("CUPS_SERVER", server);
+        return;
+    }
+
+    let host_socket = Path::new(&server);
+    if !is_socket(host_socket) {
+        crate::warn!("No CUPS server is listening at {server}");
+        return;
+    }
+    if !(run_shared && host_socket.starts_with("/run")) {
+        bwrap.ro_bind(host_socket, CUPS_DEFAULT_SOCKET);
+        environment.set("CUPS_SERVER", CUPS_DEFAULT_SOCKET);
+    }
+}
+
+/// Exposes the host's PC/SC smart card daemon socket
+pub fn setup_pcsc(bwrap: &mut BwrapBuilder, environment: &mut Environment, run_shared: bool) {
+    let host_socket = env::var_os("PCSCLITE_CSOCK_NAME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(PCSC_DEFAULT_SOCKET));
+    if !is_socket(&host_socket) {
+        return;
+    }
+    if !(run_shared && host_socket.starts_with("/run")) {
+        bwrap.ro_bind(&host_socket, PCSC_DEFAULT_SOCKET);
+        environment.set("PCSCLITE_CSOCK_NAME", PCSC_DEFAULT_SOCKET);
+    }
+}
+
+/// The `ServerName` from the user's or the system's CUPS client configuration
+fn configured_cups_server() -> Option<String> {
+    let user_config = env::var_os("HOME").map(|home| Path::new(&home).join(".cups/client.conf"));
+    user_config
+        .into_iter()
+        .chain([PathBuf::from("/etc/cups/client.conf")])
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .find_map(|config| {
+            config.lines().find_map(|line| {
+                let (key, value) = line.trim().split_once(char::is_whitespace)?;
+                key.eq_ignore_ascii_case("ServerName")
+                    .then(|| value.trim().to_owned())
+            })
+        })
+}
+
+/// The address of the host's D-Bus session bus
+fn session_bus_address(runtime_dir: &RuntimeDir) -> String {
+    env::var("DBUS_SESSION_BUS_ADDRESS")
+        .ok()
+        .filter(|address| !address.is_empty())
+        .unwrap_or_else(|| {
+            format!(
+                "unix:path={}",
+                runtime_dir.host.join(SESSION_BUS_SOCKET).display()
+            )
+        })
+}
+
+/// Points the sandbox to its session bus socket. Services started by D-Bus activation are also told the bus
+/// which started them (`DBUS_STARTER_ADDRESS`), which has to be the same.
+fn set_session_bus_address(environment: &mut Environment, socket: &Path) {
+    let mut address = OsString::from("unix:path=");
+    address.push(socket);
+    if env::var("DBUS_STARTER_BUS_TYPE").is_ok_and(|bus_type| bus_type == "session") {
+        environment.set("DBUS_STARTER_ADDRESS", &address);
+    }
+    environment.set("DBUS_SESSION_BUS_ADDRESS", address);
+}
+
+/// Exposes the host's D-Bus session bus
+pub fn setup_session_bus(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    runtime_dir: &RuntimeDir,
+) {
+    let address = session_bus_address(runtime_dir);
+    let Some(path) = address.strip_prefix("unix:path=") else {
+        // Abstract sockets and TCP addresses don't depend on the filesystem
+        return;
+    };
+    let host_socket = PathBuf::from(path.split(',').next().unwrap_or(path));
+    if !is_socket(&host_socket) {
+        return;
+    }
+
+    let socket = runtime_dir.bind_from_host(bwrap, &host_socket, Path::new(SESSION_BUS_SOCKET));
+    set_session_bus_address(environment, &socket);
+}
+
+/// Connects the sandbox to the D-Bus session bus through a proxy with the given filter. When the runtime dir
+/// is shared, the host's bus can be reached directly and isn't filtered.
+pub fn setup_filtered_session_bus(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    runtime_dir: &RuntimeDir,
+    filter: &[String],
+) {
+    if runtime_dir.shared {
+        setup_session_bus(bwrap, environment, runtime_dir);
+        return;
+    }
+
+    let proxy_socket = bwrap.data_path("session-bus");
+    match DbusProxy::spawn(&session_bus_address(runtime_dir), &proxy_socket, filter) {
+        Ok(proxy) => {
+            bwrap.add_dbus_proxy(proxy);
+            let socket = runtime_dir.generated_file(SESSION_BUS_SOCKET);
+            bwrap.ro_bind(&proxy_socket, &socket);
+            set_session_bus_address(environment, &socket);
+        }
+        Err(err) => crate::warn!("Could not set up the filtered session bus: {err:#}"),
+    }
+}
+
+/// The app's view of the document portal on the host, which contains the files the user granted it access to.
+/// Only exists while the portal runs.
+pub(crate) fn app_documents(runtime_dir: &RuntimeDir, app_id: &str) -> PathBuf {
+    runtime_dir
+        .host
+        .join(DOCUMENT_PORTAL_DIR)
+        .join("by-app")
+        .join(app_id)
+}
+
+/// Where the app finds its documents in the sandbox
+pub(crate) fn sandbox_documents(runtime_dir: &RuntimeDir) -> PathBuf {
+    runtime_dir.sandbox.join(DOCUMENT_PORTAL_DIR)
+}
+
+/// Exposes the app's view of the document portal
+pub fn setup_document_portal(bwrap: &mut BwrapBuilder, runtime_dir: &RuntimeDir, app_id: &str) {
+    if runtime_dir.shared {
+        return;
+    }
+
+    let app_documents = app_documents(runtime_dir, app_id);
+    if app_documents.is_dir() {
+        bwrap.bind(&app_documents, sandbox_documents(runtime_dir));
+    }
+}
+
+/// Lets GSettings read the host's dconf database, with changes going through the dconf service on the session bus.
+/// `home_visible` is whether the host home directory (and with it the database) is visible in the sandbox.
+pub fn setup_dconf(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    runtime_dir: &RuntimeDir,
+    home_visible: bool,
+) {
+    if runtime_dir.shared && home_visible {
+        return;
+    }
+    let Some(home) = env::var_os("HOME").map(PathBuf::from) else {
+        return;
+    };
+    let dconf_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home.join(".config"))
+        .join("dconf");
+    if !dconf_dir.join("user").is_file() {
+        return;
+    }
+
+    if !home_visible {
+        bwrap.ro_bind(&dconf_dir, &dconf_dir);
+    }
+    // Contains the shared memory file used to notify clients about changes
+    let host_runtime_dconf = runtime_dir.host.join("dconf");
+    if host_runtime_dconf.is_dir() {
+        runtime_dir.bind_from_host(bwrap, &host_runtime_dconf, Path::new("dconf"));
+    }
+
+    environment.set("DCONF_USER_CONFIG_DIR", dconf_dir);
+    environment.set("GSETTINGS_BACKEND", "dconf");
+}
+
+/// Exposes the host's local X11 display socket along with the matching Xauthority cookies.
+/// Remote displays (`host:0`) are passed through as is.
+pub fn setup_x11(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    runtime_dir: &RuntimeDir,
+    share_tmp: bool,
+) -> anyhow::Result<()> {
+    let Some(display) = env::var("DISPLAY")
+        .ok()
+        .filter(|display| !display.is_empty())
+    else {
+        environment.unset("DISPLAY");
+        return Ok(());
+    };
+    let Some(display_number) = parse_local_display(&display) else {
+        return Ok(());
+    };
+
+    let socket = PathBuf::from(format!("{X11_SOCKET_DIR}/X{display_number}"));
+    if !share_tmp && is_socket(&socket) {
+        bwrap.ro_bind(&socket, &socket);
+    }
+    environment.set("DISPLAY", format!(":{display_number}"));
+
+    let xauthority = env::var_os("XAUTHORITY")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".Xauthority")));
+    if let Some(xauthority) = xauthority
+        && let Ok(data) = fs::read(&xauthority)
+    {
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+        let cookies = filter_xauth(&data, hostname.trim().as_bytes(), display_number);
+        if !cookies.is_empty() {
+            let path = runtime_dir.generated_file("Xauthority");
+            bwrap
+                .ro_bind_data(&path, &cookies)
+                .context("Could not write Xauthority")?;
+            environment.set("XAUTHORITY", path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Extracts the display number of a local display (`:0`, `:1.0`, `unix:0`)
+fn parse_local_display(display: &str) -> Option<&str> {
+    let (host, display) = display.rsplit_once(':')?;
+    if !host.is_empty() && host != "unix" {
+        return None;
+    }
+    let number = display
+        .split_once('.')
+        .map_or(display, |(number, _)| number);
+    (!number.is_empty() && number.bytes().all(|byte| byte.is_ascii_digit())).then_some(number)
+}
+
+/// Keeps the Xauthority entries which apply to the given local display.
+/// Entries consist of a big-endian 16 bit family followed by the address, display number, auth name and auth data,
+/// each of which is prefixed by its big-endian 16 bit length.
+fn filter_xauth(data: &[u8], hostname: &[u8], display_number: &str) -> Vec<u8> {
+    fn read_u16(data: &[u8], pos: &mut usize) -> Option<u16> {
+        let value = data.get(*pos..*pos + 2)?;
+        *pos += 2;
+        Some(u16::from_be_bytes([value[0], value[1]]))
+    }
+    fn read_field<'a>(data: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+        let len = read_u16(data, pos)? as usize;
+        let value = data.get(*pos..*pos + len)?;
+        *pos += len;
+        Some(value)
+    }
+
+    let mut output = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let start = pos;
+        let Some(family) = read_u16(data, &mut pos) else {
+            break;
+        };
+        let (Some(address), Some(number), Some(_name), Some(_data)) = (
+            read_field(data, &mut pos),
+            read_field(data, &mut pos),
+            read_field(data, &mut pos),
+            read_field(data, &mut pos),
+        ) else {
+            break;
+        };
+
+        let local =
+            family == XAUTH_FAMILY_WILD || (family == XAUTH_FAMILY_LOCAL && address == hostname);
+        if local && number == display_number.as_bytes() {
+            output.extend_from_slice(&data[start..pos]);
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sockets::{filter_xauth, parse_local_display};
+
+    fn xauth_entry(family: u16, address: &str, number: &str) -> Vec<u8> {
+        let mut entry = family.to_be_bytes().to_vec();
+        for field in [address, number, "MIT-MAGIC-COOKIE-1", "0123456789abcdef"] {
+            entry.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            entry.extend_from_slice(field.as_bytes());
+        }
+        entry
+    }
+
+    #[test]
+    fn parse_display() {
+        assert_eq!(parse_local_display(":0"), Some("0"));
+        assert_eq!(parse_local_display(":1.0"), Some("1"));
+        assert_eq!(parse_local_display("unix:2"), Some("2"));
+        assert_eq!(parse_local_display("remote:0"), None);
+        assert_eq!(parse_local_display("invalid"), None);
+    }
+
+    #[test]
+    fn filter_xauth_entries() {
+        let local = xauth_entry(256, "myhost", "0");
+        let other_display = xauth_entry(256, "myhost", "1");
+        let other_host = xauth_entry(256, "otherhost", "0");
+        let wild = xauth_entry(65535, "", "0");
+
+        let data = [local.clone(), other_display, other_host, wild.clone()].concat();
+        assert_eq!(filter_xauth(&data, b"myhost", "0"), [local, wild].concat());
+        assert!(filter_xauth(&data[..5], b"myhost", "0").is_empty());
+    }
+}
@@ -0,0 +1,38 @@
+use std::{env, path::PathBuf};
+
+/// Where flatbox keeps its state when running as root, next to the system installation of flatpak
+pub const STATE_DIR: &str = "/var/lib/flatbox";
+/// The data dirs of the apps in the state dir, in place of `~/.var/app`
+const APPS_DIR: &str = "app";
+/// flatbox's caches in the state dir, in place of `~/.cache/flatbox`
+const CACHE_DIR: &str = "cache";
+/// The installation of `flatbox install` in the state dir
+const INSTALLATION_DIR: &str = "installation";
+
+/// Whether flatbox runs as root, e.g. from a systemd system service or with sudo. Its state then doesn't
+/// depend on `HOME` and the XDG base dirs, which are unset in services and may still be the ones of the user
+/// with sudo, so no root-owned files end up in a user's home.
+pub fn is_system_mode() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// The state dir of a systemd service (`StateDirectory=`), or `/var/lib/flatbox`
+pub fn state_dir() -> PathBuf {
+    env::var_os("STATE_DIRECTORY")
+        .and_then(|dirs| env::split_paths(&dirs).next())
+        .filter(|dir| dir.is_absolute())
+        .unwrap_or_else(|| PathBuf::from(STATE_DIR))
+}
+
+/// The data dir of an app, shared by the services running it
+pub fn app_data_dir(app_id: &str) -> PathBuf {
+    state_dir().join(APPS_DIR).join(app_id)
+}
+
+pub fn cache_dir() -> PathBuf {
+    state_dir().join(CACHE_DIR)
+}
+
+pub fn installation_dir() -> PathBuf {
+    state_dir().join(INSTALLATION_DIR)
+}
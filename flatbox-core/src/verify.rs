@@ -0,0 +1,122 @@
+use crate::{
+    error::FlatboxError,
+    ostree::{self, repo_arg},
+};
+use anyhow::Context;
+use std::{fmt, fs, path::Path};
+
+/// Levels between a deployment and its installation: `<kind>/<id>/<arch>/<branch>/<commit>`
+const DEPLOYMENT_DEPTH: usize = 5;
+/// The parts of a deployment which come from the commit unchanged. Flatpak rewrites the exported files and
+/// adds its own bookkeeping next to them.
+const COMMITTED_PATHS: [&str; 2] = ["/files", "/metadata"];
+
+/// How a file of a deployment differs from its commit
+#[derive(Debug, PartialEq, Eq)]
+pub enum Difference {
+    Modified(String),
+    Missing(String),
+    Added(String),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Modified(path) => write!(f, "modified: {path}"),
+            Self::Missing(path) => write!(f, "missing: {path}"),
+            Self::Added(path) => write!(f, "added: {path}"),
+        }
+    }
+}
+
+/// Compares the files of a deployment (e.g. the `active` dir of an installed ref) with the checksums of the
+/// commit it was checked out from, in the repo of its installation
+pub fn verify_deployment(deployment: &Path) -> anyhow::Result<Vec<Difference>> {
+    let deployment = fs::canonicalize(deployment)
+        .with_context(|| format!("Could not find {}", deployment.display()))?;
+    let commit = deployment
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| name.len() == 64 && name.bytes().all(|byte| byte.is_ascii_hexdigit()))
+        .with_context(|| {
+            format!(
+                "{} isn't a deployment of an OSTree commit",
+                deployment.display()
+            )
+        })?;
+    let Some(installation) = deployment.ancestors().nth(DEPLOYMENT_DEPTH) else {
+        return Err(FlatboxError::NotInInstallation {
+            path: deployment.to_owned(),
+        }
+        .into());
+    };
+    let repo = installation.join("repo");
+    let repo_arg = &repo_arg(&repo);
+    ostree::ostree(&["rev-parse".as_ref(), repo_arg, commit.as_ref()]).with_context(|| {
+        format!(
+            "Commit {commit} isn't in {}, the deployment can't be verified",
+            repo.display()
+        )
+    })?;
+
+    crate::info!("Comparing {} with commit {commit}", deployment.display());
+    // The commits of flatpak are owned by root, the deployments by whoever installed them
+    let diff = ostree::ostree(&[
+        "diff".as_ref(),
+        repo_arg,
+        "--owner-uid=0".as_ref(),
+        "--owner-gid=0".as_ref(),
+        commit.as_ref(),
+        deployment.as_ref(),
+    ])?;
+    Ok(parse_diff(&diff))
+}
+
+/// Parses the output of `ostree diff`, keeping the differences of the files from the commit
+fn parse_diff(diff: &str) -> Vec<Difference> {
+    diff.lines()
+        .filter_map(|line| {
+            let (kind, path) = line.split_once(char::is_whitespace)?;
+            let path = path.trim();
+            let committed = COMMITTED_PATHS.iter().any(|committed| {
+                path.strip_prefix(committed)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            });
+            if !committed {
+                return None;
+            }
+            let path = path.to_owned();
+            match kind {
+                "M" => Some(Difference::Modified(path)),
+                "D" => Some(Difference::Missing(path)),
+                "A" => Some(Difference::Added(path)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::verify::{Difference, parse_diff};
+
+    #[test]
+    fn parses_differences_of_committed_files() {
+        let diff = "M    /files/lib/libfoo.so.1\n\
+                    M    /export/share/applications/org.example.App.desktop\n\
+                    D    /files/share/icons/hicolor/index.theme\n\
+                    A    /files/bin/injected\n\
+                    A    /deploy\n\
+                    A    /files-extra\n\
+                    M    /metadata\n";
+        assert_eq!(
+            parse_diff(diff),
+            [
+                Difference::Modified("/files/lib/libfoo.so.1".to_owned()),
+                Difference::Missing("/files/share/icons/hicolor/index.theme".to_owned()),
+                Difference::Added("/files/bin/injected".to_owned()),
+                Difference::Modified("/metadata".to_owned()),
+            ]
+        );
+    }
+}
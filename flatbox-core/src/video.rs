@@ -0,0 +1,34 @@
+use crate::{bwrap::BwrapBuilder, environment::Environment};
+use std::{fs, path::Path};
+
+const RENDER_NODES_DIR: &str = "/dev/dri";
+const VAAPI_DRIVER_SUFFIX: &str = "_drv_video.so";
+
+/// Points libva and libvdpau to the hardware video decode drivers in a mounted library dir
+/// (`lib` of a GL or codecs extension, or the host graphics dir).
+/// `source_lib_dir` is the dir on the host and `mount_lib_dir` the path it is mounted at.
+pub fn add_driver_env(environment: &mut Environment, source_lib_dir: &Path, mount_lib_dir: &Path) {
+    let has_vaapi_drivers = fs::read_dir(source_lib_dir.join("dri")).is_ok_and(|entries| {
+        entries.flatten().any(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.ends_with(VAAPI_DRIVER_SUFFIX))
+        })
+    });
+    if has_vaapi_drivers {
+        environment.append_path("LIBVA_DRIVERS_PATH", mount_lib_dir.join("dri"));
+    }
+
+    // libvdpau only supports a single driver dir, so the first one found is used
+    if source_lib_dir.join("vdpau").is_dir() && environment.get("VDPAU_DRIVER_PATH").is_none() {
+        environment.set("VDPAU_DRIVER_PATH", mount_lib_dir.join("vdpau"));
+    }
+}
+
+/// Binds the DRM render nodes used for hardware decoding into a private `/dev`
+pub fn setup_render_nodes(bwrap: &mut BwrapBuilder) {
+    if Path::new(RENDER_NODES_DIR).is_dir() {
+        bwrap.dev_bind(RENDER_NODES_DIR, RENDER_NODES_DIR);
+    }
+}
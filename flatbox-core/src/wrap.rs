@@ -0,0 +1,305 @@
+use crate::{bwrap::BwrapBuilder, environment::Environment, error::FlatboxError};
+use anyhow::Context;
+use std::{
+    env, fmt, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+/// Where the host's root is exposed in the sandbox
+const HOST_ROOT: &str = "/run/host/root";
+/// Generated commands (e.g. perf from the host) are placed here and added to `PATH`
+const BIN_DIR: &str = "/run/flatbox/bin";
+/// The host's dir the output of the tools is written to (the current dir) is mounted here
+pub const OUTPUT_DIR: &str = "/run/flatbox/output";
+/// Dirs searched for tools on the host
+const HOST_PATH: [&str; 4] = ["/usr/local/bin", "/usr/bin", "/bin", "/usr/sbin"];
+/// The dirs of the host's valgrind, which looks its tools up in `VALGRIND_LIB`
+const VALGRIND_LIB_DIRS: [&str; 3] = [
+    "/usr/libexec/valgrind",
+    "/usr/lib/valgrind",
+    "/usr/lib64/valgrind",
+];
+/// A tool with its default arguments and the options naming its output files
+type KnownTool = (
+    &'static str,
+    &'static [&'static str],
+    &'static [&'static str],
+);
+const KNOWN_TOOLS: [KnownTool; 4] = [
+    ("strace", &["-f", "-o", "strace.log"], &["-o", "--output"]),
+    ("ltrace", &["-f", "-o", "ltrace.log"], &["-o", "--output"]),
+    (
+        "valgrind",
+        &["--log-file=valgrind.%p.log"],
+        &["--log-file", "--xml-file"],
+    ),
+    (
+        "perf",
+        &["record", "-g", "-o", "perf.data"],
+        &["-o", "--output"],
+    ),
+];
+
+/// A tool the command is run with, written as `TOOL[:ARGS]` with comma or space separated arguments, e.g.
+/// `strace:-f,-e trace=file`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Wrapper {
+    pub tool: String,
+    /// The arguments given, or the tool's defaults
+    pub args: Vec<String>,
+}
+
+impl FromStr for Wrapper {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (tool, args) = match value.split_once(':') {
+            Some((tool, args)) => (tool, Some(args)),
+            None => (value, None),
+        };
+        if tool.is_empty() || tool.contains('/') {
+            return Err(format!("expected the name of a tool, found '{tool}'"));
+        }
+        let args = match args {
+            Some(args) => args
+                .split([',', ' '])
+                .filter(|arg| !arg.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            None => known_tool(tool)
+                .map(|(_, default_args, _)| {
+                    default_args.iter().map(|arg| arg.to_string()).collect()
+                })
+                .unwrap_or_default(),
+        };
+        Ok(Self {
+            tool: tool.to_owned(),
+            args,
+        })
+    }
+}
+
+impl fmt::Display for Wrapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.tool, self.args.join(","))
+    }
+}
+
+fn known_tool(tool: &str) -> Option<&'static KnownTool> {
+    KNOWN_TOOLS.iter().find(|(name, _, _)| *name == tool)
+}
+
+impl Wrapper {
+    /// The arguments with relative output files placed in the output dir
+    fn args_with_output_dir(&self) -> Vec<String> {
+        let Some((_, _, output_options)) = known_tool(&self.tool) else {
+            return self.args.clone();
+        };
+        let in_output_dir = |path: &str| {
+            if path.starts_with('/') {
+                path.to_owned()
+            } else {
+                format!("{OUTPUT_DIR}/{path}")
+            }
+        };
+
+        let mut args = Vec::with_capacity(self.args.len());
+        let mut output_follows = false;
+        for arg in &self.args {
+            if output_follows {
+                args.push(in_output_dir(arg));
+                output_follows = false;
+            } else if output_options.contains(&arg.as_str()) {
+                args.push(arg.clone());
+                output_follows = true;
+            } else if let Some((option, path)) = arg.split_once('=')
+                && output_options.contains(&option)
+            {
+                args.push(format!("{option}={}", in_output_dir(path)));
+            } else {
+                args.push(arg.clone());
+            }
+        }
+        args
+    }
+}
+
+/// The command running a tool in the sandbox: the runtime's (an SDK's) when it has one, and otherwise the host's
+/// through the host's dynamic loader and libraries, which needs the host's root to be exposed
+pub(crate) fn tool_command(
+    bwrap: &mut BwrapBuilder,
+    runtime_files_path: &Path,
+    tool: &str,
+    host_root_exposed: bool,
+) -> anyhow::Result<Vec<String>> {
+    if runtime_files_path.join("bin").join(tool).exists() {
+        return Ok(vec![format!("/usr/bin/{tool}")]);
+    }
+    let Some(host_tool) = HOST_PATH
+        .iter()
+        .map(|dir| Path::new(dir).join(tool))
+        .find(|path| path.is_file())
+    else {
+        return Err(FlatboxError::ToolNotFound {
+            tool: tool.to_owned(),
+        }
+        .into());
+    };
+    if !host_root_exposed {
+        return Err(FlatboxError::HostRootRequired {
+            what: format!("{tool} isn't in the runtime, and using the host's one"),
+        }
+        .into());
+    }
+    let host_tool = fs::canonicalize(&host_tool)?;
+    crate::debug!("Using the host's {}", host_tool.display());
+
+    if tool == "valgrind"
+        && let Some(lib_dir) = VALGRIND_LIB_DIRS.iter().find(|dir| Path::new(dir).is_dir())
+    {
+        bwrap.set_env("VALGRIND_LIB", in_host_root(Path::new(lib_dir)));
+    }
+    host_binary_command(&host_tool)
+}
+
+/// The command running a binary of the host in the sandbox, through the host's dynamic loader and libraries in
+/// the host's root
+pub(crate) fn host_binary_command(host_binary: &Path) -> anyhow::Result<Vec<String>> {
+    let mut command = Vec::new();
+    if let Some((loader, library_dirs)) = host_libraries(host_binary)? {
+        command.push(in_host_root(&loader));
+        command.push("--library-path".to_owned());
+        command.push(
+            library_dirs
+                .iter()
+                .map(|dir| in_host_root(dir))
+                .collect::<Vec<_>>()
+                .join(":"),
+        );
+    }
+    command.push(in_host_root(host_binary));
+    Ok(command)
+}
+
+/// Makes a command available in the sandbox as `name`, through a shell script in a dir added to `PATH`
+pub(crate) fn install_launcher(
+    bwrap: &mut BwrapBuilder,
+    environment: &mut Environment,
+    name: &str,
+    command: &[String],
+) -> anyhow::Result<()> {
+    let script: Vec<String> = command.iter().map(|arg| shell_quote(arg)).collect();
+    let launcher = bwrap.data_path(name);
+    fs::write(
+        &launcher,
+        format!("#!/bin/sh\nexec {} \"$@\"\n", script.join(" ")),
+    )
+    .with_context(|| format!("Could not write {}", launcher.display()))?;
+    fs::set_permissions(&launcher, fs::Permissions::from_mode(0o755))?;
+    bwrap.ro_bind(&launcher, Path::new(BIN_DIR).join(name));
+    environment.prepend_path("PATH", BIN_DIR);
+    Ok(())
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+fn in_host_root(path: &Path) -> String {
+    Path::new(HOST_ROOT)
+        .join(path.strip_prefix("/").unwrap_or(path))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The dynamic loader of a host binary and the dirs of the libraries it loads, as resolved by `ldd`. None for
+/// static binaries.
+fn host_libraries(binary: &Path) -> anyhow::Result<Option<(PathBuf, Vec<PathBuf>)>> {
+    let output = Command::new("ldd")
+        .arg(binary)
+        .output()
+        .context("Could not run ldd")?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let mut loader = None;
+    let mut library_dirs = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let line = line.trim();
+        let path = match line.split_once("=>") {
+            Some((_, resolved)) => resolved.split_whitespace().next(),
+            None => line
+                .split_whitespace()
+                .next()
+                .filter(|path| path.starts_with('/')),
+        };
+        // Resolved on the host, as absolute links would point into the runtime in the sandbox
+        let Some(path) = path.and_then(|path| fs::canonicalize(path).ok()) else {
+            continue;
+        };
+        if line.contains("=>") {
+            if let Some(dir) = path.parent()
+                && !library_dirs.iter().any(|known| known == dir)
+            {
+                library_dirs.push(dir.to_owned());
+            }
+        } else {
+            loader = Some(path);
+        }
+    }
+    Ok(loader.map(|loader| (loader, library_dirs)))
+}
+
+/// Prepends the tool to the command line. The host's current dir is mounted writable for its output, relative
+/// output files of the known tools (e.g. `strace -o`) are written there.
+pub fn wrap_command_line(
+    bwrap: &mut BwrapBuilder,
+    wrapper: &Wrapper,
+    runtime_files_path: &Path,
+    host_root_exposed: bool,
+    command_line: Vec<String>,
+) -> anyhow::Result<Vec<String>> {
+    let mut wrapped = tool_command(bwrap, runtime_files_path, &wrapper.tool, host_root_exposed)?;
+    let output_dir = env::current_dir().context("Could not determine the current dir")?;
+    crate::info!(
+        "Running the command in {}, writing its output to {}",
+        wrapper.tool,
+        output_dir.display()
+    );
+    bwrap.bind(&output_dir, OUTPUT_DIR);
+    wrapped.extend(wrapper.args_with_output_dir());
+    wrapped.extend(command_line);
+    Ok(wrapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wrap::Wrapper;
+
+    #[test]
+    fn wrappers_write_to_the_output_dir() {
+        let args = |wrapper: &str| wrapper.parse::<Wrapper>().unwrap().args_with_output_dir();
+        assert_eq!(
+            args("strace"),
+            ["-f", "-o", "/run/flatbox/output/strace.log"]
+        );
+        assert_eq!(
+            args("strace:-f,-o out,-e trace=file"),
+            ["-f", "-o", "/run/flatbox/output/out", "-e", "trace=file"]
+        );
+        assert_eq!(
+            args("valgrind:--tool=massif,--log-file=/tmp/v.log,--xml-file=v.xml"),
+            [
+                "--tool=massif",
+                "--log-file=/tmp/v.log",
+                "--xml-file=/run/flatbox/output/v.xml"
+            ]
+        );
+        assert_eq!(args("heaptrack:-o,x"), ["-o", "x"]);
+        assert!("".parse::<Wrapper>().is_err());
+        assert!("/usr/bin/strace".parse::<Wrapper>().is_err());
+    }
+}
@@ -1,35 +1,349 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{Parser, Subcommand, ValueEnum};
+use flatbox_core::{build::BindMount, logging::LogFormat, options::SandboxOptions};
+use std::{ffi::OsString, os::fd::RawFd, path::PathBuf};
 
 #[derive(Parser)]
 #[command(version, about)]
 pub struct Args {
     #[command(subcommand)]
     pub command: Command,
-    #[arg(short, long, default_value = "false")]
-    pub verbose: bool,
+    /// Log more details: -v for the sandbox setup, -vv for each phase, -vvv for every mount.
+    /// FLATBOX_LOG (e.g. `warn,extensions=debug`) takes precedence
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    /// Format of the log messages on stderr
+    #[arg(long, global = true, value_name = "FORMAT", default_value = "text")]
+    pub log_format: LogFormat,
+    /// Config file to use instead of $XDG_CONFIG_HOME/flatbox/config.toml
+    #[arg(long, global = true, value_name = "PATH", conflicts_with = "no_config")]
+    pub config: Option<PathBuf>,
+    /// Ignore the config file
+    #[arg(long, global = true, default_value_t)]
+    pub no_config: bool,
 }
 
 #[derive(Subcommand)]
 pub enum Command {
-    Run(RunCommand),
+    /// Run a command in the environment of a flatpak app or runtime
+    Run(Box<RunCommand>),
+    /// Run the command of an app in gdb, in its SDK (like `run --devel`) with the debug info and sources
+    /// extensions of the app and runtime mounted
+    Debug(Box<RunCommand>),
+    /// Run a command in a build dir created by `flatpak build-init`, like `flatpak build`: its files are mounted
+    /// writable at /app, in the SDK it declares. flatpak-builder's options of `flatpak build` are understood.
+    Build(Box<BuildCommand>),
+    /// Compose the sandbox `run` would create and save it to a dir, to launch it later with `flatbox run --plan DIR`
+    /// without resolving the app, parsing its metadata or probing extensions again
+    Prepare(Box<PrepareCommand>),
+    /// Write the filesystem `run` would create (the runtime, app, extensions and generated files) to a dir or
+    /// tarball, to inspect or diff it, or to use it on machines without flatpak
+    ExportRootfs(Box<ExportRootfsCommand>),
+    /// Build an OCI image of the environment `run` would create, running its command, and import it into podman
+    /// or write it as an OCI image layout
+    ExportImage(Box<ExportImageCommand>),
+    /// Write an OCI runtime bundle with the environment `run` would create, to be run by crun, runc or podman
+    ExportOci(Box<ExportOciCommand>),
+    /// List the runtime and extensions required by an app or runtime, and whether they are installed
+    Deps(DepsCommand),
+    /// Pull an app or runtime (e.g. org.freedesktop.Platform//24.08) from the remotes configured for flatpak into
+    /// flatbox's own installation in $XDG_DATA_HOME/flatbox/installation, or update it
+    Install(InstallCommand),
+    /// Check that the files of an installed app or runtime match the checksums of the OSTree commit it was
+    /// deployed from, to find tampered or corrupted deployments
+    Verify(VerifyCommand),
+    /// Show where an installed app or runtime was deployed from: its remote, commit, installed subpaths and size
+    Info(InfoCommand),
+    /// Check that the host can run sandboxes and suggest fixes for the problems found
+    Doctor,
+    /// Remove what crashed launches left behind (setup dirs, partial cache files, cgroups) and the checkouts of
+    /// bundles and unpacked images which weren't used for a while. Also done automatically once a day.
+    Gc(GcCommand),
+    /// Keep the installations indexed and launch the sandboxes of `flatbox run`, for faster repeated launches.
+    /// Listens on $XDG_RUNTIME_DIR/flatbox/daemon.sock, which `flatbox run` uses when it exists.
+    Daemon(DaemonCommand),
+    /// Provide the org.freedesktop.Flatpak service of flatpak-session-helper on the session bus, so the tools
+    /// calling `flatpak-spawn --host` (or its HostCommand method) run their commands on the host under flatbox
+    Portal,
+    /// Print a systemd user service running an app at login, e.g. `flatbox generate-unit --app org.example.App > ~/.config/systemd/user/org.example.App.service`
+    GenerateUnit(GenerateUnitCommand),
+    /// Install the launchers (.desktop files), D-Bus services and icons an app exports into $XDG_DATA_HOME,
+    /// rewritten to run the app through flatbox, so it shows up in the menus of the desktop and can be started by
+    /// D-Bus activation
+    ExportDesktop(ExportDesktopCommand),
+    /// Print the effective configuration, including the defaults of unset values
+    Config,
+    /// Print a shell completion script, e.g. `flatbox completions bash > ~/.local/share/bash-completion/completions/flatbox`
+    Completions { shell: Shell },
+    /// List the installed apps or runtimes, used by the shell completions
+    #[command(hide = true)]
+    ListInstalled { kind: RefKind },
+    /// Run a command on the host from a sandbox started with --host-commands, used by `flatbox-spawn` in the
+    /// sandbox
+    #[command(hide = true)]
+    HostSpawn(HostSpawnCommand),
 }
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum RefKind {
+    App,
+    Runtime,
+}
+
+/// Options of `flatbox run`: the sandbox and how flatbox runs it
 #[derive(Parser)]
 pub struct RunCommand {
-    /// Flatpak app id (com.example.example) to use as the environment.
+    #[command(flatten)]
+    pub sandbox: SandboxOptions,
+    /// Run in a transient systemd user scope, which shows up in `systemctl --user` with the resource usage of
+    /// the sandbox and is stopped on logout.
+    #[arg(long, default_value_t)]
+    pub systemd_scope: bool,
+    /// Terminate the sandbox when the command hasn't exited after SECS seconds: it's sent SIGTERM, and SIGKILL
+    /// 5 seconds later. flatbox exits with 124 then. The sandbox runs in its own process group, so it can't
+    /// read from the terminal, and `--new-session` can't be used, as its command would leave the group.
+    #[arg(long, value_name = "SECS", conflicts_with = "new_session")]
+    pub timeout: Option<u64>,
+    /// Run the command in a new pseudo-terminal proxied by flatbox, so interactive shells and TUI apps get a
+    /// terminal with job control and window size changes, without being able to inject input into the host's
+    /// terminal (`TIOCSTI`).
+    #[arg(long, default_value_t, conflicts_with = "new_session")]
+    pub pty: bool,
+    /// Print how long each phase of the startup took when the command exits: scanning the installations,
+    /// parsing the metadata, the extensions, starting bwrap, creating the sandbox and ldconfig
+    #[arg(long, default_value_t)]
+    pub time_report: bool,
+    /// Launch the sandbox of `flatbox prepare` saved in DIR. The app, runtime and sandbox options were applied
+    /// when preparing it, only the command (which defaults to the prepared one) and the backend are used.
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["app", "runtime"])]
+    pub plan: Option<PathBuf>,
+    /// Launch the sandbox from this process, even when `flatbox daemon` is running
+    #[arg(long, default_value_t)]
+    pub no_daemon: bool,
+}
+
+#[derive(Parser)]
+pub struct BuildCommand {
+    /// Build dir created by `flatpak build-init`, with the `metadata` of the app and its `files` and `var`
+    pub directory: PathBuf,
+    #[command(flatten)]
+    pub run: RunCommand,
+    /// Mount a host dir writable at a path of the sandbox, as `DEST=SRC`. Can be repeated.
+    #[arg(long, value_name = "DEST=SRC")]
+    pub bind_mount: Vec<BindMount>,
+    /// Dir of the sandbox to run the command in, like `--cwd`
+    #[arg(long, value_name = "DIR")]
+    pub build_dir: Option<PathBuf>,
+    /// Mount the files of the build dir read-only
+    #[arg(long, default_value_t)]
+    pub readonly: bool,
+    /// Accepted for compatibility with `flatpak build`, flatbox waits for the sandbox anyway
+    #[arg(long, default_value_t, hide = true)]
+    pub die_with_parent: bool,
+    /// Accepted for compatibility with `flatpak build`, the app's data dir is created like with `run`
+    #[arg(long, default_value_t, hide = true)]
+    pub with_appdir: bool,
+}
+
+#[derive(Parser)]
+pub struct PrepareCommand {
+    /// Directory to save the sandbox (`plan.toml` and the generated files in `data`) to
+    #[arg(long, value_name = "DIR")]
+    pub out: PathBuf,
+    #[command(flatten)]
+    pub run: RunCommand,
+}
+
+#[derive(Parser)]
+pub struct ExportOciCommand {
+    /// Directory to write the bundle (`config.json` and `rootfs`) to
+    #[arg(long, value_name = "DIR")]
+    pub out: PathBuf,
+    #[command(flatten)]
+    pub run: RunCommand,
+}
+
+#[derive(Parser)]
+pub struct ExportRootfsCommand {
+    /// Directory to write the filesystem to, or a tarball when it ends with .tar, .tar.gz, .tar.xz or .tar.zst
+    #[arg(long, value_name = "PATH")]
+    pub out: PathBuf,
+    #[command(flatten)]
+    pub run: RunCommand,
+}
+
+#[derive(Parser)]
+pub struct ExportImageCommand {
+    /// Name of the image, e.g. `example:latest`
+    #[arg(long)]
+    pub tag: String,
+    /// Directory to write the OCI image layout to, instead of importing the image into podman
+    #[arg(long, value_name = "DIR")]
+    pub out: Option<PathBuf>,
+    #[command(flatten)]
+    pub run: RunCommand,
+}
+
+#[derive(Parser)]
+pub struct DaemonCommand {
+    /// Additional Flatpak installation dirs to index (the default ones and the ones of the config are indexed too)
     #[arg(long)]
+    pub flatpak_install_path: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+pub struct DepsCommand {
+    /// Flatpak app id (com.example.example), or full or partial ref (com.example.example//beta) to check.
+    #[arg(long, required_unless_present = "runtime", conflicts_with = "runtime")]
     pub app: Option<String>,
-    /// Flatpak runtime id in its full format (org.gnome.Platform/x86_64/48) to use as the environment. Mutually exclusive with `--app`.
+    /// Flatpak runtime id (org.gnome.Platform/x86_64/48, or partial like org.gnome.Platform) to check.
     #[arg(long)]
     pub runtime: Option<String>,
-    /// Additional Flatpak installation dirs (/var/lib/flatpak and $HOME/.local/share/flatpak are used by default)
+    /// Additional Flatpak installation dirs (/var/lib/flatpak and $HOME/.local/share/flatpak, or $FLATPAK_SYSTEM_DIR and $FLATPAK_USER_DIR, are used by default)
     #[arg(long)]
     pub flatpak_install_path: Vec<PathBuf>,
-    /// When running on a system with AppArmor active, this makes sure the application runs with unconfined privileges.
-    /// It can be used to avoid applying unprivileged profiles normally intended for user Flatpak apps.
-    #[arg(long, default_value_t)]
-    pub apparmor_unconfined: bool,
-    pub command: String,
-    pub args: Vec<String>,
+    /// Only use the custom installation NAME declared in /etc/flatpak/installations.d (and the extra install dirs).
+    #[arg(long, value_name = "NAME")]
+    pub installation: Option<String>,
+    /// Architecture of the app and runtime (x86_64, i386, aarch64, ...), defaults to the host's.
+    #[arg(long)]
+    pub arch: Option<String>,
+    /// Branch of the app to use instead of the current one (e.g. stable or beta).
+    #[arg(long, requires = "app")]
+    pub branch: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct InstallCommand {
+    /// Full or partial ref to install ([app/|runtime/]id[/arch[/branch]]), which has to match a single ref of the
+    /// remotes
+    pub reference: String,
+    /// Only pull from the remote NAME
+    #[arg(long, value_name = "NAME")]
+    pub remote: Option<String>,
+    /// Architecture of the ref when not part of it (x86_64, i386, aarch64, ...), defaults to the host's.
+    #[arg(long)]
+    pub arch: Option<String>,
+    /// Additional Flatpak installation dirs whose remotes are used (the ones of /var/lib/flatpak and $HOME/.local/share/flatpak are used by default)
+    #[arg(long)]
+    pub flatpak_install_path: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+pub struct VerifyCommand {
+    /// Flatpak app id (com.example.example), or full or partial ref (com.example.example//beta) to verify.
+    #[arg(long, required_unless_present = "runtime", conflicts_with = "runtime")]
+    pub app: Option<String>,
+    /// Flatpak runtime or extension id (org.gnome.Platform/x86_64/48, or partial like org.gnome.Platform) to verify.
+    #[arg(long)]
+    pub runtime: Option<String>,
+    /// Additional Flatpak installation dirs (/var/lib/flatpak and $HOME/.local/share/flatpak, or $FLATPAK_SYSTEM_DIR and $FLATPAK_USER_DIR, are used by default)
+    #[arg(long)]
+    pub flatpak_install_path: Vec<PathBuf>,
+    /// Only use the custom installation NAME declared in /etc/flatpak/installations.d (and the extra install dirs).
+    #[arg(long, value_name = "NAME")]
+    pub installation: Option<String>,
+    /// Architecture of the app or runtime (x86_64, i386, aarch64, ...), defaults to the host's.
+    #[arg(long)]
+    pub arch: Option<String>,
+    /// Branch of the app to use instead of the current one (e.g. stable or beta).
+    #[arg(long, requires = "app")]
+    pub branch: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct InfoCommand {
+    /// Flatpak app id (com.example.example), or full or partial ref (com.example.example//beta) to show.
+    #[arg(long, required_unless_present = "runtime", conflicts_with = "runtime")]
+    pub app: Option<String>,
+    /// Flatpak runtime or extension id (org.gnome.Platform/x86_64/48, or partial like org.gnome.Platform) to show.
+    #[arg(long)]
+    pub runtime: Option<String>,
+    /// Additional Flatpak installation dirs (/var/lib/flatpak and $HOME/.local/share/flatpak, or $FLATPAK_SYSTEM_DIR and $FLATPAK_USER_DIR, are used by default)
+    #[arg(long)]
+    pub flatpak_install_path: Vec<PathBuf>,
+    /// Only use the custom installation NAME declared in /etc/flatpak/installations.d (and the extra install dirs).
+    #[arg(long, value_name = "NAME")]
+    pub installation: Option<String>,
+    /// Architecture of the app or runtime (x86_64, i386, aarch64, ...), defaults to the host's.
+    #[arg(long)]
+    pub arch: Option<String>,
+    /// Branch of the app to use instead of the current one (e.g. stable or beta).
+    #[arg(long, requires = "app")]
+    pub branch: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct GenerateUnitCommand {
+    /// Flatpak app id (com.example.example), or full or partial ref (com.example.example//beta) to run.
+    #[arg(long)]
+    pub app: String,
+    /// Options passed to `flatbox run`, given after `--` (e.g. `-- --private-tmp --gl=host`)
+    #[arg(last = true)]
+    pub run_args: Vec<String>,
+}
+
+#[derive(Parser)]
+pub struct GcCommand {
+    /// Only list what would be removed
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Days after which unused checkouts and images are removed
+    #[arg(long, value_name = "DAYS", default_value_t = 30)]
+    pub max_age: u64,
+}
+
+/// The options of `flatpak-spawn --host`
+#[derive(Parser)]
+pub struct HostSpawnCommand {
+    /// Run the command on the host, the only supported mode
+    #[arg(long)]
+    pub host: bool,
+    /// Start the command with an empty environment instead of the one of flatbox on the host
+    #[arg(long)]
+    pub clear_env: bool,
+    /// Set a variable for the command
+    #[arg(long, value_name = "VAR=VALUE")]
+    pub env: Vec<String>,
+    /// Working dir of the command, defaults to the current dir (or the home dir when it only exists in the
+    /// sandbox)
+    #[arg(long, value_name = "DIR")]
+    pub directory: Option<PathBuf>,
+    /// Pass an fd on to the command, with the same number. The standard streams are always passed.
+    #[arg(long, value_name = "FD")]
+    pub forward_fd: Vec<RawFd>,
+    /// The command and its arguments
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<OsString>,
+}
+
+#[derive(Parser)]
+pub struct ExportDesktopCommand {
+    /// Flatpak app id (com.example.example), or full or partial ref (com.example.example//beta) to export.
+    #[arg(long)]
+    pub app: String,
+    /// Additional Flatpak installation dirs (/var/lib/flatpak and $HOME/.local/share/flatpak, or $FLATPAK_SYSTEM_DIR and $FLATPAK_USER_DIR, are used by default)
+    #[arg(long)]
+    pub flatpak_install_path: Vec<PathBuf>,
+    /// Only use the custom installation NAME declared in /etc/flatpak/installations.d (and the extra install dirs).
+    #[arg(long, value_name = "NAME")]
+    pub installation: Option<String>,
+    /// Architecture of the app (x86_64, i386, aarch64, ...), defaults to the host's.
+    #[arg(long)]
+    pub arch: Option<String>,
+    /// Branch of the app to use instead of the current one (e.g. stable or beta).
+    #[arg(long)]
+    pub branch: Option<String>,
+    /// Remove the launchers and icons exported before instead
+    #[arg(long)]
+    pub remove: bool,
+    /// Options passed to `flatbox run` by the launchers, given after `--` (e.g. `-- --private-tmp --gl=host`)
+    #[arg(last = true)]
+    pub run_args: Vec<String>,
 }
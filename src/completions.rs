@@ -0,0 +1,247 @@
+use crate::args::{Args, RefKind, Shell};
+use clap::{Arg, CommandFactory};
+use std::{fmt::Write, fs, path::PathBuf};
+
+const BIN_NAME: &str = "flatbox";
+/// Options completed with the installed refs, listed by the hidden `list-installed` subcommand for the kind of the same name
+const REF_OPTIONS: [&str; 2] = ["app", "runtime"];
+
+/// Generates a completion script for `shell` from the command line definition
+pub fn generate(shell: Shell) -> String {
+    let mut cmd = Args::command();
+    cmd.build();
+    let subcommands: Vec<(&str, Vec<&Arg>, String)> = cmd
+        .get_subcommands()
+        .filter(|sub| !sub.is_hide_set())
+        .map(|sub| {
+            let options = sub.get_arguments().filter(|arg| is_option(arg)).collect();
+            let about = sub
+                .get_about()
+                .map(|about| about.to_string())
+                .unwrap_or_default();
+            (sub.get_name(), options, about)
+        })
+        .collect();
+    let global_options: Vec<&Arg> = cmd.get_arguments().filter(|arg| is_option(arg)).collect();
+
+    match shell {
+        Shell::Bash => bash_script(&subcommands, &global_options),
+        Shell::Zsh => format!(
+            "#compdef {BIN_NAME}\nautoload -U +X bashcompinit && bashcompinit\n{}",
+            bash_script(&subcommands, &global_options)
+        ),
+        Shell::Fish => fish_script(&subcommands, &global_options),
+    }
+}
+
+fn is_option(arg: &Arg) -> bool {
+    !arg.is_hide_set() && (arg.get_long().is_some() || arg.get_short().is_some())
+}
+
+fn takes_value(arg: &Arg) -> bool {
+    arg.get_num_args().is_some_and(|num| num.takes_values())
+}
+
+fn flags(arg: &Arg) -> Vec<String> {
+    arg.get_short()
+        .map(|short| format!("-{short}"))
+        .into_iter()
+        .chain(arg.get_long().map(|long| format!("--{long}")))
+        .collect()
+}
+
+fn possible_values(arg: &Arg) -> Vec<String> {
+    arg.get_possible_values()
+        .iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_owned())
+        .collect()
+}
+
+fn bash_script(subcommands: &[(&str, Vec<&Arg>, String)], global_options: &[&Arg]) -> String {
+    let mut value_cases = String::new();
+    let mut completed = Vec::new();
+    for arg in subcommands
+        .iter()
+        .flat_map(|(_, options, _)| options)
+        .chain(global_options)
+        .filter(|arg| takes_value(arg))
+    {
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        if completed.contains(&long) {
+            continue;
+        }
+        completed.push(long);
+
+        let words = if REF_OPTIONS.contains(&long) {
+            format!("$({BIN_NAME} list-installed {long} 2>/dev/null)")
+        } else {
+            possible_values(arg).join(" ")
+        };
+        if words.is_empty() {
+            // Completed by the default (file name) completion
+            writeln!(value_cases, "        --{long}) return ;;").unwrap();
+        } else {
+            writeln!(
+                value_cases,
+                "        --{long}) COMPREPLY=($(compgen -W \"{words}\" -- \"$cur\")); return ;;"
+            )
+            .unwrap();
+        }
+    }
+
+    let names: Vec<&str> = subcommands.iter().map(|(name, _, _)| *name).collect();
+    let global_flags: Vec<String> = global_options.iter().flat_map(|arg| flags(arg)).collect();
+    let mut subcommand_cases = format!(
+        "        \"\") COMPREPLY=($(compgen -W \"{} {}\" -- \"$cur\")) ;;\n",
+        names.join(" "),
+        global_flags.join(" ")
+    );
+    for (name, options, _) in subcommands {
+        let sub_flags: Vec<String> = options.iter().flat_map(|arg| flags(arg)).collect();
+        writeln!(
+            subcommand_cases,
+            "        {name}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")) ;;",
+            sub_flags.join(" ")
+        )
+        .unwrap();
+    }
+
+    format!(
+        r#"_{BIN_NAME}() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    local subcommand="" i
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case "${{COMP_WORDS[i]}}" in
+            {subcommand_names}) subcommand="${{COMP_WORDS[i]}}"; break ;;
+        esac
+    done
+
+    case "$prev" in
+{value_cases}    esac
+
+    if [[ "$cur" != -* && -n "$subcommand" ]]; then
+        return
+    fi
+    case "$subcommand" in
+{subcommand_cases}    esac
+}}
+complete -o default -F _{BIN_NAME} {BIN_NAME}
+"#,
+        subcommand_names = names.join("|"),
+    )
+}
+
+fn fish_script(subcommands: &[(&str, Vec<&Arg>, String)], global_options: &[&Arg]) -> String {
+    let mut script = String::new();
+    let names: Vec<&str> = subcommands.iter().map(|(name, _, _)| *name).collect();
+    for (name, _, about) in subcommands {
+        writeln!(
+            script,
+            "complete -c {BIN_NAME} -f -n 'not __fish_seen_subcommand_from {}' -a {name} -d {}",
+            names.join(" "),
+            fish_quote(about)
+        )
+        .unwrap();
+    }
+
+    let option_lines = global_options.iter().map(|arg| (None, *arg)).chain(
+        subcommands
+            .iter()
+            .flat_map(|(name, options, _)| options.iter().map(|arg| (Some(*name), *arg))),
+    );
+    for (subcommand, arg) in option_lines {
+        let mut line = format!("complete -c {BIN_NAME}");
+        if let Some(subcommand) = subcommand {
+            write!(line, " -n '__fish_seen_subcommand_from {subcommand}'").unwrap();
+        }
+        if let Some(short) = arg.get_short() {
+            write!(line, " -s {short}").unwrap();
+        }
+        if let Some(long) = arg.get_long() {
+            write!(line, " -l {long}").unwrap();
+        }
+        if takes_value(arg) {
+            line.push_str(" -r");
+            let values = match arg.get_long().filter(|long| REF_OPTIONS.contains(long)) {
+                Some(long) => Some(format!("({BIN_NAME} list-installed {long} 2>/dev/null)")),
+                None => Some(possible_values(arg).join(" ")).filter(|values| !values.is_empty()),
+            };
+            if let Some(values) = values {
+                write!(line, " -f -a {}", fish_quote(&values)).unwrap();
+            }
+        }
+        if let Some(help) = arg.get_help() {
+            let help = help.to_string();
+            write!(
+                line,
+                " -d {}",
+                fish_quote(help.lines().next().unwrap_or_default())
+            )
+            .unwrap();
+        }
+        script.push_str(&line);
+        script.push('\n');
+    }
+    script
+}
+
+fn fish_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// App ids or full runtime refs installed in any of the installations, for shell completion
+pub fn list_installed(kind: RefKind, install_dirs: &[PathBuf]) -> Vec<String> {
+    let mut refs = Vec::new();
+    for dir in install_dirs {
+        let entries = |path: PathBuf| {
+            fs::read_dir(path)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .filter_map(|entry| Some((entry.file_name().into_string().ok()?, entry.path())))
+        };
+        match kind {
+            RefKind::App => refs.extend(
+                entries(dir.join("app"))
+                    .filter(|(_, path)| path.join("current").exists())
+                    .map(|(name, _)| name),
+            ),
+            RefKind::Runtime => {
+                for (name, name_path) in entries(dir.join("runtime")) {
+                    for (arch, arch_path) in entries(name_path) {
+                        for (branch, branch_path) in entries(arch_path) {
+                            if branch_path.join("active").exists() {
+                                refs.push(format!("{name}/{arch}/{branch}"));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    refs.sort();
+    refs.dedup();
+    refs
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{args::Shell, completions::generate};
+
+    #[test]
+    fn dynamic_ref_completion() {
+        let bash = generate(Shell::Bash);
+        assert!(bash.contains("--app) COMPREPLY=($(compgen -W \"$(flatbox list-installed app"));
+        assert!(bash.contains("--gl) COMPREPLY=($(compgen -W \"extension host\""));
+        assert!(!bash.contains("list-installed)"));
+
+        let fish = generate(Shell::Fish);
+        assert!(
+            fish.contains("-l runtime -r -f -a '(flatbox list-installed runtime 2>/dev/null)'")
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use crate::args::RunCommand;
+use anyhow::Context;
+use clap::{ArgMatches, parser::ValueSource};
+use flatbox_core::{
+    cgroup::{CpuQuota, MemorySize},
+    options::GlMode,
+    priority::IoPriority,
+    rlimit::Rlimit,
+};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Settings applied to every invocation, read from `$XDG_CONFIG_HOME/flatbox/config.toml`.
+/// Command line flags take precedence over them.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub verbose: bool,
+    /// Searched after the default installations and the ones given with `--flatpak-install-path`
+    pub flatpak_install_path: Vec<PathBuf>,
+    pub run: RunConfig,
+}
+
+/// Defaults for `flatbox run`, set in the `[run]` table of the config or in a per-app profile
+/// (`$XDG_CONFIG_HOME/flatbox/apps/<app-id>.toml`)
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RunConfig {
+    pub no_host_root: bool,
+    pub strict_run: bool,
+    pub run_allow: Vec<String>,
+    pub share_runtime_dir: bool,
+    pub private_tmp: bool,
+    pub strict_shares: bool,
+    pub systemd_scope: bool,
+    pub memory_limit: Option<MemorySize>,
+    pub cpu_quota: Option<CpuQuota>,
+    pub pids_limit: Option<u32>,
+    /// Applied before the `--rlimit` flags
+    pub rlimit: Vec<Rlimit>,
+    pub nice: Option<i32>,
+    pub ionice: Option<IoPriority>,
+    pub oom_score_adj: Option<i32>,
+    pub gl: Option<GlMode>,
+    /// Applied before the `--env` flags
+    pub env: IndexMap<String, String>,
+    /// Filesystem permissions, applied before the `--filesystem` flags
+    pub filesystem: Vec<String>,
+    /// Command to run when none is given, instead of the one declared by the app
+    pub command: Option<String>,
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("flatbox"))
+}
+
+pub fn default_config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("config.toml"))
+}
+
+impl Config {
+    /// Reads the config file, a missing file results in the default config
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents)
+                .with_context(|| format!("Could not parse config {}", path.display())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => {
+                Err(err).with_context(|| format!("Could not read config {}", path.display()))
+            }
+        }
+    }
+}
+
+/// Reads the launch profile of an app, if there is one
+pub fn load_app_profile(app_id: &str) -> anyhow::Result<Option<RunConfig>> {
+    let Some(path) = config_dir().map(|dir| dir.join("apps").join(format!("{app_id}.toml"))) else {
+        return Ok(None);
+    };
+    match fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents)
+            .map(Some)
+            .with_context(|| format!("Could not parse app profile {}", path.display())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => {
+            Err(err).with_context(|| format!("Could not read app profile {}", path.display()))
+        }
+    }
+}
+
+impl RunConfig {
+    /// Layers a more specific config (an app profile) on top of this one
+    pub fn merge(&mut self, other: RunConfig) {
+        self.no_host_root |= other.no_host_root;
+        self.strict_run |= other.strict_run;
+        self.share_runtime_dir |= other.share_runtime_dir;
+        self.private_tmp |= other.private_tmp;
+        self.strict_shares |= other.strict_shares;
+        self.systemd_scope |= other.systemd_scope;
+        self.memory_limit = other.memory_limit.or(self.memory_limit);
+        self.cpu_quota = other.cpu_quota.or(self.cpu_quota);
+        self.pids_limit = other.pids_limit.or(self.pids_limit);
+        self.gl = other.gl.or(self.gl);
+        self.run_allow.extend(other.run_allow);
+        self.rlimit.extend(other.rlimit);
+        self.nice = other.nice.or(self.nice);
+        self.ionice = other.ionice.or(self.ionice);
+        self.oom_score_adj = other.oom_score_adj.or(self.oom_score_adj);
+        self.env.extend(other.env);
+        self.filesystem.extend(other.filesystem);
+        self.command = other.command.or(self.command.take());
+    }
+
+    /// Fills in the settings not given on the command line.
+    /// Flags which can only be turned on aren't applied when they'd conflict with ones given on the command line.
+    pub fn apply(&self, run: &mut RunCommand, matches: &ArgMatches) {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        run.systemd_scope |= self.systemd_scope;
+
+        let sandbox = &mut run.sandbox;
+
+        if self.no_host_root && !sandbox.share_runtime_dir && !sandbox.strict_run {
+            sandbox.no_host_root = true;
+        }
+        if self.strict_run && !sandbox.no_host_root {
+            sandbox.strict_run = true;
+        }
+        if self.share_runtime_dir && !sandbox.no_host_root {
+            sandbox.share_runtime_dir = true;
+        }
+        sandbox.private_tmp |= self.private_tmp;
+        sandbox.strict_shares |= self.strict_shares;
+        sandbox.memory_limit = sandbox.memory_limit.or(self.memory_limit);
+        sandbox.cpu_quota = sandbox.cpu_quota.or(self.cpu_quota);
+        sandbox.pids_limit = sandbox.pids_limit.or(self.pids_limit);
+        sandbox.nice = sandbox.nice.or(self.nice);
+        sandbox.ionice = sandbox.ionice.or(self.ionice);
+        sandbox.oom_score_adj = sandbox.oom_score_adj.or(self.oom_score_adj);
+        if let Some(gl) = self.gl
+            && !from_cli("gl")
+        {
+            sandbox.gl = gl;
+        }
+
+        if let Some(command) = &self.command
+            && sandbox.command.is_none()
+            && sandbox.command_override.is_none()
+        {
+            sandbox.command_override = Some(command.clone());
+        }
+
+        prepend(&mut sandbox.run_allow, self.run_allow.iter().cloned());
+        prepend(&mut sandbox.rlimit, self.rlimit.iter().cloned());
+        prepend(
+            &mut sandbox.env,
+            self.env
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone())),
+        );
+        prepend(&mut sandbox.filesystem, self.filesystem.iter().cloned());
+    }
+}
+
+/// Inserts config values before the command line ones, so the latter are applied last
+fn prepend<T>(values: &mut Vec<T>, defaults: impl IntoIterator<Item = T>) {
+    let cli_values = std::mem::take(values);
+    values.extend(defaults);
+    values.extend(cli_values);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, RunConfig};
+    use flatbox_core::options::GlMode;
+    use std::path::PathBuf;
+
+    #[test]
+    fn parse_config() {
+        let config: Config = toml::from_str(
+            r#"
+verbose = true
+flatpak-install-path = ["/mnt/flatpak"]
+
+[run]
+strict-run = true
+run-allow = ["media"]
+gl = "host"
+filesystem = ["~/src:ro"]
+
+[run.env]
+EDITOR = "vim"
+"#,
+        )
+        .unwrap();
+
+        assert!(config.verbose);
+        assert_eq!(config.flatpak_install_path, [PathBuf::from("/mnt/flatpak")]);
+        assert!(config.run.strict_run);
+        assert!(!config.run.no_host_root);
+        assert_eq!(config.run.gl, Some(GlMode::Host));
+        assert_eq!(
+            config.run.env.get("EDITOR").map(String::as_str),
+            Some("vim")
+        );
+        assert!(toml::from_str::<Config>("unknown = 1").is_err());
+    }
+
+    #[test]
+    fn merge_app_profile() {
+        let mut run: RunConfig = toml::from_str(
+            r#"
+strict-run = true
+filesystem = ["~/src:ro"]
+env = { EDITOR = "vim", LANG = "C.UTF-8" }
+"#,
+        )
+        .unwrap();
+        let profile: RunConfig = toml::from_str(
+            r#"
+gl = "host"
+command = "app-wrapper"
+filesystem = ["xdg-download"]
+env = { EDITOR = "nano" }
+"#,
+        )
+        .unwrap();
+        run.merge(profile);
+
+        assert!(run.strict_run);
+        assert_eq!(run.gl, Some(GlMode::Host));
+        assert_eq!(run.command.as_deref(), Some("app-wrapper"));
+        assert_eq!(run.filesystem, ["~/src:ro", "xdg-download"]);
+        assert_eq!(run.env.get("EDITOR").map(String::as_str), Some("nano"));
+        assert_eq!(run.env.get("LANG").map(String::as_str), Some("C.UTF-8"));
+    }
+}
@@ -0,0 +1,427 @@
+use anyhow::{Context, bail};
+use flatbox_core::{
+    fd_passing::{place_fds, recv_with_fds, send_with_fds},
+    index,
+};
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, DirBuilder},
+    io::{self, Read, Write},
+    mem,
+    os::{
+        fd::{AsRawFd, OwnedFd, RawFd},
+        unix::{
+            ffi::{OsStrExt, OsStringExt},
+            fs::DirBuilderExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    process::{self, ExitCode},
+    ptr,
+    sync::atomic::{AtomicI32, Ordering},
+    thread,
+};
+
+/// Socket of the daemon, in the user's runtime dir
+const SOCKET_PATH: &str = "flatbox/daemon.sock";
+/// The client's standard streams, which are passed on to the sandbox
+const STDIO_FDS: [RawFd; 3] = [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO];
+/// Signals the client receives which are forwarded to the sandbox, as it isn't in the client's process group
+const FORWARDED_SIGNALS: [libc::c_int; 4] =
+    [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGQUIT];
+/// Requests are a command line and an environment, anything larger isn't read
+pub(crate) const MAX_REQUEST_LEN: usize = 4 * 1024 * 1024;
+/// Exit code when the sandbox couldn't be launched
+const FAILURE_EXIT_CODE: u8 = 1;
+
+/// The connection to the daemon (or the host command helper), written to by the signal handler
+static SIGNAL_SOCKET: AtomicI32 = AtomicI32::new(-1);
+
+pub fn socket_path() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(&dir).join(SOCKET_PATH))
+}
+
+/// A command to run: its command line, working dir and environment, and the numbers of the fds passed along
+/// it. Sent by clients to the daemon to launch a sandbox, and by `flatbox-spawn --host` to the host command
+/// helper of the sandbox.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Request {
+    pub(crate) args: Vec<OsString>,
+    pub(crate) cwd: PathBuf,
+    /// Start from an empty environment instead of the one of the server
+    pub(crate) clear_env: bool,
+    pub(crate) env: Vec<(OsString, OsString)>,
+    pub(crate) fds: Vec<RawFd>,
+}
+
+impl Request {
+    /// The launch of this process, with its standard streams
+    fn current() -> anyhow::Result<Self> {
+        Ok(Self {
+            args: env::args_os().collect(),
+            cwd: env::current_dir().context("Could not read the working dir")?,
+            clear_env: true,
+            env: env::vars_os().collect(),
+            fds: STDIO_FDS.to_vec(),
+        })
+    }
+
+    /// Sends the request prefixed with its length, along with the fds it lists
+    pub(crate) fn send(&self, socket: &UnixStream) -> anyhow::Result<()> {
+        let encoded = self.encode();
+        if encoded.len() > MAX_REQUEST_LEN {
+            bail!("The command line and environment exceed the limit of {MAX_REQUEST_LEN} bytes");
+        }
+        let mut message = (encoded.len() as u32).to_le_bytes().to_vec();
+        message.extend(encoded);
+        send_with_fds(socket, &message, &self.fds)?;
+        Ok(())
+    }
+
+    /// Receives a request and the fds it lists, which are at most `max_fds`. Requests longer than
+    /// `MAX_REQUEST_LEN` aren't read.
+    pub(crate) fn receive(
+        stream: &mut UnixStream,
+        max_fds: usize,
+    ) -> anyhow::Result<(Self, Vec<OwnedFd>)> {
+        let mut len = [0; 4];
+        let (received, fds) =
+            recv_with_fds(stream, &mut len, max_fds).context("Could not read the request")?;
+        stream.read_exact(&mut len[received..])?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_REQUEST_LEN {
+            bail!("The request of {len} bytes exceeds the limit of {MAX_REQUEST_LEN} bytes");
+        }
+        let mut request = vec![0; len];
+        stream.read_exact(&mut request)?;
+        let request = Self::decode(&request)?;
+        if fds.len() != request.fds.len() {
+            bail!(
+                "Expected {} fds along the request, received {}",
+                request.fds.len(),
+                fds.len()
+            );
+        }
+        Ok((request, fds))
+    }
+
+    /// NUL terminated fields: the working dir, whether the environment is cleared, the number of fds, the fds,
+    /// the number of arguments, the arguments and the variables
+    fn encode(&self) -> Vec<u8> {
+        let mut fields = vec![
+            self.cwd.as_os_str().to_owned(),
+            u8::from(self.clear_env).to_string().into(),
+            self.fds.len().to_string().into(),
+        ];
+        fields.extend(self.fds.iter().map(|fd| fd.to_string().into()));
+        fields.push(self.args.len().to_string().into());
+        fields.extend(self.args.iter().cloned());
+        fields.extend(self.env.iter().map(|(key, value)| {
+            let mut var = key.clone();
+            var.push("=");
+            var.push(value);
+            var
+        }));
+        fields
+            .into_iter()
+            .flat_map(|field| field.into_vec().into_iter().chain([0]))
+            .collect()
+    }
+
+    fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut fields = bytes
+            .strip_suffix(&[0])
+            .context("Unterminated request")?
+            .split(|byte| *byte == 0)
+            .map(OsStr::from_bytes);
+        let cwd = fields.next().context("Missing working dir")?.into();
+        let clear_env = fields.next().context("Missing environment flag")? == "1";
+        let fds_len = next_number(&mut fields, "number of fds")?;
+        let fds = (0..fds_len)
+            .map(|_| next_number(&mut fields, "fd").map(|fd| fd as RawFd))
+            .collect::<anyhow::Result<_>>()?;
+        let args_len = next_number(&mut fields, "number of arguments")?;
+        let args: Vec<OsString> = fields
+            .by_ref()
+            .take(args_len)
+            .map(OsStr::to_owned)
+            .collect();
+        if args.len() != args_len || args.is_empty() {
+            bail!("Missing arguments");
+        }
+        let env = fields
+            .map(|var| {
+                let bytes = var.as_bytes();
+                let split = bytes.iter().position(|byte| *byte == b'=');
+                let split = split.with_context(|| format!("Invalid variable {}", var.display()))?;
+                Ok((
+                    OsStr::from_bytes(&bytes[..split]).to_owned(),
+                    OsStr::from_bytes(&bytes[split + 1..]).to_owned(),
+                ))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            args,
+            cwd,
+            clear_env,
+            env,
+            fds,
+        })
+    }
+}
+
+fn next_number<'a>(
+    fields: &mut impl Iterator<Item = &'a OsStr>,
+    name: &str,
+) -> anyhow::Result<usize> {
+    fields
+        .next()
+        .and_then(|field| field.to_str()?.parse().ok())
+        .with_context(|| format!("Invalid {name}"))
+}
+
+/// Lets the running daemon launch the sandbox with the command line, working dir, environment and standard
+/// streams of this process, and waits for it to exit. Returns `None` when no daemon is running.
+pub fn launch() -> anyhow::Result<Option<u8>> {
+    let Some(path) = socket_path() else {
+        return Ok(None);
+    };
+    let mut socket = match UnixStream::connect(&path) {
+        Ok(socket) => socket,
+        Err(err) => {
+            flatbox_core::debug!("Not using the daemon at {}: {err}", path.display());
+            return Ok(None);
+        }
+    };
+    flatbox_core::debug!("Launching through the daemon at {}", path.display());
+
+    Request::current()?
+        .send(&socket)
+        .context("Could not send the request to the daemon")?;
+
+    forward_signals(&socket);
+
+    let mut code = [0];
+    socket
+        .read_exact(&mut code)
+        .context("The daemon could not launch the sandbox, see its log")?;
+    Ok(Some(code[0]))
+}
+
+/// Sends the signals this process receives over the socket, for the server to deliver them to the process
+/// group it started
+pub(crate) fn forward_signals(socket: &UnixStream) {
+    SIGNAL_SOCKET.store(socket.as_raw_fd(), Ordering::Relaxed);
+    for signal in FORWARDED_SIGNALS {
+        unsafe { libc::signal(signal, forward_signal as *const () as libc::sighandler_t) };
+    }
+}
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let signal = signal as u8;
+    // SAFETY: write is async-signal-safe
+    unsafe {
+        libc::write(
+            SIGNAL_SOCKET.load(Ordering::Relaxed),
+            ptr::from_ref(&signal).cast(),
+            1,
+        )
+    };
+}
+
+/// Keeps the installations indexed and launches sandboxes for the clients connecting to the socket, each
+/// in a process forked from the daemon, so the setup starts out with the index instead of scanning the
+/// installations. The rest of the setup runs for each launch.
+pub fn serve(install_dirs: &[PathBuf]) -> anyhow::Result<ExitCode> {
+    let path =
+        socket_path().context("No user runtime dir (XDG_RUNTIME_DIR) to create the socket in")?;
+    if UnixStream::connect(&path).is_ok() {
+        bail!("A daemon is already listening on {}", path.display());
+    }
+    let dir = path.parent().context("Invalid socket path")?;
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("Could not create {}", dir.display()))?;
+    // Left behind by a daemon which didn't exit cleanly
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Could not listen on {}", path.display()))?;
+
+    index::index_installations(install_dirs);
+    // The processes handling the clients are reaped automatically
+    unsafe { libc::signal(libc::SIGCHLD, libc::SIG_IGN) };
+    flatbox_core::info!("Listening on {}", path.display());
+
+    let uid = unsafe { libc::getuid() };
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                flatbox_core::warn!("Could not accept a client: {err}");
+                continue;
+            }
+        };
+        match peer_uid(&stream) {
+            Ok(peer) if peer == uid => {}
+            Ok(peer) => {
+                flatbox_core::warn!("Refusing a client of user {peer}");
+                continue;
+            }
+            Err(err) => {
+                flatbox_core::warn!("Could not identify a client: {err}");
+                continue;
+            }
+        }
+        index::refresh(install_dirs);
+        match unsafe { libc::fork() } {
+            -1 => flatbox_core::warn!("Could not fork: {}", io::Error::last_os_error()),
+            0 => {
+                drop(listener);
+                if let Err(err) = handle_client(stream) {
+                    flatbox_core::warn!("Could not launch the sandbox: {err:#}");
+                }
+                process::exit(0);
+            }
+            _ => {}
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Launches the sandbox requested by the client in a new process group, forwards the client's signals to it
+/// and reports its exit code to the client
+fn handle_client(mut stream: UnixStream) -> anyhow::Result<()> {
+    unsafe { libc::signal(libc::SIGCHLD, libc::SIG_DFL) };
+
+    let (request, fds) = Request::receive(&mut stream, STDIO_FDS.len())?;
+    if request.fds != STDIO_FDS {
+        bail!("Expected the standard streams of the client");
+    }
+
+    let pid = match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()).context("Could not fork"),
+        0 => {
+            drop(stream);
+            let code = match enter_request(&request, fds) {
+                Ok(()) => crate::run_for_client(request.args),
+                Err(err) => {
+                    eprintln!("Error: {err:?}");
+                    FAILURE_EXIT_CODE
+                }
+            };
+            process::exit(code.into());
+        }
+        pid => pid,
+    };
+    drop(fds);
+
+    deliver_signals(&stream, pid)?;
+
+    let mut status = 0;
+    if unsafe { libc::waitpid(pid, &mut status, 0) } < 0 {
+        return Err(io::Error::last_os_error()).context("Could not wait for the sandbox");
+    }
+    let code = if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        libc::WEXITSTATUS(status)
+    };
+    stream.write_all(&[code as u8])?;
+    Ok(())
+}
+
+/// Delivers the signals forwarded by the client to the process group `pid`, and SIGHUP when the client is gone
+pub(crate) fn deliver_signals(stream: &UnixStream, pid: libc::pid_t) -> io::Result<()> {
+    let mut signals = stream.try_clone()?;
+    thread::spawn(move || {
+        let mut signal = [0];
+        loop {
+            let signal = match signals.read(&mut signal) {
+                Ok(1) => signal[0].into(),
+                // The client is gone, like a closed terminal
+                _ => libc::SIGHUP,
+            };
+            unsafe { libc::kill(-pid, signal) };
+            if signal == libc::SIGHUP {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Takes over the client's standard streams, working dir and environment, and its own process group
+fn enter_request(request: &Request, fds: Vec<OwnedFd>) -> anyhow::Result<()> {
+    let mut placed: Vec<(RawFd, RawFd)> =
+        fds.iter().map(AsRawFd::as_raw_fd).zip(STDIO_FDS).collect();
+    place_fds(&mut placed).context("Could not take over the standard streams")?;
+    drop(fds);
+    unsafe { libc::setpgid(0, 0) };
+    // SAFETY: the forked process has a single thread
+    unsafe {
+        if request.clear_env {
+            for (key, _) in env::vars_os() {
+                env::remove_var(key);
+            }
+        }
+        for (key, value) in &request.env {
+            env::set_var(key, value);
+        }
+    }
+    env::set_current_dir(&request.cwd)
+        .with_context(|| format!("Could not change to {}", request.cwd.display()))?;
+    Ok(())
+}
+
+pub(crate) fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut cred = unsafe { mem::zeroed::<libc::ucred>() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            ptr::from_mut(&mut cred).cast(),
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::daemon::Request;
+    use std::path::PathBuf;
+
+    #[test]
+    fn request_round_trip() {
+        let request = Request {
+            args: vec![
+                "flatbox".into(),
+                "run".into(),
+                "--app=org.example.App".into(),
+                "".into(),
+            ],
+            cwd: PathBuf::from("/home/user/src"),
+            clear_env: true,
+            env: vec![
+                ("HOME".into(), "/home/user".into()),
+                ("EMPTY".into(), "".into()),
+                ("EQUALS".into(), "a=b".into()),
+            ],
+            fds: vec![0, 1, 2, 5],
+        };
+        assert_eq!(Request::decode(&request.encode()).unwrap(), request);
+        assert!(Request::decode(b"/\x000\x000\x005\x00flatbox\x00").is_err());
+        assert!(Request::decode(b"/\x000\x003\x000\x001\x002\x000\x00").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use crate::args::DepsCommand;
+use anyhow::Context;
+use flatbox_core::{
+    arch,
+    extensions::{self, ExtensionContext, ExtensionPointStatus},
+    find_app_path, find_install_path, flatpak_install_dirs,
+    keyfile::parse_keyfile,
+    list_available_runtimes,
+    refs::{self, AppRef},
+};
+use std::{fs, process::ExitCode};
+
+pub fn deps(cmd: DepsCommand) -> anyhow::Result<ExitCode> {
+    let install_dirs =
+        flatpak_install_dirs(&cmd.flatpak_install_path, cmd.installation.as_deref())?;
+    let available_runtimes =
+        list_available_runtimes(&install_dirs).context("Could not list runtimes")?;
+
+    let mut missing_refs = Vec::new();
+
+    let app_ref = cmd
+        .app
+        .as_deref()
+        .map(|app| AppRef::parse(app, cmd.arch.as_deref(), cmd.branch.as_deref()))
+        .transpose()?;
+    let arch = app_ref
+        .as_ref()
+        .map_or(cmd.arch.clone(), |app_ref| app_ref.arch.clone());
+
+    let raw_app_metadata = match &app_ref {
+        Some(app_ref) => {
+            let metadata_path = find_app_path(app_ref, &install_dirs)?.join("metadata");
+            let raw = fs::read_to_string(&metadata_path).context("Could not read app metadata")?;
+            Some((metadata_path, raw))
+        }
+        None => None,
+    };
+    let app_metadata = raw_app_metadata
+        .as_ref()
+        .map(|(path, raw)| parse_keyfile(raw).map_err(|err| err.in_file(path)))
+        .transpose()?;
+
+    let runtime = match (&app_metadata, &cmd.runtime) {
+        (Some(app_metadata), _) => app_metadata
+            .get("Application")
+            .and_then(|app| app.get("runtime"))
+            .context("Could not read app runtime")?
+            .to_string(),
+        (None, Some(runtime)) => {
+            let default_arch = arch.clone().unwrap_or_else(arch::host_arch);
+            refs::resolve_runtime_ref(runtime, &default_arch, &install_dirs)?
+        }
+        (None, None) => unreachable!("Either app or runtime is required by the arguments"),
+    };
+    let runtime = match &arch {
+        Some(arch) => arch::ref_with_arch(&runtime, arch),
+        None => runtime,
+    };
+
+    let ctx = ExtensionContext::new(&runtime, &available_runtimes, &install_dirs)?;
+
+    match find_install_path(&runtime, false, &install_dirs) {
+        Some(runtime_path) => {
+            println!("Runtime {runtime}: installed");
+
+            let metadata_path = runtime_path.join("active").join("metadata");
+            let raw_runtime_metadata =
+                fs::read_to_string(&metadata_path).context("Could not read runtime metadata")?;
+            let runtime_metadata =
+                parse_keyfile(&raw_runtime_metadata).map_err(|err| err.in_file(&metadata_path))?;
+
+            let statuses = extensions::extension_points_status(&ctx, &runtime_metadata);
+            print_extensions(&statuses, &mut missing_refs);
+        }
+        None => {
+            println!("Runtime {runtime}: missing");
+            missing_refs.push(runtime.clone());
+        }
+    }
+
+    if let Some(app_metadata) = &app_metadata {
+        let statuses = extensions::extension_points_status(&ctx, app_metadata);
+        if !statuses.is_empty() {
+            println!();
+            println!("App extensions:");
+            print_extensions(&statuses, &mut missing_refs);
+        }
+    }
+
+    if missing_refs.is_empty() {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        println!();
+        println!("Missing dependencies can be installed with:");
+        for missing_ref in &missing_refs {
+            println!("  flatpak install {missing_ref}");
+        }
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+fn print_extensions(statuses: &[ExtensionPointStatus], missing_refs: &mut Vec<String>) {
+    for status in statuses {
+        let state = if !status.installed.is_empty() {
+            format!("installed ({})", status.installed.join(", "))
+        } else if status.required {
+            if let Some(suggested_ref) = &status.suggested_ref {
+                missing_refs.push(suggested_ref.clone());
+            }
+            "missing".to_owned()
+        } else {
+            "not installed (optional)".to_owned()
+        };
+        println!("  Extension {}: {state}", status.name);
+    }
+}
@@ -0,0 +1,359 @@
+use crate::args::RunCommand;
+use anyhow::{Context, bail};
+use clap::Parser;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// The launchers and D-Bus services exported by apps with their extension, relative to `$XDG_DATA_HOME` and to
+/// the `export/share` of their deployment
+const EXPORTS: [(&str, &str); 2] = [("applications", "desktop"), ("dbus-1/services", "service")];
+const ICONS_DIR: &str = "icons";
+/// Marks the launchers and services written by flatbox
+const FLATBOX_KEY: &str = "X-Flatbox";
+/// Field codes of files and URLs, which are forwarded into the sandbox between `@@` or `@@u` and `@@`
+const FILE_FIELD_CODES: [&str; 2] = ["%f", "%F"];
+const URL_FIELD_CODES: [&str; 2] = ["%u", "%U"];
+/// Characters which have to be quoted in the arguments of `Exec`
+const RESERVED_CHARS: &str = " \t\n\"'\\><~|&;$*?#()`";
+
+/// Installs the launchers of an app (its deployment's `export/share/applications`), its D-Bus services
+/// (`export/share/dbus-1/services`, so the session bus can start it on demand) and its icons into
+/// `$XDG_DATA_HOME`, with `Exec` running the app through flatbox with the given `flatbox run` options. Returns
+/// the launchers and services written.
+pub fn export_launchers(
+    app: &str,
+    app_id: &str,
+    deployment: &Path,
+    run_args: &[String],
+) -> anyhow::Result<Vec<PathBuf>> {
+    let run_args: Vec<&str> = ["run", "--app", app]
+        .into_iter()
+        .chain(run_args.iter().map(String::as_str))
+        .collect();
+    // Catches typos now rather than when the launcher is clicked
+    RunCommand::try_parse_from(&run_args)?;
+    let exe = env::current_exe().context("Could not find the flatbox executable")?;
+    let flatbox_args: Vec<String> = [exe.to_string_lossy().into_owned()]
+        .into_iter()
+        .chain(run_args.iter().map(|arg| arg.to_string()))
+        .collect();
+
+    let data_home = data_home()?;
+    let export = deployment.join("export").join("share");
+    let mut written = Vec::new();
+    for (dir, extension) in EXPORTS {
+        for file in exported_files(&export.join(dir))? {
+            if file
+                .extension()
+                .is_none_or(|file_extension| file_extension != extension)
+            {
+                continue;
+            }
+            // The session bus starts a service for the name of its file, which has to be one the app owns
+            if extension == "service" && !is_own_bus_name(&file, app_id) {
+                flatbox_core::warn!(
+                    "Not exporting {}, which isn't named after the app",
+                    file.display()
+                );
+                continue;
+            }
+            let path = export.join(dir).join(&file);
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            let contents = rewrite_launcher(&contents, &flatbox_args)
+                .with_context(|| format!("Could not rewrite {}", path.display()))?;
+            let target = data_home.join(dir).join(&file);
+            write_file(&target, contents.as_bytes())?;
+            written.push(target);
+        }
+    }
+    if written.is_empty() {
+        bail!("{app} doesn't export any launchers or D-Bus services");
+    }
+
+    for icon in exported_files(&export.join(ICONS_DIR))? {
+        let contents = fs::read(export.join(ICONS_DIR).join(&icon))?;
+        write_file(&data_home.join(ICONS_DIR).join(icon), &contents)?;
+    }
+    Ok(written)
+}
+
+/// Removes the launchers and services `export_launchers` wrote for an app, and its icons. Returns the
+/// launchers and services removed.
+pub fn remove_launchers(deployment: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let data_home = data_home()?;
+    let export = deployment.join("export").join("share");
+    let mut removed = Vec::new();
+    for (dir, _) in EXPORTS {
+        for file in exported_files(&export.join(dir))? {
+            let target = data_home.join(dir).join(&file);
+            // Files of the same name which flatbox didn't write are left alone
+            let ours = fs::read_to_string(&target).is_ok_and(|contents| {
+                contents
+                    .lines()
+                    .any(|line| line.starts_with(&format!("{FLATBOX_KEY}=")))
+            });
+            if ours {
+                fs::remove_file(&target)
+                    .with_context(|| format!("Could not remove {}", target.display()))?;
+                removed.push(target);
+            }
+        }
+    }
+    for icon in exported_files(&export.join(ICONS_DIR))? {
+        let _ = fs::remove_file(data_home.join(ICONS_DIR).join(icon));
+    }
+    Ok(removed)
+}
+
+/// Whether a D-Bus service is named after the app's id or a name below it, the names apps may export services for
+fn is_own_bus_name(service: &Path, app_id: &str) -> bool {
+    service.file_stem().is_some_and(|name| {
+        let name = name.to_string_lossy();
+        name == app_id
+            || name
+                .strip_prefix(app_id)
+                .is_some_and(|rest| rest.starts_with('.'))
+    })
+}
+
+fn data_home() -> anyhow::Result<PathBuf> {
+    flatbox_core::data_home().context("Neither XDG_DATA_HOME nor HOME is set")
+}
+
+fn write_file(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Could not create {}", parent.display()))?;
+    }
+    fs::write(path, contents).with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// The files below an export dir, relative to it. Nothing when the app doesn't export the dir.
+fn exported_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        let Ok(entries) = fs::read_dir(dir.join(&relative)) else {
+            continue;
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            // The exports are symlinks into the app's files
+            if dir.join(&path).is_dir() {
+                pending.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Rewrites the `Exec` lines of a launcher or D-Bus service to run its command through flatbox, and marks it
+/// as flatbox's
+fn rewrite_launcher(contents: &str, flatbox_args: &[String]) -> anyhow::Result<String> {
+    let mut rewritten = String::with_capacity(contents.len());
+    let mut marked = false;
+    for line in contents.lines() {
+        if let Some(exec) = line.strip_prefix("Exec=") {
+            rewritten.push_str("Exec=");
+            rewritten.push_str(&rewrite_exec(exec, flatbox_args)?);
+        } else if line.starts_with("TryExec=") {
+            // Names the command in the sandbox, which isn't on the host
+            continue;
+        } else {
+            rewritten.push_str(line);
+        }
+        rewritten.push('\n');
+        // In the main group, `[Desktop Entry]` or `[D-BUS Service]`
+        if !marked && line.starts_with('[') {
+            rewritten.push_str(&format!("{FLATBOX_KEY}=true\n"));
+            marked = true;
+        }
+    }
+    Ok(rewritten)
+}
+
+/// Rewrites a command line of `Exec` to run through flatbox. The command lines flatpak exports
+/// (`flatpak run --command=CMD APP ARGS`) have their command and arguments kept, others are run as they are.
+/// File and URL field codes are forwarded into the sandbox.
+fn rewrite_exec(exec: &str, flatbox_args: &[String]) -> anyhow::Result<String> {
+    let args = split_exec(exec)?;
+    let (command, app_args) = match flatpak_run_args(&args) {
+        Some(flatpak_run) => flatpak_run,
+        None => {
+            let (command, app_args) = args.split_first().context("Exec is empty")?;
+            (Some(command.clone()), forward_field_codes(app_args))
+        }
+    };
+
+    // The arguments of Exec keep their field codes and `%%`, those of flatbox are literal
+    let mut exec_args: Vec<String> = flatbox_args
+        .iter()
+        .map(|arg| quote_exec_arg(&arg.replace('%', "%%")))
+        .collect();
+    if let Some(command) = command {
+        exec_args.push("--command".to_owned());
+        exec_args.push(quote_exec_arg(&command));
+    }
+    if app_args.iter().any(|arg| arg.starts_with("@@")) {
+        exec_args.push("--file-forwarding".to_owned());
+    }
+    if !app_args.is_empty() {
+        exec_args.push("--".to_owned());
+        exec_args.extend(app_args.iter().map(|arg| quote_exec_arg(arg)));
+    }
+    Ok(exec_args.join(" "))
+}
+
+/// The command (if given) and the arguments of a `flatpak run` command line
+fn flatpak_run_args(args: &[String]) -> Option<(Option<String>, Vec<String>)> {
+    let program = args.first()?;
+    if Path::new(program).file_name()? != "flatpak" || args.get(1)? != "run" {
+        return None;
+    }
+    let mut command = None;
+    let mut rest = args[2..].iter();
+    // The options come before the app id
+    for arg in rest.by_ref() {
+        if let Some(value) = arg.strip_prefix("--command=") {
+            command = Some(value.to_owned());
+        } else if !arg.starts_with('-') {
+            break;
+        }
+    }
+    Some((command, rest.cloned().collect()))
+}
+
+/// Wraps bare file and URL field codes in the markers of `--file-forwarding`
+fn forward_field_codes(args: &[String]) -> Vec<String> {
+    let mut forwarded = Vec::with_capacity(args.len());
+    for arg in args {
+        if FILE_FIELD_CODES.contains(&arg.as_str()) {
+            forwarded.extend(["@@".to_owned(), arg.clone(), "@@".to_owned()]);
+        } else if URL_FIELD_CODES.contains(&arg.as_str()) {
+            forwarded.extend(["@@u".to_owned(), arg.clone(), "@@".to_owned()]);
+        } else {
+            forwarded.push(arg.clone());
+        }
+    }
+    forwarded
+}
+
+/// Splits the value of `Exec` into its arguments, undoing the escaping of desktop entry strings and the quoting
+/// of arguments
+fn split_exec(exec: &str) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = unescape_string(exec).into_iter().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                arg.get_or_insert_default();
+            }
+            '\\' if quoted => {
+                let escaped = chars.next().context("Exec ends with a backslash")?;
+                arg.get_or_insert_default().push(escaped);
+            }
+            ' ' | '\t' | '\n' if !quoted => args.extend(arg.take()),
+            c => arg.get_or_insert_default().push(c),
+        }
+    }
+    if quoted {
+        bail!("Exec has an unterminated quote");
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+/// Undoes the escape sequences of desktop entry string values
+fn unescape_string(value: &str) -> Vec<char> {
+    let mut unescaped = Vec::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => unescaped.push(' '),
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Quotes an argument of `Exec` and escapes it as a desktop entry string. Field codes and the forwarding markers
+/// are kept as they are.
+fn quote_exec_arg(arg: &str) -> String {
+    let is_field_code = arg.len() == 2 && arg.starts_with('%');
+    if is_field_code || arg == "@@" || arg == "@@u" {
+        return arg.to_owned();
+    }
+    let quoted = if arg.is_empty() || arg.chars().any(|c| RESERVED_CHARS.contains(c)) {
+        let mut quoted = String::from("\"");
+        for c in arg.chars() {
+            if matches!(c, '"' | '`' | '$' | '\\') {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted.push('"');
+        quoted
+    } else {
+        arg.to_owned()
+    };
+    quoted.replace('\\', "\\\\")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::desktop::{rewrite_exec, split_exec};
+
+    #[test]
+    fn rewrites_exec_lines() {
+        let flatbox = ["/usr/bin/flatbox", "run", "--app", "org.example.App"].map(str::to_owned);
+        assert_eq!(
+            rewrite_exec(
+                "/usr/bin/flatpak run --branch=stable --arch=x86_64 --command=example-2 \
+                 --file-forwarding org.example.App @@u %U @@",
+                &flatbox
+            )
+            .unwrap(),
+            "/usr/bin/flatbox run --app org.example.App --command example-2 --file-forwarding -- @@u %U @@"
+        );
+        assert_eq!(
+            rewrite_exec("example --new-window %f", &flatbox).unwrap(),
+            "/usr/bin/flatbox run --app org.example.App --command example --file-forwarding -- \
+             --new-window @@ %f @@"
+        );
+        let my_files = [
+            "/opt/flat box/flatbox",
+            "run",
+            "--filesystem=~/My Files",
+            "100%",
+        ]
+        .map(str::to_owned);
+        let exec = rewrite_exec("sh -c \"printf 100%% \\\\\"$1\\\\\"\"", &my_files).unwrap();
+        assert_eq!(
+            exec,
+            "\"/opt/flat box/flatbox\" run \"--filesystem=~/My Files\" 100%% --command sh -- -c \
+             \"printf 100%% \\\\\"\\\\$1\\\\\"\""
+        );
+        assert_eq!(
+            split_exec(&exec).unwrap()[4..],
+            ["--command", "sh", "--", "-c", "printf 100%% \"$1\""]
+        );
+    }
+}
@@ -0,0 +1,439 @@
+use flatbox_core::{
+    FlatboxError,
+    bwrap::BwrapBuilder,
+    environment::Environment,
+    list_available_runtimes,
+    nested::{self, OuterSandbox},
+    nvidia,
+    sockets::{self, RuntimeDir},
+    system,
+};
+use std::{
+    env, fs,
+    io::{self, ErrorKind},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, ExitCode, Output},
+};
+
+const BWRAP_NOT_INSTALLED_HINT: &str =
+    "Install bubblewrap with your package manager (usually the `bubblewrap` package)";
+
+const SYSTEM_BUS_SOCKET: &str = "/run/dbus/system_bus_socket";
+const GL_EXTENSION_PREFIX: &str = "org.freedesktop.Platform.GL";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warning,
+    Failure,
+}
+
+struct Check {
+    status: Status,
+    message: String,
+    fix: Option<String>,
+}
+
+impl Check {
+    fn ok(message: impl Into<String>) -> Self {
+        Self {
+            status: Status::Ok,
+            message: message.into(),
+            fix: None,
+        }
+    }
+
+    fn warning(message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            status: Status::Warning,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn failure(message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            status: Status::Failure,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+/// Checks whether the host can run flatbox sandboxes and prints how to fix the problems found
+pub fn doctor(install_dirs: &[PathBuf]) -> ExitCode {
+    let mut checks = Vec::new();
+    check_nesting(&mut checks);
+    check_system_mode(&mut checks);
+    let bwrap_setuid = check_bwrap(&mut checks);
+    check_user_namespaces(&mut checks, bwrap_setuid);
+    check_install_dirs(&mut checks, install_dirs);
+    check_sockets(&mut checks);
+    check_gl_extensions(&mut checks, install_dirs);
+
+    for check in &checks {
+        let label = match check.status {
+            Status::Ok => "ok",
+            Status::Warning => "warning",
+            Status::Failure => "FAILED",
+        };
+        println!("[{label}] {}", check.message);
+        if let Some(fix) = &check.fix {
+            println!("    {fix}");
+        }
+    }
+
+    if checks.iter().any(|check| check.status == Status::Failure) {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Returns whether bwrap is installed setuid, in which case it doesn't need unprivileged user namespaces
+fn check_bwrap(checks: &mut Vec<Check>) -> bool {
+    match probe_bwrap() {
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            checks.push(Check::failure(
+                "bwrap is not installed",
+                BWRAP_NOT_INSTALLED_HINT,
+            ));
+            return false;
+        }
+        Err(err) => checks.push(Check::failure(
+            format!("bwrap could not be started: {err}"),
+            "Make sure the bwrap in your PATH is executable",
+        )),
+        Ok(output) if output.status.success() => {
+            checks.push(Check::ok("bwrap can create a sandbox"));
+        }
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            checks.push(Check::failure(
+                format!("bwrap failed to create a sandbox: {}", stderr.trim()),
+                bwrap_failure_hint(&stderr, apparmor_restricts_userns()),
+            ));
+        }
+    }
+
+    find_in_path("bwrap")
+        .and_then(|path| fs::metadata(path).ok())
+        .is_some_and(|metadata| metadata.permissions().mode() & 0o4000 != 0)
+}
+
+/// Runs a minimal sandbox, to tell failures of bwrap itself apart from the ones of the sandboxed command
+pub fn probe_bwrap() -> io::Result<Output> {
+    Command::new("bwrap")
+        .args([
+            "--ro-bind",
+            "/",
+            "/",
+            "--dev",
+            "/dev",
+            "--proc",
+            "/proc",
+            "true",
+        ])
+        .output()
+}
+
+/// Suggests a fix for the common reasons of bwrap failing to create a sandbox, based on its error output
+pub fn bwrap_failure_hint(stderr: &str, apparmor_restricted: bool) -> &'static str {
+    let denied = stderr.contains("Permission denied") || stderr.contains("Operation not permitted");
+    if apparmor_restricted && denied {
+        "AppArmor restricts unprivileged user namespaces: retry with `--apparmor-unconfined`, or install an AppArmor profile allowing `userns` for bwrap"
+    } else if stderr.contains("setting up uid map")
+        || stderr.contains("No permissions to creat")
+        || stderr.contains("Creating new namespace failed")
+    {
+        "Unprivileged user namespaces are disabled: enable them with `sysctl kernel.unprivileged_userns_clone=1` and `sysctl user.max_user_namespaces=15000`, or install bwrap setuid"
+    } else {
+        "Run `flatbox doctor` to check the host setup"
+    }
+}
+
+/// Suggests what to do about an error of flatbox-core
+pub fn error_hint(err: &FlatboxError) -> Option<String> {
+    match err {
+        FlatboxError::AppNotFound { app, .. } => Some(format!(
+            "Install it with `flatpak install {app}`, or pass the installation it's in with `--flatpak-install-path`"
+        )),
+        FlatboxError::RuntimeNotFound { runtime, .. } => Some(format!(
+            "Install it with `flatpak install {runtime}`, or pass the installation it's in with `--flatpak-install-path`"
+        )),
+        FlatboxError::ExtensionSetup { .. } => Some(
+            "Run `flatbox deps` to list the extensions and whether they're installed".to_owned(),
+        ),
+        FlatboxError::BwrapSpawn { program, source } if source.kind() == ErrorKind::NotFound => {
+            Some(match program.as_str() {
+                "bwrap" => BWRAP_NOT_INSTALLED_HINT.to_owned(),
+                "aa-exec" => {
+                    "Install the AppArmor utilities or run without `--apparmor-unconfined`"
+                        .to_owned()
+                }
+                _ => format!("Install {program} or use another `--backend`"),
+            })
+        }
+        FlatboxError::NestedSandbox { outer } => Some(nested_sandbox_hint(outer).to_owned()),
+        FlatboxError::RemoteNotFound { name: None } => {
+            Some("Add one with `flatpak remote-add`".to_owned())
+        }
+        FlatboxError::ToolNotFound { tool } => Some(format!(
+            "Install {tool} on the host, or use an SDK which has it"
+        )),
+        FlatboxError::HostRootRequired { .. } => Some("Run without `--no-host-root`".to_owned()),
+        FlatboxError::ExtensionPointNotFound { .. } => {
+            Some("Install the runtime it extends, or mount it with `--extension-image`".to_owned())
+        }
+        FlatboxError::AmbiguousChoice { .. } => {
+            Some("Pass the full ref, or run it on a terminal to choose".to_owned())
+        }
+        FlatboxError::RootCgroup
+        | FlatboxError::CgroupControllerDisabled { .. }
+        | FlatboxError::CgroupV2Required => {
+            Some("Use `--systemd-scope` to apply the resource limits".to_owned())
+        }
+        _ => None,
+    }
+}
+
+fn nested_sandbox_hint(outer: &OuterSandbox) -> &'static str {
+    match outer {
+        OuterSandbox::Flatpak(_) => {
+            "Run flatbox on the host with `flatpak-spawn --host flatbox ...`, which needs the app to be allowed to talk to org.freedesktop.Flatpak"
+        }
+        OuterSandbox::Flatbox => {
+            "Run flatbox on the host with `flatbox-spawn --host flatbox ...` in a sandbox started with `--host-commands`, or allow unprivileged user namespaces on the host"
+        }
+    }
+}
+
+/// Sandboxes inside of sandboxes need a new user namespace
+fn check_nesting(checks: &mut Vec<Check>) {
+    let Some(outer) = nested::outer_sandbox() else {
+        return;
+    };
+    if nested::can_create_user_namespace() {
+        checks.push(Check::ok(format!(
+            "Running inside of {outer}, which allows nested sandboxes"
+        )));
+    } else {
+        checks.push(Check::failure(
+            format!("Running inside of {outer}, which doesn't allow nested sandboxes"),
+            nested_sandbox_hint(&outer),
+        ));
+    }
+}
+
+fn check_system_mode(checks: &mut Vec<Check>) {
+    if system::is_system_mode() {
+        checks.push(Check::ok(format!(
+            "Running as root, the state of flatbox and the apps is kept in {}",
+            system::state_dir().display()
+        )));
+    }
+}
+
+fn read_sysctl(path: &str) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_owned())
+}
+
+pub fn apparmor_restricts_userns() -> bool {
+    read_sysctl("/proc/sys/kernel/apparmor_restrict_unprivileged_userns").as_deref() == Some("1")
+}
+
+fn check_user_namespaces(checks: &mut Vec<Check>, bwrap_setuid: bool) {
+    // A setuid bwrap creates the namespaces itself
+    let disabled = |message: &str, fix: &str| {
+        let mut check = Check::failure(message, fix);
+        if bwrap_setuid {
+            check.status = Status::Warning;
+        }
+        check
+    };
+    let mut enabled = true;
+
+    if read_sysctl("/proc/sys/kernel/unprivileged_userns_clone").as_deref() == Some("0") {
+        enabled = false;
+        checks.push(disabled(
+            "Unprivileged user namespaces are disabled (kernel.unprivileged_userns_clone = 0)",
+            "Enable them with `sysctl kernel.unprivileged_userns_clone=1`",
+        ));
+    }
+    if read_sysctl("/proc/sys/user/max_user_namespaces").as_deref() == Some("0") {
+        enabled = false;
+        checks.push(disabled(
+            "User namespaces are disabled (user.max_user_namespaces = 0)",
+            "Enable them with `sysctl user.max_user_namespaces=15000`",
+        ));
+    }
+    if apparmor_restricts_userns() {
+        checks.push(Check::warning(
+            "AppArmor restricts unprivileged user namespaces",
+            "Use `flatbox run --apparmor-unconfined`, or install an AppArmor profile allowing `userns` for bwrap",
+        ));
+    }
+
+    if enabled {
+        checks.push(Check::ok("Unprivileged user namespaces are enabled"));
+    }
+}
+
+fn check_install_dirs(checks: &mut Vec<Check>, install_dirs: &[PathBuf]) {
+    let mut any_readable = false;
+    for dir in install_dirs {
+        match fs::read_dir(dir) {
+            Ok(_) => {
+                any_readable = true;
+                checks.push(Check::ok(format!(
+                    "Installation {} is readable",
+                    dir.display()
+                )));
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => checks.push(Check::failure(
+                format!("Installation {} is not readable: {err}", dir.display()),
+                "Check the permissions of the installation dir",
+            )),
+        }
+    }
+    if !any_readable {
+        checks.push(Check::failure(
+            "No flatpak installation found",
+            "Install an app or runtime with flatpak, or pass its installation with --flatpak-install-path",
+        ));
+    }
+}
+
+fn check_sockets(checks: &mut Vec<Check>) {
+    let runtime_dir = match RuntimeDir::new(false) {
+        Ok(runtime_dir) if runtime_dir.host.is_dir() => runtime_dir,
+        // System services have no session to share
+        _ if system::is_system_mode() => {
+            checks.push(Check::ok(
+                "No user runtime dir, sandboxes get no session sockets",
+            ));
+            return;
+        }
+        _ => {
+            checks.push(Check::warning(
+                "No user runtime dir (XDG_RUNTIME_DIR), so no session sockets can be exposed",
+                "Run flatbox from a logged in user session, or set XDG_RUNTIME_DIR",
+            ));
+            return;
+        }
+    };
+    // The setup functions are only used to find the sockets, what they generate is discarded
+    let mut bwrap = BwrapBuilder::new();
+    let mut environment = Environment::default();
+
+    let wayland = sockets::setup_wayland(&mut bwrap, &mut environment, &runtime_dir);
+    let x11 = env::var_os("DISPLAY").is_some_and(|display| !display.is_empty());
+    checks.push(match (wayland, x11) {
+        (true, _) => Check::ok("Wayland socket found"),
+        (false, true) => Check::ok("X11 display found"),
+        (false, false) => Check::warning(
+            "Neither a Wayland socket nor an X11 display was found, graphical apps won't start",
+            "Run flatbox from a graphical session, or set WAYLAND_DISPLAY or DISPLAY",
+        ),
+    });
+
+    let pulseaudio =
+        sockets::setup_pulseaudio(&mut bwrap, &mut environment, &runtime_dir).unwrap_or(false);
+    let pipewire = sockets::setup_pipewire(&mut bwrap, &mut environment, &runtime_dir);
+    checks.push(if pulseaudio || pipewire {
+        Check::ok("Sound server socket found")
+    } else {
+        Check::warning(
+            "No PulseAudio or PipeWire socket found, apps will fall back to ALSA",
+            "Start pipewire-pulse or pulseaudio in your session for shared sound output",
+        )
+    });
+
+    let session_bus = match env::var("DBUS_SESSION_BUS_ADDRESS") {
+        Ok(address) if !address.is_empty() => true,
+        _ => sockets::is_socket(&runtime_dir.host.join("bus")),
+    };
+    checks.push(if session_bus {
+        Check::ok("Session bus found")
+    } else {
+        Check::warning(
+            "No D-Bus session bus found, portals and desktop integration won't work",
+            "Run flatbox from a desktop session, or start one with `dbus-run-session`",
+        )
+    });
+
+    checks.push(if sockets::is_socket(Path::new(SYSTEM_BUS_SOCKET)) {
+        Check::ok("System bus found")
+    } else {
+        Check::warning(
+            format!("No D-Bus system bus at {SYSTEM_BUS_SOCKET}"),
+            "Start the system's dbus service",
+        )
+    });
+}
+
+fn check_gl_extensions(checks: &mut Vec<Check>, install_dirs: &[PathBuf]) {
+    let Ok(runtimes) = list_available_runtimes(install_dirs) else {
+        return;
+    };
+
+    // GL extensions of runtimes other than the freedesktop one are named with the same suffixes
+    let is_installed = |name: &str| {
+        runtimes
+            .iter()
+            .any(|runtime| runtime.ends_with(&format!(".GL.{name}")))
+    };
+
+    if let Some(version) = nvidia::driver_version() {
+        let extension = format!("nvidia-{}", version.replace('.', "-"));
+        if is_installed(&extension) {
+            checks.push(Check::ok(format!(
+                "NVIDIA driver {version} has a matching GL extension"
+            )));
+        } else {
+            checks.push(Check::failure(
+                format!("No GL extension matches the loaded NVIDIA driver {version}"),
+                format!(
+                    "Run `flatpak install flathub {GL_EXTENSION_PREFIX}.{extension}`, or use `flatbox run --gl=host`"
+                ),
+            ));
+        }
+    } else if is_installed("default") {
+        checks.push(Check::ok("Mesa GL extension is installed"));
+    } else {
+        checks.push(Check::warning(
+            "No Mesa GL extension is installed",
+            format!("Run `flatpak install flathub {GL_EXTENSION_PREFIX}.default`, or use `flatbox run --gl=host`"),
+        ));
+    }
+}
+
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::doctor::bwrap_failure_hint;
+
+    #[test]
+    fn bwrap_failure_hints() {
+        let uid_map = "bwrap: setting up uid map: Permission denied\n";
+        assert!(bwrap_failure_hint(uid_map, true).contains("--apparmor-unconfined"));
+        assert!(bwrap_failure_hint(uid_map, false).contains("unprivileged_userns_clone"));
+        assert!(
+            bwrap_failure_hint(
+                "bwrap: No permissions to creating new namespace, likely because the kernel does not allow non-privileged user namespaces.",
+                false
+            )
+            .contains("user namespaces are disabled")
+        );
+        assert!(bwrap_failure_hint("bwrap: Can't find source path /x", false).contains("doctor"));
+    }
+}
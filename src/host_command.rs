@@ -0,0 +1,180 @@
+use crate::{
+    args::HostSpawnCommand,
+    daemon::{Request, deliver_signals, forward_signals, peer_uid},
+};
+use anyhow::{Context, bail};
+use flatbox_core::{fd_passing::place_fds, host_command::SOCKET_ENV};
+use std::{
+    env,
+    fs::File,
+    io::{Read, Write},
+    os::{
+        fd::{AsRawFd, OwnedFd, RawFd},
+        unix::{
+            net::{UnixListener, UnixStream},
+            process::{CommandExt, ExitStatusExt},
+        },
+    },
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread,
+};
+
+/// The standard streams, always passed to the command
+const STDIO_FDS: [RawFd; 3] = [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO];
+/// The most fds passed with `--forward-fd`
+const MAX_FORWARDED_FDS: usize = 16;
+/// Exit code when the command couldn't be started, like in shells
+const NOT_STARTED_EXIT_CODE: u8 = 127;
+
+/// Runs a command on the host through the helper of the sandbox, passing on the standard streams and the
+/// forwarded fds, and waits for it to exit. Used by `flatbox-spawn --host` in the sandbox.
+pub fn spawn(cmd: HostSpawnCommand) -> anyhow::Result<u8> {
+    if !cmd.host {
+        bail!("Only commands on the host (--host) can be run");
+    }
+    let path = env::var_os(SOCKET_ENV).with_context(|| {
+        format!("{SOCKET_ENV} isn't set, the sandbox has to be started with --host-commands")
+    })?;
+    let mut socket = UnixStream::connect(&path).with_context(|| {
+        format!(
+            "Could not connect to the host command helper at {}",
+            Path::new(&path).display()
+        )
+    })?;
+
+    let cwd = match cmd.directory {
+        Some(directory) => directory,
+        None => env::current_dir().context("Could not read the working dir")?,
+    };
+    let env = cmd
+        .env
+        .iter()
+        .map(|var| {
+            let (key, value) = var
+                .split_once('=')
+                .with_context(|| format!("Expected VAR=VALUE, found '{var}'"))?;
+            Ok((key.into(), value.into()))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let mut fds = STDIO_FDS.to_vec();
+    for fd in cmd.forward_fd {
+        if !fds.contains(&fd) {
+            fds.push(fd);
+        }
+    }
+    if fds.len() > STDIO_FDS.len() + MAX_FORWARDED_FDS {
+        bail!("At most {MAX_FORWARDED_FDS} fds can be forwarded");
+    }
+    let request = Request {
+        args: cmd.command,
+        cwd,
+        clear_env: cmd.clear_env,
+        env,
+        fds,
+    };
+    request
+        .send(&socket)
+        .context("Could not send the command to the host")?;
+    forward_signals(&socket);
+
+    let mut code = [0];
+    socket
+        .read_exact(&mut code)
+        .context("The host command helper exited")?;
+    Ok(code[0])
+}
+
+/// Runs the commands requested by the sandbox on the host, each in a thread, for as long as flatbox runs
+pub fn serve(listener: UnixListener) {
+    let uid = unsafe { libc::getuid() };
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    flatbox_core::warn!("Could not accept a host command: {err}");
+                    continue;
+                }
+            };
+            if !peer_uid(&stream).is_ok_and(|peer| peer == uid) {
+                flatbox_core::warn!("Refusing a host command of another user");
+                continue;
+            }
+            thread::spawn(move || {
+                if let Err(err) = handle_request(stream) {
+                    flatbox_core::warn!("Could not run a host command: {err:#}");
+                }
+            });
+        }
+    });
+}
+
+fn handle_request(mut stream: UnixStream) -> anyhow::Result<()> {
+    let (request, fds) = Request::receive(&mut stream, STDIO_FDS.len() + MAX_FORWARDED_FDS)?;
+    if !request.fds.starts_with(&STDIO_FDS) {
+        bail!("Expected the standard streams of the command");
+    }
+    flatbox_core::info!("Running {:?} on the host", request.args);
+
+    let stderr = fds[libc::STDERR_FILENO as usize].try_clone()?;
+    let code = match start(&request, fds) {
+        Ok(mut child) => {
+            deliver_signals(&stream, child.id() as libc::pid_t)?;
+            let status = child.wait()?;
+            match (status.code(), status.signal()) {
+                (Some(code), _) => code as u8,
+                (None, Some(signal)) => (128 + signal) as u8,
+                (None, None) => NOT_STARTED_EXIT_CODE,
+            }
+        }
+        Err(err) => {
+            let _ = writeln!(File::from(stderr), "flatbox-spawn: {err:#}");
+            NOT_STARTED_EXIT_CODE
+        }
+    };
+    stream.write_all(&[code])?;
+    Ok(())
+}
+
+/// Starts the command in its own process group with the fds of the sandbox at their numbers
+fn start(request: &Request, mut fds: Vec<OwnedFd>) -> anyhow::Result<Child> {
+    let extra_fds: Vec<(OwnedFd, RawFd)> = fds
+        .drain(STDIO_FDS.len()..)
+        .zip(request.fds[STDIO_FDS.len()..].iter().copied())
+        .collect();
+    let [stdin, stdout, stderr]: [OwnedFd; 3] = fds
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Expected the standard streams"))?;
+
+    let mut command = Command::new(&request.args[0]);
+    command
+        .args(&request.args[1..])
+        .stdin(Stdio::from(stdin))
+        .stdout(Stdio::from(stdout))
+        .stderr(Stdio::from(stderr))
+        .process_group(0);
+    if request.clear_env {
+        command.env_clear();
+    }
+    command.envs(request.env.iter().map(|(key, value)| (key, value)));
+    // The sandbox's working dir may only exist in the sandbox
+    if request.cwd.is_dir() {
+        command.current_dir(&request.cwd);
+    } else if let Some(home) = env::var_os("HOME") {
+        command.current_dir(home);
+    }
+    let mut raw_fds: Vec<(RawFd, RawFd)> = extra_fds
+        .iter()
+        .map(|(fd, target)| (fd.as_raw_fd(), *target))
+        .collect();
+    // SAFETY: only makes syscalls in the forked child
+    unsafe {
+        command.pre_exec(move || place_fds(&mut raw_fds));
+    }
+    let child = command
+        .spawn()
+        .with_context(|| format!("Could not start {}", request.args[0].display()))?;
+    drop(extra_fds);
+    Ok(child)
+}
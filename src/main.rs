@@ -1,591 +1,664 @@
 mod args;
-mod bwrap;
-mod keyfile;
-
-use crate::keyfile::parse_keyfile;
-use anyhow::{Context, anyhow, bail};
-use args::{Args, RunCommand};
-use bwrap::BwrapBuilder;
-use clap::Parser;
-use indexmap::IndexMap;
+mod completions;
+mod config;
+mod daemon;
+mod deps;
+mod desktop;
+mod doctor;
+mod host_command;
+mod portal;
+mod systemd;
+mod time_report;
+
+use anyhow::{Context, bail};
+use args::{
+    Args, BuildCommand, ExportDesktopCommand, ExportImageCommand, ExportOciCommand,
+    ExportRootfsCommand, InfoCommand, InstallCommand, PrepareCommand, RunCommand, VerifyCommand,
+};
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use config::Config;
+use flatbox_core::{
+    FlatboxError, RunOptions, Sandbox, SandboxBuilder, arch,
+    build::BuildDir,
+    cgroup, coredump, deploy, find_app_path, find_install_path, flatpak_install_dirs, gc,
+    image::{self, ImageConfig},
+    install,
+    logging::{self, Filter, Level},
+    oci,
+    options::{Backend, SandboxOptions},
+    prepared,
+    refs::{self, AppRef},
+    rootfs, verify,
+};
 use std::{
-    collections::HashSet,
-    env, fs,
+    env,
+    ffi::OsString,
+    fs,
+    os::unix::net::UnixListener,
     path::{Path, PathBuf},
-    process::{ExitCode, Stdio},
+    process::ExitCode,
+    time::{Duration, SystemTime},
 };
 
-const DEFAULT_INSTALL_PATH: &str = "/var/lib/flatpak";
-const ROOT_USR_MERGED_DIRS: [&str; 5] = ["bin", "lib", "lib32", "lib64", "sbin"];
-const FORBIDDEN_HOST_ROOT_DIRS: [&str; 5] = ["app", "usr", "run", "etc", "var"];
-const FORBIDDEN_RUN_DIRS: [&str; 2] = ["flatpak", "host"];
-const EXPOSED_ETC_PATHS: [&str; 3] = ["passwd", "group", "shadow"];
-const EXTENSION_PREFIX: &str = "Extension ";
-const PATH_BINDINDGS: [(&str, &str, bool); 6] = [
-    ("/", "/run/host/root", true),
-    ("/usr/share/fonts", "/run/host/fonts", false),
-    ("/usr/lib/fontconfig/cache", "/run/host/fonts-cache", false),
-    ("/usr/share/icons", "/run/host/share/icons", false),
-    ("/etc/machine-id", "/etc/machine-id", false),
-    (
-        "/var/lib/dbus/machine-id",
-        "/var/lib/dbus/machine-id",
-        false,
-    ),
-];
-const DEFAULT_ENV: [(&str, Option<&str>); 44] = [
-    ("FLATBOX_ENV", Some("1")),
-    ("PATH", Some("/app/bin:/usr/bin")),
-    ("LD_LIBRARY_PATH", None),
-    ("LD_PRELOAD", None),
-    ("LD_AUDIT", None),
-    ("XDG_CONFIG_DIRS", Some("/app/etc/xdg:/etc/xdg")),
-    ("XDG_DATA_DIRS", Some("/app/share:/usr/share")),
-    ("SHELL", Some("/bin/sh")),
-    ("TEMP", None),
-    ("TEMPDIR", None),
-    ("TMP", None),
-    ("TMPDIR", None),
-    ("container", None),
-    ("TZDIR", None),
-    ("PYTHONPATH", None),
-    ("PYTHONPYCACHEPREFIX", None),
-    ("PERLLIB", None),
-    ("PERL5LIB", None),
-    ("XCURSOR_PATH", None),
-    ("GST_PLUGIN_PATH_1_0", None),
-    ("GST_REGISTRY", None),
-    ("GST_REGISTRY_1_0", None),
-    ("GST_PLUGIN_PATH", None),
-    ("GST_PLUGIN_SYSTEM_PATH", None),
-    ("GST_PLUGIN_SCANNER", None),
-    ("GST_PLUGIN_SCANNER_1_0", None),
-    ("GST_PLUGIN_SYSTEM_PATH_1_0", None),
-    ("GST_PRESET_PATH", None),
-    ("GST_PTP_HELPER", None),
-    ("GST_PTP_HELPER_1_0", None),
-    ("GST_INSTALL_PLUGINS_HELPER", None),
-    ("KRB5CCNAME", None),
-    ("XKB_CONFIG_ROOT", None),
-    ("GIO_EXTRA_MODULES", None),
-    ("GDK_BACKEND", None),
-    ("VK_ADD_DRIVER_FILES", None),
-    ("VK_ADD_LAYER_PATH", None),
-    ("VK_DRIVER_FILES", None),
-    ("VK_ICD_FILENAMES", None),
-    ("VK_LAYER_PATH", None),
-    ("__EGL_EXTERNAL_PLATFORM_CONFIG_DIRS", None),
-    ("__EGL_EXTERNAL_PLATFORM_CONFIG_FILENAMES", None),
-    ("__EGL_VENDOR_LIBRARY_DIRS", None),
-    ("__EGL_VENDOR_LIBRARY_FILENAMES", None),
-];
-
-fn main() -> anyhow::Result<ExitCode> {
-    let args = Args::try_parse()?;
-
-    match args.command {
-        args::Command::Run(cmd) => run(cmd, args.verbose),
+/// Exit code when the command is killed by `--timeout`, the same as the one of coreutils' `timeout`
+const TIMEOUT_EXIT_CODE: u8 = 124;
+/// What `flatbox build` runs without a command
+const BUILD_SHELL: &str = "/bin/sh";
+
+fn main() -> ExitCode {
+    match try_main() {
+        Ok(code) => code,
+        Err(err) => {
+            report_error(&err);
+            ExitCode::FAILURE
+        }
     }
 }
 
-fn run(run: RunCommand, verbose: bool) -> anyhow::Result<ExitCode> {
-    let user_install_dir = env::var("HOME")
-        .ok()
-        .map(|home| {
-            Path::new(&home)
-                .join(".local")
-                .join("share")
-                .join("flatpak")
-        })
-        .filter(|path| path.exists());
-
-    let install_dirs: Vec<PathBuf> = [PathBuf::from(DEFAULT_INSTALL_PATH)]
-        .into_iter()
-        .chain(user_install_dir)
-        .chain(run.flatpak_install_path)
-        .collect();
-
-    let available_runtimes =
-        list_available_runtimes(&install_dirs).context("Could not list runtimes")?;
-
-    let raw_app_metadata: Option<String>;
-    let (runtime, app_files_path, app_metadata) = match (&run.app, run.runtime) {
-        (Some(app), None) => {
-            let app_path = find_install_path(app, true, &install_dirs)
-                .context("Could not find app install dir")?
-                .join("current")
-                .join("active");
-            let app_metadata_path = app_path.join("metadata");
-
-            raw_app_metadata =
-                Some(fs::read_to_string(app_metadata_path).context("Could not read app metadata")?);
-            let app_metadata = parse_keyfile(raw_app_metadata.as_ref().unwrap())
-                .context("Could not parse app metadata")?;
+fn report_error(err: &anyhow::Error) {
+    eprintln!("Error: {err:?}");
+    if let Some(hint) = err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<FlatboxError>())
+        .and_then(doctor::error_hint)
+    {
+        eprintln!("\n{hint}");
+    }
+}
 
-            let app_runtime = app_metadata
-                .get("Application")
-                .and_then(|app| app.get("runtime"))
-                .context("Could not read app runtime")?
-                .to_string();
+/// The parsed command line and the config it selects
+struct Invocation {
+    args: Args,
+    matches: ArgMatches,
+    config: Config,
+    config_path: Option<PathBuf>,
+}
 
-            let app_files_path = app_path.join("files");
+/// Parses the command line and loads the config, and sets up the logging they ask for
+fn parse_invocation(argv: impl IntoIterator<Item = OsString>) -> anyhow::Result<Invocation> {
+    let matches = Args::command().try_get_matches_from(argv)?;
+    let args = Args::from_arg_matches(&matches)?;
 
-            (app_runtime, Some(app_files_path), Some(app_metadata))
-        }
-        (None, Some(runtime)) => (runtime, None, None),
-        (Some(_), Some(_)) => bail!("Only app or runtime flags can be used at once"),
-        (None, None) => bail!("Either app or runtime has to be specified"),
+    let config_path = args.config.clone().or_else(config::default_config_path);
+    let config = match &config_path {
+        Some(path) if !args.no_config => Config::load(path)?,
+        _ => Config::default(),
     };
+    let verbosity = args.verbose.max(config.verbose as u8);
+    let filter = env::var(logging::LOG_ENV)
+        .ok()
+        .and_then(|spec| {
+            spec.parse::<Filter>()
+                .inspect_err(|err| eprintln!("Ignoring {}: {err}", logging::LOG_ENV))
+                .ok()
+        })
+        .unwrap_or_else(|| Filter::new(Level::from_verbosity(verbosity)));
+    logging::init(filter, args.log_format);
+
+    Ok(Invocation {
+        args,
+        matches,
+        config,
+        config_path,
+    })
+}
 
-    let runtime_path = find_install_path(&runtime, false, &install_dirs)
-        .context("Could not find runtime install dir")?
-        .join("active");
-    let runtime_metadata_path = runtime_path.join("metadata");
-
-    let raw_runtime_metadata =
-        fs::read_to_string(runtime_metadata_path).context("Could not read runtime metadata")?;
-    let runtime_metadata =
-        parse_keyfile(&raw_runtime_metadata).context("Could not parse runtime metadata")?;
-
-    let runtime_env = runtime_metadata
-        .get("Environment")
-        .cloned()
-        .unwrap_or_default();
-
-    let runtime_files_path = runtime_path.join("files");
-
-    let mut bwrap = BwrapBuilder::new();
-
-    setup_runtime(&mut bwrap, &runtime_files_path, app_files_path.as_deref())?;
-
-    setup_host_root_dirs(&mut bwrap)?;
-
-    setup_runtime_extensions(
-        &mut bwrap,
-        &runtime_metadata,
-        &available_runtimes,
-        &install_dirs,
-    )?;
+fn try_main() -> anyhow::Result<ExitCode> {
+    let Invocation {
+        args,
+        matches,
+        config,
+        config_path,
+    } = parse_invocation(env::args_os())?;
 
-    if let Some(ref app_meta) = app_metadata {
-        setup_app_extensions(
-            &mut bwrap,
-            app_meta,
-            &runtime,
-            &available_runtimes,
-            &install_dirs,
-        )?;
+    match args.command {
+        args::Command::Run(mut cmd) => {
+            apply_config(&mut cmd, config, args.no_config, &matches)?;
+            // A scope has to contain the sandbox, which the daemon's process wouldn't be in
+            if !cmd.no_daemon
+                && !cmd.systemd_scope
+                && let Some(code) = daemon::launch()?
+            {
+                return Ok(ExitCode::from(code));
+            }
+            run_command(*cmd).map(ExitCode::from)
+        }
+        args::Command::Debug(mut cmd) => {
+            apply_config(&mut cmd, config, args.no_config, &matches)?;
+            if cmd.plan.is_some() {
+                bail!("A prepared sandbox can't be debugged, debug the app instead");
+            }
+            cmd.sandbox.devel = true;
+            cmd.sandbox.debug = true;
+            run_command(*cmd).map(ExitCode::from)
+        }
+        args::Command::Build(mut cmd) => {
+            apply_config(&mut cmd.run, config, args.no_config, &matches)?;
+            build(*cmd)
+        }
+        args::Command::Prepare(mut cmd) => {
+            apply_config(&mut cmd.run, config, args.no_config, &matches)?;
+            prepare(*cmd)
+        }
+        args::Command::ExportRootfs(mut cmd) => {
+            apply_config(&mut cmd.run, config, args.no_config, &matches)?;
+            export_rootfs(*cmd)
+        }
+        args::Command::ExportImage(mut cmd) => {
+            apply_config(&mut cmd.run, config, args.no_config, &matches)?;
+            export_image(*cmd)
+        }
+        args::Command::ExportOci(mut cmd) => {
+            apply_config(&mut cmd.run, config, args.no_config, &matches)?;
+            export_oci(*cmd)
+        }
+        args::Command::Deps(mut cmd) => {
+            cmd.flatpak_install_path.extend(config.flatpak_install_path);
+            deps::deps(cmd)
+        }
+        args::Command::Install(mut cmd) => {
+            cmd.flatpak_install_path.extend(config.flatpak_install_path);
+            install(cmd)
+        }
+        args::Command::Verify(mut cmd) => {
+            cmd.flatpak_install_path.extend(config.flatpak_install_path);
+            verify(cmd)
+        }
+        args::Command::Info(mut cmd) => {
+            cmd.flatpak_install_path.extend(config.flatpak_install_path);
+            info(cmd)
+        }
+        args::Command::Doctor => Ok(doctor::doctor(&flatpak_install_dirs(
+            &config.flatpak_install_path,
+            None,
+        )?)),
+        args::Command::Gc(cmd) => {
+            // Ages too long to be represented never pass anyway
+            let max_age = Duration::from_secs(cmd.max_age.saturating_mul(24 * 60 * 60));
+            for path in gc::collect(max_age, cmd.dry_run) {
+                println!("{}", path.display());
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        args::Command::Daemon(mut cmd) => {
+            cmd.flatpak_install_path.extend(config.flatpak_install_path);
+            daemon::serve(&flatpak_install_dirs(&cmd.flatpak_install_path, None)?)
+        }
+        args::Command::Portal => portal::serve(),
+        args::Command::GenerateUnit(cmd) => {
+            print!("{}", systemd::service_unit(&cmd.app, &cmd.run_args)?);
+            Ok(ExitCode::SUCCESS)
+        }
+        args::Command::ExportDesktop(cmd) => {
+            let mut install_dirs = cmd.flatpak_install_path.clone();
+            install_dirs.extend(config.flatpak_install_path);
+            export_desktop(cmd, &install_dirs)
+        }
+        args::Command::Completions { shell } => {
+            print!("{}", completions::generate(shell));
+            Ok(ExitCode::SUCCESS)
+        }
+        args::Command::HostSpawn(cmd) => host_command::spawn(cmd).map(ExitCode::from),
+        args::Command::ListInstalled { kind } => {
+            let install_dirs = flatpak_install_dirs(&config.flatpak_install_path, None)?;
+            for installed in completions::list_installed(kind, &install_dirs) {
+                println!("{installed}");
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        args::Command::Config => {
+            if let Some(path) = config_path.filter(|_| !args.no_config) {
+                println!("# {}", path.display());
+            }
+            print!(
+                "{}",
+                toml::to_string_pretty(&config).context("Could not serialize config")?
+            );
+            Ok(ExitCode::SUCCESS)
+        }
     }
+}
 
-    add_ld_so_conf(&mut bwrap)?;
-
-    setup_env(&mut bwrap, runtime_env, run.app.as_deref());
-
-    if run.apparmor_unconfined
-        && let Ok(current_profiles) = fs::read_to_string("/sys/kernel/security/apparmor/profiles")
-        && current_profiles.contains("(unconfined)")
+/// Runs a command in a build dir, in the SDK of the app being built
+fn build(cmd: BuildCommand) -> anyhow::Result<ExitCode> {
+    let mut run = cmd.run;
+    let sandbox = &run.sandbox;
+    if sandbox.app.is_some()
+        || sandbox.runtime.is_some()
+        || sandbox.bundle.is_some()
+        || sandbox.ostree_repo.is_some()
+        || sandbox.rootfs.is_some()
+        || sandbox.oci_image.is_some()
+        || run.plan.is_some()
     {
-        bwrap = bwrap.wrap_apparmor_unconfined();
+        bail!("The app and SDK to build with are the ones of the build dir");
     }
-
-    // bwrap.bind_data("/etc/ld.so.cache", &[])?;
-
-    let (mut cmd, _data) = bwrap.finish();
-    if verbose {
-        eprintln!("Generated cmd: {cmd:#?}");
+    run.sandbox.build = Some(BuildDir {
+        path: cmd.directory,
+        bind_mounts: cmd.bind_mount,
+        readonly: cmd.readonly,
+    });
+    if cmd.build_dir.is_some() {
+        run.sandbox.cwd = cmd.build_dir;
     }
+    // Like flatpak build, a shell is started without a command
+    if run.sandbox.command.is_none() && run.sandbox.command_override.is_none() {
+        run.sandbox.command = Some(BUILD_SHELL.to_owned());
+    }
+    run_command(run).map(ExitCode::from)
+}
 
-    // let ldconfig_status = Command::new(cmd.get_program())
-    //     .args(cmd.get_args())
-    //     .arg("ldconfig")
-    //     .arg("-X")
-    //     .output()?;
-    // eprintln!(
-    //     "ldconfig status: {} {}{}",
-    //     ldconfig_status.status,
-    //     String::from_utf8_lossy(&ldconfig_status.stdout),
-    //     String::from_utf8_lossy(&ldconfig_status.stderr)
-    // );
-
-    let mut child = cmd
-        .arg("sh")
-        .arg("-c")
-        .arg(format!(
-            "ldconfig && {} {}",
-            run.command,
-            run.args.join(" ")
-        ))
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .spawn()?;
-
-    let out = child.wait()?;
+/// Runs the `flatbox run` command line of a daemon's client, in the process forked for it, and returns
+/// the exit code
+fn run_for_client(argv: Vec<OsString>) -> u8 {
+    let result = parse_invocation(argv).and_then(|invocation| {
+        let args::Command::Run(mut cmd) = invocation.args.command else {
+            bail!("The daemon only runs sandboxes");
+        };
+        apply_config(
+            &mut cmd,
+            invocation.config,
+            invocation.args.no_config,
+            &invocation.matches,
+        )?;
+        run_command(*cmd)
+    });
+    result.unwrap_or_else(|err| {
+        report_error(&err);
+        1
+    })
+}
 
-    Ok(out
-        .code()
-        .and_then(|code| u8::try_from(code).ok())
-        .map(ExitCode::from)
-        .unwrap_or(ExitCode::SUCCESS))
+/// Runs the sandbox, in a systemd scope when requested
+fn run_command(cmd: RunCommand) -> anyhow::Result<u8> {
+    if cmd.systemd_scope && env::var_os(systemd::SCOPE_ENV).is_none() {
+        let sandbox = &cmd.sandbox;
+        let id = sandbox.app.as_ref().or(sandbox.runtime.as_ref());
+        let id = id.and_then(|id| id.split('/').next()).unwrap_or("sandbox");
+        let limits = match &cmd.plan {
+            Some(plan) => prepared::load(plan)?.plan().limits,
+            None => sandbox.resource_limits(),
+        };
+        return Err(systemd::exec_in_scope(id, &limits.systemd_properties()));
+    }
+    gc::auto_collect();
+    run(cmd)
 }
 
-fn setup_runtime(
-    bwrap: &mut BwrapBuilder,
-    runtime_files_path: &Path,
-    app_files_path: Option<&Path>,
+/// Fills in the run options not given on the command line from the config and the app's profile
+fn apply_config(
+    run: &mut RunCommand,
+    config: Config,
+    no_config: bool,
+    matches: &ArgMatches,
 ) -> anyhow::Result<()> {
-    bwrap.ro_bind(runtime_files_path, "/usr");
-
-    if let Some(app_path) = app_files_path {
-        bwrap.ro_bind(app_path, "/app");
+    let mut run_config = config.run;
+    if !no_config
+        && let Some(app) = &run.sandbox.app
+        && let Some(profile) = config::load_app_profile(app.split('/').next().unwrap_or(app))?
+    {
+        run_config.merge(profile);
     }
+    if let Some((_, run_matches)) = matches.subcommand() {
+        run_config.apply(run, run_matches);
+    }
+    run.sandbox
+        .flatpak_install_path
+        .extend(config.flatpak_install_path);
+    Ok(())
+}
 
-    let runtime_etc = fs::read_dir(runtime_files_path.join("etc"))?;
-
-    for entry in runtime_etc {
-        let entry = entry?;
-        let path = entry.path();
-
-        let target_path = path
-            .strip_prefix(runtime_files_path)
-            .expect("Could not strip etc path prefix");
+fn run(run: RunCommand) -> anyhow::Result<u8> {
+    let options = RunOptions {
+        backend: run.sandbox.backend,
+        timeout: run.timeout.map(Duration::from_secs),
+        pty: run.pty,
+        time_startup: run.time_report,
+    };
+    let coredump_dir = run.sandbox.coredump_dir.clone();
+    let host_commands = run.sandbox.host_commands;
+    if run.time_report {
+        logging::record_timings();
+    }
+    let prepared = run
+        .plan
+        .as_deref()
+        .map(|plan| load_prepared(plan, &run.sandbox))
+        .transpose()?;
+    // The container backends apply the limits to their containers. Created first, so the helpers started
+    // while setting up the sandbox are limited too, and removed last.
+    let limits = match &prepared {
+        Some(sandbox) => sandbox.plan().limits,
+        None => run.sandbox.resource_limits(),
+    };
+    let _cgroup = if !limits.is_empty()
+        && matches!(options.backend, Backend::Bwrap | Backend::Native)
+        && env::var_os(systemd::SCOPE_ENV).is_none()
+    {
+        Some(cgroup::limit_process(&limits)?)
+    } else {
+        None
+    };
+    let sandbox = match prepared {
+        Some(sandbox) => sandbox,
+        None => SandboxBuilder::with_options(run.sandbox).build()?,
+    };
+    if options.backend != Backend::Bwrap {
+        flatbox_core::info!("Sandbox plan: {:#?}", sandbox.plan());
+    }
+    // Bound into the sandbox, so it has to exist before the sandbox is created
+    if host_commands {
+        let path = sandbox
+            .setup_dir()
+            .join(flatbox_core::host_command::SOCKET_NAME);
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Could not listen on {}", path.display()))?;
+        host_command::serve(listener);
+    }
 
-        if let Ok(symlink_target) = fs::read_link(&path) {
-            bwrap.symlink(&symlink_target, target_path);
-        } else {
-            bwrap.ro_bind(&path, target_path);
+    let started = SystemTime::now();
+    let result = sandbox.run(&options);
+    if run.time_report {
+        eprint!("{}", time_report::format(&logging::timings()));
+    }
+    if let Some(coredump_dir) = &coredump_dir {
+        for core in coredump::find_cores(coredump_dir, started) {
+            eprintln!("A process of the sandbox dumped core to {}", core.display());
         }
     }
-
-    for dir in ROOT_USR_MERGED_DIRS {
-        if runtime_files_path.join(dir).exists() {
-            bwrap.symlink(Path::new("/usr").join(dir), dir);
+    let status = match result {
+        Ok(status) => status,
+        Err(err) if matches!(err.downcast_ref(), Some(FlatboxError::TimedOut { .. })) => {
+            eprintln!("{err}");
+            return Ok(TIMEOUT_EXIT_CODE);
         }
+        Err(err) => return Err(err),
+    };
+    // bwrap exits with 1 when it can't set up the sandbox, which a failing command may do as well,
+    // so check whether a minimal sandbox works before blaming bwrap
+    if options.backend == Backend::Bwrap
+        && status.code() == Some(1)
+        && let Ok(probe) = doctor::probe_bwrap()
+        && !probe.status.success()
+    {
+        // bwrap has already printed its error, only add what to do about it
+        eprintln!(
+            "bwrap could not create the sandbox. {}",
+            doctor::bwrap_failure_hint(
+                &String::from_utf8_lossy(&probe.stderr),
+                doctor::apparmor_restricts_userns()
+            )
+        );
     }
 
-    bwrap.ro_bind_data("/.flatpak-info", &[])?;
-
-    Ok(())
+    Ok(status
+        .code()
+        .and_then(|code| u8::try_from(code).ok())
+        .unwrap_or_default())
 }
 
-fn setup_runtime_extensions(
-    bwrap: &mut BwrapBuilder,
-    runtime_metadata: &IndexMap<&str, IndexMap<&str, &str>>,
-    available_runtimes: &[String],
-    install_dirs: &[PathBuf],
-) -> anyhow::Result<()> {
-    let runtime = runtime_metadata
-        .get("Runtime")
-        .and_then(|runtime| runtime.get("runtime"))
-        .context("Missing runtime spec")?;
-    let mut runtime_split = runtime.split('/').skip(1);
-    let arch = runtime_split
-        .next()
-        .context("Could not extract architecture from runtime id")?;
-    let version = runtime_split
-        .next()
-        .context("Could not extract version from runtime id")?;
-
-    for (group, metadata) in runtime_metadata {
-        if let Some(extension) = group.strip_prefix(EXTENSION_PREFIX) {
-            setup_extension(
-                metadata,
-                bwrap,
-                extension,
-                arch,
-                version,
-                available_runtimes,
-                install_dirs,
-                Path::new("/usr"),
-            )
-            .with_context(|| format!("Could not set up extension {extension}"))?;
-        }
+/// Loads a sandbox saved by `flatbox prepare`, running the command given in `options` if there is one
+fn load_prepared(plan: &Path, options: &SandboxOptions) -> anyhow::Result<Sandbox> {
+    let mut sandbox = prepared::load(plan)?;
+    if options.command.is_some() || options.command_override.is_some() {
+        sandbox.set_command_line(options.command_line(None)?);
     }
-
-    Ok(())
+    Ok(sandbox)
 }
 
-fn setup_app_extensions(
-    bwrap: &mut BwrapBuilder,
-    app_metadata: &IndexMap<&str, IndexMap<&str, &str>>,
-    runtime: &str,
-    available_runtimes: &[String],
-    install_dirs: &[PathBuf],
-) -> anyhow::Result<()> {
-    let mut runtime_split = runtime.split('/').skip(1);
-    let arch = runtime_split
-        .next()
-        .context("Could not extract architecture from runtime id")?;
-    let version = runtime_split
-        .next()
-        .context("Could not extract version from runtime id")?;
-
-    for (group, metadata) in app_metadata {
-        if let Some(extension) = group.strip_prefix(EXTENSION_PREFIX) {
-            setup_extension(
-                metadata,
-                bwrap,
-                extension,
-                arch,
-                version,
-                available_runtimes,
-                install_dirs,
-                Path::new("/app"),
-            )
-            .with_context(|| format!("Could not set up app extension {extension}"))?;
-        }
+/// Composes the sandbox of the run options, or loads the prepared one
+fn build_sandbox(run: RunCommand) -> anyhow::Result<Sandbox> {
+    match &run.plan {
+        Some(plan) => load_prepared(plan, &run.sandbox),
+        None => SandboxBuilder::with_options(run.sandbox).build(),
     }
-
-    Ok(())
 }
 
-fn setup_extension(
-    extension_metadata: &IndexMap<&str, &str>,
-    bwrap: &mut BwrapBuilder,
-    name: &str,
-    arch: &str,
-    runtime_version: &str,
-    available_runtimes: &[String],
-    install_dirs: &[PathBuf],
-    base_path: &Path,
-) -> anyhow::Result<()> {
-    let directory = extension_metadata
-        .get("directory")
-        .context("Missing directory")?;
-
-    let expected_prefix = format!("{name}.");
-    let extension_base_mount_path = base_path.join(directory);
-
-    let allowed_versions = extension_metadata
-        .get("versions")
-        .or_else(|| extension_metadata.get("version"))
-        .map(|version| version.to_string())
-        .unwrap_or_else(|| runtime_version.to_owned());
-
-    bwrap.tmpfs(&extension_base_mount_path);
-
-    let mut mounted_paths = Vec::new();
-
-    for extension in available_runtimes {
-        let Some(extension_impl_name) = extension.strip_prefix(&expected_prefix) else {
-            continue;
-        };
-
-        let enabled: bool = match extension_metadata.get("enable-if").copied() {
-            Some("active-gl-driver") => match extension_impl_name {
-                "default" | "host" => true,
-                _ => {
-                    if let Some(nvidia_version) = extension_impl_name.strip_prefix("nvidia-") {
-                        fs::read_to_string("/sys/module/nvidia/version")
-                            .map(|version| version.trim().replace('.', "-"))
-                            .is_ok_and(|allowed_version| allowed_version == nvidia_version)
-                    } else {
-                        false
-                    }
-                }
-            },
-            Some(enable_if) => {
-                eprintln!("Unsupported enable-if reason '{enable_if}' on extension '{name}'");
-                false
-            }
-            None => true,
-        };
-
-        if !enabled {
-            continue;
-        }
+fn prepare(prepare: PrepareCommand) -> anyhow::Result<ExitCode> {
+    let sandbox = build_sandbox(prepare.run)?;
+    prepared::save(&sandbox, &prepare.out)?;
+    eprintln!(
+        "Prepared the sandbox in {}, launch it with `flatbox run --plan {} [COMMAND]`",
+        prepare.out.display(),
+        prepare.out.display()
+    );
+    Ok(ExitCode::SUCCESS)
+}
 
-        if let Some(full_extension_path) = allowed_versions
-            .split(';')
-            .map(|version| {
-                Path::new(extension)
-                    .join(arch)
-                    .join(version)
-                    .join("active")
-                    .join("files")
-            })
-            .find_map(|path| find_install_path(path, false, install_dirs))
-        {
-            let extension_mount_path = extension_base_mount_path.join(extension_impl_name);
-            bwrap.ro_bind(&full_extension_path, &extension_mount_path);
-            mounted_paths.push((full_extension_path, extension_mount_path));
-        }
+fn export_rootfs(mut export: ExportRootfsCommand) -> anyhow::Result<ExitCode> {
+    // The command isn't part of the filesystem, but runtimes don't declare one to fall back to
+    let options = &mut export.run.sandbox;
+    if options.command.is_none() && options.command_override.is_none() {
+        options.command = Some("sh".to_owned());
     }
+    let prepared_data = export.run.plan.as_deref().map(prepared::data_dir);
+    let sandbox = build_sandbox(export.run)?;
+    let data_dirs: Vec<&Path> = [sandbox.setup_dir()]
+        .into_iter()
+        .chain(prepared_data.as_deref())
+        .collect();
+    rootfs::export(&export.out, sandbox.plan(), &data_dirs)?;
+    eprintln!("Wrote the filesystem to {}", export.out.display());
+    Ok(ExitCode::SUCCESS)
+}
 
-    let mut existing_symlinks = HashSet::new();
-    for (source, target) in &mounted_paths {
-        if let Some(merge_dirs) = extension_metadata.get("merge-dirs") {
-            let mut processed_paths = HashSet::new();
-            for merge_dir in merge_dirs.split(';') {
-                if let Ok(entries) = fs::read_dir(source.join(merge_dir)) {
-                    for entry in entries {
-                        let entry = entry?;
-
-                        if !entry.file_type()?.is_file() {
-                            continue;
-                        }
-
-                        if processed_paths.insert(entry.path()) {
-                            let symlink_source = target.join(merge_dir).join(entry.file_name());
-                            let symlink_target = extension_base_mount_path
-                                .join(merge_dir)
-                                .join(entry.file_name());
-                            if existing_symlinks.insert(symlink_target.clone()) {
-                                bwrap.symlink(symlink_source, symlink_target);
-                            }
-                        }
-                    }
-                }
-            }
+fn export_image(export: ExportImageCommand) -> anyhow::Result<ExitCode> {
+    let options = &export.run.sandbox;
+    // The arch of a ref given by the app or runtime, like the sandbox uses
+    let arch = options
+        .arch
+        .clone()
+        .or_else(|| {
+            let reference = options.app.as_ref().or(options.runtime.as_ref())?;
+            let arch = reference.split('/').nth(1)?;
+            (!arch.is_empty()).then(|| arch.to_owned())
+        })
+        .unwrap_or_else(arch::host_arch);
+    let prepared_data = export.run.plan.as_deref().map(prepared::data_dir);
+    let sandbox = build_sandbox(export.run)?;
+    let data_dirs: Vec<&Path> = [sandbox.setup_dir()]
+        .into_iter()
+        .chain(prepared_data.as_deref())
+        .collect();
+    let command_line = sandbox.command_line();
+    let config = ImageConfig {
+        arch: &arch,
+        entrypoint: &sandbox.argv()[..sandbox.argv().len() - command_line.len()],
+        cmd: command_line,
+    };
+    match &export.out {
+        Some(out) => {
+            image::export_layout(out, &export.tag, sandbox.plan(), &data_dirs, &config)?;
+            eprintln!(
+                "Wrote the image to {}, copy it with e.g. `skopeo copy oci:{}:{} docker-daemon:{}`",
+                out.display(),
+                out.display(),
+                export.tag,
+                export.tag
+            );
         }
-
-        if let Some(add_ld_path) = extension_metadata.get("add-ld-path") {
-            let ld_path = target.join(add_ld_path);
-            let mut ld_contents = ld_path
-                .to_str()
-                .context("Invalid ld path formed")?
-                .to_owned();
-            ld_contents.push('\n');
-
-            let impl_name = target
-                .file_name()
-                .context("Invalid extension name")?
-                .to_str()
-                .context("Invalid extension name")?;
-            let filename = format!("runtime-{name}.{impl_name}.conf");
-            let ld_config_path = Path::new("/run/flatpak/ld.so.conf.d").join(filename);
-
-            bwrap.ro_bind_data(&ld_config_path, ld_contents.as_bytes())?;
+        None => {
+            image::import_podman(&export.tag, sandbox.plan(), &data_dirs, &config)?;
+            eprintln!("Imported the image into podman as {}", export.tag);
         }
     }
-
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
-fn add_ld_so_conf(bwrap: &mut BwrapBuilder) -> anyhow::Result<()> {
-    let contents = "\
-include /run/flatpak/ld.so.conf.d/app-*.conf
-include /app/etc/ld.so.conf
-/app/lib
-include /run/flatpak/ld.so.conf.d/runtime-*.conf
-";
-
-    bwrap.ro_bind_data("/etc/ld.so.conf", contents.as_bytes())?;
-    Ok(())
+fn export_oci(export: ExportOciCommand) -> anyhow::Result<ExitCode> {
+    let sandbox = build_sandbox(export.run)?;
+    oci::export_bundle(
+        &export.out,
+        sandbox.plan(),
+        sandbox.setup_dir(),
+        sandbox.argv(),
+    )?;
+    eprintln!(
+        "Wrote the bundle to {}, run it with e.g. `crun run --bundle {} <name>`",
+        export.out.display(),
+        export.out.display()
+    );
+    Ok(ExitCode::SUCCESS)
 }
 
-fn setup_host_root_dirs(bwrap: &mut BwrapBuilder) -> anyhow::Result<()> {
-    let root_dirs = fs::read_dir("/").context("Could not read root dir")?;
-    for entry in root_dirs {
-        let entry = entry.context("Could not evaluate root dir")?;
-        if let Some(filename) = entry.file_name().to_str() {
-            let entry_path = entry.path();
-            if FORBIDDEN_HOST_ROOT_DIRS.contains(&filename)
-                || ROOT_USR_MERGED_DIRS.contains(&filename)
-            {
-                continue;
-            }
-
-            if fs::exists(&entry_path).is_ok_and(|exists| exists) {
-                bwrap.bind(&entry_path, &entry_path);
-            }
-        }
+fn install(cmd: InstallCommand) -> anyhow::Result<ExitCode> {
+    let install_dirs = flatpak_install_dirs(&cmd.flatpak_install_path, None)?;
+    let deployment = install::install(
+        &cmd.reference,
+        cmd.remote.as_deref(),
+        cmd.arch.as_deref(),
+        &install_dirs,
+    )?;
+    if deployment.updated {
+        eprintln!(
+            "Installed {} from {} (commit {}) to {}",
+            deployment.reference,
+            deployment.remote,
+            deployment.commit,
+            deployment.path.display()
+        );
+    } else {
+        eprintln!("{} is already up to date", deployment.reference);
     }
+    Ok(ExitCode::SUCCESS)
+}
 
-    let run_dirs = fs::read_dir("/run").context("Could not read root dir")?;
-    for entry in run_dirs {
-        let entry = entry.context("Could not evaluate run dir")?;
-        if let Some(filename) = entry.file_name().to_str() {
-            let entry_path = entry.path();
-            if FORBIDDEN_RUN_DIRS.contains(&filename) {
-                continue;
-            }
-
-            if fs::exists(&entry_path).is_ok_and(|exists| exists) {
-                bwrap.bind(&entry_path, &entry_path);
-            }
+/// The name and the active deployment of the installed app or runtime
+fn find_deployment(
+    app: Option<&str>,
+    runtime: Option<&str>,
+    arch: Option<&str>,
+    branch: Option<&str>,
+    install_dirs: &[PathBuf],
+) -> anyhow::Result<(String, PathBuf)> {
+    match (app, runtime) {
+        (Some(app), _) => {
+            let app_ref = AppRef::parse(app, arch, branch)?;
+            let deployment = find_app_path(&app_ref, install_dirs)?;
+            Ok((app_ref.id, deployment))
         }
-    }
-
-    for name in EXPOSED_ETC_PATHS {
-        let path = Path::new("/etc").join(name);
-        if path.exists() {
-            bwrap.ro_bind(&path, &path);
+        (None, Some(runtime)) => {
+            let default_arch = arch.map_or_else(arch::host_arch, str::to_owned);
+            let runtime = refs::resolve_runtime_ref(runtime, &default_arch, install_dirs)?;
+            let runtime_path =
+                find_install_path(&runtime, false, install_dirs).ok_or_else(|| {
+                    FlatboxError::RuntimeNotFound {
+                        runtime: runtime.clone(),
+                        searched_dirs: install_dirs.to_vec(),
+                    }
+                })?;
+            Ok((runtime, runtime_path.join("active")))
         }
+        (None, None) => unreachable!("Either app or runtime is required by the arguments"),
     }
+}
 
-    for (source, target, writable) in PATH_BINDINDGS {
-        if Path::new(source).exists() {
-            if writable {
-                bwrap.bind(source, target);
-            } else {
-                bwrap.ro_bind(source, target);
-            }
+fn export_desktop(
+    cmd: ExportDesktopCommand,
+    extra_install_dirs: &[PathBuf],
+) -> anyhow::Result<ExitCode> {
+    let install_dirs = flatpak_install_dirs(extra_install_dirs, cmd.installation.as_deref())?;
+    let (app_id, deployment) = find_deployment(
+        Some(&cmd.app),
+        None,
+        cmd.arch.as_deref(),
+        cmd.branch.as_deref(),
+        &install_dirs,
+    )?;
+    if cmd.remove {
+        for launcher in desktop::remove_launchers(&deployment)? {
+            println!("Removed {}", launcher.display());
         }
+        return Ok(ExitCode::SUCCESS);
     }
 
-    bwrap.dev_bind("/dev", "/dev");
-    bwrap.symlink("/run", "/var/run");
-
-    Ok(())
-}
-
-fn setup_env(bwrap: &mut BwrapBuilder, runtime_env: IndexMap<&str, &str>, app_id: Option<&str>) {
-    for (env, value) in DEFAULT_ENV {
-        match value {
-            Some(value) => bwrap.set_env(env, value),
-            None => bwrap.unset_env(env),
-        };
+    // The launchers find the same deployment
+    let mut run_args = Vec::new();
+    for path in &cmd.flatpak_install_path {
+        run_args.push(format!("--flatpak-install-path={}", path.display()));
     }
-
-    for (env, value) in runtime_env {
-        bwrap.set_env(env, value);
+    let selection = [
+        ("installation", &cmd.installation),
+        ("arch", &cmd.arch),
+        ("branch", &cmd.branch),
+    ];
+    for (option, value) in selection {
+        if let Some(value) = value {
+            run_args.push(format!("--{option}={value}"));
+        }
     }
-
-    if let Some(app) = app_id
-        && let Ok(home) = env::var("HOME")
-    {
-        let app_id_dir = Path::new(&home).join(".var").join("app").join(app);
-        bwrap.set_env("XDG_DATA_HOME", app_id_dir.join("data"));
-        bwrap.set_env("XDG_CONFIG_HOME", app_id_dir.join("config"));
-        bwrap.set_env("XDG_CACHE_HOME", app_id_dir.join("cache"));
-        bwrap.set_env("XDG_STATE_HOME", app_id_dir.join(".local").join("state"));
+    run_args.extend(cmd.run_args);
+    for launcher in desktop::export_launchers(&cmd.app, &app_id, &deployment, &run_args)? {
+        println!("Installed {}", launcher.display());
     }
+    Ok(ExitCode::SUCCESS)
 }
 
-fn list_available_runtimes(install_dirs: &[PathBuf]) -> anyhow::Result<Vec<String>> {
-    let mut output = Vec::new();
-
-    for dir in install_dirs {
-        let dir_runtimes = fs::read_dir(dir.join("runtime"))
-            .into_iter()
-            .flatten()
-            .map(|entry| {
-                entry.context("Could not read entry").and_then(|entry| {
-                    entry
-                        .file_name()
-                        .into_string()
-                        .map_err(|_| anyhow!("Invalid runtime name"))
-                })
-            })
-            .collect::<anyhow::Result<Vec<String>>>()?;
+fn verify(cmd: VerifyCommand) -> anyhow::Result<ExitCode> {
+    let install_dirs =
+        flatpak_install_dirs(&cmd.flatpak_install_path, cmd.installation.as_deref())?;
+    let (name, deployment) = find_deployment(
+        cmd.app.as_deref(),
+        cmd.runtime.as_deref(),
+        cmd.arch.as_deref(),
+        cmd.branch.as_deref(),
+        &install_dirs,
+    )?;
 
-        output.extend(dir_runtimes);
+    let differences = verify::verify_deployment(&deployment)?;
+    if differences.is_empty() {
+        println!("{name}: all files match the commit");
+        return Ok(ExitCode::SUCCESS);
     }
-
-    Ok(output)
+    for difference in &differences {
+        println!("  {difference}");
+    }
+    println!(
+        "{name}: {} files differ from the commit, reinstall it to repair the deployment",
+        differences.len()
+    );
+    Ok(ExitCode::FAILURE)
 }
 
-fn find_install_path(
-    name: impl AsRef<Path>,
-    is_app: bool,
-    install_dirs: &[PathBuf],
-) -> Option<PathBuf> {
-    let infix = if is_app { "app" } else { "runtime" };
-    for dir in install_dirs {
-        let path = Path::new(dir).join(infix).join(name.as_ref());
-        if path.exists() {
-            return Some(path);
+fn info(cmd: InfoCommand) -> anyhow::Result<ExitCode> {
+    let install_dirs =
+        flatpak_install_dirs(&cmd.flatpak_install_path, cmd.installation.as_deref())?;
+    let (name, deployment) = find_deployment(
+        cmd.app.as_deref(),
+        cmd.runtime.as_deref(),
+        cmd.arch.as_deref(),
+        cmd.branch.as_deref(),
+        &install_dirs,
+    )?;
+    let deploy_data = deploy::read_deploy_data(&deployment)?;
+
+    println!("{name}");
+    if let Some(app_name) = &deploy_data.appdata_name {
+        match &deploy_data.appdata_version {
+            Some(version) => println!("  Name: {app_name} {version}"),
+            None => println!("  Name: {app_name}"),
         }
     }
-    None
+    println!("  Origin: {}", deploy_data.origin);
+    println!("  Commit: {}", deploy_data.commit);
+    if let Ok(location) = fs::canonicalize(&deployment) {
+        println!("  Location: {}", location.display());
+    }
+    if !deploy_data.subpaths.is_empty() {
+        println!("  Subpaths: {}", deploy_data.subpaths.join(" "));
+    }
+    println!(
+        "  Installed size: {:.1} MB",
+        deploy_data.installed_size as f64 / 1_000_000.0
+    );
+    if !deploy_data.previous_ids.is_empty() {
+        println!("  Previous ids: {}", deploy_data.previous_ids.join(" "));
+    }
+    if let Some(eol) = &deploy_data.eol {
+        println!("  End of life: {eol}");
+    }
+    if let Some(rebase) = &deploy_data.eol_rebase {
+        println!("  Replaced by: {rebase}");
+    }
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,362 @@
+use anyhow::{Context, bail};
+use flatbox_core::{
+    dbus::{
+        BUS_NAME, BUS_PATH, BusSender, Connection, Message, MessageType, NO_REPLY_EXPECTED, Value,
+    },
+    fd_passing::place_fds,
+};
+use std::{
+    collections::HashMap,
+    env,
+    ffi::OsString,
+    io, mem,
+    os::{
+        fd::{AsRawFd, OwnedFd, RawFd},
+        unix::{
+            ffi::OsStringExt,
+            process::{CommandExt, ExitStatusExt},
+        },
+    },
+    path::PathBuf,
+    process::{Command, ExitCode, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// The name flatpak-session-helper owns, which `flatpak-spawn --host` calls
+const FLATPAK_NAME: &str = "org.freedesktop.Flatpak";
+const DEVELOPMENT_PATH: &str = "/org/freedesktop/Flatpak/Development";
+const DEVELOPMENT_INTERFACE: &str = "org.freedesktop.Flatpak.Development";
+const DEVELOPMENT_VERSION: u32 = 1;
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+/// Flags of HostCommand
+const CLEAR_ENV: u32 = 1;
+const WATCH_BUS: u32 = 2;
+/// RequestName flag and reply
+const DO_NOT_QUEUE: u32 = 4;
+const PRIMARY_OWNER: u32 = 1;
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.freedesktop.Flatpak.Development">
+    <property name="version" type="u" access="read"/>
+    <method name="HostCommand">
+      <arg type="ay" name="cwd_path" direction="in"/>
+      <arg type="aay" name="argv" direction="in"/>
+      <arg type="a{uh}" name="fds" direction="in"/>
+      <arg type="a{ss}" name="envs" direction="in"/>
+      <arg type="u" name="flags" direction="in"/>
+      <arg type="u" name="pid" direction="out"/>
+    </method>
+    <method name="HostCommandSignal">
+      <arg type="u" name="pid" direction="in"/>
+      <arg type="u" name="signal" direction="in"/>
+      <arg type="b" name="to_process_group" direction="in"/>
+    </method>
+    <signal name="HostCommandExited">
+      <arg type="u" name="pid"/>
+      <arg type="u" name="exit_status"/>
+    </signal>
+  </interface>
+</node>
+"#;
+
+/// A command started for a client of the bus
+struct HostCommand {
+    /// The unique name of the client
+    owner: String,
+    /// Kill the command when the client disconnects
+    watch_bus: bool,
+}
+
+type HostCommands = Arc<Mutex<HashMap<u32, HostCommand>>>;
+
+/// Provides the `org.freedesktop.Flatpak` Development interface of flatpak-session-helper on the session bus,
+/// running the commands requested with `HostCommand` on the host, until the bus goes away
+pub fn serve() -> anyhow::Result<ExitCode> {
+    let mut bus = Connection::session()?;
+    let request = Message::method_call(
+        BUS_NAME,
+        BUS_PATH,
+        BUS_NAME,
+        "RequestName",
+        vec![
+            Value::Str(FLATPAK_NAME.to_owned()),
+            Value::U32(DO_NOT_QUEUE),
+        ],
+    );
+    let reply = bus
+        .call(&request)
+        .with_context(|| format!("Could not own {FLATPAK_NAME}"))?;
+    if reply.body.first().and_then(Value::as_u32) != Some(PRIMARY_OWNER) {
+        bail!(
+            "{FLATPAK_NAME} is already provided on the session bus, e.g. by flatpak-session-helper"
+        );
+    }
+    // Tells which clients disconnect, for the commands watching the bus
+    let rule = format!("type='signal',sender='{BUS_NAME}',member='NameOwnerChanged',arg2=''");
+    bus.call(&Message::method_call(
+        BUS_NAME,
+        BUS_PATH,
+        BUS_NAME,
+        "AddMatch",
+        vec![Value::Str(rule)],
+    ))?;
+    flatbox_core::info!(
+        "Providing {FLATPAK_NAME} on the session bus as {}",
+        bus.unique_name
+    );
+
+    let sender = bus.sender();
+    let commands = HostCommands::default();
+    loop {
+        let message = bus.receive()?;
+        match message.kind {
+            MessageType::MethodCall => {
+                let reply = handle_call(&sender, &commands, message);
+                if let Some(reply) = reply {
+                    sender.send(&reply)?;
+                }
+            }
+            MessageType::Signal if message.is_method(BUS_NAME, "NameOwnerChanged") => {
+                if let Some(name) = message.body.first().and_then(Value::as_str) {
+                    kill_watching(&commands, name);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Handles a method call, returns the reply unless the caller doesn't expect one
+fn handle_call(
+    sender: &Arc<BusSender>,
+    commands: &HostCommands,
+    mut call: Message,
+) -> Option<Message> {
+    let no_reply = call.flags & NO_REPLY_EXPECTED != 0;
+    let reply = if call.path.as_deref() != Some(DEVELOPMENT_PATH) {
+        call.error(
+            "org.freedesktop.DBus.Error.UnknownObject",
+            &format!("No object at {}", call.path.as_deref().unwrap_or_default()),
+        )
+    } else if call.is_method(DEVELOPMENT_INTERFACE, "HostCommand") {
+        match host_command(sender, commands, &mut call) {
+            Ok(pid) => call.method_return(vec![Value::U32(pid)]),
+            Err(err) => call.error("org.freedesktop.DBus.Error.Failed", &format!("{err:#}")),
+        }
+    } else if call.is_method(DEVELOPMENT_INTERFACE, "HostCommandSignal") {
+        match host_command_signal(commands, &call) {
+            Ok(()) => call.method_return(Vec::new()),
+            Err(err) => call.error("org.freedesktop.DBus.Error.Failed", &format!("{err:#}")),
+        }
+    } else if call.is_method(PROPERTIES_INTERFACE, "Get") {
+        match call.body.get(1).and_then(Value::as_str) {
+            Some("version") => call.method_return(vec![Value::Variant(Box::new(Value::U32(
+                DEVELOPMENT_VERSION,
+            )))]),
+            _ => call.error(
+                "org.freedesktop.DBus.Error.UnknownProperty",
+                "No such property",
+            ),
+        }
+    } else if call.is_method(PROPERTIES_INTERFACE, "GetAll") {
+        let version = Value::DictEntry(
+            Box::new(Value::Str("version".to_owned())),
+            Box::new(Value::Variant(Box::new(Value::U32(DEVELOPMENT_VERSION)))),
+        );
+        call.method_return(vec![Value::Array("{sv}".to_owned(), vec![version])])
+    } else if call.is_method("org.freedesktop.DBus.Introspectable", "Introspect") {
+        call.method_return(vec![Value::Str(INTROSPECTION.to_owned())])
+    } else if call.is_method("org.freedesktop.DBus.Peer", "Ping") {
+        call.method_return(Vec::new())
+    } else {
+        call.error(
+            "org.freedesktop.DBus.Error.UnknownMethod",
+            &format!(
+                "No method {}.{}",
+                call.interface.as_deref().unwrap_or_default(),
+                call.member.as_deref().unwrap_or_default()
+            ),
+        )
+    };
+    (!no_reply).then_some(reply)
+}
+
+/// The bytes of an `ay` argument, without the terminating NUL
+fn byte_string(value: &Value) -> Option<Vec<u8>> {
+    let mut bytes = value.as_bytes()?;
+    if bytes.last() == Some(&0) {
+        bytes.pop();
+    }
+    Some(bytes)
+}
+
+/// Starts `HostCommand(cwd, argv, fds, envs, flags)` in a new session, with the fds passed at their numbers, and
+/// sends `HostCommandExited` to the caller once it exits
+fn host_command(
+    sender: &Arc<BusSender>,
+    commands: &HostCommands,
+    call: &mut Message,
+) -> anyhow::Result<u32> {
+    let owner = call.sender.clone().context("Unknown caller")?;
+    let invalid = || anyhow::anyhow!("Invalid arguments, expected (ay, aay, a{{uh}}, a{{ss}}, u)");
+    let [cwd, argv, fds, envs, flags] = &call.body[..] else {
+        return Err(invalid());
+    };
+    let cwd = PathBuf::from(OsString::from_vec(byte_string(cwd).ok_or_else(invalid)?));
+    let argv = argv
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|arg| byte_string(arg).map(OsString::from_vec))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(invalid)?;
+    let flags = flags.as_u32().ok_or_else(invalid)?;
+    if flags & !(CLEAR_ENV | WATCH_BUS) != 0 {
+        bail!("Unsupported flags {flags:#x}");
+    }
+    let Some(program) = argv.first() else {
+        bail!("No command given");
+    };
+
+    let mut passed_fds: Vec<Option<OwnedFd>> =
+        mem::take(&mut call.fds).into_iter().map(Some).collect();
+    let mut fd_map: Vec<(OwnedFd, RawFd)> = Vec::new();
+    for entry in fds.as_array().ok_or_else(invalid)? {
+        let Some((Value::U32(target), Value::Fd(index))) = entry.as_entry() else {
+            return Err(invalid());
+        };
+        let fd = passed_fds
+            .get_mut(*index as usize)
+            .and_then(Option::take)
+            .with_context(|| format!("No fd {index} passed"))?;
+        fd_map.push((fd, *target as RawFd));
+    }
+
+    let mut command = Command::new(program);
+    command
+        .args(&argv[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if flags & CLEAR_ENV != 0 {
+        command.env_clear();
+    }
+    for entry in envs.as_array().ok_or_else(invalid)? {
+        let Some((Some(key), Some(value))) = entry
+            .as_entry()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+        else {
+            return Err(invalid());
+        };
+        command.env(key, value);
+    }
+    // The caller's working dir may only exist in its sandbox
+    if cwd.is_dir() {
+        command.current_dir(&cwd);
+    } else if let Some(home) = env::var_os("HOME") {
+        command.current_dir(home);
+    }
+
+    let mut raw_fds: Vec<(RawFd, RawFd)> = fd_map
+        .iter()
+        .map(|(fd, target)| (fd.as_raw_fd(), *target))
+        .collect();
+    // SAFETY: only makes syscalls in the forked child
+    unsafe {
+        command.pre_exec(move || {
+            place_fds(&mut raw_fds)?;
+            if libc::setsid() < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Interactive commands (e.g. a shell in a terminal of an IDE) get their terminal back
+            if libc::isatty(libc::STDIN_FILENO) == 1 {
+                libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY, 0);
+            }
+            Ok(())
+        });
+    }
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("Could not start {}", program.display()))?;
+    drop(fd_map);
+    let pid = child.id();
+    flatbox_core::info!("Running {argv:?} on the host for {owner} as {pid}");
+
+    commands
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(
+            pid,
+            HostCommand {
+                owner: owner.clone(),
+                watch_bus: flags & WATCH_BUS != 0,
+            },
+        );
+    let sender = Arc::clone(sender);
+    let commands = Arc::clone(commands);
+    thread::spawn(move || {
+        let status = match child.wait() {
+            Ok(status) => status.into_raw() as u32,
+            Err(err) => {
+                flatbox_core::warn!("Could not wait for {pid}: {err}");
+                return;
+            }
+        };
+        commands
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(&pid);
+        let exited = Message::signal(
+            Some(&owner),
+            DEVELOPMENT_PATH,
+            DEVELOPMENT_INTERFACE,
+            "HostCommandExited",
+            vec![Value::U32(pid), Value::U32(status)],
+        );
+        if let Err(err) = sender.send(&exited) {
+            flatbox_core::warn!("Could not report the exit of {pid}: {err}");
+        }
+    });
+    Ok(pid)
+}
+
+/// `HostCommandSignal(pid, signal, to_process_group)`, for the commands the caller started
+fn host_command_signal(commands: &HostCommands, call: &Message) -> anyhow::Result<()> {
+    let [
+        Value::U32(pid),
+        Value::U32(signal),
+        Value::Bool(to_process_group),
+    ] = &call.body[..]
+    else {
+        bail!("Invalid arguments, expected (u, u, b)");
+    };
+    let commands = commands.lock().unwrap_or_else(|err| err.into_inner());
+    match commands.get(pid) {
+        Some(command) if Some(&command.owner) == call.sender.as_ref() => {}
+        _ => bail!("No command {pid} started by the caller"),
+    }
+    let result = unsafe {
+        if *to_process_group {
+            libc::killpg(*pid as libc::pid_t, *signal as libc::c_int)
+        } else {
+            libc::kill(*pid as libc::pid_t, *signal as libc::c_int)
+        }
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error()).with_context(|| format!("Could not signal {pid}"));
+    }
+    Ok(())
+}
+
+/// Kills the commands watching the bus which a disconnected client started
+fn kill_watching(commands: &HostCommands, name: &str) {
+    let commands = commands.lock().unwrap_or_else(|err| err.into_inner());
+    for (pid, command) in commands.iter() {
+        if command.watch_bus && command.owner == name {
+            flatbox_core::info!("{name} disconnected, killing {pid}");
+            unsafe { libc::killpg(*pid as libc::pid_t, libc::SIGKILL) };
+        }
+    }
+}
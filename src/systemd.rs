@@ -0,0 +1,117 @@
+use crate::args::RunCommand;
+use anyhow::{Context, anyhow};
+use clap::Parser;
+use std::{env, fmt::Write, io, os::unix::process::CommandExt, process::Command};
+
+/// Set for flatbox once it runs in its scope, so it isn't wrapped again. Unset in the sandbox.
+pub const SCOPE_ENV: &str = "FLATBOX_SCOPE";
+
+/// Runs flatbox again with the same arguments in a transient user scope named after `id`, with the given unit properties.
+/// The scope shows up in `systemctl --user`, accounts the resources of the sandbox and its helpers
+/// (like the D-Bus proxies) and is stopped on logout. Only returns when flatbox couldn't be started in it.
+pub fn exec_in_scope(id: &str, properties: &[String]) -> anyhow::Error {
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => return anyhow!(err).context("Could not find the flatbox executable"),
+    };
+    let err = Command::new("systemd-run")
+        .args(["--user", "--scope", "--quiet", "--collect"])
+        .arg(format!(
+            "--unit={}",
+            unit_name(&format!("flatbox-{id}-{}", std::process::id()))
+        ))
+        .arg(format!("--description=flatbox {id}"))
+        .args(
+            properties
+                .iter()
+                .map(|property| format!("--property={property}")),
+        )
+        .arg("--")
+        .arg(exe)
+        .args(env::args_os().skip(1))
+        .env(SCOPE_ENV, "1")
+        .exec();
+    if err.kind() == io::ErrorKind::NotFound {
+        anyhow!("systemd-run is not installed, run without --systemd-scope")
+    } else {
+        anyhow!(err).context("Could not run systemd-run")
+    }
+}
+
+/// A user service running `app` with the given `flatbox run` options, started with the graphical session
+pub fn service_unit(app: &str, run_args: &[String]) -> anyhow::Result<String> {
+    let run_args: Vec<&str> = ["run", "--app", app]
+        .into_iter()
+        .chain(run_args.iter().map(String::as_str))
+        .collect();
+    // Catches typos now rather than when the unit fails at login
+    RunCommand::try_parse_from(&run_args)?;
+
+    let exe = env::current_exe().context("Could not find the flatbox executable")?;
+    let mut exec_start = exec_arg(&exe.to_string_lossy());
+    for arg in run_args {
+        exec_start.push(' ');
+        exec_start.push_str(&exec_arg(arg));
+    }
+
+    let mut unit = String::new();
+    writeln!(unit, "[Unit]").unwrap();
+    writeln!(unit, "Description={} (flatbox)", app.replace('%', "%%")).unwrap();
+    writeln!(unit, "PartOf=graphical-session.target").unwrap();
+    writeln!(unit, "After=graphical-session.target").unwrap();
+    writeln!(unit).unwrap();
+    writeln!(unit, "[Service]").unwrap();
+    writeln!(unit, "Type=exec").unwrap();
+    writeln!(unit, "ExecStart={exec_start}").unwrap();
+    writeln!(unit, "Restart=on-failure").unwrap();
+    writeln!(unit).unwrap();
+    writeln!(unit, "[Install]").unwrap();
+    writeln!(unit, "WantedBy=graphical-session.target").unwrap();
+    Ok(unit)
+}
+
+/// Replaces the characters systemd doesn't allow in unit names
+fn unit_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, ':' | '_' | '.' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Quotes an argument of `ExecStart`, escaping the specifiers and variables systemd would expand
+fn exec_arg(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty()
+        && !escaped
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'))
+    {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::systemd::{exec_arg, unit_name};
+
+    #[test]
+    fn unit_escaping() {
+        assert_eq!(
+            unit_name("flatbox-org.gnome.Platform/x86_64/48-12"),
+            "flatbox-org.gnome.Platform_x86_64_48-12"
+        );
+        assert_eq!(exec_arg("--gl=host"), "--gl=host");
+        assert_eq!(exec_arg("~/My Files:ro"), "\"~/My Files:ro\"");
+        assert_eq!(exec_arg("HOME=$HOME"), "HOME=$$HOME");
+        assert_eq!(exec_arg("100%"), "100%%");
+        assert_eq!(exec_arg(";"), "\";\"");
+        assert_eq!(exec_arg("a\"b"), "\"a\\\"b\"");
+        assert_eq!(exec_arg(""), "\"\"");
+    }
+}
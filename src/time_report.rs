@@ -0,0 +1,61 @@
+use flatbox_core::logging::Timing;
+use std::time::Duration;
+
+/// Formats the durations as an indented table, with the total of the top-level phases
+pub fn format(timings: &[Timing]) -> String {
+    let width = timings
+        .iter()
+        .map(|timing| timing.depth * 2 + timing.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("total".len());
+    let total: Duration = timings
+        .iter()
+        .filter(|timing| timing.depth == 0)
+        .map(|timing| timing.duration)
+        .sum();
+    let mut report = String::from("Startup time report:\n");
+    for (depth, name, duration) in timings
+        .iter()
+        .map(|timing| (timing.depth, timing.name, timing.duration))
+        .chain([(0, "total", total)])
+    {
+        let indent = "  ".repeat(depth);
+        let name_width = width - indent.len();
+        report.push_str(&format!(
+            "  {indent}{name:<name_width$} {:>9.3}ms\n",
+            duration.as_secs_f64() * 1000.0
+        ));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::time_report::format;
+    use flatbox_core::logging::Timing;
+    use std::time::Duration;
+
+    #[test]
+    fn report_totals_top_level_phases() {
+        let timing = |name, depth, millis| Timing {
+            name,
+            depth,
+            duration: Duration::from_millis(millis),
+        };
+        let report = format(&[
+            timing("resolve", 0, 5),
+            timing("metadata", 1, 2),
+            timing("spawn", 0, 1),
+        ]);
+        assert_eq!(
+            report,
+            "Startup time report:
+  resolve        5.000ms
+    metadata     2.000ms
+  spawn          1.000ms
+  total          6.000ms
+"
+        );
+    }
+}